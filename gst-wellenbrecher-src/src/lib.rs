@@ -1,9 +1,11 @@
 use gst::glib;
 
+mod wbheatmapsrc;
 mod wbsrc;
 
 fn plugin_init(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
     wbsrc::register(plugin)?;
+    wbheatmapsrc::register(plugin)?;
     Ok(())
 }
 
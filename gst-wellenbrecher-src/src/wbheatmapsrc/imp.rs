@@ -0,0 +1,380 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use gst::glib;
+use gst::prelude::{ElementExt, ParamSpecBuilderExt, ToValue};
+use gst::subclass::prelude::{
+    ElementImpl, GstObjectImpl, ObjectImpl, ObjectImplExt, ObjectSubclass, ObjectSubclassExt,
+};
+use gst_base::prelude::BaseSrcExt;
+use gst_base::subclass::prelude::{BaseSrcImpl, BaseSrcImplExt};
+
+use once_cell::sync::Lazy;
+use wellenbrecher_canvas::{Bgra, Canvas};
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "wbheatmapsrc",
+        gst::DebugColorFlags::empty(),
+        Some("Wellenbrecher canvas activity heatmap source"),
+    )
+});
+
+const DEFAULT_DECAY: f64 = 0.92;
+
+#[derive(Debug, Clone)]
+struct Settings {
+    width: u32,
+    height: u32,
+    flink: PathBuf,
+    file: bool,
+    decay: f64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            width: 1280,
+            height: 720,
+            flink: PathBuf::from("/tmp/wellenbrecher-canvas"),
+            file: false,
+            decay: DEFAULT_DECAY,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    canvas: Option<Canvas>,
+    /// canvas contents of the previous frame, used to detect writes
+    previous: Vec<Bgra>,
+    heat: Vec<f32>,
+}
+
+#[derive(Default)]
+pub struct WellenbrecherHeatmapSrc {
+    settings: Mutex<Settings>,
+    state: Mutex<State>,
+}
+
+/// Maps heat in `0..=1` from black over red and yellow to white
+#[inline]
+fn heat_color(heat: f32) -> [u8; 4] {
+    let channel = |offset: f32| ((heat * 3.0 - offset).clamp(0.0, 1.0) * 255.0) as u8;
+    [channel(2.0), channel(1.0), channel(0.0), 0xff]
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for WellenbrecherHeatmapSrc {
+    const NAME: &'static str = "WellenbrecherHeatmapSrc";
+    type Type = super::WellenbrecherHeatmapSrc;
+    type ParentType = gst_base::BaseSrc;
+}
+
+impl ObjectImpl for WellenbrecherHeatmapSrc {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpecString::builder("flink")
+                    .nick("Shared memory file link")
+                    .blurb("Shared memory file link")
+                    .default_value("/tmp/wellenbrecher-canvas")
+                    .build(),
+                glib::ParamSpecBoolean::builder("file")
+                    .nick("Plain file canvas")
+                    .blurb("Treat flink as a plain canvas file instead of shared memory")
+                    .default_value(false)
+                    .build(),
+                glib::ParamSpecUInt::builder("width")
+                    .nick("Canvas width")
+                    .blurb("Width of the wellenbrecher canvas")
+                    .minimum(1)
+                    .default_value(1280)
+                    .build(),
+                glib::ParamSpecUInt::builder("height")
+                    .nick("Canvas height")
+                    .blurb("Height of the wellenbrecher canvas")
+                    .minimum(1)
+                    .default_value(720)
+                    .build(),
+                glib::ParamSpecDouble::builder("decay")
+                    .nick("Heat decay")
+                    .blurb("Fraction of the heat a pixel keeps per frame without writes")
+                    .minimum(0.0)
+                    .maximum(1.0)
+                    .default_value(DEFAULT_DECAY)
+                    .build(),
+            ]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        match pspec.name() {
+            "flink" => {
+                let mut settings = self.settings.lock().unwrap();
+                let flink: String = value.get().expect("type checked upstream");
+                gst::info!(
+                    CAT,
+                    imp: self,
+                    "Changing canvas file link from {} to {}",
+                    settings.flink.to_string_lossy(),
+                    flink
+                );
+                settings.flink = PathBuf::from(flink);
+            }
+            "file" => {
+                let mut settings = self.settings.lock().unwrap();
+                let file = value.get().expect("type checked upstream");
+                gst::info!(
+                    CAT,
+                    imp: self,
+                    "Changing plain file canvas from {} to {}",
+                    settings.file,
+                    file
+                );
+                settings.file = file;
+            }
+            "width" => {
+                let mut settings = self.settings.lock().unwrap();
+                let width = value.get().expect("type checked upstream");
+                gst::info!(
+                    CAT,
+                    imp: self,
+                    "Changing width from {} to {}",
+                    settings.width,
+                    width
+                );
+                settings.width = width;
+            }
+            "height" => {
+                let mut settings = self.settings.lock().unwrap();
+                let height = value.get().expect("type checked upstream");
+                gst::info!(
+                    CAT,
+                    imp: self,
+                    "Changing height from {} to {}",
+                    settings.height,
+                    height
+                );
+                settings.height = height;
+            }
+            "decay" => {
+                let mut settings = self.settings.lock().unwrap();
+                let decay = value.get().expect("type checked upstream");
+                gst::info!(
+                    CAT,
+                    imp: self,
+                    "Changing decay from {} to {}",
+                    settings.decay,
+                    decay
+                );
+                settings.decay = decay;
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match pspec.name() {
+            "flink" => {
+                let settings = self.settings.lock().unwrap();
+                settings.flink.to_string_lossy().to_value()
+            }
+            "file" => {
+                let settings = self.settings.lock().unwrap();
+                settings.file.to_value()
+            }
+            "width" => {
+                let settings = self.settings.lock().unwrap();
+                settings.width.to_value()
+            }
+            "height" => {
+                let settings = self.settings.lock().unwrap();
+                settings.height.to_value()
+            }
+            "decay" => {
+                let settings = self.settings.lock().unwrap();
+                settings.decay.to_value()
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn constructed(&self) {
+        self.parent_constructed();
+        let obj = self.obj();
+        obj.set_live(true);
+        obj.set_format(gst::Format::Time);
+    }
+}
+
+impl GstObjectImpl for WellenbrecherHeatmapSrc {}
+
+impl ElementImpl for WellenbrecherHeatmapSrc {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gst::subclass::ElementMetadata> = Lazy::new(|| {
+            gst::subclass::ElementMetadata::new(
+                "Wellenbrecher canvas activity heatmap source",
+                "Source",
+                "Renders a decaying heatmap of the pixels written to the wellenbrecher canvas",
+                "bits0rcerer https://github.com/bits0rcerer",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
+            let caps = gst_video::VideoCapsBuilder::new()
+                .format_list([gst_video::VideoFormat::Bgra])
+                .build();
+            let src_pad_template = gst::PadTemplate::new(
+                "src",
+                gst::PadDirection::Src,
+                gst::PadPresence::Always,
+                &caps,
+            )
+            .unwrap();
+
+            vec![src_pad_template]
+        });
+
+        PAD_TEMPLATES.as_ref()
+    }
+}
+
+impl BaseSrcImpl for WellenbrecherHeatmapSrc {
+    fn set_caps(&self, caps: &gst::Caps) -> Result<(), gst::LoggableError> {
+        let info = gst_video::VideoInfo::from_caps(caps).map_err(|_| {
+            gst::loggable_error!(CAT, "Failed to build `VideoInfo` from caps {}", caps)
+        })?;
+
+        gst::debug!(CAT, imp: self, "Configuring for caps {}", caps);
+
+        self.obj()
+            .set_blocksize(info.width() * info.height() * std::mem::size_of::<Bgra>() as u32);
+
+        let _ = self
+            .obj()
+            .post_message(gst::message::Latency::builder().src(&*self.obj()).build());
+
+        Ok(())
+    }
+
+    fn fixate(&self, mut caps: gst::Caps) -> gst::Caps {
+        caps.truncate();
+        {
+            let caps = caps.make_mut();
+            let s = caps.structure_mut(0).unwrap();
+
+            let settings = self.settings.lock().unwrap();
+
+            s.fixate_field_nearest_int("width", settings.width as i32);
+            s.fixate_field_nearest_int("height", settings.height as i32);
+        }
+
+        self.parent_fixate(caps)
+    }
+
+    fn start(&self) -> Result<(), gst::ErrorMessage> {
+        let settings = self.settings.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+
+        if state.canvas.is_none() {
+            let canvas = match settings.file {
+                true => Canvas::open_file(settings.flink.as_path(), None),
+                false => Canvas::open(settings.flink.as_path(), true, None),
+            }
+            .map_err(|e| {
+                gst::error_msg!(
+                    gst::ResourceError::OpenRead,
+                    ["Unable to open shared memory canvas: {}", e]
+                )
+            })?;
+
+            if canvas.width() != settings.width || canvas.height() != settings.height {
+                return Err(gst::error_msg!(
+                    gst::ResourceError::Settings,
+                    [
+                        "specified canvas dimensions ({}x{}) do not match shared canvas dimensions ({}x{})",
+                        settings.width,
+                        settings.height,
+                        canvas.width(),
+                        canvas.height()
+                    ]
+                ));
+            }
+
+            state.previous = canvas.pixel_slice().to_vec();
+            state.heat = vec![0.0; state.previous.len()];
+            let _ = state.canvas.insert(canvas);
+        }
+
+        gst::debug!(CAT, imp: self, "Opened shared memory canvas {:?}", settings.flink);
+        gst::info!(CAT, imp: self, "Started");
+
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), gst::ErrorMessage> {
+        let mut state = self.state.lock().unwrap();
+        let _ = state.canvas.take();
+        state.previous = Vec::new();
+        state.heat = Vec::new();
+
+        gst::info!(CAT, imp: self, "Stopped");
+
+        Ok(())
+    }
+
+    fn fill(
+        &self,
+        _offset: u64,
+        length: u32,
+        buffer: &mut gst::BufferRef,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let decay = self.settings.lock().unwrap().decay as f32;
+        let mut state = self.state.lock().unwrap();
+        let State {
+            canvas,
+            previous,
+            heat,
+        } = &mut *state;
+        let canvas = if let Some(canvas) = canvas {
+            canvas
+        } else {
+            gst::error!(CAT, imp: self, "shared memory canvas not mapped");
+            return Err(gst::FlowError::Error);
+        };
+
+        let size = (length as usize).min(heat.len() * std::mem::size_of::<Bgra>());
+        {
+            let mut map = buffer.map_writable().map_err(|_| {
+                gst::element_imp_error!(self, gst::LibraryError::Failed, ["Failed to map buffer"]);
+                gst::FlowError::Error
+            })?;
+
+            for (((pixel, previous), heat), out) in canvas
+                .pixel_slice()
+                .iter()
+                .zip(previous.iter_mut())
+                .zip(heat.iter_mut())
+                .zip(map[..size].chunks_exact_mut(std::mem::size_of::<Bgra>()))
+            {
+                *heat = if pixel != previous {
+                    *previous = *pixel;
+                    1.0
+                } else {
+                    *heat * decay
+                };
+                out.copy_from_slice(&heat_color(*heat));
+            }
+        }
+        buffer.set_size(size);
+
+        Ok(gst::FlowSuccess::Ok)
+    }
+}
@@ -0,0 +1,17 @@
+use gst::glib;
+use gst::prelude::*;
+
+mod imp;
+
+glib::wrapper! {
+    pub struct WellenbrecherHeatmapSrc(ObjectSubclass<imp::WellenbrecherHeatmapSrc>) @extends gst_base::BaseSrc, gst::Element, gst::Object;
+}
+
+pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
+    gst::Element::register(
+        Some(plugin),
+        "wbheatmapsrc",
+        gst::Rank::NONE,
+        WellenbrecherHeatmapSrc::static_type(),
+    )
+}
@@ -1,6 +1,7 @@
 use std::cmp::min;
 use std::path::PathBuf;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use gst::glib;
 use gst::glib::bitflags::Flags;
@@ -27,6 +28,20 @@ struct Settings {
     width: u32,
     height: u32,
     flink: PathBuf,
+    file: bool,
+    /// 0 for a live source
+    num_frames: u32,
+    /// 0 for a live source, num_frames takes precedence
+    capture_duration: u64,
+    /// only show pixels owned by this user, 0 shows all pixels
+    user_id_filter: u32,
+}
+
+impl Settings {
+    #[inline]
+    fn is_live(&self) -> bool {
+        self.num_frames == 0 && self.capture_duration == 0
+    }
 }
 
 impl Default for Settings {
@@ -35,6 +50,10 @@ impl Default for Settings {
             width: 1280,
             height: 720,
             flink: PathBuf::from("/tmp/wellenbrecher-canvas"),
+            file: false,
+            num_frames: 0,
+            capture_duration: 0,
+            user_id_filter: 0,
         }
     }
 }
@@ -42,6 +61,16 @@ impl Default for Settings {
 #[derive(Debug, Default)]
 struct State {
     canvas: Option<Canvas>,
+    capture: Option<Capture>,
+}
+
+/// Non-live capture of a fixed number of frames, sampled in real time
+#[derive(Debug)]
+struct Capture {
+    frame_duration: gst::ClockTime,
+    frame_limit: u64,
+    frames: u64,
+    started: Option<Instant>,
 }
 
 #[derive(Default)]
@@ -68,6 +97,11 @@ impl ObjectImpl for WellenbrecherSrc {
                     .blurb("Shared memory file link")
                     .default_value("/tmp/wellenbrecher-canvas")
                     .build(),
+                glib::ParamSpecBoolean::builder("file")
+                    .nick("Plain file canvas")
+                    .blurb("Treat flink as a plain canvas file instead of shared memory")
+                    .default_value(false)
+                    .build(),
                 glib::ParamSpecUInt::builder("width")
                     .nick("Canvas width")
                     .blurb("Width of the wellenbrecher canvas")
@@ -80,6 +114,21 @@ impl ObjectImpl for WellenbrecherSrc {
                     .minimum(1)
                     .default_value(720)
                     .build(),
+                glib::ParamSpecUInt::builder("user-id-filter")
+                    .nick("User id filter")
+                    .blurb("Only show pixels owned by this user, all others are transparent black (0 = show all pixels)")
+                    .default_value(0)
+                    .build(),
+                glib::ParamSpecUInt::builder("num-frames")
+                    .nick("Number of frames")
+                    .blurb("Capture this many frames as a non-live source and send EOS afterwards (0 = live)")
+                    .default_value(0)
+                    .build(),
+                glib::ParamSpecUInt64::builder("capture-duration")
+                    .nick("Capture duration")
+                    .blurb("Capture for this many nanoseconds as a non-live source and send EOS afterwards (0 = live), num-frames takes precedence")
+                    .default_value(0)
+                    .build(),
             ]
         });
 
@@ -105,6 +154,18 @@ impl ObjectImpl for WellenbrecherSrc {
                     .obj()
                     .post_message(gst::message::Latency::builder().src(&*self.obj()).build());
             }
+            "file" => {
+                let mut settings = self.settings.lock().unwrap();
+                let file = value.get().expect("type checked upstream");
+                gst::info!(
+                    CAT,
+                    imp: self,
+                    "Changing plain file canvas from {} to {}",
+                    settings.file,
+                    file
+                );
+                settings.file = file;
+            }
             "width" => {
                 let mut settings = self.settings.lock().unwrap();
                 let width = value.get().expect("type checked upstream");
@@ -129,6 +190,50 @@ impl ObjectImpl for WellenbrecherSrc {
                 );
                 settings.height = height;
             }
+            "user-id-filter" => {
+                let mut settings = self.settings.lock().unwrap();
+                let user_id_filter = value.get().expect("type checked upstream");
+                gst::info!(
+                    CAT,
+                    imp: self,
+                    "Changing user id filter from {} to {}",
+                    settings.user_id_filter,
+                    user_id_filter
+                );
+                settings.user_id_filter = user_id_filter;
+            }
+            "num-frames" => {
+                let mut settings = self.settings.lock().unwrap();
+                let num_frames = value.get().expect("type checked upstream");
+                gst::info!(
+                    CAT,
+                    imp: self,
+                    "Changing number of frames from {} to {}",
+                    settings.num_frames,
+                    num_frames
+                );
+                settings.num_frames = num_frames;
+                let live = settings.is_live();
+                drop(settings);
+
+                self.obj().set_live(live);
+            }
+            "capture-duration" => {
+                let mut settings = self.settings.lock().unwrap();
+                let capture_duration = value.get().expect("type checked upstream");
+                gst::info!(
+                    CAT,
+                    imp: self,
+                    "Changing capture duration from {} to {}",
+                    settings.capture_duration,
+                    capture_duration
+                );
+                settings.capture_duration = capture_duration;
+                let live = settings.is_live();
+                drop(settings);
+
+                self.obj().set_live(live);
+            }
             _ => unimplemented!(),
         }
     }
@@ -139,6 +244,10 @@ impl ObjectImpl for WellenbrecherSrc {
                 let settings = self.settings.lock().unwrap();
                 settings.flink.to_string_lossy().to_value()
             }
+            "file" => {
+                let settings = self.settings.lock().unwrap();
+                settings.file.to_value()
+            }
             "width" => {
                 let settings = self.settings.lock().unwrap();
                 settings.width.to_value()
@@ -147,6 +256,18 @@ impl ObjectImpl for WellenbrecherSrc {
                 let settings = self.settings.lock().unwrap();
                 settings.height.to_value()
             }
+            "user-id-filter" => {
+                let settings = self.settings.lock().unwrap();
+                settings.user_id_filter.to_value()
+            }
+            "num-frames" => {
+                let settings = self.settings.lock().unwrap();
+                settings.num_frames.to_value()
+            }
+            "capture-duration" => {
+                let settings = self.settings.lock().unwrap();
+                settings.capture_duration.to_value()
+            }
             _ => unimplemented!(),
         }
     }
@@ -203,6 +324,41 @@ impl BaseSrcImpl for WellenbrecherSrc {
 
         gst::debug!(CAT, imp: self, "Configuring for caps {}", caps);
 
+        let settings = self.settings.lock().unwrap().clone();
+        if !settings.is_live() {
+            let fps = info.fps();
+            let frame_duration = (fps.numer() > 0)
+                .then(|| {
+                    gst::ClockTime::from_nseconds(
+                        gst::ClockTime::SECOND.nseconds() * fps.denom() as u64 / fps.numer() as u64,
+                    )
+                })
+                .filter(|duration| duration.nseconds() > 0)
+                .ok_or_else(|| {
+                    gst::loggable_error!(CAT, "capturing requires a fixed framerate, got {}", fps)
+                })?;
+            let frame_limit = match settings.num_frames {
+                0 => settings
+                    .capture_duration
+                    .div_ceil(frame_duration.nseconds()),
+                num_frames => num_frames as u64,
+            };
+
+            gst::info!(
+                CAT,
+                imp: self,
+                "Capturing {} frames at {}",
+                frame_limit,
+                fps
+            );
+            self.state.lock().unwrap().capture = Some(Capture {
+                frame_duration,
+                frame_limit,
+                frames: 0,
+                started: None,
+            });
+        }
+
         self.obj()
             .set_blocksize(info.width() * info.height() * std::mem::size_of::<Bgra>() as u32);
 
@@ -223,6 +379,9 @@ impl BaseSrcImpl for WellenbrecherSrc {
 
             s.fixate_field_nearest_int("width", settings.width as i32);
             s.fixate_field_nearest_int("height", settings.height as i32);
+            if !settings.is_live() {
+                s.fixate_field_nearest_fraction("framerate", gst::Fraction::new(30, 1));
+            }
         }
 
         self.parent_fixate(caps)
@@ -233,8 +392,11 @@ impl BaseSrcImpl for WellenbrecherSrc {
         let mut state = self.state.lock().unwrap();
 
         if state.canvas.is_none() {
-            let canvas = Canvas::open(settings.flink.as_path(), false, None)
-                .expect("unable to open shared memory");
+            let canvas = match settings.file {
+                true => Canvas::open_file(settings.flink.as_path(), None),
+                false => Canvas::open(settings.flink.as_path(), false, None),
+            }
+            .expect("unable to open shared memory");
 
             if canvas.width() != settings.width || canvas.width() != settings.height {
                 panic!("specified canvas dimensions ({}x{}) do not match shared canvas dimensions ({}x{})",
@@ -253,6 +415,7 @@ impl BaseSrcImpl for WellenbrecherSrc {
     fn stop(&self) -> Result<(), gst::ErrorMessage> {
         let mut state = self.state.lock().unwrap();
         let _ = state.canvas.take();
+        let _ = state.capture.take();
 
         gst::info!(CAT, imp: self, "Stopped");
 
@@ -265,8 +428,29 @@ impl BaseSrcImpl for WellenbrecherSrc {
         length: u32,
         buffer: &mut gst::BufferRef,
     ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let user_id_filter = self.settings.lock().unwrap().user_id_filter;
         let mut state = self.state.lock().unwrap();
-        let canvas = if let Some(canvas) = &mut state.canvas {
+        let State { canvas, capture } = &mut *state;
+
+        if let Some(capture) = capture {
+            if capture.frames >= capture.frame_limit {
+                gst::info!(CAT, imp: self, "Captured {} frames", capture.frames);
+                return Err(gst::FlowError::Eos);
+            }
+
+            // sample the canvas at the framerate even if downstream is faster
+            let started = *capture.started.get_or_insert_with(Instant::now);
+            let due = started + Duration::from(capture.frame_duration * capture.frames);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                std::thread::sleep(wait);
+            }
+
+            buffer.set_pts(capture.frame_duration * capture.frames);
+            buffer.set_duration(capture.frame_duration);
+            capture.frames += 1;
+        }
+
+        let canvas = if let Some(canvas) = canvas {
             canvas
         } else {
             gst::error!(CAT, imp: self, "shared memory canvas not mapped");
@@ -274,19 +458,38 @@ impl BaseSrcImpl for WellenbrecherSrc {
         };
 
         let pixels = canvas.pixel_byte_slice();
-        unsafe {
+        let len = min(length as usize, pixels.len());
+        {
             let mut map = buffer.map_writable().map_err(|_| {
                 gst::element_imp_error!(self, gst::LibraryError::Failed, ["Failed to map buffer"]);
                 gst::FlowError::Error
             })?;
 
-            std::ptr::copy_nonoverlapping(
-                pixels.as_ptr(),
-                map.as_mut_ptr(),
-                min(length as usize, pixels.len()),
-            )
+            match user_id_filter {
+                0 => unsafe {
+                    std::ptr::copy_nonoverlapping(pixels.as_ptr(), map.as_mut_ptr(), len)
+                },
+                user_id => {
+                    for ((out, pixel), owner) in map[..len]
+                        .chunks_exact_mut(std::mem::size_of::<Bgra>())
+                        .zip(canvas.pixel_slice())
+                        .zip(canvas.user_id_slice())
+                    {
+                        let pixel = match *owner == user_id {
+                            true => *pixel,
+                            false => Bgra {
+                                b: 0,
+                                g: 0,
+                                r: 0,
+                                a: 0,
+                            },
+                        };
+                        out.copy_from_slice(&[pixel.b, pixel.g, pixel.r, pixel.a]);
+                    }
+                }
+            }
         }
-        buffer.set_size(min(length as usize, pixels.len()));
+        buffer.set_size(len);
 
         Ok(gst::FlowSuccess::Ok)
     }
@@ -1,6 +1,10 @@
-use std::num::{NonZeroU16, NonZeroU32};
+use std::num::{NonZeroU16, NonZeroU32, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+
+use wellenbrecher_canvas::instance::{self, DEFAULT_CANVAS_FILE_LINK, DEFAULT_STATS_FILE_LINK};
 
 #[derive(Parser, Clone, Debug)]
 #[command(author, version, about)]
@@ -13,12 +17,16 @@ pub struct Args {
     #[arg(long, default_value_t = NonZeroU32::new(720).unwrap())]
     pub height: NonZeroU32,
 
+    /// Name of the server instance whose canvas to use, replaces the default file links
+    #[arg(long, global = true, value_parser = instance::parse_name)]
+    pub instance: Option<String>,
+
     /// Canvas shared memory file link
-    #[arg(short = 'l', long = "canvas-file-link", default_value_t = String::from("/tmp/wellenbrecher-canvas"))]
+    #[arg(short = 'l', long = "canvas-file-link", default_value_t = String::from(DEFAULT_CANVAS_FILE_LINK))]
     pub canvas_file_link: String,
 
-    /// Canvas shared memory file link
-    #[arg(short, long, default_value_t = NonZeroU16::new(30).unwrap())]
+    /// Maximum canvas updates per second
+    #[arg(short, long, global = true, default_value_t = NonZeroU16::new(30).unwrap())]
     pub fps: NonZeroU16,
 
     #[command(subcommand)]
@@ -26,4 +34,152 @@ pub struct Args {
 }
 
 #[derive(Subcommand, Clone, Debug)]
-pub enum Commands {}
+pub enum Commands {
+    /// Periodically take snapshots of the canvas until stopped
+    Snapshots {
+        /// Time between two snapshots (e.g. 30s, 5m)
+        #[arg(short, long, default_value = "60s", value_parser = humantime::parse_duration)]
+        interval: Duration,
+
+        /// Number of snapshots to keep, older ones are deleted
+        #[arg(short, long, default_value_t = NonZeroUsize::new(120).unwrap())]
+        keep: NonZeroUsize,
+
+        /// Directory to write the snapshots to
+        #[arg(short, long)]
+        dir: PathBuf,
+
+        /// Snapshot file format
+        #[arg(long, value_enum, default_value_t = SnapshotFormat::Png)]
+        format: SnapshotFormat,
+    },
+    /// Rotate and/or scale the canvas contents in place
+    ///
+    /// The shared memory canvas is recreated if its dimensions change,
+    /// running servers have to be restarted in that case.
+    Transform {
+        /// Clockwise rotation in degrees
+        #[arg(long, value_enum, required_unless_present = "scale")]
+        rotate: Option<Rotation>,
+
+        /// Scale factor applied after rotating (e.g. 2x, 0.5x)
+        #[arg(long, value_parser = parse_scale)]
+        scale: Option<f64>,
+    },
+    /// Change the dimensions of a persisted canvas, keeping the overlapping region
+    ///
+    /// Meant to be run between sessions, running servers have to be restarted
+    /// with the new dimensions.
+    Resize {
+        /// New canvas width
+        #[arg(long)]
+        width: NonZeroU32,
+
+        /// New canvas height
+        #[arg(long)]
+        height: NonZeroU32,
+    },
+    /// Continuously copy the canvas of another pixelflut server into the local canvas
+    ///
+    /// The remote canvas is polled pixel by pixel, at most --fps times per second.
+    Mirror {
+        /// Address of the pixelflut server to mirror (host:port)
+        #[arg(long, required_unless_present = "discover")]
+        from: Option<String>,
+
+        /// Mirror the first pixelflut server announced via mDNS on the local network
+        #[arg(long, conflicts_with = "from")]
+        discover: bool,
+    },
+    /// Interactive prompt to edit the canvas by hand, e.g. to clean up a region
+    ///
+    /// Pixels written from the prompt are attributed to user 0.
+    Repl,
+    /// Animate procedural content on the canvas until stopped, at most --fps frames per second
+    Demo {
+        #[arg(value_enum)]
+        effect: DemoEffect,
+    },
+    /// Check the canvas for consistency, e.g. after a crash
+    ///
+    /// Exits with an error if problems were found that are not repaired.
+    Verify {
+        /// Traffic stats shared memory file link, used to find the known users
+        #[arg(long, default_value = DEFAULT_STATS_FILE_LINK)]
+        stats_file_link: PathBuf,
+
+        /// Highest user id that is allowed to own pixels, instead of taking it from the stats
+        #[arg(long)]
+        max_user_id: Option<u32>,
+
+        /// Reset the owner of pixels that belong to unknown users
+        #[arg(long, default_value_t = false)]
+        repair: bool,
+    },
+    /// List the running server instances
+    Instances,
+}
+
+impl Args {
+    /// Replaces default file links with the ones of --instance
+    pub fn with_instance(mut self) -> Self {
+        let Some(name) = &self.instance else {
+            return self;
+        };
+
+        let (canvas_file_link, stats_file_link) = instance::file_links(name);
+        if self.canvas_file_link == DEFAULT_CANVAS_FILE_LINK {
+            self.canvas_file_link = canvas_file_link;
+        }
+        if let Commands::Verify {
+            stats_file_link: link,
+            ..
+        } = &mut self.command
+        {
+            if link.as_path() == Path::new(DEFAULT_STATS_FILE_LINK) {
+                *link = stats_file_link.into();
+            }
+        }
+        self
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum SnapshotFormat {
+    /// RGB image of the canvas
+    Png,
+    /// zstd compressed raw canvas including the user ids
+    Zstd,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum DemoEffect {
+    Plasma,
+    Fire,
+    #[value(name = "gameoflife")]
+    GameOfLife,
+    Starfield,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Rotation {
+    #[value(name = "90")]
+    Clockwise90,
+    #[value(name = "180")]
+    Clockwise180,
+    #[value(name = "270")]
+    Clockwise270,
+}
+
+fn parse_scale(s: &str) -> Result<f64, String> {
+    let factor = s
+        .strip_suffix('x')
+        .unwrap_or(s)
+        .parse::<f64>()
+        .map_err(|e| e.to_string())?;
+
+    match factor.is_finite() && factor > 0.0 {
+        true => Ok(factor),
+        false => Err(format!("invalid scale factor: {factor}")),
+    }
+}
@@ -0,0 +1,224 @@
+use std::f32::consts::TAU;
+use std::num::NonZeroU16;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tracing::{debug, info};
+
+use wellenbrecher_canvas::{Bgra, Canvas};
+
+use crate::cli::DemoEffect;
+
+const STARS: usize = 1024;
+/// Game of life restarts with a new random population after this many generations
+const GAME_OF_LIFE_GENERATIONS: u32 = 1000;
+
+/// xorshift, good enough to make things look random
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        Self(seed | 1)
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 32) as u32
+    }
+
+    /// uniform in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+struct Star {
+    x: f32,
+    y: f32,
+    z: f32,
+}
+
+impl Star {
+    fn spawn(rng: &mut Rng, z: f32) -> Self {
+        Self {
+            x: rng.next_f32() * 2.0 - 1.0,
+            y: rng.next_f32() * 2.0 - 1.0,
+            z,
+        }
+    }
+}
+
+enum Demo {
+    Plasma,
+    Fire { heat: Vec<u8> },
+    GameOfLife { cells: Vec<bool>, generation: u32 },
+    Starfield { stars: Vec<Star> },
+}
+
+impl Demo {
+    fn new(effect: DemoEffect, width: u32, height: u32, rng: &mut Rng) -> Self {
+        let len = (width * height) as usize;
+        match effect {
+            DemoEffect::Plasma => Demo::Plasma,
+            DemoEffect::Fire => Demo::Fire { heat: vec![0; len] },
+            DemoEffect::GameOfLife => Demo::GameOfLife {
+                cells: (0..len).map(|_| rng.next_f32() < 0.25).collect(),
+                generation: 0,
+            },
+            DemoEffect::Starfield => Demo::Starfield {
+                stars: (0..STARS)
+                    .map(|_| {
+                        let z = rng.next_f32();
+                        Star::spawn(rng, z)
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    fn render(&mut self, pixels: &mut [Bgra], width: u32, height: u32, t: f32, rng: &mut Rng) {
+        let (w, h) = (width as usize, height as usize);
+        match self {
+            Demo::Plasma => {
+                for (i, pixel) in pixels.iter_mut().enumerate() {
+                    let x = (i % w) as f32 / w as f32;
+                    let y = (i / w) as f32 / h as f32;
+                    let v = (x * 10.0 + t).sin()
+                        + ((y * 8.0 + t * 0.7).sin() + (x * 6.0 - t * 0.5).cos())
+                        + ((x - 0.5).hypot(y - 0.5) * 16.0 - t * 1.3).sin();
+                    *pixel = hue(v / 4.0 + t * 0.05);
+                }
+            }
+            Demo::Fire { heat } => {
+                for x in 0..w {
+                    heat[(h - 1) * w + x] = match rng.next_u32() % 3 {
+                        0 => 0,
+                        _ => 0xff,
+                    };
+                }
+                for y in 0..h - 1 {
+                    for x in 0..w {
+                        let below = (y + 1) * w;
+                        let sum = heat[below + (x + w - 1) % w] as u32
+                            + heat[below + x] as u32
+                            + heat[below + (x + 1) % w] as u32
+                            + heat[(y + 2).min(h - 1) * w + x] as u32;
+                        heat[y * w + x] = (sum * 32 / 129) as u8;
+                    }
+                }
+                for (pixel, &heat) in pixels.iter_mut().zip(heat.iter()) {
+                    let heat = heat as u32 * 3;
+                    *pixel = Bgra {
+                        r: heat.min(0xff) as u8,
+                        g: heat.saturating_sub(0xff).min(0xff) as u8,
+                        b: heat.saturating_sub(0x1fe).min(0xff) as u8,
+                        a: 0xff,
+                    };
+                }
+            }
+            Demo::GameOfLife { cells, generation } => {
+                *generation += 1;
+                if *generation > GAME_OF_LIFE_GENERATIONS {
+                    *generation = 0;
+                    cells
+                        .iter_mut()
+                        .for_each(|cell| *cell = rng.next_f32() < 0.25);
+                }
+
+                let next = (0..w * h)
+                    .map(|i| {
+                        let (x, y) = (i % w, i / w);
+                        let neighbours = [
+                            (w - 1, h - 1),
+                            (0, h - 1),
+                            (1, h - 1),
+                            (w - 1, 0),
+                            (1, 0),
+                            (w - 1, 1),
+                            (0, 1),
+                            (1, 1),
+                        ]
+                        .iter()
+                        .filter(|&&(dx, dy)| cells[(y + dy) % h * w + (x + dx) % w])
+                        .count();
+                        matches!((cells[i], neighbours), (true, 2) | (_, 3))
+                    })
+                    .collect();
+                *cells = next;
+
+                for (pixel, &alive) in pixels.iter_mut().zip(cells.iter()) {
+                    *pixel = Bgra::from_bw(if alive { 0xff } else { 0x00 });
+                }
+            }
+            Demo::Starfield { stars } => {
+                pixels.fill(Bgra::from_bw(0x00));
+                for star in stars.iter_mut() {
+                    star.z -= 0.01;
+                    if star.z <= 0.01 {
+                        *star = Star::spawn(rng, 1.0);
+                    }
+
+                    let x = (star.x / star.z + 1.0) * 0.5 * width as f32;
+                    let y = (star.y / star.z + 1.0) * 0.5 * height as f32;
+                    if !(0.0..width as f32).contains(&x) || !(0.0..height as f32).contains(&y) {
+                        *star = Star::spawn(rng, 1.0);
+                        continue;
+                    }
+                    pixels[y as usize * w + x as usize] =
+                        Bgra::from_bw(((1.0 - star.z) * 0xff as f32) as u8);
+                }
+            }
+        }
+    }
+}
+
+/// Rainbow color for a hue in turns
+fn hue(turns: f32) -> Bgra {
+    let channel = |offset: f32| ((((turns + offset) * TAU).sin() * 0.5 + 0.5) * 0xff as f32) as u8;
+    Bgra {
+        r: channel(0.0),
+        g: channel(1.0 / 3.0),
+        b: channel(2.0 / 3.0),
+        a: 0xff,
+    }
+}
+
+pub fn run(mut canvas: Canvas, effect: DemoEffect, fps: NonZeroU16) -> eyre::Result<()> {
+    let (width, height) = (canvas.width(), canvas.height());
+    info!("playing {effect:?} on the canvas ({width}x{height}) at {fps} fps");
+
+    let mut rng = Rng::new();
+    let mut demo = Demo::new(effect, width, height, &mut rng);
+    canvas.clear_user_ids();
+    let generation = canvas.generation();
+
+    let frame_duration = Duration::from_secs(1) / fps.get() as u32;
+    let start = Instant::now();
+    let mut next = start;
+    loop {
+        if canvas.generation() != generation {
+            return Err(eyre::eyre!(
+                "the canvas was resized, cleared or removed, restart the demo"
+            ));
+        }
+
+        let t = start.elapsed().as_secs_f32();
+        demo.render(canvas.pixel_slice_mut(), width, height, t, &mut rng);
+
+        next += frame_duration;
+        let now = Instant::now();
+        match next.checked_duration_since(now) {
+            Some(timeout) => thread::sleep(timeout),
+            None => {
+                debug!("rendering the frame took longer than {frame_duration:?}");
+                next = now;
+            }
+        }
+    }
+}
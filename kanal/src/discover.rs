@@ -0,0 +1,44 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use tracing::info;
+
+const SERVICE_TYPE: &str = "_pixelflut._tcp.local.";
+
+/// Browses the local network for pixelflut servers announced via mDNS and returns the first one found
+pub fn find_server(timeout: Duration) -> eyre::Result<SocketAddr> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+
+    let deadline = Instant::now() + timeout;
+    let found = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let Ok(event) = events.recv_timeout(remaining) else {
+            break None;
+        };
+        let ServiceEvent::ServiceResolved(service) = event else {
+            continue;
+        };
+        // prefer IPv4, link-local IPv6 addresses are useless without a scope id
+        let Some(ip) = service
+            .get_addresses()
+            .iter()
+            .min_by_key(|ip| ip.is_ipv6())
+            .copied()
+        else {
+            continue;
+        };
+
+        info!(
+            "found {} ({}x{} canvas)",
+            service.get_fullname(),
+            service.get_property_val_str("width").unwrap_or("?"),
+            service.get_property_val_str("height").unwrap_or("?"),
+        );
+        break Some(SocketAddr::new(ip, service.get_port()));
+    };
+
+    let _ = daemon.shutdown();
+    found.ok_or_else(|| eyre::eyre!("no pixelflut server announced itself within {timeout:?}"))
+}
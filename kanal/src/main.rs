@@ -1,10 +1,22 @@
+use std::time::Duration;
+
 use clap::Parser;
-use tracing::Level;
+use tracing::{info, Level};
 use tracing_subscriber::EnvFilter;
 
+use wellenbrecher_canvas::instance;
 use wellenbrecher_canvas::Canvas;
 
 mod cli;
+mod demo;
+mod discover;
+mod mirror;
+mod repl;
+mod snapshots;
+mod transform;
+mod verify;
+
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn setup_logging() -> eyre::Result<()> {
     if cfg!(debug_assertions) {
@@ -37,14 +49,80 @@ fn setup_logging() -> eyre::Result<()> {
     Ok(())
 }
 
+fn resize(mut canvas: Canvas, width: u32, height: u32) -> eyre::Result<()> {
+    if (canvas.width(), canvas.height()) == (width, height) {
+        info!("the canvas already is {width}x{height}");
+        return Ok(());
+    }
+
+    info!(
+        "resizing the canvas from {}x{} to {width}x{height}, restart running servers with the new dimensions",
+        canvas.width(),
+        canvas.height()
+    );
+    canvas.resize(width, height)?;
+    Ok(())
+}
+
+fn list_instances() -> eyre::Result<()> {
+    let instances = instance::list()?;
+    if instances.is_empty() {
+        info!("no instances are running");
+    }
+    for instance in instances {
+        println!(
+            "{}\tpid {}\tcanvas {}\tstats {}",
+            instance.name, instance.pid, instance.canvas_file_link, instance.stats_file_link
+        );
+    }
+    Ok(())
+}
+
 fn main() -> eyre::Result<()> {
     setup_logging()?;
 
-    let args = cli::Args::parse();
+    let args = cli::Args::parse().with_instance();
+
+    if let cli::Commands::Instances = args.command {
+        return list_instances();
+    }
+
+    // opening the canvas is part of the checks
+    if let cli::Commands::Verify {
+        stats_file_link,
+        max_user_id,
+        repair,
+    } = &args.command
+    {
+        return verify::run(
+            args.canvas_file_link.as_ref(),
+            stats_file_link,
+            *max_user_id,
+            *repair,
+        );
+    }
 
-    let _canvas = Canvas::open(args.canvas_file_link.as_ref(), true, None)?;
+    let canvas = Canvas::open(args.canvas_file_link.as_ref(), true, None)?;
 
-    match &args.command {
-        _ => todo!(),
+    match args.command {
+        cli::Commands::Snapshots {
+            interval,
+            keep,
+            dir,
+            format,
+        } => snapshots::run(&canvas, interval, keep, &dir, format),
+        cli::Commands::Transform { rotate, scale } => transform::run(canvas, rotate, scale),
+        cli::Commands::Resize { width, height } => resize(canvas, width.get(), height.get()),
+        // --discover is the only alternative to --from
+        cli::Commands::Mirror { from, .. } => {
+            let from = match from {
+                Some(from) => from,
+                None => discover::find_server(DISCOVERY_TIMEOUT)?.to_string(),
+            };
+            mirror::run(canvas, &from, args.fps)
+        }
+        cli::Commands::Repl => repl::run(canvas),
+        cli::Commands::Demo { effect } => demo::run(canvas, effect, args.fps),
+        cli::Commands::Verify { .. } | cli::Commands::Instances => unreachable!(),
     }
 }
@@ -0,0 +1,103 @@
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::net::TcpStream;
+use std::num::NonZeroU16;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use wellenbrecher_canvas::{Bgra, Canvas};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+pub fn run(mut canvas: Canvas, from: &str, fps: NonZeroU16) -> eyre::Result<()> {
+    loop {
+        if let Err(e) = follow(&mut canvas, from, fps) {
+            warn!("mirroring {from} failed: {e}; reconnecting…");
+            thread::sleep(RECONNECT_DELAY);
+        }
+    }
+}
+
+fn follow(canvas: &mut Canvas, from: &str, fps: NonZeroU16) -> eyre::Result<()> {
+    let stream = TcpStream::connect(from)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    writer.write_all(b"SIZE\n")?;
+    writer.flush()?;
+    let mut line = String::new();
+    read_line(&mut reader, &mut line)?;
+    let (remote_width, remote_height) = parse_size(&line)?;
+
+    let width = remote_width.min(canvas.width());
+    let height = remote_height.min(canvas.height());
+    if (remote_width, remote_height) != (canvas.width(), canvas.height()) {
+        warn!(
+            "remote canvas ({remote_width}x{remote_height}) differs from the local canvas ({}x{}), mirroring {width}x{height} only",
+            canvas.width(),
+            canvas.height()
+        );
+    }
+    info!("mirroring {from}");
+
+    let frame_time = Duration::from_secs(1) / fps.get() as u32;
+    loop {
+        let start = Instant::now();
+
+        // pipeline one row of requests at a time
+        for y in 0..height {
+            for x in 0..width {
+                writeln!(writer, "PX {x} {y}")?;
+            }
+            writer.flush()?;
+
+            for _ in 0..width {
+                read_line(&mut reader, &mut line)?;
+                let (x, y, color) = parse_pixel(&line)?;
+                if x < width && y < height {
+                    let idx = (y * canvas.width() + x) as usize;
+                    canvas.pixel_slice_mut()[idx] = color;
+                }
+            }
+        }
+
+        if let Some(remaining) = frame_time.checked_sub(start.elapsed()) {
+            thread::sleep(remaining);
+        }
+    }
+}
+
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> std::io::Result<()> {
+    line.clear();
+    match reader.read_line(line)? {
+        0 => Err(ErrorKind::UnexpectedEof.into()),
+        _ => Ok(()),
+    }
+}
+
+fn parse_size(line: &str) -> eyre::Result<(u32, u32)> {
+    match line.split_ascii_whitespace().collect::<Vec<_>>().as_slice() {
+        ["SIZE", width, height] => Ok((width.parse()?, height.parse()?)),
+        _ => Err(eyre::eyre!("unexpected reply to SIZE: {line:?}")),
+    }
+}
+
+fn parse_pixel(line: &str) -> eyre::Result<(u32, u32, Bgra)> {
+    match line.split_ascii_whitespace().collect::<Vec<_>>().as_slice() {
+        ["PX", x, y, color] => {
+            let value = u32::from_str_radix(color, 16)?;
+            let color = match color.len() {
+                6 => Bgra::from_rgb(value),
+                8 => Bgra::from_rgba(value),
+                2 => Bgra::from_bw(value as u8),
+                _ => return Err(eyre::eyre!("invalid color in reply: {line:?}")),
+            };
+            Ok((x.parse()?, y.parse()?, color))
+        }
+        _ => Err(eyre::eyre!("unexpected reply to PX: {line:?}")),
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+
+use tracing::info;
+
+use wellenbrecher_canvas::{Bgra, Canvas, UserID};
+
+/// Pixels written from the repl are not attributed to any user
+const REPL_USER_ID: UserID = 0;
+
+/// Number of edits that can be undone
+const UNDO_DEPTH: usize = 32;
+
+const HELP: &str = "\
+px <x> <y>                    print the color and user of pixel (x, y)
+px <x> <y> <color>            set pixel (x, y)
+fill <color>                  fill the whole canvas
+rect <x> <y> <w> <h> <color>  fill a rectangle, clipped to the canvas
+undo-region                   restore the region changed by the last edit
+help                          print this help
+quit                          leave the repl
+
+colors are given as ww, rrggbb or rrggbbaa";
+
+/// Contents of a region before it was edited
+struct Region {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    pixels: Vec<Bgra>,
+    user_ids: Vec<UserID>,
+}
+
+impl Region {
+    fn save(canvas: &Canvas, x: u32, y: u32, width: u32, height: u32) -> Self {
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        let mut user_ids = Vec::with_capacity((width * height) as usize);
+        for row in y..y + height {
+            let start = (row * canvas.width() + x) as usize;
+            let end = start + width as usize;
+            pixels.extend_from_slice(&canvas.pixel_slice()[start..end]);
+            user_ids.extend_from_slice(&canvas.user_id_slice()[start..end]);
+        }
+
+        Self {
+            x,
+            y,
+            width,
+            height,
+            pixels,
+            user_ids,
+        }
+    }
+
+    fn restore(&self, canvas: &mut Canvas) {
+        let canvas_width = canvas.width();
+        for (i, row) in (self.y..self.y + self.height).enumerate() {
+            let start = (row * canvas_width + self.x) as usize;
+            let end = start + self.width as usize;
+            let saved = i * self.width as usize..(i + 1) * self.width as usize;
+            canvas.pixel_slice_mut()[start..end].copy_from_slice(&self.pixels[saved.clone()]);
+            canvas.user_id_slice_mut()[start..end].copy_from_slice(&self.user_ids[saved]);
+        }
+        canvas.recount_user_pixels();
+    }
+}
+
+pub fn run(mut canvas: Canvas) -> eyre::Result<()> {
+    info!(
+        "editing canvas ({}x{}), type help for a list of commands",
+        canvas.width(),
+        canvas.height()
+    );
+
+    let mut undo: VecDeque<Region> = VecDeque::with_capacity(UNDO_DEPTH);
+    let stdin = std::io::stdin();
+    let mut stdout = std::io::stdout();
+    let mut line = String::new();
+
+    loop {
+        print!("> ");
+        stdout.flush()?;
+
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match execute(&mut canvas, &mut undo, &words) {
+            Ok(Reply::Continue(None)) => {}
+            Ok(Reply::Continue(Some(message))) => println!("{message}"),
+            Ok(Reply::Quit) => return Ok(()),
+            Err(e) => println!("error: {e}"),
+        }
+    }
+}
+
+enum Reply {
+    Continue(Option<String>),
+    Quit,
+}
+
+fn execute(
+    canvas: &mut Canvas,
+    undo: &mut VecDeque<Region>,
+    words: &[&str],
+) -> Result<Reply, String> {
+    match words {
+        [] => Ok(Reply::Continue(None)),
+        ["px", x, y] => {
+            let (x, y) = (parse_coordinate(x)?, parse_coordinate(y)?);
+            if x >= canvas.width() || y >= canvas.height() {
+                return Err(format!("pixel ({x}, {y}) out of bounds"));
+            }
+            let color = canvas.pixel(x, y).map_err(|e| e.to_string())?;
+            let user_id = canvas.user(x, y).map_err(|e| e.to_string())?;
+            Ok(Reply::Continue(Some(format!(
+                "{:06x} [user: {user_id}]",
+                color.rgb()
+            ))))
+        }
+        ["px", x, y, color] => {
+            let (x, y, color) = (
+                parse_coordinate(x)?,
+                parse_coordinate(y)?,
+                parse_color(color)?,
+            );
+            if x >= canvas.width() || y >= canvas.height() {
+                return Err(format!("pixel ({x}, {y}) out of bounds"));
+            }
+            Ok(edit(canvas, undo, x, y, 1, 1, color))
+        }
+        ["fill", color] => {
+            let color = parse_color(color)?;
+            let (width, height) = (canvas.width(), canvas.height());
+            Ok(edit(canvas, undo, 0, 0, width, height, color))
+        }
+        ["rect", x, y, width, height, color] => {
+            let (x, y) = (parse_coordinate(x)?, parse_coordinate(y)?);
+            let (width, height) = (parse_coordinate(width)?, parse_coordinate(height)?);
+            let color = parse_color(color)?;
+            if x >= canvas.width() || y >= canvas.height() {
+                return Err(format!("rectangle origin ({x}, {y}) out of bounds"));
+            }
+
+            let width = width.min(canvas.width() - x);
+            let height = height.min(canvas.height() - y);
+            Ok(edit(canvas, undo, x, y, width, height, color))
+        }
+        ["undo-region"] => match undo.pop_back() {
+            Some(region)
+                if region.x + region.width <= canvas.width()
+                    && region.y + region.height <= canvas.height() =>
+            {
+                region.restore(canvas);
+                Ok(Reply::Continue(Some(format!(
+                    "restored {}x{} at ({}, {}), {} edits left to undo",
+                    region.width,
+                    region.height,
+                    region.x,
+                    region.y,
+                    undo.len()
+                ))))
+            }
+            Some(_) => Err("canvas was resized, unable to undo".to_string()),
+            None => Err("nothing to undo".to_string()),
+        },
+        ["help"] => Ok(Reply::Continue(Some(HELP.to_string()))),
+        ["quit" | "exit"] => Ok(Reply::Quit),
+        [command, ..] => Err(format!(
+            "unknown command or arguments for {command}, try help"
+        )),
+    }
+}
+
+/// Saves the rectangle for undo-region before drawing it
+fn edit(
+    canvas: &mut Canvas,
+    undo: &mut VecDeque<Region>,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    color: Bgra,
+) -> Reply {
+    if undo.len() == UNDO_DEPTH {
+        undo.pop_front();
+    }
+    undo.push_back(Region::save(canvas, x, y, width, height));
+
+    for y in y..y + height {
+        for x in x..x + width {
+            canvas.set_pixel(x, y, color, REPL_USER_ID).unwrap();
+        }
+    }
+    Reply::Continue(Some(format!("changed {} pixels", width * height)))
+}
+
+fn parse_coordinate(s: &str) -> Result<u32, String> {
+    s.parse().map_err(|e| format!("invalid number {s}: {e}"))
+}
+
+fn parse_color(color: &str) -> Result<Bgra, String> {
+    let value =
+        u32::from_str_radix(color, 16).map_err(|e| format!("invalid color {color}: {e}"))?;
+    match color.len() {
+        2 => Ok(Bgra::from_bw(value as u8)),
+        6 => Ok(Bgra::from_rgb(value)),
+        8 => Ok(Bgra::from_rgba(value)),
+        _ => Err("expected a color in the form ww, rrggbb or rrggbbaa".to_string()),
+    }
+}
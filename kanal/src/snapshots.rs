@@ -0,0 +1,142 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use eyre::WrapErr;
+use tracing::{info, warn};
+
+use wellenbrecher_canvas::Canvas;
+
+use crate::cli::SnapshotFormat;
+
+const SNAPSHOT_PREFIX: &str = "canvas-";
+
+impl SnapshotFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            SnapshotFormat::Png => "png",
+            SnapshotFormat::Zstd => "zst",
+        }
+    }
+}
+
+pub fn run(
+    canvas: &Canvas,
+    interval: Duration,
+    keep: NonZeroUsize,
+    dir: &Path,
+    format: SnapshotFormat,
+) -> eyre::Result<()> {
+    std::fs::create_dir_all(dir)
+        .wrap_err_with(|| format!("unable to create snapshot directory {}", dir.display()))?;
+    info!(
+        "taking a snapshot every {} into {}, keeping the latest {keep}",
+        humantime::format_duration(interval),
+        dir.display()
+    );
+
+    let mut next = Instant::now();
+    loop {
+        let path = snapshot_path(dir, format);
+        match write_snapshot(canvas, &path, format) {
+            Ok(()) => info!("wrote snapshot {}", path.display()),
+            Err(e) => warn!("unable to write snapshot {}: {e}", path.display()),
+        }
+
+        if let Err(e) = rotate(dir, format, keep) {
+            warn!("unable to remove old snapshots: {e}");
+        }
+
+        next += interval;
+        let now = Instant::now();
+        match next.checked_duration_since(now) {
+            Some(timeout) => thread::sleep(timeout),
+            None => {
+                warn!("taking the snapshot took longer than the interval");
+                next = now;
+            }
+        }
+    }
+}
+
+fn snapshot_path(dir: &Path, format: SnapshotFormat) -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    dir.join(format!(
+        "{SNAPSHOT_PREFIX}{timestamp:013}.{}",
+        format.extension()
+    ))
+}
+
+/// Writes to a temporary file first, so readers of the directory never see partial snapshots
+fn write_snapshot(canvas: &Canvas, path: &Path, format: SnapshotFormat) -> eyre::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let writer = BufWriter::new(File::create(&tmp_path)?);
+
+    let result = match format {
+        SnapshotFormat::Png => write_png(canvas, writer),
+        SnapshotFormat::Zstd => write_zstd(canvas, writer),
+    };
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn write_png(canvas: &Canvas, writer: impl Write) -> eyre::Result<()> {
+    let mut encoder = png::Encoder::new(writer, canvas.width(), canvas.height());
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&canvas.to_rgb8())?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Width and height (little endian) followed by the pixel and user id planes
+/// as laid out in the shared memory canvas
+fn write_zstd(canvas: &Canvas, writer: impl Write) -> eyre::Result<()> {
+    let mut encoder = zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    encoder.write_all(&canvas.width().to_le_bytes())?;
+    encoder.write_all(&canvas.height().to_le_bytes())?;
+    encoder.write_all(canvas.pixel_byte_slice())?;
+    encoder.write_all(canvas.user_id_byte_slice())?;
+    encoder.finish()?.flush()?;
+    Ok(())
+}
+
+fn rotate(dir: &Path, format: SnapshotFormat, keep: NonZeroUsize) -> eyre::Result<()> {
+    let mut snapshots = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == format.extension())
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with(SNAPSHOT_PREFIX))
+        })
+        .collect::<Vec<_>>();
+
+    if snapshots.len() <= keep.get() {
+        return Ok(());
+    }
+
+    // timestamps are zero padded, so the names sort chronologically
+    snapshots.sort_unstable();
+    for snapshot in &snapshots[..snapshots.len() - keep.get()] {
+        std::fs::remove_file(snapshot)?;
+    }
+
+    Ok(())
+}
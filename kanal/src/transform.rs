@@ -0,0 +1,99 @@
+use tracing::{info, warn};
+
+use wellenbrecher_canvas::{Bgra, Canvas, UserID};
+
+use crate::cli::Rotation;
+
+/// Detached copy of the canvas contents
+struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<Bgra>,
+    user_ids: Vec<UserID>,
+}
+
+impl Image {
+    /// Builds a new image of the given size, `source` maps destination to source coordinates
+    fn remap(&self, width: u32, height: u32, source: impl Fn(u32, u32) -> (u32, u32)) -> Self {
+        let len = (width * height) as usize;
+        let mut pixels = Vec::with_capacity(len);
+        let mut user_ids = Vec::with_capacity(len);
+
+        for y in 0..height {
+            for x in 0..width {
+                let (sx, sy) = source(x, y);
+                let idx = (sy * self.width + sx) as usize;
+                pixels.push(self.pixels[idx]);
+                user_ids.push(self.user_ids[idx]);
+            }
+        }
+
+        Self {
+            width,
+            height,
+            pixels,
+            user_ids,
+        }
+    }
+
+    fn rotate(&self, rotation: Rotation) -> Self {
+        let (w, h) = (self.width, self.height);
+        match rotation {
+            Rotation::Clockwise90 => self.remap(h, w, |x, y| (y, h - 1 - x)),
+            Rotation::Clockwise180 => self.remap(w, h, |x, y| (w - 1 - x, h - 1 - y)),
+            Rotation::Clockwise270 => self.remap(h, w, |x, y| (w - 1 - y, x)),
+        }
+    }
+
+    /// Nearest neighbor scaling
+    fn scale(&self, factor: f64) -> eyre::Result<Self> {
+        let width = (self.width as f64 * factor).round().max(1.0);
+        let height = (self.height as f64 * factor).round().max(1.0);
+        if width * height > u32::MAX as f64 {
+            return Err(eyre::eyre!("scaled canvas ({width}x{height}) is too large"));
+        }
+
+        let (width, height) = (width as u32, height as u32);
+        let (w, h) = (self.width as u64, self.height as u64);
+        Ok(self.remap(width, height, |x, y| {
+            (
+                (x as u64 * w / width as u64) as u32,
+                (y as u64 * h / height as u64) as u32,
+            )
+        }))
+    }
+}
+
+pub fn run(mut canvas: Canvas, rotation: Option<Rotation>, scale: Option<f64>) -> eyre::Result<()> {
+    let mut image = Image {
+        width: canvas.width(),
+        height: canvas.height(),
+        pixels: canvas.pixel_slice().to_vec(),
+        user_ids: canvas.user_id_slice().to_vec(),
+    };
+
+    if let Some(rotation) = rotation {
+        image = image.rotate(rotation);
+    }
+    if let Some(factor) = scale {
+        image = image.scale(factor)?;
+    }
+
+    if image.width != canvas.width() || image.height != canvas.height() {
+        warn!(
+            "canvas dimensions change from {}x{} to {}x{}, restart running servers",
+            canvas.width(),
+            canvas.height(),
+            image.width,
+            image.height
+        );
+        canvas.resize(image.width, image.height)?;
+    }
+
+    canvas.pixel_slice_mut().copy_from_slice(&image.pixels);
+    canvas.user_id_slice_mut().copy_from_slice(&image.user_ids);
+    canvas.recount_user_pixels();
+
+    info!("transformed canvas ({}x{})", image.width, image.height);
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use tracing::{info, warn};
+
+use wellenbrecher_canvas::{Canvas, Stats, UserID};
+
+/*
+The canvas header carries no magic or version, a segment is considered valid if it opens:
+its size has to match the dimensions in the header. Beyond that the user id plane is checked
+against the users the server knows about. Stats counters are integers and can't be NaN,
+they are checked to be readable and consistent in size.
+ */
+
+/// Range of user ids that might own pixels, None if it can't be told
+fn known_users(stats: Option<&Stats>, max_user_id: Option<UserID>) -> Option<UserID> {
+    if max_user_id.is_some() {
+        return max_user_id;
+    }
+
+    let users = stats?.users();
+    // users beyond the end of the stats segment are not tracked
+    if users.last()?.load() != (0, 0) {
+        return None;
+    }
+    let known = users.iter().rposition(|counters| counters.load() != (0, 0));
+    Some(known.map_or(0, |idx| idx as UserID + 1))
+}
+
+pub fn run(
+    canvas_path: &Path,
+    stats_path: &Path,
+    max_user_id: Option<UserID>,
+    repair: bool,
+) -> eyre::Result<()> {
+    let mut canvas = Canvas::open(canvas_path, true, None)
+        .map_err(|e| eyre::eyre!("unable to open the canvas at {canvas_path:?}: {e}"))?;
+    info!(
+        "canvas: {}x{}, generation {}",
+        canvas.width(),
+        canvas.height(),
+        canvas.generation()
+    );
+
+    let stats = match Stats::open(stats_path) {
+        Ok(stats) => {
+            let connections: u32 = stats
+                .connections()
+                .iter()
+                .map(|connections| connections.load(Ordering::Relaxed))
+                .sum();
+            info!(
+                "stats: {} workers, {} users, {connections} connections, generation {}",
+                stats.workers().len(),
+                stats.users().len(),
+                stats.generation()
+            );
+            Some(stats)
+        }
+        Err(e) => {
+            warn!("unable to open the stats at {stats_path:?}: {e}");
+            None
+        }
+    };
+
+    let Some(known_users) = known_users(stats.as_ref(), max_user_id) else {
+        warn!("unable to tell the range of known users, pass --max-user-id to check the user ids");
+        return Ok(());
+    };
+
+    let unknown = canvas
+        .user_id_slice()
+        .iter()
+        .filter(|&&uid| uid > known_users)
+        .count();
+    if unknown == 0 {
+        info!("all pixels belong to known users (max user id: {known_users})");
+        return Ok(());
+    }
+
+    warn!("{unknown} pixels belong to unknown users (max user id: {known_users})");
+    if !repair {
+        return Err(eyre::eyre!(
+            "the canvas is inconsistent, run with --repair to fix it"
+        ));
+    }
+
+    canvas
+        .user_id_slice_mut()
+        .iter_mut()
+        .filter(|uid| **uid > known_users)
+        .for_each(|uid| *uid = 0);
+    canvas.recount_user_pixels();
+    info!("reset the user of {unknown} pixels");
+
+    Ok(())
+}
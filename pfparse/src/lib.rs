@@ -17,88 +17,153 @@ pub enum Command {
     SetPixel { x: u16, y: u16, color: Bgra },
 }
 
+/// Command borrowing its arguments from the input buffer, nothing is decoded yet
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RawCommand<'a> {
+    Help,
+    Size,
+    Offset {
+        x: &'a [u8],
+        y: &'a [u8],
+    },
+    GetPixel {
+        x: &'a [u8],
+        y: &'a [u8],
+    },
+    SetPixel {
+        x: &'a [u8],
+        y: &'a [u8],
+        color: &'a [u8],
+    },
+}
+
+impl<'a> RawCommand<'a> {
+    /// Splits a single line without its line feed, empty lines are skipped
+    pub fn split<E: CommandExecutionError>(line: &'a [u8]) -> Result<Option<Self>, ParserError<E>> {
+        if line.is_empty() {
+            return Ok(None);
+        }
+
+        let cmd = match line.strip_suffix(b"\r").unwrap_or(line) {
+            b"HELP" => RawCommand::Help,
+            b"SIZE" => RawCommand::Size,
+            [b'O', b'F', b'F', b'S', b'E', b'T', b' ', cords @ ..] => {
+                let mut cords = cords.split(|b| *b == b' ');
+                match (cords.next(), cords.next(), cords.next()) {
+                    (Some(x), Some(y), None) => RawCommand::Offset { x, y },
+                    _ => return Err(ParserError::InvalidCoordinates),
+                }
+            }
+            [b'P', b'X', b' ', params @ ..] => {
+                let mut params = params.split(|b| *b == b' ');
+                match (params.next(), params.next(), params.next(), params.next()) {
+                    (Some(x), Some(y), Some(color), None) => RawCommand::SetPixel { x, y, color },
+                    (Some(x), Some(y), None, None) => RawCommand::GetPixel { x, y },
+                    _ => return Err(ParserError::InvalidCoordinates),
+                }
+            }
+            _ => return Err(ParserError::UnknownCommand),
+        };
+
+        Ok(Some(cmd))
+    }
+
+    pub fn decode<E: CommandExecutionError>(&self) -> Result<Command, ParserError<E>> {
+        Ok(match *self {
+            RawCommand::Help => Command::Help,
+            RawCommand::Size => Command::Size,
+            RawCommand::Offset { x, y } => Command::Offset {
+                x: parse_coordinate(x)?,
+                y: parse_coordinate(y)?,
+            },
+            RawCommand::GetPixel { x, y } => Command::GetPixel {
+                x: parse_coordinate(x)?,
+                y: parse_coordinate(y)?,
+            },
+            RawCommand::SetPixel { x, y, color } => {
+                let color = parse_color(color)?;
+                Command::SetPixel {
+                    x: parse_coordinate(x)?,
+                    y: parse_coordinate(y)?,
+                    color,
+                }
+            }
+        })
+    }
+}
+
+#[inline]
+pub fn parse_coordinate<E: CommandExecutionError>(
+    coordinate: &[u8],
+) -> Result<u16, ParserError<E>> {
+    Ok(u16::from_str(std::str::from_utf8(coordinate)?)?)
+}
+
+/// Parses `rrggbb`, `aarrggbb` or `ww` (gray)
+#[inline]
+pub fn parse_color<E: CommandExecutionError>(color: &[u8]) -> Result<Bgra, ParserError<E>> {
+    Ok(match std::str::from_utf8(color)? {
+        argb if argb.len() == 6 => Bgra::from_rgb(u32::from_str_radix(argb, 16)?),
+        rgb if rgb.len() == 8 => Bgra::from_argb(u32::from_str_radix(rgb, 16)?),
+        bw if bw.len() == 2 => Bgra::from_bw(u8::from_str_radix(bw, 16)?),
+        _ => return Err(ParserError::InvalidColor),
+    })
+}
+
 pub trait CommandHandler {
     type Error;
 
     fn handle(&mut self, cmd: Command) -> Result<(), Self::Error>;
 }
 
+/// Receives commands still borrowing from the input, decoding is up to the handler
+pub trait RawCommandHandler {
+    type Error: CommandExecutionError;
+
+    fn handle_raw(&mut self, cmd: RawCommand<'_>) -> Result<(), ParserError<Self::Error>>;
+}
+
+/// Decodes raw commands for a [CommandHandler]
+struct Decoder<'h, H>(&'h mut H);
+
+impl<E: CommandExecutionError, H: CommandHandler<Error = E>> RawCommandHandler for Decoder<'_, H> {
+    type Error = E;
+
+    #[inline]
+    fn handle_raw(&mut self, cmd: RawCommand<'_>) -> Result<(), ParserError<E>> {
+        Ok(self.0.handle(cmd.decode()?)?)
+    }
+}
+
 pub trait PixelflutParser {
+    fn feed_raw<E: CommandExecutionError>(
+        &mut self,
+        data: &[u8],
+        handler: &mut impl RawCommandHandler<Error = E>,
+    ) -> Result<(), ParserError<E>>;
+
+    #[inline]
     fn feed<E: CommandExecutionError>(
         &mut self,
         data: &[u8],
         handler: &mut impl CommandHandler<Error = E>,
-    ) -> Result<(), ParserError<E>>;
+    ) -> Result<(), ParserError<E>> {
+        self.feed_raw(data, &mut Decoder(handler))
+    }
 }
 
 pub struct NaiveParser;
 
 impl PixelflutParser for NaiveParser {
     #[inline]
-    fn feed<E: CommandExecutionError>(
+    fn feed_raw<E: CommandExecutionError>(
         &mut self,
         data: &[u8],
-        handler: &mut impl CommandHandler<Error = E>,
+        handler: &mut impl RawCommandHandler<Error = E>,
     ) -> Result<(), ParserError<E>> {
-        for cmd in data.split(|b| *b == b'\n') {
-            match cmd {
-                [] => {}
-                [b'H', b'E', b'L', b'P'] | [b'H', b'E', b'L', b'P', b'\r'] => {
-                    handler.handle(Command::Help)?;
-                }
-                [b'S', b'I', b'Z', b'E'] | [b'S', b'I', b'Z', b'E', b'\r'] => {
-                    handler.handle(Command::Size)?;
-                }
-                [b'O', b'F', b'F', b'S', b'E', b'T', b' ', cords @ .., b'\r']
-                | [b'O', b'F', b'F', b'S', b'E', b'T', b' ', cords @ ..] => {
-                    match cords
-                        .split(|b| *b == b' ')
-                        .collect::<Vec<&[u8]>>()
-                        .as_slice()
-                    {
-                        [x, y] => {
-                            handler.handle(Command::Offset {
-                                x: u16::from_str(std::str::from_utf8(x)?)?,
-                                y: u16::from_str(std::str::from_utf8(y)?)?,
-                            })?;
-                        }
-                        _ => return Err(ParserError::InvalidCoordinates),
-                    }
-                }
-                [b'P', b'X', b' ', params @ .., b'\r'] | [b'P', b'X', b' ', params @ ..] => {
-                    match params
-                        .split(|b| *b == b' ')
-                        .collect::<Vec<&[u8]>>()
-                        .as_slice()
-                    {
-                        [x, y, color] => {
-                            let color = match std::str::from_utf8(color)? {
-                                argb if argb.len() == 6 => {
-                                    Bgra::from_rgb(u32::from_str_radix(argb, 16)?)
-                                }
-                                rgb if rgb.len() == 8 => {
-                                    Bgra::from_argb(u32::from_str_radix(rgb, 16)?)
-                                }
-                                bw if bw.len() == 2 => Bgra::from_bw(u8::from_str_radix(bw, 16)?),
-                                _ => return Err(ParserError::InvalidColor),
-                            };
-
-                            handler.handle(Command::SetPixel {
-                                x: u16::from_str(std::str::from_utf8(x)?)?,
-                                y: u16::from_str(std::str::from_utf8(y)?)?,
-                                color,
-                            })?;
-                        }
-                        [x, y] => {
-                            handler.handle(Command::GetPixel {
-                                x: u16::from_str(std::str::from_utf8(x)?)?,
-                                y: u16::from_str(std::str::from_utf8(y)?)?,
-                            })?;
-                        }
-                        _ => return Err(ParserError::InvalidCoordinates),
-                    }
-                }
-                _ => return Err(ParserError::UnknownCommand),
+        for line in data.split(|b| *b == b'\n') {
+            if let Some(cmd) = RawCommand::split(line)? {
+                handler.handle_raw(cmd)?;
             }
         }
 
@@ -255,4 +320,49 @@ mod tests {
             handler.latest
         );
     }
+
+    #[test]
+    fn raw_command_test() {
+        #[derive(PartialEq, Debug)]
+        struct Infallible;
+        impl CommandExecutionError for Infallible {}
+
+        struct Handler<'a> {
+            data: &'a [u8],
+            count: usize,
+        }
+        impl RawCommandHandler for Handler<'_> {
+            type Error = Infallible;
+
+            fn handle_raw(&mut self, cmd: RawCommand<'_>) -> Result<(), ParserError<Infallible>> {
+                if let RawCommand::SetPixel { x, y, color } = cmd {
+                    // arguments are borrowed from the input
+                    for arg in [x, y, color] {
+                        assert!(self.data.as_ptr_range().contains(&arg.as_ptr()));
+                    }
+                    assert_eq!((b"420".as_slice(), b"ff".as_slice()), (x, color));
+                }
+                self.count += 1;
+                Ok(())
+            }
+        }
+
+        let data = b"PX 420 69 ff\r\nSIZE\n\nPX 1 2\n";
+        let mut handler = Handler { data, count: 0 };
+        assert_eq!(Ok(()), NaiveParser.feed_raw(data, &mut handler));
+        assert_eq!(3, handler.count);
+
+        assert_eq!(
+            Err(ParserError::InvalidCoordinates),
+            NaiveParser.feed_raw(b"PX 1 2 3 4\n", &mut handler)
+        );
+        assert_eq!(
+            Err(ParserError::InvalidCoordinates),
+            NaiveParser.feed_raw(b"OFFSET 1\n", &mut handler)
+        );
+        assert_eq!(
+            Err(ParserError::UnknownCommand),
+            NaiveParser.feed_raw(b"NOPE\n", &mut handler)
+        );
+    }
 }
@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+
+use egui::Color32;
+
+use crate::Camera;
+
+const MAX_ZOOM: f32 = 2.5;
+/// Radians per second of the zoom and pan movements
+const ZOOM_SPEED: f32 = 0.05;
+const PAN_SPEED: f32 = 0.03;
+
+pub struct AttractConfig {
+    /// Time without new pixels until the attract mode starts
+    pub idle_after: Duration,
+    /// Shown on top of the canvas while idle, e.g. how to connect to the server
+    pub connection_info: Option<String>,
+}
+
+/// Slowly moves over the canvas while nobody is drawing
+pub struct AttractMode {
+    config: AttractConfig,
+    /// pixel counter of the server when it last changed
+    pixels: Option<u64>,
+    last_activity: Instant,
+    active_since: Option<Instant>,
+}
+
+impl AttractMode {
+    pub fn new(config: AttractConfig) -> Self {
+        Self {
+            config,
+            pixels: None,
+            last_activity: Instant::now(),
+            active_since: None,
+        }
+    }
+
+    /// Tracks the total pixel counter of the server, any change ends the attract mode
+    pub fn update(&mut self, pixels: u64) {
+        if self.pixels != Some(pixels) {
+            self.pixels = Some(pixels);
+            self.last_activity = Instant::now();
+            self.active_since = None;
+            return;
+        }
+
+        if self.active_since.is_none() && self.last_activity.elapsed() >= self.config.idle_after {
+            self.active_since = Some(Instant::now());
+        }
+    }
+
+    /// None while somebody is drawing
+    pub fn camera(&self) -> Option<Camera> {
+        let since = self.active_since?;
+        let t = since.elapsed().as_secs_f32();
+
+        // start from the full view and zoom in smoothly
+        let zoom = 1.0 + (MAX_ZOOM - 1.0) * (1.0 - (t * ZOOM_SPEED).cos()) / 2.0;
+        // keep the view inside the canvas
+        let range = 0.5 - 0.5 / zoom;
+        Some(Camera {
+            zoom,
+            center: [
+                0.5 + range * (t * PAN_SPEED).sin(),
+                0.5 + range * (t * PAN_SPEED * 0.7).sin(),
+            ],
+        })
+    }
+
+    pub fn ui(&self, ctx: &egui::Context) {
+        let (Some(since), Some(connection_info)) =
+            (self.active_since, self.config.connection_info.as_ref())
+        else {
+            return;
+        };
+
+        let pulse = 0.6 + 0.4 * (since.elapsed().as_secs_f32() * 2.0).sin();
+        egui::Area::new("attract")
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .interactable(false)
+            .show(ctx, |ui| {
+                ui.label(
+                    egui::RichText::new(connection_info)
+                        .size(48.0)
+                        .strong()
+                        .color(Color32::WHITE.gamma_multiply(pulse)),
+                );
+            });
+    }
+}
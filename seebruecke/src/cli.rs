@@ -1,5 +1,10 @@
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+
 use clap::Parser;
 
+use wellenbrecher_canvas::instance::{self, DEFAULT_CANVAS_FILE_LINK, DEFAULT_STATS_FILE_LINK};
+
 #[derive(Parser, Clone)]
 #[command(author, version, about)]
 pub struct Args {
@@ -11,11 +16,68 @@ pub struct Args {
     #[arg(long = "list-gpus", default_value_t = false)]
     pub list_gpus: bool,
 
+    /// Monitor to go fullscreen on, by index or name, see --list-monitors
+    #[arg(long, env = "SEEBRUECKE_MONITOR")]
+    pub monitor: Option<String>,
+
+    /// List available monitors
+    #[arg(long = "list-monitors", default_value_t = false)]
+    pub list_monitors: bool,
+
     /// Start in fullscreen mode
     #[arg(short, long, default_value_t = false, env = "SEEBRUECKE_FULLSCREEN")]
     pub fullscreen: bool,
 
+    /// Name of the server instance to show, replaces the default file links
+    #[arg(long, value_parser = instance::parse_name, env = "WELLENBRECHER_INSTANCE")]
+    pub instance: Option<String>,
+
     /// Canvas shared memory file link
-    #[arg(short = 'l', long, default_value_t = String::from("/tmp/wellenbrecher-canvas"), env = "WELLENBRECHER_CANVAS_FLINK")]
+    #[arg(short = 'l', long, default_value_t = String::from(DEFAULT_CANVAS_FILE_LINK), env = "WELLENBRECHER_CANVAS_FLINK")]
     pub canvas_file_link: String,
+
+    /// Open a canvas stored in a plain memory mapped file instead of shared memory,
+    /// e.g. on platforms without shared memory file links
+    #[arg(long, conflicts_with = "playback", env = "SEEBRUECKE_CANVAS_FILE")]
+    pub canvas_file: Option<PathBuf>,
+
+    /// Traffic stats shared memory file link
+    #[arg(long, default_value_t = String::from(DEFAULT_STATS_FILE_LINK), env = "WELLENBRECHER_STATS_FLINK")]
+    pub stats_file_link: String,
+
+    /// Play back a directory of snapshots taken by `kanal snapshots` instead of the live canvas
+    #[arg(long, env = "SEEBRUECKE_PLAYBACK")]
+    pub playback: Option<PathBuf>,
+
+    /// Snapshots per second during playback
+    #[arg(long, default_value_t = NonZeroU32::new(10).unwrap(), env = "SEEBRUECKE_PLAYBACK_FPS")]
+    pub playback_fps: NonZeroU32,
+
+    /// Canvas pixels between two lines of the coordinate grid (toggled with G), thinned out when zoomed out
+    #[arg(long, default_value_t = NonZeroU32::new(10).unwrap(), env = "SEEBRUECKE_GRID_SPACING")]
+    pub grid_spacing: NonZeroU32,
+
+    /// Seconds without new pixels until slowly moving over the canvas, disabled if not set
+    #[arg(long, env = "SEEBRUECKE_ATTRACT_AFTER")]
+    pub attract_after: Option<NonZeroU32>,
+
+    /// Text shown while idle, e.g. how to connect to the server
+    #[arg(long, requires = "attract_after", env = "SEEBRUECKE_CONNECTION_INFO")]
+    pub connection_info: Option<String>,
+}
+
+impl Args {
+    /// Replaces default file links with the ones of --instance
+    pub fn with_instance(mut self) -> Self {
+        if let Some(name) = &self.instance {
+            let (canvas_file_link, stats_file_link) = instance::file_links(name);
+            if self.canvas_file_link == DEFAULT_CANVAS_FILE_LINK {
+                self.canvas_file_link = canvas_file_link;
+            }
+            if self.stats_file_link == DEFAULT_STATS_FILE_LINK {
+                self.stats_file_link = stats_file_link;
+            }
+        }
+        self
+    }
 }
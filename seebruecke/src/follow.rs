@@ -0,0 +1,95 @@
+use std::time::Instant;
+
+use wellenbrecher_canvas::{Bgra, Canvas};
+
+use crate::Camera;
+
+/// Cells per canvas side the activity is tracked in
+const GRID: usize = 16;
+/// Share of the activity that is left after one second
+const DECAY_PER_SEC: f32 = 0.3;
+/// Share of the remaining distance to the most active region covered per second
+const PAN_PER_SEC: f32 = 0.8;
+
+/// Pans a zoomed in camera towards the region with the most writes recently
+#[derive(Default)]
+pub struct ActivityFollow {
+    pub enabled: bool,
+    /// canvas contents of the previous frame, used to detect writes
+    previous: Vec<Bgra>,
+    dimensions: (u32, u32),
+    activity: Vec<f32>,
+    last_update: Option<Instant>,
+}
+
+impl ActivityFollow {
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+        // stale while disabled
+        self.previous.clear();
+        self.last_update = None;
+    }
+
+    pub fn update(&mut self, canvas: &Canvas, view: &mut Camera) {
+        if !self.enabled {
+            return;
+        }
+
+        let now = Instant::now();
+        let secs = self
+            .last_update
+            .replace(now)
+            .map_or(0.0, |last| (now - last).as_secs_f32());
+
+        let pixels = canvas.pixel_slice();
+        let dimensions = (canvas.width(), canvas.height());
+        if dimensions != self.dimensions || self.previous.len() != pixels.len() {
+            self.dimensions = dimensions;
+            self.previous = pixels.to_vec();
+            self.activity = vec![0.0; GRID * GRID];
+            return;
+        }
+
+        let decay = DECAY_PER_SEC.powf(secs);
+        self.activity.iter_mut().for_each(|heat| *heat *= decay);
+
+        let (width, height) = (dimensions.0 as usize, dimensions.1 as usize);
+        for (y, (row, previous_row)) in pixels
+            .chunks_exact(width)
+            .zip(self.previous.chunks_exact_mut(width))
+            .enumerate()
+        {
+            let cell_row = y * GRID / height * GRID;
+            for (x, (pixel, previous)) in row.iter().zip(previous_row.iter_mut()).enumerate() {
+                if pixel != previous {
+                    *previous = *pixel;
+                    self.activity[cell_row + x * GRID / width] += 1.0;
+                }
+            }
+        }
+
+        // nothing to follow in a zoomed out view or without any writes
+        if view.zoom <= 1.0 {
+            return;
+        }
+        let Some((hottest, _)) = self
+            .activity
+            .iter()
+            .enumerate()
+            .filter(|(_, &heat)| heat > 0.0)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        else {
+            return;
+        };
+
+        let target = [
+            ((hottest % GRID) as f32 + 0.5) / GRID as f32,
+            ((hottest / GRID) as f32 + 0.5) / GRID as f32,
+        ];
+        let step = (PAN_PER_SEC * secs).min(1.0);
+        for (center, target) in view.center.iter_mut().zip(target) {
+            *center += (target - *center) * step;
+        }
+        *view = view.clamped();
+    }
+}
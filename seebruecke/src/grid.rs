@@ -0,0 +1,129 @@
+use egui::{Align2, Color32, FontId, Pos2, Rect, Stroke};
+
+/// Grid lines closer than this (in points) are thinned out
+const MIN_LINE_DISTANCE: f32 = 8.0;
+/// Ruler labels closer than this (in points) are thinned out
+const MIN_LABEL_DISTANCE: f32 = 48.0;
+const RULER_WIDTH: f32 = 28.0;
+
+/// Coordinate grid with rulers along the canvas edges and a crosshair following the pointer
+pub struct GridOverlay {
+    pub visible: bool,
+    pub crosshair: bool,
+    /// canvas pixels between two grid lines
+    spacing: u32,
+}
+
+impl GridOverlay {
+    pub fn new(spacing: u32) -> Self {
+        Self {
+            visible: false,
+            crosshair: false,
+            spacing,
+        }
+    }
+
+    /// `screen` is the canvas area in the window in points, `region` the part of the canvas
+    /// shown in it in canvas pixels
+    pub fn ui(&self, ctx: &egui::Context, screen: Rect, region: Rect) {
+        if !self.visible && !self.crosshair {
+            return;
+        }
+
+        let painter = ctx.layer_painter(egui::LayerId::new(
+            egui::Order::Background,
+            egui::Id::new("grid"),
+        ));
+        let painter = painter.with_clip_rect(screen);
+        let scale = screen.width() / region.width();
+        let to_screen = |x: f32, y: f32| {
+            Pos2::new(
+                screen.left() + (x - region.left()) * scale,
+                screen.top() + (y - region.top()) * scale,
+            )
+        };
+
+        if self.visible {
+            let line_spacing = thinned(self.spacing, scale, MIN_LINE_DISTANCE);
+            let label_spacing = thinned(line_spacing, scale, MIN_LABEL_DISTANCE);
+            let line = Stroke::new(1.0, Color32::from_white_alpha(48));
+            let font = FontId::monospace(10.0);
+
+            painter.rect_filled(
+                Rect::from_min_size(screen.min, egui::vec2(screen.width(), RULER_WIDTH)),
+                0.0,
+                Color32::from_black_alpha(160),
+            );
+            painter.rect_filled(
+                Rect::from_min_size(screen.min, egui::vec2(RULER_WIDTH, screen.height())),
+                0.0,
+                Color32::from_black_alpha(160),
+            );
+
+            for x in lines(region.left(), region.right(), line_spacing) {
+                let top = to_screen(x as f32, region.top());
+                painter.vline(top.x, screen.y_range(), line);
+                if x % label_spacing == 0 {
+                    painter.text(
+                        top + egui::vec2(2.0, 2.0),
+                        Align2::LEFT_TOP,
+                        x,
+                        font.clone(),
+                        Color32::WHITE,
+                    );
+                }
+            }
+            for y in lines(region.top(), region.bottom(), line_spacing) {
+                let left = to_screen(region.left(), y as f32);
+                painter.hline(screen.x_range(), left.y, line);
+                if y % label_spacing == 0 {
+                    painter.text(
+                        left + egui::vec2(2.0, 2.0),
+                        Align2::LEFT_TOP,
+                        y,
+                        font.clone(),
+                        Color32::WHITE,
+                    );
+                }
+            }
+        }
+
+        if self.crosshair {
+            let Some(pointer) = ctx.pointer_hover_pos().filter(|pos| screen.contains(*pos)) else {
+                return;
+            };
+            let x = (region.left() + (pointer.x - screen.left()) / scale).floor();
+            let y = (region.top() + (pointer.y - screen.top()) / scale).floor();
+
+            // outline the pixel under the pointer
+            let pixel = Rect::from_two_pos(to_screen(x, y), to_screen(x + 1.0, y + 1.0));
+            let center = pixel.center();
+            let stroke = Stroke::new(1.0, Color32::from_rgb(255, 64, 64));
+            painter.vline(center.x, screen.y_range(), stroke);
+            painter.hline(screen.x_range(), center.y, stroke);
+            painter.rect_stroke(pixel, 0.0, stroke);
+            painter.text(
+                pixel.right_bottom() + egui::vec2(4.0, 4.0),
+                Align2::LEFT_TOP,
+                format!("{x} {y}"),
+                FontId::monospace(14.0),
+                Color32::WHITE,
+            );
+        }
+    }
+}
+
+/// Smallest multiple of `spacing` by a power of two that is at least `min_distance` points wide
+fn thinned(spacing: u32, scale: f32, min_distance: f32) -> u32 {
+    let mut spacing = spacing.max(1);
+    while (spacing as f32 * scale) < min_distance {
+        spacing = spacing.saturating_mul(2);
+    }
+    spacing
+}
+
+/// Canvas coordinates of the grid lines between `from` and `to`
+fn lines(from: f32, to: f32, spacing: u32) -> impl Iterator<Item = u32> {
+    let first = (from.max(0.0) as u32).div_ceil(spacing) * spacing;
+    (first..to.max(0.0).ceil() as u32).step_by(spacing as usize)
+}
@@ -0,0 +1,166 @@
+use egui::{Color32, Sense};
+use egui_plot::{Bar, BarChart, Plot};
+
+use wellenbrecher_canvas::Canvas;
+
+/// Buckets per color channel
+const BINS: usize = 64;
+/// Colors are counted with 5 bits per channel to find the most used ones
+const QUANTIZED_BITS: u32 = 5;
+const TOP_COLORS: usize = 8;
+/// Pixels counted per frame, a full pass over a 1280x720 canvas takes 4 frames
+const PIXELS_PER_FRAME: usize = 1 << 18;
+
+/// Histogram of the colors on the canvas, counted incrementally a few rows per frame
+pub struct ColorHistogram {
+    pub visible: bool,
+    /// next row of the current pass
+    row: u32,
+    dimensions: (u32, u32),
+    channels: [[u32; BINS]; 3],
+    quantized: Vec<u32>,
+    /// results of the last complete pass
+    shown_channels: [[u32; BINS]; 3],
+    top_colors: Vec<(Color32, f64)>,
+}
+
+impl Default for ColorHistogram {
+    fn default() -> Self {
+        Self {
+            visible: false,
+            row: 0,
+            dimensions: (0, 0),
+            channels: [[0; BINS]; 3],
+            quantized: vec![0; 1 << (3 * QUANTIZED_BITS)],
+            shown_channels: [[0; BINS]; 3],
+            top_colors: Vec::new(),
+        }
+    }
+}
+
+impl ColorHistogram {
+    pub fn update(&mut self, canvas: &Canvas) {
+        if !self.visible {
+            return;
+        }
+
+        let dimensions = (canvas.width(), canvas.height());
+        if dimensions != self.dimensions {
+            self.dimensions = dimensions;
+            self.restart();
+        }
+
+        let (width, height) = dimensions;
+        let rows = (PIXELS_PER_FRAME / width as usize).max(1) as u32;
+        let end = height.min(self.row + rows);
+        let pixels = &canvas.pixel_slice()[(self.row * width) as usize..(end * width) as usize];
+
+        let shift = 8 - QUANTIZED_BITS;
+        for pixel in pixels {
+            let (r, g, b) = (pixel.r as usize, pixel.g as usize, pixel.b as usize);
+            self.channels[0][r * BINS / 256] += 1;
+            self.channels[1][g * BINS / 256] += 1;
+            self.channels[2][b * BINS / 256] += 1;
+            self.quantized[((r >> shift) << (2 * QUANTIZED_BITS))
+                | ((g >> shift) << QUANTIZED_BITS)
+                | (b >> shift)] += 1;
+        }
+
+        self.row = end;
+        if self.row == height {
+            self.finish_pass((width * height) as f64);
+        }
+    }
+
+    fn restart(&mut self) {
+        self.row = 0;
+        self.channels = [[0; BINS]; 3];
+        self.quantized.fill(0);
+    }
+
+    fn finish_pass(&mut self, pixels: f64) {
+        self.shown_channels = self.channels;
+
+        let mut top = self
+            .quantized
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .collect::<Vec<_>>();
+        top.sort_unstable_by(|(_, a), (_, b)| b.cmp(a));
+
+        let mask = (1 << QUANTIZED_BITS) - 1;
+        // center of the bucket
+        let expand = |c: usize| ((c << (8 - QUANTIZED_BITS)) | (1 << (7 - QUANTIZED_BITS))) as u8;
+        self.top_colors = top
+            .into_iter()
+            .take(TOP_COLORS)
+            .map(|(color, &count)| {
+                (
+                    Color32::from_rgb(
+                        expand((color >> (2 * QUANTIZED_BITS)) & mask),
+                        expand((color >> QUANTIZED_BITS) & mask),
+                        expand(color & mask),
+                    ),
+                    count as f64 / pixels,
+                )
+            })
+            .collect();
+
+        self.restart();
+    }
+
+    pub fn ui(&self, ctx: &egui::Context) {
+        if !self.visible {
+            return;
+        }
+
+        egui::Window::new("Colors")
+            .anchor(egui::Align2::LEFT_TOP, [50.0, 50.0])
+            .resizable(false)
+            .movable(true)
+            .title_bar(false)
+            .show(ctx, |ui| {
+                Plot::new("color_histogram")
+                    .width(256.0)
+                    .height(120.0)
+                    .allow_drag(false)
+                    .allow_zoom(false)
+                    .allow_scroll(false)
+                    .allow_boxed_zoom(false)
+                    .show_axes([false, false])
+                    .show(ui, |plot_ui| {
+                        for (bins, color) in self.shown_channels.iter().zip([
+                            Color32::RED,
+                            Color32::GREEN,
+                            Color32::BLUE,
+                        ]) {
+                            let bars = bins
+                                .iter()
+                                .enumerate()
+                                .map(|(i, &count)| Bar::new(i as f64, count as f64).width(1.0))
+                                .collect();
+                            plot_ui.bar_chart(BarChart::new(bars).color(color.gamma_multiply(0.5)));
+                        }
+                    });
+
+                for (color, share) in &self.top_colors {
+                    ui.horizontal(|ui| {
+                        let (rect, _) =
+                            ui.allocate_exact_size(egui::vec2(16.0, 16.0), Sense::hover());
+                        ui.painter().rect_filled(rect, 2.0, *color);
+                        ui.colored_label(
+                            Color32::WHITE,
+                            format!(
+                                "#{:02x}{:02x}{:02x} {:.1}%",
+                                color.r(),
+                                color.g(),
+                                color.b(),
+                                share * 100.0
+                            ),
+                        );
+                    });
+                }
+            });
+    }
+}
@@ -1,12 +1,15 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use bytemuck_derive::{Pod, Zeroable};
 use egui::ahash::{HashMap, HashMapExt};
 use egui::mutex::RwLock;
-use egui::{Align2, ViewportId};
+use egui::{Align2, Rect, ViewportId};
 use egui_winit::EventResponse;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 use wgpu::util::{BufferInitDescriptor, DeviceExt};
 use wgpu::{
     Backends, BindGroup, BufferBindingType, BufferUsages, CompositeAlphaMode, ImageDataLayout,
@@ -18,10 +21,21 @@ use winit::{
     window::Window,
 };
 
-use wellenbrecher_canvas::{Bgra, Canvas, UserID};
+use wellenbrecher_canvas::{Bgra, Canvas, Stats, UserID};
 
+pub use crate::attract::AttractConfig;
+use crate::attract::AttractMode;
+use crate::follow::ActivityFollow;
+pub use crate::grid::GridOverlay;
+use crate::histogram::ColorHistogram;
+pub use crate::playback::Playback;
 use crate::texture::{StorageTexture, Texture};
 
+mod attract;
+mod follow;
+mod grid;
+mod histogram;
+mod playback;
 mod texture;
 
 #[repr(C)]
@@ -67,6 +81,80 @@ impl Vertex {
     }
 }
 
+/// Traffic rates derived from the server stats between two frames
+struct TrafficSample {
+    at: Instant,
+    bytes: u64,
+    pixels: u64,
+    worker_pixels: Vec<u64>,
+    bits_per_sec: f64,
+    pixels_per_sec: f64,
+    worker_pixels_per_sec: Vec<f64>,
+}
+
+impl TrafficSample {
+    fn new(stats: Option<&Stats>) -> Self {
+        let (bytes, pixels) = stats.map(Stats::total).unwrap_or_default();
+        Self {
+            at: Instant::now(),
+            bytes,
+            pixels,
+            worker_pixels: stats.map(worker_pixels).unwrap_or_default(),
+            bits_per_sec: 0.0,
+            pixels_per_sec: 0.0,
+            worker_pixels_per_sec: Vec::new(),
+        }
+    }
+}
+
+/// Part of the canvas shown in the window, in texture coordinates
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Camera {
+    /// 1.0 shows the whole canvas
+    zoom: f32,
+    center: [f32; 2],
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            zoom: 1.0,
+            center: [0.5, 0.5],
+        }
+    }
+}
+
+impl Camera {
+    const MAX_ZOOM: f32 = 16.0;
+
+    fn zoomed(self, factor: f32) -> Self {
+        Self {
+            zoom: (self.zoom * factor).clamp(1.0, Self::MAX_ZOOM),
+            ..self
+        }
+        .clamped()
+    }
+
+    /// Moves the center so that the view stays inside the canvas
+    fn clamped(self) -> Self {
+        let half_extent = 0.5 / self.zoom;
+        Self {
+            center: self
+                .center
+                .map(|center| center.clamp(half_extent, 1.0 - half_extent)),
+            ..self
+        }
+    }
+}
+
+fn worker_pixels(stats: &Stats) -> Vec<u64> {
+    stats
+        .workers()
+        .iter()
+        .map(|counters| counters.load().1)
+        .collect()
+}
+
 struct State {
     surface: wgpu::Surface,
     device: Arc<wgpu::Device>,
@@ -78,17 +166,39 @@ struct State {
     canvas_texture: Texture,
     uid_map_texture: StorageTexture,
     window: Window,
+    bind_group_layout: wgpu::BindGroupLayout,
+    fragment_shader_state: wgpu::Buffer,
     bind_group: BindGroup,
     canvas: Canvas,
+    canvas_generation: u64,
     push_constants: Push,
     egui_state: egui_winit::State,
     egui_context: egui::Context,
     egui_render_state: egui_wgpu::RenderState,
-    last_rx_bytes: (Instant, u64, f64),
+    stats: Option<Stats>,
+    stats_file_link: PathBuf,
+    stats_generation: u64,
+    last_traffic: TrafficSample,
+    playback: Option<Playback>,
+    color_histogram: ColorHistogram,
+    /// camera in use, the attract mode takes over the view of the operator
+    camera: Camera,
+    view: Camera,
+    follow: ActivityFollow,
+    grid: GridOverlay,
+    attract: Option<AttractMode>,
 }
 
 impl State {
-    async fn new(window: Window, gpu_index: usize, canvas: Canvas) -> eyre::Result<Self> {
+    async fn new(
+        window: Window,
+        gpu_index: usize,
+        canvas: Canvas,
+        stats_file_link: PathBuf,
+        playback: Option<Playback>,
+        attract: Option<AttractConfig>,
+        grid: GridOverlay,
+    ) -> eyre::Result<Self> {
         let size = window.inner_size();
 
         let instance = wgpu::Instance::default();
@@ -196,98 +306,19 @@ impl State {
             label: Some("bind_group_layout"),
         });
 
-        let canvas_texture = Texture::new(
-            &device,
-            TextureFormat::Bgra8UnormSrgb,
-            canvas.width(),
-            canvas.height(),
-            Some("canvas_texture"),
-        )?;
-
-        let uid_map_texture = StorageTexture::new(
-            &device,
-            TextureFormat::R32Uint,
-            canvas.width(),
-            canvas.height(),
-            Some("user_id_map"),
-        )?;
-
-        let secondary_canvas_texture = StorageTexture::new(
-            &device,
-            TextureFormat::Rgba8Unorm,
-            canvas.width(),
-            canvas.height(),
-            Some("secondary_canvas_texture"),
-        )?;
-        queue.write_texture(
-            secondary_canvas_texture.texture.as_image_copy(),
-            vec![0u8; (canvas.width() * canvas.height()) as usize * std::mem::size_of::<Bgra>()]
-                .as_slice(),
-            ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(canvas.width() * std::mem::size_of::<Bgra>() as u32),
-                rows_per_image: Some(canvas.height()),
-            },
-            canvas_texture.texture.size(),
-        );
-
-        let secondary_uid_map_texture = StorageTexture::new(
-            &device,
-            TextureFormat::R32Uint,
-            canvas.width(),
-            canvas.height(),
-            Some("secondary_user_id_map"),
-        )?;
-        queue.write_texture(
-            secondary_uid_map_texture.texture.as_image_copy(),
-            vec![0u8; (canvas.width() * canvas.height()) as usize * std::mem::size_of::<UserID>()]
-                .as_slice(),
-            ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(canvas.width() * std::mem::size_of::<UserID>() as u32),
-                rows_per_image: Some(canvas.height()),
-            },
-            canvas_texture.texture.size(),
-        );
-
         let fragment_shader_state = device.create_buffer_init(&BufferInitDescriptor {
             contents: bytemuck::bytes_of(&FragmentShaderState::default()),
             usage: BufferUsages::STORAGE,
             label: Some("fragment_shader_state"),
         });
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&canvas_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&canvas_texture.sampler),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&uid_map_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: wgpu::BindingResource::TextureView(&secondary_canvas_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 4,
-                    resource: wgpu::BindingResource::TextureView(&secondary_uid_map_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 5,
-                    resource: wgpu::BindingResource::Buffer(
-                        fragment_shader_state.as_entire_buffer_binding(),
-                    ),
-                },
-            ],
-            label: Some("bind_group"),
-        });
+        let (canvas_texture, uid_map_texture, bind_group) = create_canvas_bindings(
+            &device,
+            &queue,
+            &bind_group_layout,
+            &fragment_shader_state,
+            &canvas,
+        )?;
 
         let vertex_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: Some("vertex_shader"),
@@ -398,6 +429,13 @@ impl State {
             ))),
         };
 
+        let canvas_generation = canvas.generation();
+        let stats = Stats::open(stats_file_link.as_path())
+            .map_err(|e| warn!("unable to open traffic stats: {e}"))
+            .ok();
+        let stats_generation = stats.as_ref().map(Stats::generation).unwrap_or_default();
+        let last_traffic = TrafficSample::new(stats.as_ref());
+
         Ok(Self {
             surface,
             device,
@@ -408,14 +446,27 @@ impl State {
             vertex_buffer,
             canvas_texture,
             uid_map_texture,
+            bind_group_layout,
+            fragment_shader_state,
             bind_group,
             window,
             canvas,
+            canvas_generation,
             push_constants,
             egui_state,
             egui_context,
             egui_render_state,
-            last_rx_bytes: (Instant::now(), 0, 0.0f64),
+            stats,
+            stats_file_link,
+            stats_generation,
+            last_traffic,
+            playback,
+            color_histogram: ColorHistogram::default(),
+            camera: Camera::default(),
+            view: Camera::default(),
+            follow: ActivityFollow::default(),
+            grid,
+            attract: attract.map(AttractMode::new),
         })
     }
 
@@ -429,48 +480,64 @@ impl State {
             self.config.width = new_size.width;
             self.config.height = new_size.height;
 
-            let canvas_ratio = self.canvas.width() as f32 / self.canvas.height() as f32;
-            let (width, height) = match self.size.height as f32 * canvas_ratio {
-                draw_width if draw_width <= self.size.width as f32 => {
-                    (draw_width, self.size.height as f32)
-                }
-                _ => (
-                    self.size.width as f32,
-                    self.size.width as f32 * (1.0 / canvas_ratio),
-                ),
-            };
-            let x = width / self.size.width as f32;
-            let x_offset = (1.0 - x) / 2.0;
-            let y = height / self.size.height as f32;
-            let y_offset = (1.0 - y) / 2.0;
-
-            self.queue.write_buffer(
-                &self.vertex_buffer,
-                0,
-                bytemuck::cast_slice(&[
-                    Vertex {
-                        position: [x_offset + x, y_offset],
-                        tex_coords: [1.0, 1.0],
-                    },
-                    Vertex {
-                        position: [x_offset + x, y_offset + y],
-                        tex_coords: [1.0, 0.0],
-                    },
-                    Vertex {
-                        position: [x_offset, y_offset],
-                        tex_coords: [0.0, 1.0],
-                    },
-                    Vertex {
-                        position: [x_offset, y_offset + y],
-                        tex_coords: [0.0, 0.0],
-                    },
-                ]),
-            );
-
+            self.write_vertices();
             self.surface.configure(&self.device, &self.config);
         }
     }
 
+    /// Part of the window the canvas is drawn to according to its aspect ratio,
+    /// relative to the window size
+    fn canvas_area(&self) -> Rect {
+        let canvas_ratio = self.canvas.width() as f32 / self.canvas.height() as f32;
+        let (width, height) = match self.size.height as f32 * canvas_ratio {
+            draw_width if draw_width <= self.size.width as f32 => {
+                (draw_width, self.size.height as f32)
+            }
+            _ => (
+                self.size.width as f32,
+                self.size.width as f32 * (1.0 / canvas_ratio),
+            ),
+        };
+        let x = width / self.size.width as f32;
+        let y = height / self.size.height as f32;
+        Rect::from_center_size(egui::pos2(0.5, 0.5), egui::vec2(x, y))
+    }
+
+    /// Places the canvas in the window according to its aspect ratio and the camera
+    fn write_vertices(&self) {
+        let area = self.canvas_area();
+        let (x_offset, y_offset) = (area.left(), area.top());
+        let (x, y) = (area.width(), area.height());
+
+        let half_extent = 0.5 / self.camera.zoom;
+        let [center_x, center_y] = self.camera.center;
+        let (left, right) = (center_x - half_extent, center_x + half_extent);
+        let (top, bottom) = (center_y - half_extent, center_y + half_extent);
+
+        self.queue.write_buffer(
+            &self.vertex_buffer,
+            0,
+            bytemuck::cast_slice(&[
+                Vertex {
+                    position: [x_offset + x, y_offset],
+                    tex_coords: [right, bottom],
+                },
+                Vertex {
+                    position: [x_offset + x, y_offset + y],
+                    tex_coords: [right, top],
+                },
+                Vertex {
+                    position: [x_offset, y_offset],
+                    tex_coords: [left, bottom],
+                },
+                Vertex {
+                    position: [x_offset, y_offset + y],
+                    tex_coords: [left, top],
+                },
+            ]),
+        );
+    }
+
     fn input(&mut self, event: &WindowEvent) -> bool {
         let EventResponse { consumed, .. } =
             self.egui_state.on_window_event(&self.egui_context, event);
@@ -544,35 +611,339 @@ impl State {
                 info!("Highlighting cleared");
                 true
             }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::C),
+                        ..
+                    },
+                ..
+            } => {
+                self.color_histogram.visible = !self.color_histogram.visible;
+                true
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode:
+                            Some(
+                                VirtualKeyCode::Plus
+                                | VirtualKeyCode::Equals
+                                | VirtualKeyCode::NumpadAdd,
+                            ),
+                        ..
+                    },
+                ..
+            } => {
+                self.view = self.view.zoomed(1.25);
+                true
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode:
+                            Some(VirtualKeyCode::Minus | VirtualKeyCode::NumpadSubtract),
+                        ..
+                    },
+                ..
+            } => {
+                self.view = self.view.zoomed(0.8);
+                true
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::G),
+                        ..
+                    },
+                ..
+            } => {
+                self.grid.visible = !self.grid.visible;
+                true
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::X),
+                        ..
+                    },
+                ..
+            } => {
+                self.grid.crosshair = !self.grid.crosshair;
+                true
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::F),
+                        ..
+                    },
+                ..
+            } => {
+                self.follow.toggle();
+                info!(
+                    "Following the most active region: {}",
+                    if self.follow.enabled { "on" } else { "off" }
+                );
+                true
+            }
             _ => false,
         }
     }
 
-    fn update(&mut self) {}
-
-    fn build_egui(&self, ctx: &egui::Context, mut rx_bits_per_secs: f64) {
-        let pixel_user_map = self
-            .canvas
-            .user_id_slice()
-            .iter()
-            .filter(|&&uid| uid > 0)
-            .fold(HashMap::new(), |mut map, uid| {
-                match map.get_mut(uid) {
-                    Some(pixels) => *pixels += 1,
-                    None => {
-                        map.insert(uid, 1);
+    fn update(&mut self) {
+        if let Some(playback) = self.playback.as_mut() {
+            let dimensions = (self.canvas.width(), self.canvas.height());
+            if let Err(e) = playback.update(&mut self.canvas) {
+                warn!("{e:#}");
+            }
+
+            if dimensions != (self.canvas.width(), self.canvas.height()) {
+                match create_canvas_bindings(
+                    &self.device,
+                    &self.queue,
+                    &self.bind_group_layout,
+                    &self.fragment_shader_state,
+                    &self.canvas,
+                ) {
+                    Ok((canvas_texture, uid_map_texture, bind_group)) => {
+                        self.canvas_texture = canvas_texture;
+                        self.uid_map_texture = uid_map_texture;
+                        self.bind_group = bind_group;
                     }
+                    Err(e) => error!("unable to create textures for the resized canvas: {e}"),
                 }
-                map
-            });
+                // recalculate the aspect ratio
+                self.resize(self.size);
+            }
+            // the playback canvas is private, only resizing it changes its generation
+            self.canvas_generation = self.canvas.generation();
+        }
+
+        if self.canvas.generation() != self.canvas_generation {
+            self.reopen_canvas();
+        }
+        self.color_histogram.update(&self.canvas);
 
-        let mut traffic = String::default();
-        for unit in ["Bit", "kBit", "MBit", "GBit", "PBit"] {
-            traffic = format!("{rx_bits_per_secs:.1} {unit}");
-            if rx_bits_per_secs < 1000.0 {
-                break;
+        self.follow.update(&self.canvas, &mut self.view);
+
+        let attract_camera = self.attract.as_mut().and_then(|attract| {
+            // without stats there is no telling whether somebody is drawing
+            if let Some(stats) = self.stats.as_ref() {
+                attract.update(stats.total().1);
             }
-            rx_bits_per_secs /= 1024.0;
+            attract.camera()
+        });
+        let camera = attract_camera.unwrap_or(self.view);
+        if camera != self.camera {
+            self.camera = camera;
+            self.write_vertices();
+        }
+        if self
+            .stats
+            .as_ref()
+            .map_or(true, |stats| stats.generation() != self.stats_generation)
+        {
+            self.reopen_stats();
+        }
+    }
+
+    /// The server is gone or was restarted, its traffic stats have been replaced
+    fn reopen_stats(&mut self) {
+        // retried on the next frame until the server is back
+        self.stats = match Stats::open(self.stats_file_link.as_path()) {
+            Ok(stats) => {
+                self.stats_generation = stats.generation();
+                self.last_traffic = TrafficSample::new(Some(&stats));
+                Some(stats)
+            }
+            Err(e) => {
+                debug!("unable to reopen traffic stats: {e}");
+                None
+            }
+        };
+    }
+
+    fn sample_traffic(&mut self) -> Option<(f64, f64)> {
+        let stats = self.stats.as_ref()?;
+        let (bytes, pixels) = stats.total();
+
+        let secs = self.last_traffic.at.elapsed().as_secs_f64();
+        if secs.is_normal() {
+            let worker_pixels = worker_pixels(stats);
+            self.last_traffic = TrafficSample {
+                at: Instant::now(),
+                bytes,
+                pixels,
+                bits_per_sec: 8.0 * bytes.saturating_sub(self.last_traffic.bytes) as f64 / secs,
+                pixels_per_sec: pixels.saturating_sub(self.last_traffic.pixels) as f64 / secs,
+                worker_pixels_per_sec: worker_pixels
+                    .iter()
+                    .zip(self.last_traffic.worker_pixels.iter())
+                    .map(|(pixels, last)| pixels.saturating_sub(*last) as f64 / secs)
+                    .collect(),
+                worker_pixels,
+            };
+        }
+
+        Some((
+            self.last_traffic.bits_per_sec,
+            self.last_traffic.pixels_per_sec,
+        ))
+    }
+
+    /// The canvas was cleared, resized or re-created, the current mapping might be stale
+    fn reopen_canvas(&mut self) {
+        // retried on the next frame until the server has re-created the canvas
+        let canvas = match self.canvas.reopen() {
+            Ok(canvas) => canvas,
+            Err(e) => {
+                debug!("unable to reopen canvas: {e}");
+                return;
+            }
+        };
+
+        if canvas.width() != self.canvas.width() || canvas.height() != self.canvas.height() {
+            match create_canvas_bindings(
+                &self.device,
+                &self.queue,
+                &self.bind_group_layout,
+                &self.fragment_shader_state,
+                &canvas,
+            ) {
+                Ok((canvas_texture, uid_map_texture, bind_group)) => {
+                    self.canvas_texture = canvas_texture;
+                    self.uid_map_texture = uid_map_texture;
+                    self.bind_group = bind_group;
+                }
+                Err(e) => {
+                    error!("unable to create textures for the reopened canvas: {e}");
+                    return;
+                }
+            }
+        }
+
+        info!(
+            "reopened canvas ({}x{}, generation {})",
+            canvas.width(),
+            canvas.height(),
+            canvas.generation()
+        );
+        self.canvas_generation = canvas.generation();
+        self.canvas = canvas;
+        // recalculate the aspect ratio
+        self.resize(self.size);
+    }
+
+    /// Canvas area in the window in points and the part of the canvas shown in it in pixels
+    fn grid_view(&self) -> (Rect, Rect) {
+        let area = self.canvas_area();
+        let points = egui::vec2(self.size.width as f32, self.size.height as f32)
+            / self.window.scale_factor() as f32;
+        let screen = Rect::from_min_max(
+            (area.min.to_vec2() * points).to_pos2(),
+            (area.max.to_vec2() * points).to_pos2(),
+        );
+
+        let canvas = egui::vec2(self.canvas.width() as f32, self.canvas.height() as f32);
+        let region = Rect::from_center_size(
+            (egui::Vec2::from(self.camera.center) * canvas).to_pos2(),
+            canvas / self.camera.zoom,
+        );
+        (screen, region)
+    }
+
+    fn build_egui(&self, ctx: &egui::Context, traffic_rates: Option<(f64, f64)>) {
+        // counted by the server with --count-user-pixels, scanning the canvas is the fallback
+        let pixel_user_map = match self.canvas.user_pixel_counters() {
+            [] => self
+                .canvas
+                .user_id_slice()
+                .iter()
+                .filter(|&&uid| uid > 0)
+                .fold(HashMap::new(), |mut map, &uid| {
+                    match map.get_mut(&uid) {
+                        Some(pixels) => *pixels += 1,
+                        None => {
+                            map.insert(uid, 1);
+                        }
+                    }
+                    map
+                }),
+            counters => counters
+                .iter()
+                .enumerate()
+                .skip(1)
+                .map(|(uid, pixels)| (uid as UserID, pixels.load(Ordering::Relaxed)))
+                .filter(|&(_, pixels)| pixels > 0)
+                .collect(),
+        };
+
+        let (traffic, pixels) = match traffic_rates {
+            Some((mut bits_per_sec, pixels_per_sec)) => {
+                let mut traffic = String::default();
+                for unit in ["Bit", "kBit", "MBit", "GBit", "PBit"] {
+                    traffic = format!("{bits_per_sec:.1} {unit}");
+                    if bits_per_sec < 1000.0 {
+                        break;
+                    }
+                    bits_per_sec /= 1024.0;
+                }
+                (
+                    traffic,
+                    human_format::Formatter::new()
+                        .with_decimals(1)
+                        .format(pixels_per_sec),
+                )
+            }
+            None => ("n/a".to_string(), "n/a".to_string()),
+        };
+
+        // (canvas pixels, bytes, pixels set) per team
+        let teams = self.stats.as_ref().map(|stats| {
+            let mut teams = stats
+                .team_totals()
+                .into_iter()
+                .map(|(team, (bytes, pixels))| (team, (0u64, bytes, pixels)))
+                .collect::<BTreeMap<_, _>>();
+            for (&uid, &canvas_pixels) in pixel_user_map.iter() {
+                match stats.team(uid) {
+                    Some(0) | None => {}
+                    Some(team) => teams.entry(team).or_default().0 += canvas_pixels,
+                }
+            }
+            teams
+        });
+
+        let highlighted_user = self.stats.as_ref().and_then(|stats| {
+            stats
+                .user(self.push_constants.user_id_filter)
+                .map(|counters| (self.push_constants.user_id_filter, counters.load()))
+        });
+
+        if let Some(remaining) = self.canvas.announced_clear().and_then(|at| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            at.checked_sub(now)
+        }) {
+            egui::Window::new("Clear")
+                .anchor(Align2::CENTER_TOP, [0.0, 50.0])
+                .resizable(false)
+                .title_bar(false)
+                .show(ctx, |ui| {
+                    ui.colored_label(
+                        egui::Color32::YELLOW,
+                        format!("The canvas is cleared in {remaining}s"),
+                    );
+                });
         }
 
         egui::Window::new("Stats")
@@ -590,6 +961,50 @@ impl State {
                     format!("Players: {}", pixel_user_map.len()),
                 );
                 ui.colored_label(egui::Color32::WHITE, format!("Traffic: {traffic}"));
+                ui.colored_label(egui::Color32::WHITE, format!("Pixels: {pixels}/s"));
+                if !self.last_traffic.worker_pixels_per_sec.is_empty() {
+                    ui.collapsing("Workers", |ui| {
+                        let mut formatter = human_format::Formatter::new();
+                        formatter.with_decimals(1);
+                        for (i, pixels_per_sec) in
+                            self.last_traffic.worker_pixels_per_sec.iter().enumerate()
+                        {
+                            ui.colored_label(
+                                egui::Color32::WHITE,
+                                format!("Worker {i}: {}/s", formatter.format(*pixels_per_sec)),
+                            );
+                        }
+                    });
+                }
+                if let Some(teams) = teams.as_ref().filter(|teams| !teams.is_empty()) {
+                    ui.collapsing("Teams", |ui| {
+                        let mut formatter = human_format::Formatter::new();
+                        formatter.with_decimals(1);
+                        for (team, (canvas_pixels, bytes, pixels)) in teams {
+                            ui.colored_label(
+                                egui::Color32::WHITE,
+                                format!(
+                                    "Team {team}: {} on canvas, {}B, {} pixels",
+                                    formatter.format(*canvas_pixels as f64),
+                                    formatter.format(*bytes as f64),
+                                    formatter.format(*pixels as f64)
+                                ),
+                            );
+                        }
+                    });
+                }
+                if let Some((user_id, (bytes, pixels))) = highlighted_user {
+                    let mut formatter = human_format::Formatter::new();
+                    formatter.with_decimals(1);
+                    ui.colored_label(
+                        egui::Color32::WHITE,
+                        format!(
+                            "User {user_id}: {}B, {} pixels",
+                            formatter.format(bytes as f64),
+                            formatter.format(pixels as f64)
+                        ),
+                    );
+                }
             });
     }
 
@@ -626,20 +1041,9 @@ impl State {
                 label: Some("Render Encoder"),
             });
 
-        let rx_bytes_total: u64 = sys_metrics::network::get_ionets()
-            .expect("unable to get network info")
-            .into_iter()
-            .map(|m| m.rx_bytes)
-            .sum();
-
-        let secs = self.last_rx_bytes.0.elapsed().as_secs_f64();
-        let rx_bits_per_secs = if secs.is_normal() {
-            let rx_bits_per_secs = 8.0 * (rx_bytes_total - self.last_rx_bytes.1) as f64 / secs;
-            self.last_rx_bytes = (Instant::now(), rx_bytes_total, rx_bits_per_secs);
-            rx_bits_per_secs
-        } else {
-            self.last_rx_bytes.2
-        };
+        let traffic_rates = self.sample_traffic();
+        let (grid_screen, grid_region) = self.grid_view();
+        let mut playback = self.playback.take();
 
         let egui::FullOutput {
             platform_output,
@@ -650,8 +1054,17 @@ impl State {
         } = self
             .egui_context
             .run(self.egui_state.take_egui_input(&self.window), |ctx| {
-                self.build_egui(ctx, rx_bits_per_secs)
+                self.build_egui(ctx, traffic_rates);
+                self.grid.ui(ctx, grid_screen, grid_region);
+                self.color_histogram.ui(ctx);
+                if let Some(attract) = self.attract.as_ref() {
+                    attract.ui(ctx);
+                }
+                if let Some(playback) = playback.as_mut() {
+                    playback.ui(ctx);
+                }
             });
+        self.playback = playback;
 
         self.egui_state
             .handle_platform_output(&self.window, &self.egui_context, platform_output);
@@ -718,13 +1131,124 @@ impl State {
     }
 }
 
+fn create_canvas_bindings(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    bind_group_layout: &wgpu::BindGroupLayout,
+    fragment_shader_state: &wgpu::Buffer,
+    canvas: &Canvas,
+) -> eyre::Result<(Texture, StorageTexture, BindGroup)> {
+    let canvas_texture = Texture::new(
+        device,
+        TextureFormat::Bgra8UnormSrgb,
+        canvas.width(),
+        canvas.height(),
+        Some("canvas_texture"),
+    )?;
+
+    let uid_map_texture = StorageTexture::new(
+        device,
+        TextureFormat::R32Uint,
+        canvas.width(),
+        canvas.height(),
+        Some("user_id_map"),
+    )?;
+
+    let secondary_canvas_texture = StorageTexture::new(
+        device,
+        TextureFormat::Rgba8Unorm,
+        canvas.width(),
+        canvas.height(),
+        Some("secondary_canvas_texture"),
+    )?;
+    queue.write_texture(
+        secondary_canvas_texture.texture.as_image_copy(),
+        vec![0u8; (canvas.width() * canvas.height()) as usize * std::mem::size_of::<Bgra>()]
+            .as_slice(),
+        ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(canvas.width() * std::mem::size_of::<Bgra>() as u32),
+            rows_per_image: Some(canvas.height()),
+        },
+        canvas_texture.texture.size(),
+    );
+
+    let secondary_uid_map_texture = StorageTexture::new(
+        device,
+        TextureFormat::R32Uint,
+        canvas.width(),
+        canvas.height(),
+        Some("secondary_user_id_map"),
+    )?;
+    queue.write_texture(
+        secondary_uid_map_texture.texture.as_image_copy(),
+        vec![0u8; (canvas.width() * canvas.height()) as usize * std::mem::size_of::<UserID>()]
+            .as_slice(),
+        ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(canvas.width() * std::mem::size_of::<UserID>() as u32),
+            rows_per_image: Some(canvas.height()),
+        },
+        canvas_texture.texture.size(),
+    );
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&canvas_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&canvas_texture.sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&uid_map_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(&secondary_canvas_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::TextureView(&secondary_uid_map_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::Buffer(
+                    fragment_shader_state.as_entire_buffer_binding(),
+                ),
+            },
+        ],
+        label: Some("bind_group"),
+    });
+
+    Ok((canvas_texture, uid_map_texture, bind_group))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
     canvas: Canvas,
+    stats_file_link: PathBuf,
+    playback: Option<Playback>,
+    attract: Option<AttractConfig>,
+    grid: GridOverlay,
     event_loop: EventLoop<()>,
     window: Window,
     gpu_index: usize,
 ) -> eyre::Result<()> {
-    let mut state = State::new(window, gpu_index, canvas).await?;
+    let mut state = State::new(
+        window,
+        gpu_index,
+        canvas,
+        stats_file_link,
+        playback,
+        attract,
+        grid,
+    )
+    .await?;
 
     event_loop.run(move |event, _, control_flow| {
         match event {
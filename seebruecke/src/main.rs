@@ -1,3 +1,6 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
 use clap::Parser;
 use tracing::{debug, Level};
 use tracing_subscriber::EnvFilter;
@@ -5,8 +8,8 @@ use wgpu::Backends;
 use winit::event_loop::EventLoop;
 use winit::window::{Fullscreen, WindowBuilder};
 
-use seebruecke::run;
-use wellenbrecher_canvas::Canvas;
+use seebruecke::{run, AttractConfig, GridOverlay, Playback};
+use wellenbrecher_canvas::{Bgra, Canvas, CanvasCreateInfo};
 
 mod cli;
 
@@ -45,18 +48,36 @@ fn main() -> eyre::Result<()> {
     setup_logging()?;
 
     let event_loop = EventLoop::new();
+    let args = cli::Args::parse().with_instance();
+
+    if args.list_monitors {
+        for (i, monitor) in event_loop.available_monitors().enumerate() {
+            println!(
+                "{i}: {} ({}x{})",
+                monitor.name().unwrap_or_default(),
+                monitor.size().width,
+                monitor.size().height
+            );
+        }
+
+        return Ok(());
+    }
 
-    let monitor = event_loop
-        .available_monitors()
-        .next()
-        .expect("no monitor found!");
+    let monitor = match args.monitor.as_deref() {
+        None => event_loop.available_monitors().next(),
+        Some(selector) => match selector.parse::<usize>() {
+            Ok(index) => event_loop.available_monitors().nth(index),
+            Err(_) => event_loop
+                .available_monitors()
+                .find(|monitor| monitor.name().as_deref() == Some(selector)),
+        },
+    }
+    .ok_or_else(|| eyre::eyre!("monitor not found, see --list-monitors"))?;
     debug!("Monitor: {:?}", monitor.name());
 
     let mode = monitor.video_modes().next().expect("no mode found");
     debug!("Video mode: {mode}");
 
-    let args = cli::Args::parse();
-
     let window = WindowBuilder::new()
         .with_decorations(true)
         .with_resizable(true)
@@ -80,7 +101,50 @@ fn main() -> eyre::Result<()> {
         return Ok(());
     }
 
-    let canvas = Canvas::open(args.canvas_file_link.as_ref(), true, None)?;
-
-    pollster::block_on(run(canvas, event_loop, window, args.gpu_index))
+    let (canvas, playback) = match args.playback {
+        Some(dir) => {
+            let playback = Playback::open(&dir, args.playback_fps)?;
+            // private canvas the snapshots are copied into, resized to fit the first one
+            let canvas_file_link = PathBuf::from(format!(
+                "{}-playback-{}",
+                args.canvas_file_link,
+                std::process::id()
+            ));
+            let canvas = Canvas::open(
+                &canvas_file_link,
+                false,
+                Some(CanvasCreateInfo {
+                    width: 1,
+                    height: 1,
+                    initial_canvas: vec![Bgra::default()].into_boxed_slice(),
+                    timestamps: false,
+                    user_counters: 0,
+                }),
+            )?;
+            (canvas, Some(playback))
+        }
+        None => match args.canvas_file {
+            Some(path) => (Canvas::open_file(&path, None)?, None),
+            None => (
+                Canvas::open(args.canvas_file_link.as_ref(), true, None)?,
+                None,
+            ),
+        },
+    };
+
+    let attract = args.attract_after.map(|secs| AttractConfig {
+        idle_after: Duration::from_secs(secs.get().into()),
+        connection_info: args.connection_info,
+    });
+
+    pollster::block_on(run(
+        canvas,
+        args.stats_file_link.into(),
+        playback,
+        attract,
+        GridOverlay::new(args.grid_spacing.get()),
+        event_loop,
+        window,
+        args.gpu_index,
+    ))
 }
@@ -0,0 +1,225 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use eyre::WrapErr;
+use tracing::info;
+
+use wellenbrecher_canvas::{Bgra, Canvas, UserID};
+
+const SNAPSHOT_PREFIX: &str = "canvas-";
+
+/// Plays back a directory of snapshots taken by `kanal snapshots`
+pub struct Playback {
+    /// sorted chronologically
+    snapshots: Vec<PathBuf>,
+    current: usize,
+    loaded: Option<usize>,
+    playing: bool,
+    frame_duration: Duration,
+    last_advance: Instant,
+}
+
+struct Snapshot {
+    width: u32,
+    height: u32,
+    pixels: Vec<Bgra>,
+    /// not part of png snapshots
+    user_ids: Option<Vec<UserID>>,
+}
+
+impl Playback {
+    pub fn open(dir: &Path, fps: NonZeroU32) -> eyre::Result<Self> {
+        let mut snapshots = std::fs::read_dir(dir)
+            .wrap_err_with(|| format!("unable to read snapshot directory {}", dir.display()))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| timestamp(path).is_some())
+            .collect::<Vec<_>>();
+        if snapshots.is_empty() {
+            return Err(eyre::eyre!("no snapshots found in {}", dir.display()));
+        }
+
+        // timestamps are zero padded, so the names sort chronologically
+        snapshots.sort_unstable();
+        info!(
+            "playing back {} snapshots from {}",
+            snapshots.len(),
+            dir.display()
+        );
+
+        Ok(Self {
+            snapshots,
+            current: 0,
+            loaded: None,
+            playing: true,
+            frame_duration: Duration::from_secs(1) / fps.get(),
+            last_advance: Instant::now(),
+        })
+    }
+
+    /// Advances the playback and copies the current snapshot into the canvas,
+    /// resizing the canvas if the snapshot dimensions differ
+    pub fn update(&mut self, canvas: &mut Canvas) -> eyre::Result<()> {
+        if self.playing && self.last_advance.elapsed() >= self.frame_duration {
+            self.last_advance = Instant::now();
+            if self.current + 1 < self.snapshots.len() {
+                self.current += 1;
+            } else {
+                self.playing = false;
+            }
+        }
+
+        if self.loaded == Some(self.current) {
+            return Ok(());
+        }
+        // don't retry a broken snapshot every frame
+        self.loaded = Some(self.current);
+
+        let path = &self.snapshots[self.current];
+        let snapshot =
+            load(path).wrap_err_with(|| format!("unable to load snapshot {}", path.display()))?;
+
+        if snapshot.width != canvas.width() || snapshot.height != canvas.height() {
+            canvas.resize(snapshot.width, snapshot.height)?;
+        }
+        canvas.pixel_slice_mut().copy_from_slice(&snapshot.pixels);
+        match snapshot.user_ids {
+            Some(user_ids) => canvas.user_id_slice_mut().copy_from_slice(&user_ids),
+            None => canvas.user_id_slice_mut().fill(0),
+        }
+
+        Ok(())
+    }
+
+    pub fn ui(&mut self, ctx: &egui::Context) {
+        let position = match (
+            timestamp(&self.snapshots[0]),
+            timestamp(&self.snapshots[self.current]),
+        ) {
+            (Some(first), Some(current)) => {
+                let secs = current.saturating_sub(first) / 1000;
+                format!("+{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+            }
+            _ => String::default(),
+        };
+
+        egui::Window::new("Playback")
+            .anchor(egui::Align2::CENTER_BOTTOM, [0.0, -30.0])
+            .resizable(false)
+            .movable(false)
+            .title_bar(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button(if self.playing { "⏸" } else { "▶" }).clicked() {
+                        if !self.playing && self.current + 1 == self.snapshots.len() {
+                            self.current = 0;
+                        }
+                        self.playing = !self.playing;
+                        self.last_advance = Instant::now();
+                    }
+
+                    ui.style_mut().spacing.slider_width = 400.0;
+                    ui.add(
+                        egui::Slider::new(&mut self.current, 0..=self.snapshots.len() - 1)
+                            .show_value(false),
+                    );
+
+                    ui.colored_label(
+                        egui::Color32::WHITE,
+                        format!("{}/{} {position}", self.current + 1, self.snapshots.len()),
+                    );
+                });
+            });
+    }
+}
+
+/// Milliseconds since the epoch the snapshot was taken at
+fn timestamp(path: &Path) -> Option<u64> {
+    match path.extension()?.to_str()? {
+        "png" | "zst" => {}
+        _ => return None,
+    }
+
+    path.file_stem()?
+        .to_str()?
+        .strip_prefix(SNAPSHOT_PREFIX)?
+        .parse()
+        .ok()
+}
+
+fn load(path: &Path) -> eyre::Result<Snapshot> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => load_png(path),
+        Some("zst") => load_zstd(path),
+        _ => Err(eyre::eyre!("unknown snapshot format")),
+    }
+}
+
+fn load_png(path: &Path) -> eyre::Result<Snapshot> {
+    let decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0u8; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+
+    let channels = match (info.color_type, info.bit_depth) {
+        (png::ColorType::Rgb, png::BitDepth::Eight) => 3,
+        (png::ColorType::Rgba, png::BitDepth::Eight) => 4,
+        (color_type, bit_depth) => {
+            return Err(eyre::eyre!(
+                "unsupported png format {color_type:?} with {bit_depth:?}"
+            ))
+        }
+    };
+
+    let pixels = buffer[..info.buffer_size()]
+        .chunks_exact(channels)
+        .map(|rgb| Bgra {
+            r: rgb[0],
+            g: rgb[1],
+            b: rgb[2],
+            a: 0xff,
+        })
+        .collect();
+
+    Ok(Snapshot {
+        width: info.width,
+        height: info.height,
+        pixels,
+        user_ids: None,
+    })
+}
+
+/// Width and height (little endian) followed by the pixel and user id planes
+fn load_zstd(path: &Path) -> eyre::Result<Snapshot> {
+    let data = zstd::decode_all(BufReader::new(File::open(path)?))?;
+    if data.len() < 8 {
+        return Err(eyre::eyre!("truncated snapshot"));
+    }
+
+    let width = u32::from_le_bytes(data[0..4].try_into()?);
+    let height = u32::from_le_bytes(data[4..8].try_into()?);
+    let len = width as usize * height as usize;
+    let pixel_plane = len * std::mem::size_of::<Bgra>();
+    let user_id_plane = len * std::mem::size_of::<UserID>();
+    if data.len() != 8 + pixel_plane + user_id_plane {
+        return Err(eyre::eyre!(
+            "snapshot size does not match its dimensions ({width}x{height})"
+        ));
+    }
+
+    let pixels = bytemuck::cast_slice(&data[8..8 + pixel_plane]).to_vec();
+    let user_ids = data[8 + pixel_plane..]
+        .chunks_exact(std::mem::size_of::<UserID>())
+        .map(|uid| UserID::from_ne_bytes(uid.try_into().unwrap()))
+        .collect();
+
+    Ok(Snapshot {
+        width,
+        height,
+        pixels,
+        user_ids: Some(user_ids),
+    })
+}
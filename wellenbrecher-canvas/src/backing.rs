@@ -0,0 +1,255 @@
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use memmap2::MmapRaw;
+use shared_memory::Shmem;
+
+/// Prefix of a file link pointing to a System V shared memory segment
+pub(crate) const SYSV_LINK_PREFIX: &str = "sysv:";
+
+/// How a newly created canvas is shared with other processes
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ShmBackend {
+    /// POSIX shared memory, the file link contains its id
+    #[default]
+    Flink,
+    /// Anonymous memfd, the file link is a symlink into /proc of the creating process
+    /// and goes stale together with it
+    Memfd,
+    /// System V shared memory, the file link contains the segment id
+    SysV,
+    /// Plain memory mapped file, persistent by design
+    File,
+}
+
+impl FromStr for ShmBackend {
+    type Err = String;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend {
+            "flink" => Ok(ShmBackend::Flink),
+            "memfd" => Ok(ShmBackend::Memfd),
+            "sysv" => Ok(ShmBackend::SysV),
+            "file" => Ok(ShmBackend::File),
+            _ => Err("expected flink, memfd, sysv or file".to_string()),
+        }
+    }
+}
+
+/// Memory the canvas lives in
+pub(crate) enum Backing {
+    /// Shared memory segment behind a file link, Linux only in practice
+    SharedMemory(Shmem),
+    /// Plain memory mapped file, works everywhere and is never removed
+    File(MmapRaw),
+    /// memfd created by this process, kept open to be reachable through /proc
+    Memfd {
+        mmap: MmapRaw,
+        memfd: Option<File>,
+    },
+    SysV(SysVSegment),
+}
+
+impl Backing {
+    #[inline]
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        match self {
+            Backing::SharedMemory(shmem) => shmem.as_ptr(),
+            Backing::File(mmap) | Backing::Memfd { mmap, .. } => mmap.as_mut_ptr(),
+            Backing::SysV(segment) => segment.as_ptr(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_owner(&self) -> bool {
+        match self {
+            Backing::SharedMemory(shmem) => shmem.is_owner(),
+            Backing::File(_) => false,
+            Backing::Memfd { memfd, .. } => memfd.is_some(),
+            Backing::SysV(segment) => segment.owner,
+        }
+    }
+
+    pub(crate) fn backend(&self) -> ShmBackend {
+        match self {
+            Backing::SharedMemory(_) => ShmBackend::Flink,
+            Backing::File(_) => ShmBackend::File,
+            Backing::Memfd { .. } => ShmBackend::Memfd,
+            Backing::SysV(_) => ShmBackend::SysV,
+        }
+    }
+
+    /// Writes a memory mapped file back to disk, shared memory has nowhere to write to
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        match self {
+            Backing::File(mmap) => mmap.flush(),
+            Backing::SharedMemory(_) | Backing::Memfd { .. } | Backing::SysV(_) => Ok(()),
+        }
+    }
+
+    /// Gives up ownership without touching the file link, which belongs to a new segment.
+    /// The old segment is removed once all mappings are gone.
+    pub(crate) fn release(&mut self) -> Option<Shmem> {
+        match self {
+            Backing::SharedMemory(shmem) => {
+                // remove the old segment by its id only
+                let old_segment = shared_memory::ShmemConf::new()
+                    .os_id(shmem.get_os_id())
+                    .open();
+                shmem.set_owner(false);
+                old_segment.ok().map(|mut old_segment| {
+                    old_segment.set_owner(true);
+                    old_segment
+                })
+            }
+            Backing::File(_) => None,
+            Backing::Memfd { memfd, .. } => {
+                memfd.take();
+                None
+            }
+            Backing::SysV(segment) => {
+                segment.remove();
+                None
+            }
+        }
+    }
+}
+
+impl Display for Backing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backing::SharedMemory(shmem) => write!(f, "{}", shmem.get_os_id()),
+            Backing::File(_) => write!(f, "file"),
+            Backing::Memfd { .. } => write!(f, "memfd"),
+            Backing::SysV(segment) => write!(f, "{SYSV_LINK_PREFIX}{}", segment.id),
+        }
+    }
+}
+
+/// Anonymous file of the given size, `link` becomes a symlink to its fd in /proc
+/// which other processes of the same user are able to open
+#[cfg(target_os = "linux")]
+pub(crate) fn memfd(len: usize, link: &Path) -> io::Result<File> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    let fd = unsafe { libc::memfd_create(c"wellenbrecher-canvas".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let memfd = unsafe { File::from_raw_fd(fd) };
+    memfd.set_len(len as u64)?;
+    std::os::unix::fs::symlink(
+        format!("/proc/{}/fd/{}", std::process::id(), memfd.as_raw_fd()),
+        link,
+    )?;
+    Ok(memfd)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn memfd(_len: usize, _link: &Path) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "memfd canvases require Linux",
+    ))
+}
+
+/// Attached System V shared memory segment
+pub(crate) struct SysVSegment {
+    pub(crate) id: i32,
+    ptr: *mut u8,
+    len: usize,
+    /// The segment is removed once the last mapping is gone
+    owner: bool,
+}
+
+impl SysVSegment {
+    #[inline]
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub(crate) fn set_owner(&mut self, owner: bool) {
+        self.owner = owner;
+    }
+}
+
+#[cfg(unix)]
+impl SysVSegment {
+    /// The new segment is owned until told otherwise
+    pub(crate) fn create(len: usize) -> io::Result<Self> {
+        let id = unsafe { libc::shmget(libc::IPC_PRIVATE, len, libc::IPC_CREAT | 0o600) };
+        if id < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Self::attach(id, true).inspect_err(|_| unsafe {
+            libc::shmctl(id, libc::IPC_RMID, std::ptr::null_mut());
+        })
+    }
+
+    pub(crate) fn attach(id: i32, owner: bool) -> io::Result<Self> {
+        let len = unsafe {
+            let mut stat = std::mem::zeroed::<libc::shmid_ds>();
+            if libc::shmctl(id, libc::IPC_STAT, std::ptr::addr_of_mut!(stat)) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            stat.shm_segsz as usize
+        };
+
+        let ptr = unsafe { libc::shmat(id, std::ptr::null(), 0) };
+        if ptr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            id,
+            ptr: ptr as *mut u8,
+            len,
+            owner,
+        })
+    }
+
+    /// Marks the segment to be removed once the last mapping is gone
+    fn remove(&mut self) {
+        unsafe { libc::shmctl(self.id, libc::IPC_RMID, std::ptr::null_mut()) };
+        self.owner = false;
+    }
+}
+
+#[cfg(not(unix))]
+impl SysVSegment {
+    pub(crate) fn create(_len: usize) -> io::Result<Self> {
+        Self::attach(-1, false)
+    }
+
+    pub(crate) fn attach(_id: i32, _owner: bool) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "System V shared memory canvases require a unix system",
+        ))
+    }
+
+    fn remove(&mut self) {}
+}
+
+impl Drop for SysVSegment {
+    fn drop(&mut self) {
+        if self.owner {
+            self.remove();
+        }
+        #[cfg(unix)]
+        unsafe {
+            libc::shmdt(self.ptr as *const _)
+        };
+    }
+}
@@ -0,0 +1,26 @@
+use crate::Bgra;
+
+/*
+Fixed size chunks without bounds checks in the loop body, which the compiler turns into byte
+shuffles on targets with SIMD support.
+ */
+
+/// Appends the pixels as r, g, b, a bytes
+#[inline]
+pub(crate) fn extend_rgba8(out: &mut Vec<u8>, pixels: &[Bgra]) {
+    let start = out.len();
+    out.resize(start + pixels.len() * 4, 0);
+    for (rgba, pixel) in out[start..].chunks_exact_mut(4).zip(pixels) {
+        rgba.copy_from_slice(&[pixel.r, pixel.g, pixel.b, pixel.a]);
+    }
+}
+
+/// Appends the pixels as r, g, b bytes, alpha is dropped
+#[inline]
+pub(crate) fn extend_rgb8(out: &mut Vec<u8>, pixels: &[Bgra]) {
+    let start = out.len();
+    out.resize(start + pixels.len() * 3, 0);
+    for (rgb, pixel) in out[start..].chunks_exact_mut(3).zip(pixels) {
+        rgb.copy_from_slice(&[pixel.r, pixel.g, pixel.b]);
+    }
+}
@@ -0,0 +1,154 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+
+/*
+Several servers on one host are told apart by instance names. The default file links and the
+default stats dump of an instance carry its name, so canvases and stats don't collide. Running instances list themselves
+in the instances file, one line each: name, pid, canvas file link and stats file link separated
+by tabs. Lines of processes that are gone are dropped whenever the file is read.
+ */
+
+pub const DEFAULT_CANVAS_FILE_LINK: &str = "/tmp/wellenbrecher-canvas";
+pub const DEFAULT_STATS_FILE_LINK: &str = "/tmp/wellenbrecher-stats";
+pub const DEFAULT_STATS_DUMP: &str = "/tmp/wellenbrecher-stats.json";
+/// Lists the running instances
+pub const INSTANCES_FILE: &str = "/tmp/wellenbrecher-instances";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Instance {
+    pub name: String,
+    pub pid: u32,
+    pub canvas_file_link: String,
+    pub stats_file_link: String,
+}
+
+impl Instance {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(4, '\t');
+        Some(Self {
+            name: fields.next()?.to_string(),
+            pid: fields.next()?.parse().ok()?,
+            canvas_file_link: fields.next()?.to_string(),
+            stats_file_link: fields.next()?.to_string(),
+        })
+    }
+
+    fn is_running(&self) -> bool {
+        // signal 0 only checks for the process, processes of other users deny it
+        let alive = unsafe { libc::kill(self.pid as libc::pid_t, 0) } == 0;
+        alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+}
+
+/// Instance names end up in file links and nftables names
+pub fn parse_name(name: &str) -> Result<String, String> {
+    match !name.is_empty()
+        && name.len() <= 32
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        true => Ok(name.to_string()),
+        false => Err("expected up to 32 letters, digits, - or _".to_string()),
+    }
+}
+
+/// Default canvas file link of an instance
+pub fn canvas_file_link(name: &str) -> String {
+    format!("/tmp/wellenbrecher-{name}-canvas")
+}
+
+/// Default stats file link of an instance
+pub fn stats_file_link(name: &str) -> String {
+    format!("/tmp/wellenbrecher-{name}-stats")
+}
+
+/// Default file of an instance SIGUSR2 dumps the stats to
+pub fn stats_dump(name: &str) -> String {
+    format!("/tmp/wellenbrecher-{name}-stats.json")
+}
+
+/// (canvas, stats) file links of an instance, the registered ones if it is running
+pub fn file_links(name: &str) -> (String, String) {
+    match find(name) {
+        Ok(Some(instance)) => (instance.canvas_file_link, instance.stats_file_link),
+        _ => (canvas_file_link(name), stats_file_link(name)),
+    }
+}
+
+/// The running instances
+pub fn list() -> io::Result<Vec<Instance>> {
+    let mut file = match File::open(INSTANCES_FILE) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    lock(&file, libc::LOCK_SH)?;
+    read(&mut file)
+}
+
+/// Looks up a running instance by name
+pub fn find(name: &str) -> io::Result<Option<Instance>> {
+    Ok(list()?.into_iter().find(|instance| instance.name == name))
+}
+
+/// Lists `instance` as running, replacing a previous instance of the same name
+pub fn register(instance: &Instance) -> io::Result<()> {
+    update(|instances| {
+        instances.retain(|other| other.name != instance.name);
+        instances.push(instance.clone());
+    })
+}
+
+/// Removes the instance `name` of this process from the list
+pub fn unregister(name: &str) -> io::Result<()> {
+    update(|instances| {
+        instances.retain(|instance| instance.name != name || instance.pid != std::process::id())
+    })
+}
+
+fn update(f: impl FnOnce(&mut Vec<Instance>)) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o666)
+        .open(INSTANCES_FILE)?;
+    lock(&file, libc::LOCK_EX)?;
+
+    let mut instances = read(&mut file)?;
+    f(&mut instances);
+
+    file.set_len(0)?;
+    file.rewind()?;
+    let mut writer = BufWriter::new(&file);
+    for instance in instances {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            instance.name, instance.pid, instance.canvas_file_link, instance.stats_file_link
+        )?;
+    }
+    writer.flush()
+}
+
+fn read(file: &mut File) -> io::Result<Vec<Instance>> {
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    Ok(content
+        .lines()
+        .filter_map(Instance::parse)
+        .filter(Instance::is_running)
+        .collect())
+}
+
+/// Released once the file is closed
+fn lock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    match unsafe { libc::flock(file.as_raw_fd(), operation) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
@@ -4,13 +4,27 @@
 extern crate core;
 
 use std::fmt::{Debug, Formatter};
-use std::path::Path;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::ptr::{slice_from_raw_parts, slice_from_raw_parts_mut};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
 
 use bytemuck_derive::{Pod, Zeroable};
-use shared_memory::{Shmem, ShmemError};
+use memmap2::MmapRaw;
+use shared_memory::ShmemError;
 use thiserror::Error;
-use tracing::error;
+use tracing::{error, warn};
+
+pub use backing::ShmBackend;
+pub use stats::{Counters, Stats, StatsError, TeamID, STATS_VERSION};
+
+use crate::backing::{Backing, SysVSegment, SYSV_LINK_PREFIX};
+
+mod backing;
+mod convert;
+pub mod instance;
+mod stats;
 
 #[derive(Debug, Clone, Copy, Pod, Zeroable, Eq, PartialEq)]
 #[repr(C)]
@@ -95,21 +109,260 @@ impl Bgra {
 }
 
 pub type UserID = u32;
+/// Unix time in milliseconds
+pub type Timestamp = u64;
+
+/*
+Layout of the canvas segment (or file), native endian:
+
+    Header                      48 bytes, see below
+    Bgra        [width*height]  pixels, row by row
+    UserID      [width*height]  user id that set every pixel last, 0 if untouched
+    Timestamp   [width*height]  unix time in milliseconds every pixel was set at, 0 if untouched,
+                                only with FLAG_TIMESTAMPS
+    AtomicU64   [user_counters] pixels on the canvas every user id set last, index 0 counts the
+                                untouched ones, only with FLAG_USER_PIXELS
+
+Server, viewers and the gst plugin check the magic, the version, the pixel format and the flags
+before trusting the rest. The version is bumped whenever the layout changes, optional parts of the
+layout are announced by flags, unknown ones are refused.
+ */
+
+const MAGIC: [u8; 8] = *b"WBCANVAS";
+/// Version of the canvas segment layout
+pub const CANVAS_VERSION: u32 = 1;
+/// Pixels are [Bgra]
+pub const PIXEL_FORMAT_BGRA: u32 = 1;
+/// The user id plane is followed by a timestamp plane
+pub const FLAG_TIMESTAMPS: u32 = 1 << 0;
+/// The planes are followed by the pixel counters of the users
+pub const FLAG_USER_PIXELS: u32 = 1 << 1;
+/// Flags of optional layout parts this version knows
+const KNOWN_FLAGS: u32 = FLAG_TIMESTAMPS | FLAG_USER_PIXELS;
+
+/// Start of the shared memory segment, followed by the pixel and the user id plane
+#[repr(C)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    pixel_format: u32,
+    flags: u32,
+    width: u32,
+    height: u32,
+    /// Number of user pixel counters, 0 without FLAG_USER_PIXELS
+    user_counters: u32,
+    /// Bumped whenever cached dimensions or contents of consumers become stale,
+    /// e.g. before the segment is removed
+    generation: AtomicU64,
+    /// Unix time in seconds the canvas is going to be cleared at, 0 if no clear is announced
+    clear_at: AtomicU64,
+}
+
+/// Dimensions and optional parts of a canvas segment
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Layout {
+    width: u32,
+    height: u32,
+    flags: u32,
+    user_counters: u32,
+}
+
+impl Layout {
+    fn size(&self) -> usize {
+        let timestamp_size = match self.flags & FLAG_TIMESTAMPS {
+            0 => 0,
+            _ => std::mem::size_of::<Timestamp>(),
+        };
+        std::mem::size_of::<Header>()
+            + (self.width * self.height) as usize
+                * (std::mem::size_of::<Bgra>() + std::mem::size_of::<UserID>() + timestamp_size)
+            + self.user_counters as usize * std::mem::size_of::<AtomicU64>()
+    }
+}
+
+impl Header {
+    fn new(layout: Layout) -> Self {
+        Self {
+            magic: MAGIC,
+            version: CANVAS_VERSION,
+            pixel_format: PIXEL_FORMAT_BGRA,
+            flags: layout.flags,
+            width: layout.width,
+            height: layout.height,
+            user_counters: layout.user_counters,
+            generation: AtomicU64::new(0),
+            clear_at: AtomicU64::new(0),
+        }
+    }
+
+    /// Checks the header of an existing segment of `len` bytes and returns its layout
+    unsafe fn validate(ptr: *const u8, len: usize) -> Result<Layout, CanvasError> {
+        let header_size = std::mem::size_of::<Header>();
+        if len < header_size {
+            error!(
+                "canvas appears to have an invalid size (required: >{header_size}, actual {len})"
+            );
+            return Err(CanvasError::InvalidSize);
+        }
+
+        let header = &*(ptr as *const Header);
+        if header.magic != MAGIC {
+            error!("shared memory does not contain a canvas of this version, remove it with wellenbrecher --remove-canvas");
+            return Err(CanvasError::InvalidMagic);
+        }
+        if header.version != CANVAS_VERSION {
+            error!(
+                "canvas has layout version {}, supported is {CANVAS_VERSION}",
+                header.version
+            );
+            return Err(CanvasError::UnsupportedVersion(header.version));
+        }
+        if header.pixel_format != PIXEL_FORMAT_BGRA {
+            error!("canvas has unknown pixel format {}", header.pixel_format);
+            return Err(CanvasError::UnsupportedPixelFormat(header.pixel_format));
+        }
+        if header.flags & !KNOWN_FLAGS != 0 {
+            error!(
+                "canvas has unknown flags {:#x}",
+                header.flags & !KNOWN_FLAGS
+            );
+            return Err(CanvasError::UnsupportedFlags(header.flags & !KNOWN_FLAGS));
+        }
+        Ok(Layout {
+            width: header.width,
+            height: header.height,
+            flags: header.flags,
+            user_counters: match header.flags & FLAG_USER_PIXELS {
+                0 => 0,
+                _ => header.user_counters,
+            },
+        })
+    }
+}
 
 pub struct Canvas {
     width: u32,
     height: u32,
     len: usize,
-    #[allow(dead_code)]
-    shared_memory: Shmem,
+    backing: Backing,
+    flink: PathBuf,
+    header: *mut Header,
     data: *mut Bgra,
     user_id_map: *mut UserID,
+    /// null without FLAG_TIMESTAMPS
+    timestamps: *mut Timestamp,
+    /// empty without FLAG_USER_PIXELS
+    user_pixels: *const [AtomicU64],
 }
 
 pub struct CanvasCreateInfo {
     pub width: u32,
     pub height: u32,
     pub initial_canvas: Box<[Bgra]>,
+    /// Keep the time every pixel was set at, see [Canvas::timestamp]
+    pub timestamps: bool,
+    /// Count the pixels of user ids below this, see [Canvas::user_pixels]. 0 counts none
+    pub user_counters: u32,
+}
+
+impl CanvasCreateInfo {
+    pub fn from_pattern(width: u32, height: u32, pattern: InitialPattern) -> Self {
+        Self {
+            width,
+            height,
+            initial_canvas: pattern.generate(width, height),
+            timestamps: false,
+            user_counters: 0,
+        }
+    }
+
+    fn layout(&self) -> Layout {
+        let mut flags = 0;
+        if self.timestamps {
+            flags |= FLAG_TIMESTAMPS;
+        }
+        if self.user_counters > 0 {
+            flags |= FLAG_USER_PIXELS;
+        }
+        Layout {
+            width: self.width,
+            height: self.height,
+            flags,
+            user_counters: self.user_counters,
+        }
+    }
+}
+
+/// Built-in test patterns, e.g. to calibrate projectors and check the orientation of viewers
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum InitialPattern {
+    Checkerboard,
+    Gradient,
+    ColorBars,
+    /// Grid lines with a red marker in the top left corner
+    Grid,
+}
+
+impl InitialPattern {
+    const CHECKERBOARD_SIZE: u32 = 32;
+    const GRID_SPACING: u32 = 64;
+    const MARKER_SIZE: u32 = 16;
+
+    pub fn generate(&self, width: u32, height: u32) -> Box<[Bgra]> {
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| self.pixel(x, y, width, height))
+            .collect()
+    }
+
+    fn pixel(&self, x: u32, y: u32, width: u32, height: u32) -> Bgra {
+        match self {
+            InitialPattern::Checkerboard => {
+                match (x / Self::CHECKERBOARD_SIZE + y / Self::CHECKERBOARD_SIZE) % 2 {
+                    0 => Bgra::from_bw(0xff),
+                    _ => Bgra::from_bw(0x00),
+                }
+            }
+            InitialPattern::Gradient => Bgra {
+                r: (x as u64 * 0xff / width.saturating_sub(1).max(1) as u64) as u8,
+                g: (y as u64 * 0xff / height.saturating_sub(1).max(1) as u64) as u8,
+                b: 0x80,
+                a: 0xff,
+            },
+            InitialPattern::ColorBars => {
+                const BARS: [u32; 8] = [
+                    0xffffff, 0xffff00, 0x00ffff, 0x00ff00, 0xff00ff, 0xff0000, 0x0000ff, 0x000000,
+                ];
+                Bgra::from_rgb(BARS[(x as u64 * BARS.len() as u64 / width as u64) as usize])
+            }
+            InitialPattern::Grid => {
+                if x < Self::MARKER_SIZE && y < Self::MARKER_SIZE {
+                    return Bgra::from_rgb(0xff0000);
+                }
+                if x == 0 || y == 0 || x + 1 == width || y + 1 == height {
+                    return Bgra::from_bw(0xff);
+                }
+                match (x % Self::GRID_SPACING, y % Self::GRID_SPACING) {
+                    (0, _) | (_, 0) => Bgra::from_bw(0x80),
+                    _ => Bgra::from_bw(0x00),
+                }
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for InitialPattern {
+    type Err = String;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        match pattern {
+            "checkerboard" => Ok(InitialPattern::Checkerboard),
+            "gradient" => Ok(InitialPattern::Gradient),
+            "colorbars" => Ok(InitialPattern::ColorBars),
+            "grid" => Ok(InitialPattern::Grid),
+            _ => Err("expected checkerboard, gradient, colorbars or grid".to_string()),
+        }
+    }
 }
 
 impl Debug for CanvasCreateInfo {
@@ -119,10 +372,14 @@ impl Debug for CanvasCreateInfo {
         struct CanvasCreateInfo {
             width: u32,
             height: u32,
+            timestamps: bool,
+            user_counters: u32,
         }
         CanvasCreateInfo {
             width: self.width,
             height: self.height,
+            timestamps: self.timestamps,
+            user_counters: self.user_counters,
         }
         .fmt(f)
     }
@@ -138,22 +395,22 @@ impl Debug for Canvas {
                 r"Canvas: {{
     width: {},
     height: {},
-    shared_memory: {},
-    shared_memory_owner: {},
+    backing: {},
+    owner: {},
 }}",
                 self.width,
                 self.height,
-                self.shared_memory.get_os_id(),
-                self.shared_memory.is_owner()
+                self.backing,
+                self.backing.is_owner()
             )
         } else {
             write!(
                 f,
-                r"Canvas: {{ width: {}, height: {}, shared_memory: {}, shared_memory_owner: {} }}",
+                r"Canvas: {{ width: {}, height: {}, backing: {}, owner: {} }}",
                 self.width,
                 self.height,
-                self.shared_memory.get_os_id(),
-                self.shared_memory.is_owner()
+                self.backing,
+                self.backing.is_owner()
             )
         }
     }
@@ -166,81 +423,56 @@ impl Canvas {
         persistent_canvas: bool,
         create_info: Option<CanvasCreateInfo>,
     ) -> Result<Self, CanvasError> {
-        let header_size = 2 * std::mem::size_of::<u32>();
+        let header_size = std::mem::size_of::<Header>();
 
         match create_info {
-            Some(CanvasCreateInfo {
-                width,
-                height,
-                initial_canvas,
-            }) => {
-                let canvas_size = (width * height) as usize * std::mem::size_of::<Bgra>();
-                let uid_map_size = (width * height) as usize * std::mem::size_of::<UserID>();
-                let size = header_size + canvas_size + uid_map_size;
+            Some(create_info) => {
+                let layout = create_info.layout();
 
                 match shared_memory::ShmemConf::new()
-                    .size(size)
+                    .size(layout.size())
                     .flink(canvas_path)
                     .create()
-                    .map(|m| {
-                        unsafe {
-                            (m.as_ptr() as *mut u32).write(width);
-                            (m.as_ptr() as *mut u32).add(1).write(height);
-
-                            let slice = &mut *slice_from_raw_parts_mut(
-                                m.as_ptr().add(header_size) as *mut Bgra,
-                                (width * height) as usize,
-                            );
-
-                            slice.copy_from_slice(initial_canvas.as_ref());
-                        }
-                        m
-                    }) {
+                {
                     Ok(mut shmem) => {
                         shmem.set_owner(!persistent_canvas);
 
-                        Ok(Canvas {
-                            width,
-                            height,
-                            len: (width * height) as usize,
-                            data: unsafe { shmem.as_ptr().add(header_size) } as *mut _,
-                            user_id_map: unsafe { shmem.as_ptr().add(header_size + canvas_size) }
-                                as *mut _,
-                            shared_memory: shmem,
-                        })
+                        Ok(
+                            Self::with_backing(Backing::SharedMemory(shmem), layout, canvas_path)
+                                .init(&create_info.initial_canvas),
+                        )
                     }
                     Err(ShmemError::LinkExists) => {
-                        let canvas = Self::open(canvas_path, persistent_canvas, None)?;
-                        if canvas.width != width || canvas.height != height {
-                            error!("specified canvas dimensions ({width}x{height}) differ from shared memory canvas dimensions ({}x{})",
-                                canvas.width, canvas.height);
-                            return Err(CanvasError::InvalidSize);
-                        }
-
-                        Ok(canvas)
+                        Self::open(canvas_path, persistent_canvas, None)?.migrate(create_info)
                     }
                     Err(e) => return Err(e.into()),
                 }
             }
             None => {
-                let (width, height) = unsafe {
+                // file links of memfd and System V canvases, see ShmBackend
+                if canvas_path
+                    .symlink_metadata()
+                    .is_ok_and(|metadata| metadata.is_symlink())
+                {
+                    return Self::open_file(canvas_path, None);
+                }
+                if let Some(id) = std::fs::read_to_string(canvas_path)
+                    .ok()
+                    .and_then(|link| link.strip_prefix(SYSV_LINK_PREFIX)?.trim().parse().ok())
+                {
+                    return Self::open_sysv(canvas_path, id, persistent_canvas);
+                }
+
+                let layout = unsafe {
                     let shmem_header = shared_memory::ShmemConf::new()
                         .size(header_size)
                         .flink(canvas_path)
                         .open()?;
 
-                    if shmem_header.len() < header_size {
-                        error!("shared memory appears to have an invalid size (required: >{header_size}, actual {})", shmem_header.len());
-                        return Err(CanvasError::InvalidSize);
-                    }
-
-                    let ptr = shmem_header.as_ptr() as *const u32;
-                    (ptr.read(), ptr.add(1).read())
+                    Header::validate(shmem_header.as_ptr(), shmem_header.len())?
                 };
 
-                let canvas_size = (width * height) as usize * std::mem::size_of::<Bgra>();
-                let uid_map_size = (width * height) as usize * std::mem::size_of::<UserID>();
-                let size = header_size + canvas_size + uid_map_size;
+                let size = layout.size();
 
                 let mut shmem = shared_memory::ShmemConf::new()
                     .size(size)
@@ -254,18 +486,204 @@ impl Canvas {
 
                 shmem.set_owner(!persistent_canvas);
 
-                Ok(Canvas {
-                    width,
-                    height,
-                    len: (width * height) as usize,
-                    data: unsafe { shmem.as_ptr().add(header_size) } as *mut _,
-                    user_id_map: unsafe { shmem.as_ptr().add(header_size + canvas_size) } as *mut _,
-                    shared_memory: shmem,
-                })
+                Ok(Self::with_backing(
+                    Backing::SharedMemory(shmem),
+                    layout,
+                    canvas_path,
+                ))
             }
         }
     }
 
+    /// Opens a canvas stored in a plain memory mapped file, e.g. on platforms without
+    /// shared memory file links or to view a snapshot of a canvas.
+    ///
+    /// The layout is the same as the one of the shared memory segment. The file is created
+    /// if `create_info` is given and it does not exist yet. It is never removed.
+    #[tracing::instrument]
+    pub fn open_file(
+        path: &Path,
+        create_info: Option<CanvasCreateInfo>,
+    ) -> Result<Self, CanvasError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create_info.is_some())
+            .truncate(false)
+            .open(path)?;
+
+        if let Some(create_info) = create_info {
+            if file.metadata()?.len() == 0 {
+                let layout = create_info.layout();
+                file.set_len(layout.size() as u64)?;
+                return Ok(Self::with_backing(
+                    Backing::File(MmapRaw::map_raw(&file)?),
+                    layout,
+                    path,
+                )
+                .init(&create_info.initial_canvas));
+            }
+
+            return Self::open_file(path, None)?.migrate(create_info);
+        }
+
+        let mmap = MmapRaw::map_raw(&file)?;
+        let layout = unsafe { Header::validate(mmap.as_ptr(), mmap.len())? };
+
+        let size = layout.size();
+        if mmap.len() != size {
+            error!(
+                "canvas file appears to have an invalid size (required: {size}, actual {})",
+                mmap.len()
+            );
+            return Err(CanvasError::InvalidSize);
+        }
+
+        Ok(Self::with_backing(Backing::File(mmap), layout, path))
+    }
+
+    /// Creates a canvas shared through the given backend or opens the existing one
+    /// `canvas_path` links to
+    #[tracing::instrument]
+    pub fn create(
+        canvas_path: &Path,
+        backend: ShmBackend,
+        persistent_canvas: bool,
+        create_info: CanvasCreateInfo,
+    ) -> Result<Self, CanvasError> {
+        let layout = create_info.layout();
+        let size = layout.size();
+
+        match backend {
+            ShmBackend::Flink => {
+                return Self::open(canvas_path, persistent_canvas, Some(create_info))
+            }
+            ShmBackend::File => return Self::open_file(canvas_path, Some(create_info)),
+            ShmBackend::Memfd | ShmBackend::SysV if canvas_path.exists() => {
+                return Self::open(canvas_path, persistent_canvas, None)?.migrate(create_info)
+            }
+            ShmBackend::Memfd | ShmBackend::SysV => {}
+        }
+
+        // a dangling symlink to the memfd of a process that is gone
+        if canvas_path
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.is_symlink())
+        {
+            std::fs::remove_file(canvas_path)?;
+        }
+
+        let backing = match backend {
+            ShmBackend::Memfd => {
+                let memfd = backing::memfd(size, canvas_path)?;
+                Backing::Memfd {
+                    mmap: MmapRaw::map_raw(&memfd)?,
+                    memfd: Some(memfd),
+                }
+            }
+            _ => {
+                let mut segment = SysVSegment::create(size)?;
+                OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(canvas_path)?
+                    .write_all(format!("{SYSV_LINK_PREFIX}{}", segment.id).as_bytes())?;
+                segment.set_owner(!persistent_canvas);
+                Backing::SysV(segment)
+            }
+        };
+
+        Ok(Self::with_backing(backing, layout, canvas_path).init(&create_info.initial_canvas))
+    }
+
+    fn open_sysv(
+        canvas_path: &Path,
+        id: i32,
+        persistent_canvas: bool,
+    ) -> Result<Self, CanvasError> {
+        let segment = SysVSegment::attach(id, !persistent_canvas)?;
+        let layout = unsafe { Header::validate(segment.as_ptr(), segment.len())? };
+
+        let size = layout.size();
+        if segment.len() != size {
+            error!(
+                "shared memory appears to have an invalid size (required: {size}, actual {})",
+                segment.len()
+            );
+            return Err(CanvasError::InvalidSize);
+        }
+
+        Ok(Self::with_backing(
+            Backing::SysV(segment),
+            layout,
+            canvas_path,
+        ))
+    }
+
+    /// Opens the same canvas again with the same kind of backing, without taking ownership
+    pub fn reopen(&self) -> Result<Self, CanvasError> {
+        match self.backing.backend() {
+            ShmBackend::File => Self::open_file(&self.flink, None),
+            _ => Self::open(&self.flink, true, None),
+        }
+    }
+
+    fn with_backing(backing: Backing, layout: Layout, path: &Path) -> Self {
+        let len = (layout.width * layout.height) as usize;
+        let header_size = std::mem::size_of::<Header>();
+        let canvas_size = len * std::mem::size_of::<Bgra>();
+        let uid_map_size = len * std::mem::size_of::<UserID>();
+        let timestamp_size = match layout.flags & FLAG_TIMESTAMPS {
+            0 => 0,
+            _ => len * std::mem::size_of::<Timestamp>(),
+        };
+        let ptr = backing.as_ptr();
+
+        Canvas {
+            width: layout.width,
+            height: layout.height,
+            len,
+            flink: path.to_path_buf(),
+            header: ptr as *mut _,
+            data: unsafe { ptr.add(header_size) } as *mut _,
+            user_id_map: unsafe { ptr.add(header_size + canvas_size) } as *mut _,
+            timestamps: match timestamp_size {
+                0 => std::ptr::null_mut(),
+                _ => unsafe { ptr.add(header_size + canvas_size + uid_map_size) as *mut _ },
+            },
+            user_pixels: slice_from_raw_parts(
+                unsafe { ptr.add(header_size + canvas_size + uid_map_size + timestamp_size) }
+                    as *const _,
+                layout.user_counters as usize,
+            ),
+            backing,
+        }
+    }
+
+    fn layout(&self) -> Layout {
+        let mut flags = 0;
+        if self.has_timestamps() {
+            flags |= FLAG_TIMESTAMPS;
+        }
+        if !self.user_pixel_counters().is_empty() {
+            flags |= FLAG_USER_PIXELS;
+        }
+        Layout {
+            width: self.width,
+            height: self.height,
+            flags,
+            user_counters: self.user_pixel_counters().len() as u32,
+        }
+    }
+
+    /// Writes the header and the initial pixels of a newly created canvas
+    fn init(mut self, initial_canvas: &[Bgra]) -> Self {
+        unsafe { self.header.write(Header::new(self.layout())) };
+        self.pixel_slice_mut().copy_from_slice(initial_canvas);
+        self.recount_user_pixels();
+        self
+    }
+
     #[inline]
     fn coords_to_index(&self, x: u32, y: u32) -> usize {
         (y * self.width + x) as usize
@@ -273,7 +691,7 @@ impl Canvas {
 
     #[inline]
     pub fn pixel(&self, x: u32, y: u32) -> Result<Bgra, CanvasError> {
-        if y > self.height || x > self.width {
+        if x >= self.width || y >= self.height {
             return Err(CanvasError::PixelOutOfBounds { x, y });
         }
         let idx = self.coords_to_index(x, y);
@@ -282,7 +700,7 @@ impl Canvas {
 
     #[inline]
     pub fn user(&self, x: u32, y: u32) -> Result<UserID, CanvasError> {
-        if y > self.height || x > self.width {
+        if x >= self.width || y >= self.height {
             return Err(CanvasError::PixelOutOfBounds { x, y });
         }
         let idx = self.coords_to_index(x, y);
@@ -300,7 +718,7 @@ impl Canvas {
             0 => Ok(()),
             255 => {
                 unsafe { self.data.add(idx).write(color) };
-                unsafe { self.user_id_map.add(idx).write(user_id) };
+                self.set_owner(idx, user_id);
                 Ok(())
             }
             alpha => {
@@ -314,7 +732,7 @@ impl Canvas {
                 g += (((color2 & 0x00ff00).saturating_sub(g)) * alpha) >> 8;
                 let new_color = Bgra::from_rgb((rb & 0xff00ff) | (g & 0xff00));
                 unsafe { self.data.add(idx).write(new_color) };
-                unsafe { self.user_id_map.add(idx).write(user_id) };
+                self.set_owner(idx, user_id);
                 Ok(())
             }
         }
@@ -325,6 +743,11 @@ impl Canvas {
         unsafe { &*slice_from_raw_parts(self.data, self.len) }
     }
 
+    #[inline]
+    pub fn pixel_slice_mut(&mut self) -> &mut [Bgra] {
+        unsafe { &mut *slice_from_raw_parts_mut(self.data, self.len) }
+    }
+
     #[inline]
     pub fn pixel_byte_slice(&self) -> &[u8] {
         unsafe {
@@ -335,11 +758,83 @@ impl Canvas {
         }
     }
 
+    /// Copy of the canvas as r, g, b, a bytes
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(self.len * 4);
+        convert::extend_rgba8(&mut rgba, self.pixel_slice());
+        rgba
+    }
+
+    /// Copy of the canvas as r, g, b bytes
+    pub fn to_rgb8(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.len * 3);
+        convert::extend_rgb8(&mut rgb, self.pixel_slice());
+        rgb
+    }
+
+    /// Copy of a region of the canvas as r, g, b, a bytes, row by row
+    pub fn region_to_rgba8(
+        &self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> Result<Vec<u8>, CanvasError> {
+        let mut rgba = Vec::with_capacity(width as usize * height as usize * 4);
+        for row in self.region_rows(x, y, width, height)? {
+            convert::extend_rgba8(&mut rgba, row);
+        }
+        Ok(rgba)
+    }
+
+    /// Copy of a region of the canvas as r, g, b bytes, row by row
+    pub fn region_to_rgb8(
+        &self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> Result<Vec<u8>, CanvasError> {
+        let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+        for row in self.region_rows(x, y, width, height)? {
+            convert::extend_rgb8(&mut rgb, row);
+        }
+        Ok(rgb)
+    }
+
+    fn region_rows(
+        &self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> Result<impl Iterator<Item = &[Bgra]>, CanvasError> {
+        let in_bounds =
+            |start: u32, len: u32, max: u32| start.checked_add(len).is_some_and(|end| end <= max);
+        if !in_bounds(x, width, self.width) || !in_bounds(y, height, self.height) {
+            return Err(CanvasError::PixelOutOfBounds {
+                x: x.saturating_add(width.saturating_sub(1)),
+                y: y.saturating_add(height.saturating_sub(1)),
+            });
+        }
+
+        let pixels = self.pixel_slice();
+        Ok((y..y + height).map(move |row| {
+            let start = self.coords_to_index(x, row);
+            &pixels[start..start + width as usize]
+        }))
+    }
+
     #[inline]
     pub fn user_id_slice(&self) -> &[UserID] {
         unsafe { &*slice_from_raw_parts(self.user_id_map, self.len) }
     }
 
+    #[inline]
+    pub fn user_id_slice_mut(&mut self) -> &mut [UserID] {
+        unsafe { &mut *slice_from_raw_parts_mut(self.user_id_map, self.len) }
+    }
+
     #[inline]
     pub fn user_id_byte_slice(&self) -> &[u8] {
         unsafe {
@@ -350,6 +845,140 @@ impl Canvas {
         }
     }
 
+    /// Whether the canvas keeps the time every pixel was set at
+    #[inline]
+    pub fn has_timestamps(&self) -> bool {
+        !self.timestamps.is_null()
+    }
+
+    /// Unix time in milliseconds the pixel was set at, 0 if it is untouched.
+    /// None if the canvas has no timestamps
+    #[inline]
+    pub fn timestamp(&self, x: u32, y: u32) -> Result<Option<Timestamp>, CanvasError> {
+        if x >= self.width || y >= self.height {
+            return Err(CanvasError::PixelOutOfBounds { x, y });
+        }
+        let idx = self.coords_to_index(x, y);
+        Ok(self
+            .has_timestamps()
+            .then(|| unsafe { std::ptr::read(self.timestamps.add(idx)) }))
+    }
+
+    #[inline]
+    pub fn timestamp_slice(&self) -> Option<&[Timestamp]> {
+        self.has_timestamps()
+            .then(|| unsafe { &*slice_from_raw_parts(self.timestamps, self.len) })
+    }
+
+    #[inline]
+    pub fn timestamp_slice_mut(&mut self) -> Option<&mut [Timestamp]> {
+        self.has_timestamps()
+            .then(|| unsafe { &mut *slice_from_raw_parts_mut(self.timestamps, self.len) })
+    }
+
+    #[inline]
+    pub fn timestamp_byte_slice(&self) -> Option<&[u8]> {
+        self.has_timestamps().then(|| unsafe {
+            &*slice_from_raw_parts(
+                self.timestamps as *const _,
+                self.len * std::mem::size_of::<Timestamp>(),
+            )
+        })
+    }
+
+    /// Pixels on the canvas `user_id` set last, 0 are the untouched ones.
+    /// None if the canvas does not count the pixels of the user
+    #[inline]
+    pub fn user_pixels(&self, user_id: UserID) -> Option<u64> {
+        self.user_pixel_counters()
+            .get(user_id as usize)
+            .map(|pixels| pixels.load(Ordering::Relaxed))
+    }
+
+    /// Pixel counters of all user ids the canvas counts, indexed by user id
+    #[inline]
+    pub fn user_pixel_counters(&self) -> &[AtomicU64] {
+        unsafe { &*self.user_pixels }
+    }
+
+    /// Counts the pixels of the users again, e.g. after writing the user id plane directly
+    pub fn recount_user_pixels(&self) {
+        let counters = self.user_pixel_counters();
+        if counters.is_empty() {
+            return;
+        }
+
+        let mut pixels = vec![0u64; counters.len()];
+        for &user_id in self.user_id_slice() {
+            if let Some(pixels) = pixels.get_mut(user_id as usize) {
+                *pixels += 1;
+            }
+        }
+        for (counter, pixels) in counters.iter().zip(pixels) {
+            counter.store(pixels, Ordering::Relaxed);
+        }
+    }
+
+    /// Records who set a pixel and when
+    #[inline]
+    fn set_owner(&self, idx: usize, user_id: UserID) {
+        let counters = self.user_pixel_counters();
+        if counters.is_empty() {
+            unsafe { self.user_id_map.add(idx).write(user_id) };
+        } else {
+            // swapped to count exactly, even if several workers set the same pixel
+            let owner = unsafe { &*(self.user_id_map.add(idx) as *const AtomicU32) };
+            let previous = owner.swap(user_id, Ordering::Relaxed);
+            if previous != user_id {
+                if let Some(pixels) = counters.get(previous as usize) {
+                    pixels.fetch_sub(1, Ordering::Relaxed);
+                }
+                if let Some(pixels) = counters.get(user_id as usize) {
+                    pixels.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        if self.has_timestamps() {
+            unsafe { self.timestamps.add(idx).write(now()) };
+        }
+    }
+
+    /// Writes a file backed canvas to disk, e.g. before the server exits.
+    /// Canvases in shared memory outlive the server on their own
+    pub fn flush(&self) -> Result<(), CanvasError> {
+        Ok(self.backing.flush()?)
+    }
+
+    /// Maps all pages of the pixel, user id and timestamp planes into this process up front,
+    /// so that the first writes to them don't stall on page faults
+    pub fn prefault(&self) {
+        // smallest page size in use, touching huge pages more often than necessary is cheap
+        const PAGE_SIZE: usize = 4096;
+
+        for (ptr, len) in [
+            (
+                self.data as *const u8,
+                self.len * std::mem::size_of::<Bgra>(),
+            ),
+            (
+                self.user_id_map as *const u8,
+                self.len * std::mem::size_of::<UserID>(),
+            ),
+        ]
+        .into_iter()
+        .chain(
+            self.timestamp_byte_slice()
+                .map(|plane| (plane.as_ptr(), plane.len())),
+        ) {
+            for offset in (0..len).step_by(PAGE_SIZE) {
+                // a write fault maps the page writable, adding 0 keeps concurrent writes intact
+                let byte = unsafe { &*(ptr.add(offset) as *const AtomicU8) };
+                byte.fetch_add(0, Ordering::Relaxed);
+            }
+        }
+    }
+
     #[inline]
     pub fn width(&self) -> u32 {
         self.width
@@ -359,6 +988,195 @@ impl Canvas {
     pub fn height(&self) -> u32 {
         self.height
     }
+
+    /// Cheap to poll, a changed value means cached dimensions or textures are stale
+    /// and the canvas should be reopened
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        unsafe { (*self.header).generation.load(Ordering::Acquire) }
+    }
+
+    #[inline]
+    pub fn bump_generation(&self) -> u64 {
+        unsafe { (*self.header).generation.fetch_add(1, Ordering::AcqRel) + 1 }
+    }
+
+    /// Lets viewers warn about an upcoming clear at `at` (unix time in seconds), 0 withdraws it
+    #[inline]
+    pub fn announce_clear(&self, at: u64) {
+        unsafe { (*self.header).clear_at.store(at, Ordering::Relaxed) }
+    }
+
+    /// Unix time in seconds the canvas is going to be cleared at
+    #[inline]
+    pub fn announced_clear(&self) -> Option<u64> {
+        match unsafe { (*self.header).clear_at.load(Ordering::Relaxed) } {
+            0 => None,
+            at => Some(at),
+        }
+    }
+
+    /// Fills the canvas with `color` and forgets who set the pixels,
+    /// the pixel counters of all users drop to 0
+    pub fn clear(&mut self, color: Bgra) {
+        self.pixel_slice_mut().fill(color);
+        self.clear_user_ids();
+    }
+
+    /// Forgets who set the pixels and when, e.g. to start counting from scratch.
+    /// Bumps the generation, so do [Canvas::clear] and [Canvas::reset]
+    pub fn clear_user_ids(&mut self) {
+        self.user_id_slice_mut().fill(0);
+        if let Some(timestamps) = self.timestamp_slice_mut() {
+            timestamps.fill(0);
+        }
+        self.recount_user_pixels();
+        self.bump_generation();
+    }
+
+    /// Overwrites all pixels and forgets who set them
+    pub fn reset(&mut self, pixels: &[Bgra]) {
+        self.pixel_slice_mut().copy_from_slice(pixels);
+        self.clear_user_ids();
+    }
+
+    /// Overwrites all pixels last set by a user with the corresponding `background` pixels
+    /// and forgets who set them and when. Returns the number of erased pixels
+    pub fn erase_user(&mut self, user_id: UserID, background: &[Bgra]) -> usize {
+        let pixels = unsafe { &mut *slice_from_raw_parts_mut(self.data, self.len) };
+        let user_ids = unsafe { &mut *slice_from_raw_parts_mut(self.user_id_map, self.len) };
+
+        let mut erased = 0;
+        for (idx, ((pixel, owner), background)) in
+            pixels.iter_mut().zip(user_ids).zip(background).enumerate()
+        {
+            if *owner == user_id {
+                *pixel = *background;
+                *owner = 0;
+                if self.has_timestamps() {
+                    unsafe { self.timestamps.add(idx).write(0) };
+                }
+                erased += 1;
+            }
+        }
+        self.recount_user_pixels();
+        self.bump_generation();
+        erased
+    }
+
+    /// Moves the canvas into a new shared memory segment (or file) of the given size,
+    /// keeping the overlapping region of all planes.
+    ///
+    /// The file link is atomically replaced to point to the new segment. The old segment
+    /// is removed once all mappings are gone, its generation is bumped to let them know.
+    pub fn resize(&mut self, width: u32, height: u32) -> Result<(), CanvasError> {
+        self.resize_onto(CanvasCreateInfo {
+            width,
+            height,
+            initial_canvas: vec![Bgra::default(); (width * height) as usize].into_boxed_slice(),
+            timestamps: self.has_timestamps(),
+            user_counters: self.user_pixel_counters().len() as u32,
+        })
+    }
+
+    /// Resizes an existing canvas to the dimensions it was supposed to be created with,
+    /// e.g. after a restart with a different --width or --height
+    fn migrate(mut self, create_info: CanvasCreateInfo) -> Result<Self, CanvasError> {
+        let layout = create_info.layout();
+        if self.layout() == layout {
+            return Ok(self);
+        }
+
+        warn!(
+            "migrating the {}x{} canvas at {} to {}x{} (flags {:#x}, {} user counters), the old content is kept in the top-left corner",
+            self.width,
+            self.height,
+            self.flink.display(),
+            layout.width,
+            layout.height,
+            layout.flags,
+            layout.user_counters
+        );
+        self.resize_onto(create_info)?;
+        Ok(self)
+    }
+
+    /// Like [Canvas::resize], pixels outside of the overlapping region are the initial ones
+    fn resize_onto(&mut self, create_info: CanvasCreateInfo) -> Result<(), CanvasError> {
+        let (width, height) = (create_info.width, create_info.height);
+        let mut tmp_path = self.flink.clone().into_os_string();
+        tmp_path.push(".resize");
+        let tmp_path = PathBuf::from(tmp_path);
+        // leftover of an interrupted resize
+        let _ = std::fs::remove_file(&tmp_path);
+
+        let mut resized = Canvas::create(
+            &tmp_path,
+            self.backing.backend(),
+            !self.backing.is_owner(),
+            create_info,
+        )?;
+
+        let overlap_width = self.width.min(width) as usize;
+        for y in 0..self.height.min(height) as usize {
+            let src = y * self.width as usize;
+            let dst = y * width as usize;
+            resized.pixel_slice_mut()[dst..dst + overlap_width]
+                .copy_from_slice(&self.pixel_slice()[src..src + overlap_width]);
+            resized.user_id_slice_mut()[dst..dst + overlap_width]
+                .copy_from_slice(&self.user_id_slice()[src..src + overlap_width]);
+            if let (Some(resized), Some(timestamps)) =
+                (resized.timestamp_slice_mut(), self.timestamp_slice())
+            {
+                resized[dst..dst + overlap_width]
+                    .copy_from_slice(&timestamps[src..src + overlap_width]);
+            }
+        }
+        resized.recount_user_pixels();
+        unsafe {
+            (*resized.header)
+                .generation
+                .store(self.generation() + 1, Ordering::Release)
+        };
+
+        if let Err(e) = std::fs::rename(&tmp_path, &self.flink) {
+            // shared memory removes its link along with the segment, files stay
+            drop(resized);
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e.into());
+        }
+        resized.flink = self.flink.clone();
+
+        // the link now belongs to the new segment
+        let old_segment = self.backing.release();
+        self.bump_generation();
+        *self = resized;
+        drop(old_segment);
+
+        Ok(())
+    }
+}
+
+/// Coarse wall clock, cheap enough to be read for every pixel set
+#[inline]
+fn now() -> Timestamp {
+    let mut time = std::mem::MaybeUninit::<libc::timespec>::uninit();
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_REALTIME_COARSE, time.as_mut_ptr());
+        let time = time.assume_init();
+        time.tv_sec as Timestamp * 1000 + time.tv_nsec as Timestamp / 1_000_000
+    }
+}
+
+impl Drop for Canvas {
+    fn drop(&mut self) {
+        // the segment is removed along with its owner, let other mappings know
+        if self.backing.is_owner() {
+            self.bump_generation();
+            // the segment might have been created under a different link by a resize
+            let _ = std::fs::remove_file(&self.flink);
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -367,6 +1185,401 @@ pub enum CanvasError {
     PixelOutOfBounds { x: u32, y: u32 },
     #[error("invalid shared memory size for specified canvas")]
     InvalidSize,
+    #[error("shared memory does not contain a canvas")]
+    InvalidMagic,
+    #[error("unsupported canvas layout version {0}, expected {CANVAS_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("unsupported canvas pixel format {0}")]
+    UnsupportedPixelFormat(u32),
+    #[error("unsupported canvas flags {0:#x}")]
+    UnsupportedFlags(u32),
     #[error("mapping error: {0}")]
     Mapping(#[from] ShmemError),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Canvas in a memory mapped file, removed again once dropped
+    struct TestCanvas {
+        canvas: Option<Canvas>,
+        path: PathBuf,
+    }
+
+    impl TestCanvas {
+        fn new(name: &str, create_info: CanvasCreateInfo) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "wellenbrecher-canvas-test-{}-{name}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            let canvas = Canvas::create(&path, ShmBackend::File, false, create_info)
+                .expect("unable to create the canvas");
+            Self {
+                canvas: Some(canvas),
+                path,
+            }
+        }
+    }
+
+    impl std::ops::Deref for TestCanvas {
+        type Target = Canvas;
+
+        fn deref(&self) -> &Canvas {
+            self.canvas.as_ref().unwrap()
+        }
+    }
+
+    impl std::ops::DerefMut for TestCanvas {
+        fn deref_mut(&mut self) -> &mut Canvas {
+            self.canvas.as_mut().unwrap()
+        }
+    }
+
+    impl Drop for TestCanvas {
+        fn drop(&mut self) {
+            self.canvas.take();
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn create_info(width: u32, height: u32) -> CanvasCreateInfo {
+        CanvasCreateInfo {
+            width,
+            height,
+            initial_canvas: vec![Bgra::default(); (width * height) as usize].into_boxed_slice(),
+            timestamps: true,
+            user_counters: 8,
+        }
+    }
+
+    #[test]
+    fn mutators_bump_generation() {
+        let mut canvas = TestCanvas::new("generation", create_info(4, 3));
+        let background = vec![Bgra::from_rgb(0x123456); 12];
+
+        let mut generation = canvas.generation();
+        let mut assert_bumped = |canvas: &Canvas, what: &str| {
+            assert!(
+                canvas.generation() > generation,
+                "{what} did not bump the generation"
+            );
+            generation = canvas.generation();
+        };
+
+        canvas.clear(Bgra::from_rgb(0xff0000));
+        assert_bumped(&canvas, "clear");
+        canvas.clear_user_ids();
+        assert_bumped(&canvas, "clear_user_ids");
+        canvas.reset(&background);
+        assert_bumped(&canvas, "reset");
+        canvas.set_pixel(1, 1, Bgra::from_rgb(0x00ff00), 3).unwrap();
+        canvas.erase_user(3, &background);
+        assert_bumped(&canvas, "erase_user");
+    }
+
+    /// Gives every pixel a distinct color and user id
+    fn paint(canvas: &Canvas) {
+        for y in 0..canvas.height() {
+            for x in 0..canvas.width() {
+                canvas
+                    .set_pixel(x, y, Bgra::from_rgb(y << 8 | x), 1 + (x + y) % 4)
+                    .unwrap();
+            }
+        }
+    }
+
+    /// Checks the overlap with a painted canvas and the initial pixels everywhere else
+    fn assert_painted(canvas: &Canvas, width: u32, height: u32) {
+        for y in 0..canvas.height() {
+            for x in 0..canvas.width() {
+                let (color, user) = match x < width && y < height {
+                    true => (Bgra::from_rgb(y << 8 | x), 1 + (x + y) % 4),
+                    false => (Bgra::default(), 0),
+                };
+                assert_eq!(canvas.pixel(x, y).unwrap(), color, "pixel ({x}, {y})");
+                assert_eq!(canvas.user(x, y).unwrap(), user, "user of ({x}, {y})");
+                assert_eq!(
+                    canvas.timestamp(x, y).unwrap().unwrap() != 0,
+                    user != 0,
+                    "timestamp of ({x}, {y})"
+                );
+            }
+        }
+        let painted = |user| {
+            (0..width.min(canvas.width()))
+                .flat_map(|x| (0..height.min(canvas.height())).map(move |y| 1 + (x + y) % 4))
+                .filter(|&owner| owner == user)
+                .count() as u64
+        };
+        for user in 1..=4 {
+            assert_eq!(canvas.user_pixels(user), Some(painted(user)), "user {user}");
+        }
+    }
+
+    #[test]
+    fn resize_keeps_overlap() {
+        let mut canvas = TestCanvas::new("resize", create_info(4, 3));
+        paint(&canvas);
+
+        canvas.resize(6, 5).unwrap();
+        assert_eq!((canvas.width(), canvas.height()), (6, 5));
+        assert_painted(&canvas, 4, 3);
+
+        canvas.resize(2, 2).unwrap();
+        assert_eq!((canvas.width(), canvas.height()), (2, 2));
+        assert_painted(&canvas, 4, 3);
+
+        // mixed, wider but lower
+        canvas.resize(5, 1).unwrap();
+        assert_painted(&canvas, 2, 2);
+    }
+
+    #[test]
+    fn resize_bumps_generation() {
+        let mut canvas = TestCanvas::new("resize-generation", create_info(4, 3));
+        let old_segment = canvas.reopen().unwrap();
+        let generation = canvas.generation();
+
+        canvas.resize(6, 5).unwrap();
+        assert!(old_segment.generation() > generation);
+        assert!(canvas.generation() > generation);
+        // reopening follows the link to the new segment
+        let new_segment = canvas.reopen().unwrap();
+        assert_eq!((new_segment.width(), new_segment.height()), (6, 5));
+        assert_eq!(new_segment.generation(), canvas.generation());
+    }
+
+    #[test]
+    fn migrate_on_create() {
+        let canvas = TestCanvas::new("migrate", create_info(4, 3));
+        paint(&canvas);
+        let generation = canvas.generation();
+
+        for (width, height) in [(6, 5), (3, 2)] {
+            let migrated = Canvas::create(
+                &canvas.path,
+                ShmBackend::File,
+                false,
+                create_info(width, height),
+            )
+            .unwrap();
+            assert_eq!((migrated.width(), migrated.height()), (width, height));
+            assert_painted(&migrated, 4, 3);
+        }
+        assert!(canvas.generation() > generation);
+
+        // the same layout is opened as is
+        let reopened =
+            Canvas::create(&canvas.path, ShmBackend::File, false, create_info(3, 2)).unwrap();
+        assert_painted(&reopened, 4, 3);
+    }
+
+    #[test]
+    fn failed_resize_removes_resize_link() {
+        let mut canvas = TestCanvas::new("resize-failure", create_info(4, 3));
+        let mut resize_link = canvas.path.clone().into_os_string();
+        resize_link.push(".resize");
+        let resize_link = PathBuf::from(resize_link);
+
+        // the new segment can't be moved onto a directory
+        std::fs::remove_file(&canvas.path).unwrap();
+        std::fs::create_dir(&canvas.path).unwrap();
+        std::fs::write(canvas.path.join("occupied"), []).unwrap();
+
+        assert!(canvas.resize(6, 5).is_err());
+        assert!(!resize_link.exists());
+        // the old segment is still in use
+        assert_eq!((canvas.width(), canvas.height()), (4, 3));
+        canvas.set_pixel(3, 2, Bgra::from_rgb(0xffffff), 1).unwrap();
+
+        std::fs::remove_dir_all(&canvas.path).unwrap();
+    }
+
+    /// Opens the canvas file after `corrupt` changed its bytes
+    fn open_corrupted(name: &str, corrupt: impl FnOnce(&mut Vec<u8>)) -> CanvasError {
+        let mut canvas = TestCanvas::new(name, create_info(4, 3));
+        canvas.canvas.take();
+
+        let mut bytes = std::fs::read(&canvas.path).unwrap();
+        corrupt(&mut bytes);
+        std::fs::write(&canvas.path, bytes).unwrap();
+
+        match Canvas::open_file(&canvas.path, None) {
+            Ok(_) => panic!("corrupted canvas {name} was opened"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn header_rejects_foreign_segments() {
+        let magic = 0..8;
+        let version = 8..12;
+        let pixel_format = 12..16;
+        let flags = 16..20;
+
+        assert!(matches!(
+            open_corrupted("magic", |bytes| bytes[magic].copy_from_slice(b"WBCANVAZ")),
+            CanvasError::InvalidMagic
+        ));
+        assert!(matches!(
+            open_corrupted("version", |bytes| {
+                bytes[version].copy_from_slice(&(CANVAS_VERSION + 1).to_ne_bytes())
+            }),
+            CanvasError::UnsupportedVersion(v) if v == CANVAS_VERSION + 1
+        ));
+        assert!(matches!(
+            open_corrupted("pixel-format", |bytes| {
+                bytes[pixel_format].copy_from_slice(&2u32.to_ne_bytes())
+            }),
+            CanvasError::UnsupportedPixelFormat(2)
+        ));
+        assert!(matches!(
+            open_corrupted("flags", |bytes| {
+                bytes[flags].copy_from_slice(&(KNOWN_FLAGS | 1 << 7).to_ne_bytes())
+            }),
+            CanvasError::UnsupportedFlags(0x80)
+        ));
+    }
+
+    #[test]
+    fn header_rejects_short_segments() {
+        let header_size = std::mem::size_of::<Header>();
+        for (name, len) in [
+            ("empty", Some(0)),
+            ("short-header", Some(header_size - 1)),
+            ("header-only", Some(header_size)),
+            // the header announces more than there is
+            ("short-planes", None),
+        ] {
+            let e = open_corrupted(name, |bytes| match len {
+                Some(len) => bytes.truncate(len),
+                None => drop(bytes.pop()),
+            });
+            assert!(matches!(e, CanvasError::InvalidSize), "{name}: {e}");
+        }
+    }
+
+    #[test]
+    fn plane_layout() {
+        let offset =
+            |canvas: &Canvas, ptr: *const u8| ptr as usize - canvas.backing.as_ptr() as usize;
+        let (width, height, user_counters) = (5usize, 3usize, 8usize);
+        let len = width * height;
+        assert_eq!(std::mem::size_of::<Header>(), 48);
+
+        for timestamps in [false, true] {
+            let canvas = TestCanvas::new(
+                &format!("layout-{timestamps}"),
+                CanvasCreateInfo {
+                    timestamps,
+                    ..create_info(width as u32, height as u32)
+                },
+            );
+            let timestamp_plane = match timestamps {
+                true => len * 8,
+                false => 0,
+            };
+
+            assert_eq!(offset(&canvas, canvas.data as *const u8), 48);
+            assert_eq!(
+                offset(&canvas, canvas.user_id_map as *const u8),
+                48 + len * 4
+            );
+            match timestamps {
+                true => assert_eq!(
+                    offset(&canvas, canvas.timestamps as *const u8),
+                    48 + len * 8
+                ),
+                false => assert!(canvas.timestamps.is_null()),
+            }
+            assert_eq!(
+                offset(&canvas, canvas.user_pixels as *const u8),
+                48 + len * 8 + timestamp_plane
+            );
+            assert_eq!(canvas.user_pixel_counters().len(), user_counters);
+
+            let size = 48 + len * 8 + timestamp_plane + user_counters * 8;
+            assert_eq!(canvas.layout().size(), size);
+            assert_eq!(std::fs::metadata(&canvas.path).unwrap().len(), size as u64);
+
+            // viewers find a pixel at the documented offsets
+            canvas.set_pixel(4, 2, Bgra::from_rgb(0x123456), 7).unwrap();
+            canvas.flush().unwrap();
+            let bytes = std::fs::read(&canvas.path).unwrap();
+            let idx = 2 * width + 4;
+            assert_eq!(bytes[48 + idx * 4..][..4], [0x56, 0x34, 0x12, 0xff]);
+            assert_eq!(bytes[48 + len * 4 + idx * 4..][..4], 7u32.to_ne_bytes());
+            if timestamps {
+                assert_ne!(bytes[48 + len * 8 + idx * 8..][..8], 0u64.to_ne_bytes());
+            }
+            let counter = 48 + len * 8 + timestamp_plane + 7 * 8;
+            assert_eq!(bytes[counter..][..8], 1u64.to_ne_bytes());
+        }
+    }
+
+    #[test]
+    fn user_pixel_counters() {
+        let mut canvas = TestCanvas::new("counters", create_info(4, 3));
+        let counts = |canvas: &Canvas| {
+            (0..8)
+                .map(|user| canvas.user_pixels(user).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let red = Bgra::from_rgb(0xff0000);
+        assert_eq!(counts(&canvas), [12, 0, 0, 0, 0, 0, 0, 0]);
+
+        for x in 0..4 {
+            canvas.set_pixel(x, 0, red, 1).unwrap();
+        }
+        canvas.set_pixel(0, 1, red, 2).unwrap();
+        assert_eq!(counts(&canvas), [7, 4, 1, 0, 0, 0, 0, 0]);
+
+        // overwrites move the pixel from one user to the other
+        canvas.set_pixel(1, 0, red, 2).unwrap();
+        canvas
+            .set_pixel(2, 0, Bgra::from_rgba(0xff000080), 3)
+            .unwrap();
+        assert_eq!(counts(&canvas), [7, 2, 2, 1, 0, 0, 0, 0]);
+        // setting an owned pixel again changes nothing, transparent pixels aren't set
+        canvas.set_pixel(1, 0, red, 2).unwrap();
+        canvas
+            .set_pixel(3, 0, Bgra::from_rgba(0xff000000), 4)
+            .unwrap();
+        assert_eq!(counts(&canvas), [7, 2, 2, 1, 0, 0, 0, 0]);
+        // users beyond the counters are only missing from the counts
+        canvas.set_pixel(3, 2, red, 100).unwrap();
+        assert_eq!(counts(&canvas), [6, 2, 2, 1, 0, 0, 0, 0]);
+        assert_eq!(canvas.user_pixels(100), None);
+
+        let background = vec![Bgra::default(); 12];
+        assert_eq!(canvas.erase_user(2, &background), 2);
+        assert_eq!(counts(&canvas), [8, 2, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(canvas.erase_user(5, &background), 0);
+        assert_eq!(counts(&canvas), [8, 2, 0, 1, 0, 0, 0, 0]);
+
+        canvas.clear(red);
+        assert_eq!(counts(&canvas), [12, 0, 0, 0, 0, 0, 0, 0]);
+
+        canvas.set_pixel(0, 0, red, 1).unwrap();
+        canvas.reset(&background);
+        assert_eq!(counts(&canvas), [12, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let canvas = TestCanvas::new("bounds", create_info(4, 3));
+
+        for (x, y) in [(4, 0), (0, 3), (4, 3), (u32::MAX, 0)] {
+            assert!(canvas.pixel(x, y).is_err(), "pixel({x}, {y})");
+            assert!(canvas.user(x, y).is_err(), "user({x}, {y})");
+            assert!(canvas.timestamp(x, y).is_err(), "timestamp({x}, {y})");
+            assert!(canvas.set_pixel(x, y, Bgra::from_rgb(0xff0000), 1).is_err());
+        }
+        assert!(canvas.pixel(3, 2).is_ok());
+        assert!(canvas.user(3, 2).is_ok());
+    }
 }
@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::path::Path;
+use std::ptr::slice_from_raw_parts;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use shared_memory::{Shmem, ShmemError};
+use thiserror::Error;
+use tracing::error;
+
+/// Teams group users, 0 is no team
+pub type TeamID = u32;
+
+/*
+Layout of the stats segment, native endian and 8 byte aligned:
+
+    Header                      32 bytes, see below
+    Counters    [workers]       bytes and pixels of every worker, 2 x u64 each
+    Counters    [users]         bytes and pixels of every user id, starting at 1
+    u32         [users]         team of every user id, 0 is no team
+    u32         [users]         open connections of every user id
+
+Readers check the magic and the version before trusting the rest, the version is bumped whenever
+the layout changes. The traffic counters only ever increase while the server is running.
+ */
+
+const MAGIC: [u8; 8] = *b"WBSTATS\0";
+/// Version of the stats segment layout
+pub const STATS_VERSION: u32 = 1;
+
+/// Start of the stats shared memory segment
+#[repr(C)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    workers: u32,
+    users: u32,
+    _padding: u32,
+    /// Bumped once the segment is removed, e.g. when the server exits
+    generation: AtomicU64,
+}
+
+/// Traffic a worker or user produced, counted after reading from the socket
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct Counters {
+    pub bytes: AtomicU64,
+    pub pixels: AtomicU64,
+}
+
+impl Counters {
+    #[inline]
+    pub fn add(&self, bytes: u64, pixels: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.pixels.fetch_add(pixels, Ordering::Relaxed);
+    }
+
+    /// (bytes, pixels)
+    #[inline]
+    pub fn load(&self) -> (u64, u64) {
+        (
+            self.bytes.load(Ordering::Relaxed),
+            self.pixels.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Per worker and per user traffic counters shared between the server and its viewers
+pub struct Stats {
+    shared_memory: Shmem,
+    header: *const Header,
+    workers: *const Counters,
+    users: *const Counters,
+    teams: *const AtomicU32,
+    connections: *const AtomicU32,
+}
+
+// only atomics are accessed through the mapping
+unsafe impl Send for Stats {}
+unsafe impl Sync for Stats {}
+
+impl Debug for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Stats {{ workers: {}, users: {}, shared_memory: {} }}",
+            self.workers().len(),
+            self.users().len(),
+            self.shared_memory.get_os_id()
+        )
+    }
+}
+
+impl Stats {
+    fn size(workers: u32, users: u32) -> usize {
+        std::mem::size_of::<Header>()
+            + (workers + users) as usize * std::mem::size_of::<Counters>()
+            + 2 * users as usize * std::mem::size_of::<AtomicU32>()
+    }
+
+    fn from_shmem(shared_memory: Shmem, workers: u32, users: u32) -> Self {
+        let header = shared_memory.as_ptr() as *const Header;
+        let workers_ptr =
+            unsafe { shared_memory.as_ptr().add(std::mem::size_of::<Header>()) } as *const Counters;
+        let users_ptr = unsafe { workers_ptr.add(workers as usize) };
+        let teams_ptr = unsafe { users_ptr.add(users as usize) } as *const AtomicU32;
+        Self {
+            header,
+            workers: workers_ptr,
+            users: users_ptr,
+            teams: teams_ptr,
+            connections: unsafe { teams_ptr.add(users as usize) },
+            shared_memory,
+        }
+    }
+
+    /// Creates a zeroed stats segment, replacing a leftover one of a previous run
+    #[tracing::instrument]
+    pub fn create(stats_path: &Path, workers: u32, users: u32) -> Result<Self, StatsError> {
+        let conf = || {
+            shared_memory::ShmemConf::new()
+                .size(Self::size(workers, users))
+                .flink(stats_path)
+        };
+
+        let shmem = match conf().create() {
+            Err(ShmemError::LinkExists) => {
+                if let Ok(mut stale) = shared_memory::ShmemConf::new().flink(stats_path).open() {
+                    stale.set_owner(true);
+                }
+                let _ = std::fs::remove_file(stats_path);
+                conf().create()?
+            }
+            shmem => shmem?,
+        };
+
+        unsafe {
+            let ptr = shmem.as_ptr();
+            std::ptr::write_bytes(ptr, 0, Self::size(workers, users));
+            (ptr as *mut Header).write(Header {
+                magic: MAGIC,
+                version: STATS_VERSION,
+                workers,
+                users,
+                _padding: 0,
+                generation: AtomicU64::new(0),
+            });
+        }
+
+        Ok(Self::from_shmem(shmem, workers, users))
+    }
+
+    pub fn open(stats_path: &Path) -> Result<Self, StatsError> {
+        let header_size = std::mem::size_of::<Header>();
+        let (workers, users) = unsafe {
+            let shmem_header = shared_memory::ShmemConf::new()
+                .size(header_size)
+                .flink(stats_path)
+                .open()?;
+
+            if shmem_header.len() < header_size {
+                error!("shared memory appears to have an invalid size (required: >{header_size}, actual {})", shmem_header.len());
+                return Err(StatsError::InvalidSize);
+            }
+
+            let header = &*(shmem_header.as_ptr() as *const Header);
+            if header.magic != MAGIC {
+                error!("shared memory does not contain stats");
+                return Err(StatsError::InvalidMagic);
+            }
+            if header.version != STATS_VERSION {
+                error!(
+                    "stats have layout version {}, supported is {STATS_VERSION}",
+                    header.version
+                );
+                return Err(StatsError::UnsupportedVersion(header.version));
+            }
+            (header.workers, header.users)
+        };
+
+        let size = Self::size(workers, users);
+        let mut shmem = shared_memory::ShmemConf::new()
+            .size(size)
+            .flink(stats_path)
+            .open()?;
+
+        if shmem.len() != size {
+            error!(
+                "shared memory appears to have an invalid size (required: {size}, actual {})",
+                shmem.len()
+            );
+            return Err(StatsError::InvalidSize);
+        }
+        shmem.set_owner(false);
+
+        Ok(Self::from_shmem(shmem, workers, users))
+    }
+
+    #[inline]
+    pub fn workers(&self) -> &[Counters] {
+        unsafe { &*slice_from_raw_parts(self.workers, (*self.header).workers as usize) }
+    }
+
+    #[inline]
+    pub fn users(&self) -> &[Counters] {
+        unsafe { &*slice_from_raw_parts(self.users, (*self.header).users as usize) }
+    }
+
+    /// The team of every user, indexed like [Stats::users]
+    #[inline]
+    pub fn teams(&self) -> &[AtomicU32] {
+        unsafe { &*slice_from_raw_parts(self.teams, (*self.header).users as usize) }
+    }
+
+    #[inline]
+    pub fn worker(&self, index: usize) -> Option<&Counters> {
+        self.workers().get(index)
+    }
+
+    /// User ids start at 1, users beyond the size of the segment are not tracked
+    #[inline]
+    pub fn user(&self, user_id: u32) -> Option<&Counters> {
+        self.users().get((user_id as usize).checked_sub(1)?)
+    }
+
+    #[inline]
+    pub fn team(&self, user_id: u32) -> Option<TeamID> {
+        self.teams()
+            .get((user_id as usize).checked_sub(1)?)
+            .map(|team| team.load(Ordering::Relaxed))
+    }
+
+    /// Set whenever a user id is handed to a new user
+    #[inline]
+    pub fn set_team(&self, user_id: u32, team: TeamID) {
+        if let Some(slot) = (user_id as usize)
+            .checked_sub(1)
+            .and_then(|idx| self.teams().get(idx))
+        {
+            slot.store(team, Ordering::Relaxed);
+        }
+    }
+
+    /// Open connections of every user, indexed like [Stats::users]
+    #[inline]
+    pub fn connections(&self) -> &[AtomicU32] {
+        unsafe { &*slice_from_raw_parts(self.connections, (*self.header).users as usize) }
+    }
+
+    #[inline]
+    pub fn user_connections(&self, user_id: u32) -> Option<u32> {
+        self.connections()
+            .get((user_id as usize).checked_sub(1)?)
+            .map(|connections| connections.load(Ordering::Relaxed))
+    }
+
+    /// Counts a connection of the user, UDP senders count as one while they are remembered
+    #[inline]
+    pub fn connected(&self, user_id: u32) {
+        if let Some(slot) = (user_id as usize)
+            .checked_sub(1)
+            .and_then(|idx| self.connections().get(idx))
+        {
+            slot.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn disconnected(&self, user_id: u32) {
+        if let Some(slot) = (user_id as usize)
+            .checked_sub(1)
+            .and_then(|idx| self.connections().get(idx))
+        {
+            slot.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// (bytes, pixels) of every team, users without a team are left out
+    pub fn team_totals(&self) -> BTreeMap<TeamID, (u64, u64)> {
+        self.users().iter().zip(self.teams()).fold(
+            BTreeMap::new(),
+            |mut totals, (counters, team)| {
+                let team = team.load(Ordering::Relaxed);
+                if team != 0 {
+                    let (bytes, pixels) = counters.load();
+                    let total = totals.entry(team).or_insert((0, 0));
+                    total.0 += bytes;
+                    total.1 += pixels;
+                }
+                totals
+            },
+        )
+    }
+
+    /// (bytes, pixels) of all workers
+    pub fn total(&self) -> (u64, u64) {
+        self.workers()
+            .iter()
+            .map(Counters::load)
+            .fold((0, 0), |(bytes, pixels), (b, p)| (bytes + b, pixels + p))
+    }
+
+    /// A changed value means the server is gone and the stats should be reopened
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        unsafe { (*self.header).generation.load(Ordering::Acquire) }
+    }
+}
+
+impl Drop for Stats {
+    fn drop(&mut self) {
+        if self.shared_memory.is_owner() {
+            unsafe { (*self.header).generation.fetch_add(1, Ordering::AcqRel) };
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StatsError {
+    #[error("invalid shared memory size for stats")]
+    InvalidSize,
+    #[error("shared memory does not contain stats")]
+    InvalidMagic,
+    #[error("unsupported stats layout version {0}, expected {STATS_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("mapping error: {0}")]
+    Mapping(#[from] ShmemError),
+}
@@ -0,0 +1,217 @@
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, RngCore};
+
+use wellenbrecher_canvas::Canvas;
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A wellenbrecher server running on an ephemeral port with its own temporary canvas
+pub struct TestServer {
+    process: Child,
+    port: u16,
+    canvas_file_link: PathBuf,
+}
+
+impl TestServer {
+    pub fn start(width: u32, height: u32) -> std::io::Result<Self> {
+        Self::start_with_args(width, height, &[])
+    }
+
+    pub fn start_with_args(width: u32, height: u32, args: &[&str]) -> std::io::Result<Self> {
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?
+            .port();
+        let canvas_file_link = std::env::temp_dir().join(format!(
+            "wellenbrecher-test-{:08x}",
+            thread_rng().next_u32()
+        ));
+
+        let process = Command::new(server_binary()?)
+            .arg("--no-banner")
+            .args(["--threads", "2"])
+            .args(["--port", port.to_string().as_str()])
+            .args(["--width", width.to_string().as_str()])
+            .args(["--height", height.to_string().as_str()])
+            .arg("--canvas-file-link")
+            .arg(&canvas_file_link)
+            .args(args)
+            .stdin(Stdio::null())
+            .spawn()?;
+
+        let mut server = Self {
+            process,
+            port,
+            canvas_file_link,
+        };
+        server.wait_until_listening()?;
+        Ok(server)
+    }
+
+    fn wait_until_listening(&mut self) -> std::io::Result<()> {
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.process.try_wait()? {
+                return Err(std::io::Error::other(format!(
+                    "wellenbrecher exited early: {status}"
+                )));
+            }
+
+            match TcpStream::connect(self.address()) {
+                Ok(_) => return Ok(()),
+                Err(_) if start.elapsed() < STARTUP_TIMEOUT => {
+                    thread::sleep(Duration::from_millis(50))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, self.port))
+    }
+
+    pub fn connect(&self) -> std::io::Result<TestClient> {
+        TestClient::connect(self.address())
+    }
+
+    /// Retries until the server accepts the connection, e.g. while a connection limit is
+    /// still taken by a connection that was just closed
+    pub fn connect_accepted(&self) -> std::io::Result<TestClient> {
+        let start = Instant::now();
+        loop {
+            let result = self.connect().and_then(|mut client| {
+                client.sync()?;
+                Ok(client)
+            });
+            match result {
+                Ok(client) => return Ok(client),
+                Err(_) if start.elapsed() < REPLY_TIMEOUT => {
+                    thread::sleep(Duration::from_millis(50))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Maps the server's canvas, use it to assert the canvas contents
+    pub fn canvas(&self) -> Canvas {
+        Canvas::open(self.canvas_file_link.as_ref(), true, None)
+            .expect("unable to open the test server canvas")
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+
+        if let Ok(binary) = server_binary() {
+            let _ = Command::new(binary)
+                .arg("--remove-canvas")
+                .arg("--canvas-file-link")
+                .arg(&self.canvas_file_link)
+                .status();
+        }
+    }
+}
+
+/// A scripted pixelflut client
+pub struct TestClient {
+    stream: BufReader<TcpStream>,
+}
+
+impl TestClient {
+    pub fn connect(address: SocketAddr) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        Ok(Self {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    pub fn send(&mut self, commands: &str) -> std::io::Result<()> {
+        self.send_bytes(commands.as_bytes())
+    }
+
+    pub fn send_bytes(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.stream.get_mut().write_all(data)
+    }
+
+    pub fn read_line(&mut self) -> std::io::Result<String> {
+        let mut line = String::new();
+        match self.stream.read_line(&mut line)? {
+            0 => Err(ErrorKind::UnexpectedEof.into()),
+            _ => Ok(line.trim_end_matches('\n').to_string()),
+        }
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        self.stream.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Waits until every command sent before has been processed by the server
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        self.send("SIZE\n")?;
+        let reply = self.read_line()?;
+        match reply.starts_with("SIZE ") {
+            true => Ok(()),
+            false => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unexpected reply: {reply:?}"),
+            )),
+        }
+    }
+
+    /// Returns true if the server closed the connection
+    pub fn is_closed(&mut self) -> bool {
+        let mut buf = [0u8; 1024];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return true,
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => return true,
+                Err(_) => return false,
+            }
+        }
+    }
+}
+
+/// Builds the server once per test process and returns the path to its binary
+///
+/// Set `WELLENBRECHER_BIN` to test a prebuilt binary instead.
+pub fn server_binary() -> std::io::Result<PathBuf> {
+    static BINARY: OnceLock<Result<PathBuf, String>> = OnceLock::new();
+
+    BINARY
+        .get_or_init(|| {
+            if let Ok(binary) = std::env::var("WELLENBRECHER_BIN") {
+                return Ok(PathBuf::from(binary));
+            }
+
+            let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+            let status = Command::new(cargo)
+                .args(["build", "--package", "wellenbrecher"])
+                .status()
+                .map_err(|e| format!("unable to build wellenbrecher: {e}"))?;
+            if !status.success() {
+                return Err(format!("unable to build wellenbrecher: {status}"));
+            }
+
+            let target_dir = std::env::var("CARGO_TARGET_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../target"));
+            Ok(target_dir.join("debug").join("wellenbrecher"))
+        })
+        .clone()
+        .map_err(|e| std::io::Error::new(ErrorKind::NotFound, e))
+}
@@ -0,0 +1,273 @@
+use wellenbrecher_canvas::Bgra;
+use wellenbrecher_testing::TestServer;
+
+const WIDTH: u32 = 64;
+const HEIGHT: u32 = 32;
+
+#[test]
+fn size_and_help() {
+    let server = TestServer::start(WIDTH, HEIGHT).unwrap();
+    let mut client = server.connect().unwrap();
+
+    client.send("SIZE\n").unwrap();
+    assert_eq!(
+        format!("SIZE {WIDTH} {HEIGHT}"),
+        client.read_line().unwrap()
+    );
+
+    client.send("HELP\n").unwrap();
+    assert_eq!("Welcome to Pixelflut!", client.read_line().unwrap());
+}
+
+#[test]
+fn set_and_get_pixel() {
+    let server = TestServer::start(WIDTH, HEIGHT).unwrap();
+    let mut client = server.connect().unwrap();
+
+    client
+        .send("PX 1 2 ff0000\nPX 5 6 7f\nPX 7 8 0000ffff\n")
+        .unwrap();
+    client.sync().unwrap();
+
+    let canvas = server.canvas();
+    assert_eq!(Bgra::from_rgb(0xff0000), canvas.pixel(1, 2).unwrap());
+    assert_eq!(Bgra::from_bw(0x7f), canvas.pixel(5, 6).unwrap());
+    assert_eq!(Bgra::from_rgb(0x0000ff), canvas.pixel(7, 8).unwrap());
+    assert_eq!(1, canvas.user(1, 2).unwrap());
+
+    client.send("PX 1 2\n").unwrap();
+    assert!(client.read_line().unwrap().starts_with("PX 1 2 "));
+}
+
+#[test]
+fn offset() {
+    let server = TestServer::start(WIDTH, HEIGHT).unwrap();
+    let mut client = server.connect().unwrap();
+
+    client.send("OFFSET 10 20\nPX 1 1 ffffff\n").unwrap();
+    client.sync().unwrap();
+
+    let canvas = server.canvas();
+    assert_eq!(Bgra::from_rgb(0xffffff), canvas.pixel(11, 21).unwrap());
+    assert_eq!(Bgra::default(), canvas.pixel(1, 1).unwrap());
+}
+
+#[test]
+fn out_of_bounds_closes_connection() {
+    let server = TestServer::start(WIDTH, HEIGHT).unwrap();
+
+    let mut client = server.connect().unwrap();
+    client
+        .send(format!("PX {WIDTH} 0 ffffff\n").as_str())
+        .unwrap();
+    assert!(client.is_closed());
+
+    let mut client = server.connect().unwrap();
+    client
+        .send(format!("OFFSET 0 {HEIGHT}\n").as_str())
+        .unwrap();
+    assert!(client.is_closed());
+}
+
+#[test]
+fn reconnect() {
+    let server = TestServer::start(WIDTH, HEIGHT).unwrap();
+
+    for i in 0..8 {
+        let mut client = server.connect().unwrap();
+        client.send(format!("PX {i} 0 ffffff\n").as_str()).unwrap();
+        client.sync().unwrap();
+    }
+
+    let canvas = server.canvas();
+    for i in 0..8 {
+        assert_eq!(Bgra::from_rgb(0xffffff), canvas.pixel(i, 0).unwrap());
+        assert_eq!(1, canvas.user(i, 0).unwrap());
+    }
+}
+
+/// `PX <x> <y>` reply of a color
+fn hex(color: Bgra) -> String {
+    format!("{:0>8x}", u32::from(color))
+}
+
+#[test]
+fn ping() {
+    let server = TestServer::start(WIDTH, HEIGHT).unwrap();
+    let mut client = server.connect().unwrap();
+
+    client.send("PING\nPING 1337-abc\n").unwrap();
+    assert_eq!("PONG", client.read_line().unwrap());
+    assert_eq!("PONG 1337-abc", client.read_line().unwrap());
+}
+
+#[test]
+fn quit_after_replies() {
+    let server = TestServer::start(WIDTH, HEIGHT).unwrap();
+
+    for verb in ["QUIT", "BYE"] {
+        let mut client = server.connect().unwrap();
+        client
+            .send(format!("SIZE\n{verb}\nPX 0 0 ffffff\n").as_str())
+            .unwrap();
+        assert_eq!(
+            format!("SIZE {WIDTH} {HEIGHT}"),
+            client.read_line().unwrap()
+        );
+        assert!(client.is_closed());
+    }
+
+    let mut client = server.connect().unwrap();
+    client.send("PX 0 0\n").unwrap();
+    assert_eq!(
+        format!("PX 0 0 {}", hex(Bgra::default())),
+        client.read_line().unwrap()
+    );
+}
+
+#[test]
+fn ack_mode() {
+    let server = TestServer::start(WIDTH, HEIGHT).unwrap();
+    let mut client = server.connect().unwrap();
+
+    client
+        .send("MODE ack 2\nPX 0 0 ff\nPX 1 0 ff\nPX 2 0 ff\nPX 3 0 ff\nPX 4 0 ff\n")
+        .unwrap();
+    assert_eq!("ACK 2", client.read_line().unwrap());
+    assert_eq!("ACK 2", client.read_line().unwrap());
+
+    client.send("MODE ack 0\nPX 5 0 ff\n").unwrap();
+    client.sync().unwrap();
+}
+
+#[test]
+fn get_rect() {
+    let server = TestServer::start(WIDTH, HEIGHT).unwrap();
+    let mut client = server.connect().unwrap();
+
+    client.send("PX 1 2 ff0000\nPX 2 2 00ff00\n").unwrap();
+    client.send("GETRECT 1 2 2 1\n").unwrap();
+    assert_eq!("RECT 1 2 2 1", client.read_line().unwrap());
+    assert_eq!("ff0000ff00ff00ff", client.read_line().unwrap());
+
+    client.send("GETRECT 1 2 2 1 bin\n").unwrap();
+    assert_eq!("RECT 1 2 2 1 bin", client.read_line().unwrap());
+    assert_eq!(
+        vec![0xff, 0, 0, 0xff, 0, 0xff, 0, 0xff],
+        client.read_bytes(8).unwrap()
+    );
+}
+
+#[test]
+fn compress() {
+    let server = TestServer::start(WIDTH, HEIGHT).unwrap();
+    let mut client = server.connect().unwrap();
+
+    let commands = zstd::encode_all(&b"PX 3 4 0000ff\nPX 3 4\n"[..], 0).unwrap();
+    client.send("COMPRESS zstd\n").unwrap();
+    client.send_bytes(&commands).unwrap();
+    assert_eq!(
+        format!("PX 3 4 {}", hex(Bgra::from_rgb(0x0000ff))),
+        client.read_line().unwrap()
+    );
+}
+
+#[test]
+fn extensions() {
+    let server = TestServer::start(WIDTH, HEIGHT).unwrap();
+    let mut client = server.connect().unwrap();
+
+    // replies to EXT are coalesced per read, so wait for each one
+    client.send("EXT\n").unwrap();
+    let extensions = client.read_line().unwrap();
+    client.send("PROTOCOL\n").unwrap();
+    assert_eq!(extensions, client.read_line().unwrap());
+
+    let extensions = extensions.split(' ').collect::<Vec<_>>();
+    assert_eq!("EXT", extensions[0]);
+    for extension in ["PING", "ACK", "QUIT", "FLIP", "ROTATE"] {
+        assert!(extensions.contains(&extension), "{extensions:?}");
+    }
+}
+
+#[test]
+fn flip_and_rotate() {
+    let server = TestServer::start(WIDTH, HEIGHT).unwrap();
+
+    let mut client = server.connect().unwrap();
+    client.send("FLIP H\nPX 0 0 ff0000\n").unwrap();
+    client.sync().unwrap();
+
+    let mut client = server.connect().unwrap();
+    client.send("ROTATE 90\nSIZE\n").unwrap();
+    assert_eq!(
+        format!("SIZE {HEIGHT} {WIDTH}"),
+        client.read_line().unwrap()
+    );
+    client.send("PX 0 1 00ff00\n").unwrap();
+    client.sync().unwrap();
+
+    let mut client = server.connect().unwrap();
+    client
+        .send(format!("PX {} 0\nPX {} 0\n", WIDTH - 1, WIDTH - 2).as_str())
+        .unwrap();
+    assert_eq!(
+        format!("PX {} 0 {}", WIDTH - 1, hex(Bgra::from_rgb(0xff0000))),
+        client.read_line().unwrap()
+    );
+    assert_eq!(
+        format!("PX {} 0 {}", WIDTH - 2, hex(Bgra::from_rgb(0x00ff00))),
+        client.read_line().unwrap()
+    );
+}
+
+#[test]
+fn max_connections() {
+    let server = TestServer::start_with_args(WIDTH, HEIGHT, &["--max-connections", "1"]).unwrap();
+
+    let first = server.connect_accepted().unwrap();
+
+    let mut second = server.connect().unwrap();
+    assert_eq!("ERR server full", second.read_line().unwrap());
+    assert!(second.is_closed());
+
+    drop(first);
+    server.connect_accepted().unwrap();
+}
+
+#[test]
+fn connections_per_ip() {
+    let server =
+        TestServer::start_with_args(WIDTH, HEIGHT, &["--connections-per-ip", "1"]).unwrap();
+
+    let mut first = server.connect_accepted().unwrap();
+
+    let mut second = server.connect().unwrap();
+    assert_eq!("ERR too many connections", second.read_line().unwrap());
+    assert!(second.is_closed());
+    first.sync().unwrap();
+}
+
+#[test]
+fn parse_errors_reply() {
+    let server = TestServer::start_with_args(WIDTH, HEIGHT, &["--parse-errors", "reply"]).unwrap();
+    let mut client = server.connect().unwrap();
+
+    // the ring needs the following line to tell HELLO from longer verbs
+    client
+        .send("PX 1a 0 ffffff\nPX 0 0 zz\nHELLO\nSIZE\n")
+        .unwrap();
+    assert_eq!(
+        "ERR got an invalid decimal digit \"a\"",
+        client.read_line().unwrap()
+    );
+    assert_eq!(
+        "ERR got an invalid hexadecimal digit \"z\"",
+        client.read_line().unwrap()
+    );
+    assert_eq!("ERR got an unknown verb", client.read_line().unwrap());
+    assert_eq!(
+        format!("SIZE {WIDTH} {HEIGHT}"),
+        client.read_line().unwrap()
+    );
+}
@@ -0,0 +1,54 @@
+use std::os::fd::{FromRawFd, RawFd};
+
+use socket2::{Domain, Socket, Type};
+use tracing::{info, warn};
+
+/// First file descriptor passed by the service manager, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Listening sockets passed by systemd socket activation, None if the server was not activated.
+/// Must be called at most once, the sockets are owned by the caller afterwards
+pub fn listen_fds(port: u16) -> eyre::Result<Option<Vec<Socket>>> {
+    let (Ok(pid), Ok(fds)) = (std::env::var("LISTEN_PID"), std::env::var("LISTEN_FDS")) else {
+        return Ok(None);
+    };
+    // meant for another process
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let fds = fds
+        .parse::<RawFd>()
+        .map_err(|e| eyre::eyre!("invalid LISTEN_FDS \"{fds}\": {e}"))?;
+    if fds <= 0 {
+        return Ok(None);
+    }
+
+    let sockets = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+        .map(|fd| {
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            socket.set_cloexec(true)?;
+
+            if !matches!(socket.domain()?, Domain::IPV4 | Domain::IPV6)
+                || socket.r#type()? != Type::STREAM
+                || !socket.is_listener()?
+            {
+                return Err(eyre::eyre!(
+                    "socket activation passed fd {fd}, which is not a listening TCP socket"
+                ));
+            }
+
+            if let Some(address) = socket.local_addr()?.as_socket() {
+                if address.port() != port {
+                    warn!("socket activation passed a listener on {address}, firewall rules and announcements use --port {port}");
+                }
+            }
+            Ok(socket)
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    info!(
+        "using {} listeners passed by socket activation",
+        sockets.len()
+    );
+    Ok(Some(sockets))
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use nftables::helper::NftablesError;
+use thiserror::Error;
+use tracing::info;
+
+use crate::firewall::Firewall;
+use crate::ring::ring_coordination::{mask_ip, nanos_since_epoch};
+
+#[derive(Debug, Error)]
+pub enum BanError {
+    #[error("the nftables rules could not be applied at startup")]
+    FirewallUnavailable,
+    #[error(transparent)]
+    Nftables(#[from] NftablesError),
+}
+
+/// Addresses banned at runtime, connections and datagrams of them are refused.
+///
+/// With a [Firewall] the bans are added to its nftables set as well, so that
+/// banned players do not even get to connect.
+#[derive(Debug)]
+pub struct Bans {
+    /// masked address -> banned until (ns since the epoch), u64::MAX is forever
+    banned: RwLock<HashMap<IpAddr, u64>>,
+    /// lets the common case of no bans skip the lock
+    len: AtomicUsize,
+    firewall: Option<Arc<Firewall>>,
+    /// the firewall was asked for but could not be set up, bans only reach the server
+    firewall_unavailable: bool,
+    ipv4_mask: Ipv4Addr,
+    ipv6_mask: Ipv6Addr,
+}
+
+impl Bans {
+    pub fn new(
+        firewall: Option<Arc<Firewall>>,
+        firewall_unavailable: bool,
+        ipv4_mask: Ipv4Addr,
+        ipv6_mask: Ipv6Addr,
+    ) -> Self {
+        Self {
+            banned: Default::default(),
+            len: AtomicUsize::new(0),
+            firewall,
+            firewall_unavailable,
+            ipv4_mask,
+            ipv6_mask,
+        }
+    }
+
+    #[inline]
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        if self.len.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+
+        let ip = mask_ip(ip, self.ipv4_mask, self.ipv6_mask);
+        self.banned
+            .read()
+            .expect("unable to acquire lock on bans")
+            .get(&ip)
+            .is_some_and(|&until| until > nanos_since_epoch())
+    }
+
+    /// Bans the user behind `ip` for `duration` or until it is unbanned
+    /// The server refuses `ip` in any case, an error means it did not reach the nftables set
+    pub fn ban(&self, ip: IpAddr, duration: Option<Duration>) -> Result<(), BanError> {
+        let until = match duration {
+            Some(duration) => nanos_since_epoch().saturating_add(duration.as_nanos() as u64),
+            None => u64::MAX,
+        };
+        {
+            let mut banned = self.banned.write().expect("unable to acquire lock on bans");
+            let now = nanos_since_epoch();
+            banned.retain(|_, until| *until > now);
+            banned.insert(mask_ip(ip, self.ipv4_mask, self.ipv6_mask), until);
+            self.len.store(banned.len(), Ordering::Relaxed);
+        }
+
+        match &self.firewall {
+            Some(firewall) => Ok(firewall.ban(ip, duration)?),
+            None if self.firewall_unavailable => Err(BanError::FirewallUnavailable),
+            None => {
+                match duration {
+                    Some(duration) => info!("banned {ip} for {duration:?}"),
+                    None => info!("banned {ip}"),
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns false if `ip` was not banned
+    pub fn unban(&self, ip: IpAddr) -> Result<bool, NftablesError> {
+        let was_banned = {
+            let mut banned = self.banned.write().expect("unable to acquire lock on bans");
+            let was_banned = banned
+                .remove(&mask_ip(ip, self.ipv4_mask, self.ipv6_mask))
+                .is_some_and(|until| until > nanos_since_epoch());
+            self.len.store(banned.len(), Ordering::Relaxed);
+            was_banned
+        };
+        if !was_banned {
+            return Ok(false);
+        }
+
+        match &self.firewall {
+            Some(firewall) => firewall.unban(ip)?,
+            None => info!("unbanned {ip}"),
+        }
+        Ok(true)
+    }
+
+    /// Banned addresses with the remaining ban duration, None is forever
+    pub fn list(&self) -> Vec<(IpAddr, Option<Duration>)> {
+        let now = nanos_since_epoch();
+        let mut bans = self
+            .banned
+            .read()
+            .expect("unable to acquire lock on bans")
+            .iter()
+            .filter(|(_, &until)| until > now)
+            .map(|(&ip, &until)| {
+                let remaining = (until != u64::MAX).then(|| Duration::from_nanos(until - now));
+                (ip, remaining)
+            })
+            .collect::<Vec<_>>();
+        bans.sort_by_key(|&(ip, _)| ip);
+        bans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::{BanError, Bans};
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    #[test]
+    fn ban_without_firewall() {
+        let bans = Bans::new(None, false, Ipv4Addr::BROADCAST, Ipv6Addr::from(u128::MAX));
+        assert!(bans.ban(IP, None).is_ok());
+        assert!(bans.is_banned(IP));
+    }
+
+    #[test]
+    fn ban_with_unavailable_firewall() {
+        let bans = Bans::new(None, true, Ipv4Addr::BROADCAST, Ipv6Addr::from(u128::MAX));
+        assert!(matches!(
+            bans.ban(IP, None),
+            Err(BanError::FirewallUnavailable)
+        ));
+        // refused by the server nonetheless
+        assert!(bans.is_banned(IP));
+    }
+}
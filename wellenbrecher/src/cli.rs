@@ -1,11 +1,30 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
-use std::num::{NonZeroU32, NonZeroUsize};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{ArgAction, Parser, ValueEnum};
+
+use wellenbrecher_canvas::instance::{
+    self, DEFAULT_CANVAS_FILE_LINK, DEFAULT_STATS_DUMP, DEFAULT_STATS_FILE_LINK, INSTANCES_FILE,
+};
+use wellenbrecher_canvas::{Bgra, InitialPattern, ShmBackend};
+
+use crate::palette::Palette;
+use crate::ring::pixelflut_connection_handler::ParseErrorPolicy;
+use crate::ring::ring_coordination::{Limits, SocketOptions};
+use crate::schedule::ClearSchedule;
+use crate::teams::Teams;
+use crate::{config, HELP_TEXT};
 
 #[derive(Parser, Clone)]
-#[command(author, version, about)]
+#[command(author, version, about, args_override_self = true)]
 pub struct Args {
+    /// TOML file setting options by their long name, e.g. `max-pixel-rate = 1000`.
+    /// The command line takes precedence. Limits, masks and the help text are reloaded on SIGHUP
+    #[arg(long, env = "WELLENBRECHER_CONFIG")]
+    pub config: Option<PathBuf>,
+
     /// Canvas width
     #[arg(long, default_value_t = NonZeroU32::new(1280).unwrap(), env = "CANVAS_WIDTH")]
     pub width: NonZeroU32,
@@ -14,6 +33,36 @@ pub struct Args {
     #[arg(long, default_value_t = NonZeroU32::new(720).unwrap(), env = "CANVAS_HEIGHT")]
     pub height: NonZeroU32,
 
+    /// Color a newly created canvas is filled with (ww, rrggbb or rrggbbaa)
+    #[arg(long, value_parser = parse_color, env = "WELLENBRECHER_INITIAL_COLOR")]
+    pub initial_color: Option<Bgra>,
+
+    /// Test pattern a newly created canvas is filled with (checkerboard, gradient, colorbars or grid)
+    #[arg(
+        long,
+        conflicts_with = "initial_color",
+        env = "WELLENBRECHER_INITIAL_PATTERN"
+    )]
+    pub initial_pattern: Option<InitialPattern>,
+
+    /// Map all colors to the nearest entry of this palette, one rrggbb color per line
+    #[arg(long, value_parser = parse_palette, env = "WELLENBRECHER_PALETTE")]
+    pub palette: Option<Palette>,
+
+    /// Group players into teams, one `<team id> <address>[/<prefix>] ...` per line.
+    /// Viewers and the stats dump aggregate the pixels and traffic per team
+    #[arg(long, value_parser = parse_teams, env = "WELLENBRECHER_TEAMS")]
+    pub teams: Option<Teams>,
+
+    /// Clear the canvas at fixed times, one `<minute> <hour> <day of month> <month> <day of week> clear [rrggbb]|reset`
+    /// per line in local time
+    #[arg(long, value_parser = parse_clear_schedule, env = "WELLENBRECHER_CLEAR_SCHEDULE")]
+    pub clear_schedule: Option<ClearSchedule>,
+
+    /// Seconds viewers are warned ahead of a scheduled clear, 0 disables the warning
+    #[arg(long, default_value_t = 60, env = "WELLENBRECHER_CLEAR_WARNING")]
+    pub clear_warning: u64,
+
     /// Limit the number of OS threads
     #[arg(short = 'n', long, env = "WELLENBRECHER_THREAD_LIMIT")]
     pub threads: Option<NonZeroUsize>,
@@ -23,7 +72,8 @@ pub struct Args {
         long,
         help = r#"Max connections per ip
     
-This option requires permissions to alter nftables on your system. You need to run with 'CAP_NET_ADMIN' or as root.
+Further connections of a player are refused with `ERR too many connections`. With permissions to alter nftables
+on your system they are dropped by the firewall already, for that you need to run with 'CAP_NET_ADMIN' or as root.
 
 You can get an elevated shell with:
     $ sudo --preserve-env=USER \
@@ -34,22 +84,150 @@ You can get an elevated shell with:
     )]
     pub connections_per_ip: Option<NonZeroU32>,
 
+    /// Also drop packets of players banned through the admin socket with nftables,
+    /// requires the same permissions as --connections-per-ip
+    #[arg(long, default_value_t = false, env = "WELLENBRECHER_NFT_BANS")]
+    pub nft_bans: bool,
+
+    /// Continue as this user (name or uid) once the firewall, the listeners and TLS are set up as root.
+    /// CAP_NET_ADMIN is kept if the firewall is used
+    #[arg(long, env = "WELLENBRECHER_USER")]
+    pub user: Option<String>,
+
+    /// Continue in this group (name or gid) instead of the primary group of --user
+    #[arg(long, requires = "user", env = "WELLENBRECHER_GROUP")]
+    pub group: Option<String>,
+
+    /// Only create, write and remove files in the directories of the file links, the stats dump,
+    /// the snapshots and the sockets, as well as /dev/shm, once set up. Executing is denied unless nft is needed
+    #[arg(long, default_value_t = false, env = "WELLENBRECHER_LANDLOCK")]
+    pub landlock: bool,
+
+    /// Maximum bytes per second a player may send, reads of players above it are delayed
+    /// so that TCP backpressure slows them down instead of dropping their pixels (io_uring backend only)
+    #[arg(long, env = "WELLENBRECHER_MAX_INGRESS_RATE")]
+    pub max_ingress_rate: Option<NonZeroU64>,
+
+    /// Maximum pixels per second a player may set, pixels above it are dropped
+    /// and `THROTTLED pixel-rate` is replied once per read.
+    /// Unlike the firewall limits this does not require CAP_NET_ADMIN
+    #[arg(long, env = "WELLENBRECHER_MAX_PIXEL_RATE")]
+    pub max_pixel_rate: Option<NonZeroU64>,
+
+    /// Pixels a player may set per --pixel-budget-interval, the budget refills continuously.
+    /// Pixels beyond it are rejected with `BUDGET EXHAUSTED <ms until the next pixel>`
+    #[arg(long, env = "WELLENBRECHER_PIXEL_BUDGET")]
+    pub pixel_budget: Option<NonZeroU64>,
+
+    /// Seconds in which the pixel budget refills completely
+    #[arg(long, default_value_t = unsafe { NonZeroU64::new_unchecked(60) }, env = "WELLENBRECHER_PIXEL_BUDGET_INTERVAL")]
+    pub pixel_budget_interval: NonZeroU64,
+
+    /// Maximum open connections of all players together. Further ones get `ERR server full`
+    /// and are closed right away instead of being queued
+    #[arg(long, env = "WELLENBRECHER_MAX_CONNECTIONS")]
+    pub max_connections: Option<NonZeroU64>,
+
+    /// Largest region in pixels a single GETRECT may request, bigger requests close the connection.
+    /// 0 disables GETRECT
+    #[arg(long, default_value_t = 4096, env = "WELLENBRECHER_MAX_RECT_PIXELS")]
+    pub max_rect_pixels: u32,
+
     /// Port pixelflut will run on
     #[arg(short, long, default_value_t = 1337, env = "PORT")]
     pub port: u16,
 
+    /// Listen for pixelflut via TCP on these addresses instead of every interface on --port,
+    /// e.g. --listen 10.0.0.1:1337 --listen [fd00::1]:1337. IPv6 addresses only accept IPv6.
+    /// UDP, the firewall rules and mDNS keep using --port
+    #[arg(long, value_delimiter = ',', env = "WELLENBRECHER_LISTEN")]
+    pub listen: Vec<SocketAddr>,
+
+    /// Also serve pixelflut on a unix socket at this path, e.g. for generators on the same host.
+    /// All its connections belong to one user, TLS and the socket options do not apply to them
+    #[arg(long, env = "WELLENBRECHER_UNIX_SOCKET")]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Also accept datagrams full of commands on the pixelflut port via UDP,
+    /// nothing is replied to them (io_uring backend only)
+    #[arg(long, default_value_t = false, env = "WELLENBRECHER_UDP")]
+    pub udp: bool,
+
+    /// Serve the pixelflut port via TLS only, with this PEM certificate chain
+    ///
+    /// Encryption is offloaded to the kernel after the handshake (kTLS), so the tls kernel module is required
+    /// (io_uring backend only)
+    #[arg(long, requires = "tls_key", env = "WELLENBRECHER_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert", env = "WELLENBRECHER_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
     /// IPv4 mask for the bits identifying a player
     #[arg(long, default_value_t = Ipv4Addr::from([0xff, 0xff, 0xff, 0xff]), env = "WELLENBRECHER_IPV4_MASK")]
-    pub ipv4_mask: Ipv4Addr,
+    ipv4_mask: Ipv4Addr,
+
+    /// IPv4 prefix length identifying a player, shorthand for --ipv4-mask
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=32), conflicts_with = "ipv4_mask", env = "WELLENBRECHER_IPV4_PREFIX")]
+    ipv4_prefix: Option<u8>,
 
     /// IPv6 mask for the bits identifying a player
-    #[arg(long, default_value_t = Ipv6Addr::from([0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff]), env = "WELLENBRECHER_IPV6_MASK")]
-    pub ipv6_mask: Ipv6Addr,
+    #[arg(long, default_value_t = Ipv6Addr::from([0xffff, 0xffff, 0xffff, 0xffff, 0, 0, 0, 0]), env = "WELLENBRECHER_IPV6_MASK")]
+    ipv6_mask: Ipv6Addr,
+
+    /// IPv6 prefix length identifying a player, shorthand for --ipv6-mask
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=128), conflicts_with = "ipv6_mask", env = "WELLENBRECHER_IPV6_PREFIX")]
+    ipv6_prefix: Option<u8>,
 
     /// buffer size per connection in bytes
     #[arg(long = "buffer", default_value_t = unsafe { NonZeroUsize::new_unchecked(64 * 1024) }, env = "WELLENBRECHER_BUFFER_PER_CONNECTION")]
     pub connection_buffer_size: NonZeroUsize,
 
+    /// Disable Nagle's algorithm on accepted sockets so that replies are sent right away
+    #[arg(long, default_value_t = false, env = "WELLENBRECHER_TCP_NODELAY")]
+    pub tcp_nodelay: bool,
+
+    /// Acknowledge received data right away instead of delaying ACKs on accepted sockets
+    #[arg(long, default_value_t = false, env = "WELLENBRECHER_TCP_QUICKACK")]
+    pub tcp_quickack: bool,
+
+    /// Kernel receive buffer size (SO_RCVBUF) of accepted sockets in bytes
+    #[arg(long, env = "WELLENBRECHER_SOCKET_RECV_BUFFER")]
+    pub socket_recv_buffer: Option<NonZeroUsize>,
+
+    /// Kernel send buffer size (SO_SNDBUF) of accepted sockets in bytes
+    #[arg(long, env = "WELLENBRECHER_SOCKET_SEND_BUFFER")]
+    pub socket_send_buffer: Option<NonZeroUsize>,
+
+    /// Reaction to malformed commands: drop (close the connection), skip (ignore the line),
+    /// skip-n <N> or skip-n=<N> (ignore up to N lines per connection, then close it) or reply
+    /// (answer `ERR <reason>` and ignore the line, e.g. for beginners writing their first client)
+    #[arg(long = "parse-errors", action = ArgAction::Set, num_args = 1..=2, value_names = ["POLICY", "N"], default_value = "drop", env = "WELLENBRECHER_PARSE_ERRORS")]
+    parse_error_policy: Vec<String>,
+
+    /// Parsed from --parse-errors by [Args::with_config]
+    #[arg(skip)]
+    pub parse_errors: ParseErrorPolicy,
+
+    /// Close connections that sent nothing for this many seconds, freeing their buffers (io_uring backend only)
+    #[arg(long, env = "WELLENBRECHER_IDLE_TIMEOUT")]
+    pub idle_timeout: Option<NonZeroU64>,
+
+    /// Seconds connections may keep flooding once SIGRTMIN or the admin command drain stopped accepting new ones,
+    /// the server exits as soon as all of them are gone
+    #[arg(long, default_value_t = 30, env = "WELLENBRECHER_DRAIN_GRACE_PERIOD")]
+    pub drain_grace_period: u64,
+
+    /// Reply to HELP instead of the built-in help text
+    #[arg(long, env = "WELLENBRECHER_HELP_TEXT")]
+    pub help_text: Option<String>,
+
+    /// Networking backend, epoll is meant for kernels or containers without io_uring.
+    /// auto uses io_uring and falls back to epoll if io_uring is unavailable
+    #[arg(long, value_enum, default_value_t = Backend::Auto, env = "WELLENBRECHER_BACKEND")]
+    pub backend: Backend,
+
     /// io_uring ring size for the empress and lackey rings
     #[arg(long, default_value_t = unsafe { NonZeroU32::new_unchecked(1024) }, env = "WELLENBRECHER_IO_URING_SIZE")]
     pub io_uring_size: NonZeroU32,
@@ -58,15 +236,429 @@ You can get an elevated shell with:
     #[arg(long, default_value_t = unsafe { NonZeroU32::new_unchecked(128) }, env = "WELLENBRECHER_TCP_BACKLOG")]
     pub tcp_accept_backlog: NonZeroU32,
 
+    /// Serve canvas replication to read-only mirrors on this address
+    #[arg(long, env = "WELLENBRECHER_REPLICATION_LISTEN")]
+    pub replication_listen: Option<SocketAddr>,
+
+    /// Shared secret mirrors and standbys have to present to the replication listener
+    /// and present to the primary themselves
+    #[arg(long, env = "WELLENBRECHER_REPLICATION_SECRET", hide_env_values = true)]
+    pub replication_secret: Option<String>,
+
+    /// Maximum number of mirrors and standbys replicated to at the same time
+    #[arg(long, default_value_t = unsafe { NonZeroU32::new_unchecked(4) }, env = "WELLENBRECHER_REPLICATION_MAX_MIRRORS")]
+    pub replication_max_mirrors: NonZeroU32,
+
+    /// Interval between replication updates in milliseconds
+    #[arg(long, default_value_t = unsafe { NonZeroU64::new_unchecked(100) }, env = "WELLENBRECHER_REPLICATION_INTERVAL")]
+    pub replication_interval: NonZeroU64,
+
+    /// Stream canvas deltas to read-only spectators on this address
+    #[arg(long, env = "WELLENBRECHER_SPECTATOR_LISTEN")]
+    pub spectator_listen: Option<SocketAddr>,
+
+    /// Interval between spectator frames in milliseconds
+    #[arg(long, default_value_t = unsafe { NonZeroU64::new_unchecked(100) }, env = "WELLENBRECHER_SPECTATOR_INTERVAL")]
+    pub spectator_interval: NonZeroU64,
+
+    /// Number of spectator frames between two keyframes
+    #[arg(long, default_value_t = unsafe { NonZeroU32::new_unchecked(50) }, env = "WELLENBRECHER_SPECTATOR_KEYFRAME_INTERVAL")]
+    pub spectator_keyframe_interval: NonZeroU32,
+
+    /// Announce the server on the local network via mDNS (_pixelflut._tcp)
+    /// with the canvas size and supported commands in TXT records
+    #[arg(long, default_value_t = false, env = "WELLENBRECHER_MDNS")]
+    pub mdns: bool,
+
+    /// mDNS instance name, defaults to the hostname
+    #[arg(long, requires = "mdns", env = "WELLENBRECHER_MDNS_NAME")]
+    pub mdns_name: Option<String>,
+
+    /// Run as read-only mirror of the replication stream at <host:port>
+    #[arg(long, env = "WELLENBRECHER_MIRROR_OF", conflicts_with = "standby_of")]
+    pub mirror_of: Option<String>,
+
+    /// Run as hot-standby of the replication stream at <host:port> and take over once it is gone
+    #[arg(long, env = "WELLENBRECHER_STANDBY_OF")]
+    pub standby_of: Option<String>,
+
+    /// Seconds without replication updates before the primary is considered gone
+    #[arg(long, default_value_t = unsafe { NonZeroU64::new_unchecked(5) }, env = "WELLENBRECHER_TAKEOVER_TIMEOUT")]
+    pub takeover_timeout: NonZeroU64,
+
+    /// Name of this server to run several on one host, e.g. `kanal --instance <NAME>` finds it.
+    /// Default file links and the nftables table carry the name
+    #[arg(long, value_parser = instance::parse_name, env = "WELLENBRECHER_INSTANCE")]
+    pub instance: Option<String>,
+
     /// Canvas shared memory file link
-    #[arg(short = 'l', long, default_value_t = String::from("/tmp/wellenbrecher-canvas"), env = "WELLENBRECHER_CANVAS_FLINK")]
+    #[arg(short = 'l', long, default_value_t = String::from(DEFAULT_CANVAS_FILE_LINK), env = "WELLENBRECHER_CANVAS_FLINK")]
     pub canvas_file_link: String,
 
+    /// How the canvas is shared (flink, memfd, sysv or file)
+    ///
+    /// memfd canvases go away with the server, file canvases persist across reboots.
+    /// Viewers open file canvases with their plain file option, all others through the file link
+    #[arg(long, default_value = "flink", env = "WELLENBRECHER_SHM_BACKEND")]
+    pub shm_backend: ShmBackend,
+
+    /// Touch all canvas pages before serving, avoiding page faults once players connect
+    #[arg(long, default_value_t = false, env = "WELLENBRECHER_PREFAULT")]
+    pub prefault: bool,
+
+    /// Keep the time every pixel was set at in the canvas, e.g. for heatmaps or reverting
+    /// vandalism. Costs another 8 bytes per pixel
+    #[arg(long, default_value_t = false, env = "WELLENBRECHER_PIXEL_TIMESTAMPS")]
+    pub pixel_timestamps: bool,
+
+    /// Count the pixels on the canvas of the first --stats-users users in the canvas itself,
+    /// so viewers don't have to scan it. Setting pixels gets slightly more expensive
+    #[arg(long, default_value_t = false, env = "WELLENBRECHER_COUNT_USER_PIXELS")]
+    pub count_user_pixels: bool,
+
+    /// Traffic stats shared memory file link
+    #[arg(long, default_value_t = String::from(DEFAULT_STATS_FILE_LINK), env = "WELLENBRECHER_STATS_FLINK")]
+    pub stats_file_link: String,
+
+    /// Number of users with their own traffic stats
+    #[arg(long, default_value_t = unsafe { NonZeroU32::new_unchecked(4096) }, env = "WELLENBRECHER_STATS_USERS")]
+    pub stats_users: NonZeroU32,
+
+    /// File SIGUSR2 writes a JSON dump of the client table and traffic stats to
+    #[arg(long, default_value = DEFAULT_STATS_DUMP, env = "WELLENBRECHER_STATS_DUMP")]
+    pub stats_dump: PathBuf,
+
+    /// File the canvas is written to on shutdown and restored from on start, e.g. to keep it across
+    /// reboots. Uses the zstd format of `kanal snapshots`
+    #[arg(long, env = "WELLENBRECHER_SNAPSHOT")]
+    pub snapshot: Option<PathBuf>,
+
+    /// Directory to write a timestamped snapshot of the canvas to every --snapshot-interval,
+    /// e.g. to archive the artwork of an event. `seebruecke --playback <DIR>` plays them back
+    #[arg(long, env = "WELLENBRECHER_SNAPSHOT_DIR")]
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// Seconds between two snapshots written to --snapshot-dir
+    #[arg(long, default_value_t = unsafe { NonZeroU64::new_unchecked(300) }, env = "WELLENBRECHER_SNAPSHOT_INTERVAL")]
+    pub snapshot_interval: NonZeroU64,
+
+    /// Unix socket to clear the canvas, list and kick users and change limits at runtime (io_uring only)
+    #[arg(long, env = "WELLENBRECHER_ADMIN_SOCKET")]
+    pub admin_socket: Option<PathBuf>,
+
     /// Removes the shared canvas and exits immediately
     #[arg(long, default_value_t = false)]
     pub remove_canvas: bool,
 
+    /// Log format, json writes one object per line along with the worker and connection spans
+    #[arg(long, value_enum, default_value_t = LogFormat::Compact, env = "WELLENBRECHER_LOG_FORMAT")]
+    pub log_format: LogFormat,
+
+    /// Export traces and metrics via OTLP, configured by the OTEL_EXPORTER_OTLP_* environment variables
+    #[cfg(feature = "otel")]
+    #[arg(long, default_value_t = false, env = "WELLENBRECHER_OTEL")]
+    pub otel: bool,
+
     /// Hide the banner
     #[arg(long, default_value_t = false, env = "WELLENBRECHER_HIDE_BANNER")]
     pub no_banner: bool,
 }
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum LogFormat {
+    Compact,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Backend {
+    Auto,
+    IoUring,
+    Epoll,
+}
+
+impl Args {
+    /// Parses the command line again on top of the options set in the config file
+    pub fn with_config(self) -> eyre::Result<Self> {
+        let Some(path) = &self.config else {
+            return self.with_instance().with_parse_error_policy();
+        };
+
+        let mut command_line = std::env::args_os();
+        let program = command_line.next().unwrap_or_default();
+        Self::try_parse_from(
+            std::iter::once(program)
+                .chain(config::arguments(path)?)
+                .chain(command_line),
+        )?
+        .with_instance()
+        .with_parse_error_policy()
+    }
+
+    /// Default file links and the default stats dump of an instance carry its name
+    fn with_instance(mut self) -> Self {
+        if let Some(name) = &self.instance {
+            if self.canvas_file_link == DEFAULT_CANVAS_FILE_LINK {
+                self.canvas_file_link = instance::canvas_file_link(name);
+            }
+            if self.stats_file_link == DEFAULT_STATS_FILE_LINK {
+                self.stats_file_link = instance::stats_file_link(name);
+            }
+            if self.stats_dump == Path::new(DEFAULT_STATS_DUMP) {
+                self.stats_dump = PathBuf::from(instance::stats_dump(name));
+            }
+        }
+        self
+    }
+
+    /// --parse-errors takes the number of lines of skip-n as a separate value,
+    /// which clap can't hand to a single value parser
+    fn with_parse_error_policy(mut self) -> eyre::Result<Self> {
+        self.parse_errors = parse_parse_error_policy(&self.parse_error_policy.join(" "))
+            .map_err(|e| eyre::eyre!("invalid value for --parse-errors: {e}"))?;
+        Ok(self)
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits::new(
+            self.max_ingress_rate,
+            self.max_pixel_rate,
+            self.pixel_budget,
+            Duration::from_secs(self.pixel_budget_interval.get()),
+            self.max_connections,
+            self.connections_per_ip.map(NonZeroU64::from),
+        )
+    }
+
+    /// The TCP listen addresses, every interface on --port unless --listen is given
+    pub fn listen_addresses(&self) -> eyre::Result<Vec<SocketAddr>> {
+        if self.listen.is_empty() {
+            return Ok(vec![
+                SocketAddr::from((Ipv6Addr::UNSPECIFIED, self.port)),
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.port)),
+            ]);
+        }
+
+        for (i, address) in self.listen.iter().enumerate() {
+            if self.listen[..i].contains(address) {
+                return Err(eyre::eyre!("--listen {address} is given more than once"));
+            }
+            if address.port() == 0 {
+                return Err(eyre::eyre!("--listen {address} needs a port"));
+            }
+        }
+        Ok(self.listen.clone())
+    }
+
+    /// The directories files are created, written or removed in after the setup
+    pub fn writable_directories(&self) -> Vec<PathBuf> {
+        let files = [
+            Some(Path::new(&self.canvas_file_link)),
+            Some(Path::new(&self.stats_file_link)),
+            Some(self.stats_dump.as_path()),
+            self.snapshot.as_deref(),
+            self.admin_socket.as_deref(),
+            self.unix_socket.as_deref(),
+            self.instance.as_ref().map(|_| Path::new(INSTANCES_FILE)),
+        ];
+
+        let mut directories = files
+            .into_iter()
+            .flatten()
+            .map(|file| match file.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            })
+            .chain(self.snapshot_dir.clone())
+            .chain([PathBuf::from("/dev/shm")])
+            .collect::<Vec<_>>();
+        directories.sort();
+        directories.dedup();
+        directories
+    }
+
+    pub fn socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            nodelay: self.tcp_nodelay,
+            quickack: self.tcp_quickack,
+            recv_buffer_size: self.socket_recv_buffer,
+            send_buffer_size: self.socket_send_buffer,
+        }
+    }
+
+    pub fn help_text(&self) -> Vec<u8> {
+        match &self.help_text {
+            Some(text) if text.ends_with('\n') => text.as_bytes().to_vec(),
+            Some(text) => format!("{text}\n").into_bytes(),
+            None => HELP_TEXT.to_vec(),
+        }
+    }
+
+    /// User pixel counters of the canvas, user ids start at 1
+    pub fn user_counters(&self) -> u32 {
+        match self.count_user_pixels {
+            true => self.stats_users.get() + 1,
+            false => 0,
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+            .map(|seconds| Duration::from_secs(seconds.get()))
+    }
+
+    pub fn drain_grace_period(&self) -> Duration {
+        Duration::from_secs(self.drain_grace_period)
+    }
+
+    pub fn ipv4_mask(&self) -> Ipv4Addr {
+        match self.ipv4_prefix {
+            Some(prefix) => Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)),
+            None => self.ipv4_mask,
+        }
+    }
+
+    pub fn ipv6_mask(&self) -> Ipv6Addr {
+        match self.ipv6_prefix {
+            Some(prefix) => Ipv6Addr::from(u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)),
+            None => self.ipv6_mask,
+        }
+    }
+}
+
+fn parse_color(color: &str) -> Result<Bgra, String> {
+    let value = u32::from_str_radix(color, 16).map_err(|e| e.to_string())?;
+    match color.len() {
+        2 => Ok(Bgra::from_bw(value as u8)),
+        6 => Ok(Bgra::from_rgb(value)),
+        8 => Ok(Bgra::from_rgba(value)),
+        _ => Err("expected a color in the form ww, rrggbb or rrggbbaa".to_string()),
+    }
+}
+
+fn parse_palette(path: &str) -> Result<Palette, String> {
+    Palette::load(path.as_ref())
+}
+
+fn parse_clear_schedule(path: &str) -> Result<ClearSchedule, String> {
+    ClearSchedule::load(path.as_ref())
+}
+
+fn parse_teams(path: &str) -> Result<Teams, String> {
+    Teams::load(path.as_ref())
+}
+
+fn parse_parse_error_policy(policy: &str) -> Result<ParseErrorPolicy, String> {
+    match policy {
+        "drop" => Ok(ParseErrorPolicy::Drop),
+        "skip" => Ok(ParseErrorPolicy::Skip),
+        "reply" => Ok(ParseErrorPolicy::Reply),
+        _ => match policy
+            .strip_prefix("skip-n=")
+            .or_else(|| policy.strip_prefix("skip-n "))
+        {
+            Some(n) => {
+                Ok(ParseErrorPolicy::SkipN(n.parse().map_err(|e| {
+                    format!("invalid number of lines to skip: {e}")
+                })?))
+            }
+            None => Err("expected drop, skip, skip-n <N> or reply".to_string()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+    use std::path::Path;
+
+    use clap::Parser;
+
+    use super::Args;
+    use crate::ring::pixelflut_connection_handler::ParseErrorPolicy;
+
+    fn parse_errors(arguments: &[&str]) -> eyre::Result<ParseErrorPolicy> {
+        let args = Args::try_parse_from(
+            std::iter::once("wellenbrecher").chain(arguments.iter().copied()),
+        )?;
+        Ok(args.with_parse_error_policy()?.parse_errors)
+    }
+
+    #[test]
+    fn parse_error_policies() {
+        let skip_3 = ParseErrorPolicy::SkipN(NonZeroUsize::new(3).unwrap());
+
+        assert_eq!(parse_errors(&[]).unwrap(), ParseErrorPolicy::Drop);
+        assert_eq!(
+            parse_errors(&["--parse-errors", "drop"]).unwrap(),
+            ParseErrorPolicy::Drop
+        );
+        assert_eq!(
+            parse_errors(&["--parse-errors", "skip"]).unwrap(),
+            ParseErrorPolicy::Skip
+        );
+        assert_eq!(
+            parse_errors(&["--parse-errors", "reply"]).unwrap(),
+            ParseErrorPolicy::Reply
+        );
+        assert_eq!(
+            parse_errors(&["--parse-errors", "skip-n", "3"]).unwrap(),
+            skip_3
+        );
+        assert_eq!(
+            parse_errors(&["--parse-errors", "skip-n=3"]).unwrap(),
+            skip_3
+        );
+        assert_eq!(parse_errors(&["--parse-errors=skip-n=3"]).unwrap(), skip_3);
+        assert_eq!(
+            parse_errors(&["--parse-errors", "skip-n", "3", "--width", "64"]).unwrap(),
+            skip_3
+        );
+        assert_eq!(
+            parse_errors(&["--parse-errors", "skip-n", "3", "--parse-errors", "reply"]).unwrap(),
+            ParseErrorPolicy::Reply
+        );
+    }
+
+    #[test]
+    fn invalid_parse_error_policies() {
+        for arguments in [
+            &["--parse-errors", "close"][..],
+            &["--parse-errors", "skip-n"],
+            &["--parse-errors", "skip-n", "0"],
+            &["--parse-errors", "skip-n", "three"],
+            &["--parse-errors", "skip-n=-1"],
+            &["--parse-errors", "skip", "3"],
+            &["--parse-errors", "reply", "3"],
+        ] {
+            assert!(
+                parse_errors(arguments).is_err(),
+                "{arguments:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn instance_paths() {
+        let args = Args::try_parse_from(["wellenbrecher", "--instance", "blue"])
+            .unwrap()
+            .with_instance();
+        assert_eq!(args.canvas_file_link, "/tmp/wellenbrecher-blue-canvas");
+        assert_eq!(args.stats_file_link, "/tmp/wellenbrecher-blue-stats");
+        assert_eq!(
+            args.stats_dump,
+            Path::new("/tmp/wellenbrecher-blue-stats.json")
+        );
+
+        let args = Args::try_parse_from([
+            "wellenbrecher",
+            "--instance",
+            "blue",
+            "--stats-dump",
+            "/srv/stats.json",
+        ])
+        .unwrap()
+        .with_instance();
+        assert_eq!(args.stats_dump, Path::new("/srv/stats.json"));
+    }
+}
@@ -0,0 +1,88 @@
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::Arc;
+
+use clap::Parser;
+use toml::Value;
+
+use crate::cli::Args;
+use crate::ring::ring_coordination::{HelpText, Limits, Masks};
+
+/*
+The config file is a flat TOML table of command line options: the keys are the long option names,
+true enables a flag and arrays repeat an option. It is turned into arguments that are parsed before
+the actual command line, so that the command line overrides it and the environment only fills in
+what neither of them sets.
+ */
+
+/// Command line arguments setting the options of the config file at `path`
+pub fn arguments(path: &Path) -> eyre::Result<Vec<OsString>> {
+    let config = std::fs::read_to_string(path)
+        .map_err(|e| eyre::eyre!("unable to read config {}: {e}", path.display()))?;
+    let table = config
+        .parse::<toml::Table>()
+        .map_err(|e| eyre::eyre!("unable to parse config {}: {e}", path.display()))?;
+
+    let mut arguments = Vec::new();
+    for (key, value) in table {
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+
+        for value in values {
+            match value {
+                Value::Boolean(true) => arguments.push(format!("--{key}").into()),
+                Value::Boolean(false) => {}
+                Value::String(value) => arguments.push(format!("--{key}={value}").into()),
+                Value::Integer(_) | Value::Float(_) | Value::Datetime(_) => {
+                    arguments.push(format!("--{key}={value}").into())
+                }
+                Value::Array(_) | Value::Table(_) => {
+                    return Err(eyre::eyre!(
+                        "unable to parse config {}: {key} has to be a string, number, boolean or an array of them",
+                        path.display()
+                    ))
+                }
+            }
+        }
+    }
+
+    Ok(arguments)
+}
+
+/// Settings adjustable while the server is running, reloaded on SIGHUP
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub limits: Arc<Limits>,
+    pub masks: Arc<Masks>,
+    pub help_text: Arc<HelpText>,
+}
+
+impl Settings {
+    pub fn new(args: &Args) -> Self {
+        Self {
+            limits: Arc::new(args.limits()),
+            masks: Arc::new(Masks::new(args.ipv4_mask(), args.ipv6_mask())),
+            help_text: Arc::new(HelpText::new(&args.help_text())),
+        }
+    }
+
+    /// Parses the command line and the config file again and applies the settings.
+    /// The pixel budget interval and the masks of the firewall and bans stay as they are
+    pub fn reload(&self) -> eyre::Result<()> {
+        let args = Args::try_parse()?.with_config()?;
+
+        let limits = args.limits();
+        self.limits.set_ingress_rate(limits.ingress_rate());
+        self.limits.set_pixel_rate(limits.pixel_rate());
+        self.limits
+            .set_pixel_budget(limits.pixel_budget().map(|budget| budget.pixels));
+        self.limits.set_connections(limits.connections());
+        self.limits.set_user_connections(limits.user_connections());
+        self.masks.set(args.ipv4_mask(), args.ipv6_mask());
+        self.help_text.set(&args.help_text());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,587 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use core_affinity::CoreId;
+use socket2::Socket;
+use tracing::{debug, error, info, info_span, warn};
+
+use wellenbrecher_canvas::{Canvas, Stats};
+
+use crate::bans::Bans;
+use crate::cli::Args;
+use crate::config::Settings;
+use crate::ring::features::RingFeatures;
+use crate::ring::pixelflut_connection_handler::{
+    Connection, PixelflutConnectionHandler, STATS_FLUSH_INTERVAL,
+};
+use crate::ring::ring_coordination::{
+    is_full, listen_address, open_connections, refuse, stop_listening, write_stats_dump, NewClient,
+    UserState, DRAIN_CHECK_INTERVAL, SERVER_FULL, TOO_MANY_CONNECTIONS, UNIX_PEER,
+};
+use crate::{initial_pixels, notify, open_canvas};
+
+const EPOLL_EVENTS: usize = 256;
+/// Replies a client has not read yet, it is dropped once this is exceeded
+const MAX_PENDING_REPLIES: usize = 4 * 1024 * 1024;
+/// Time a listener is left alone after running out of file descriptors or buffers
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(500);
+
+/*
+The epoll backend runs one event loop per worker. The listening sockets are shared by all
+workers, EPOLLEXCLUSIVE makes sure only one of them is woken up per new connection.
+The clients stay with the worker that accepted them. Connections are read once per readiness
+notification, the commands are executed exactly like completed io_uring reads are.
+ */
+
+struct Client {
+    connection: Connection,
+    /// Replies not written to the socket yet
+    pending: Vec<u8>,
+    /// Registered epoll events
+    interest: c_int,
+}
+
+impl Client {
+    fn interest(&self) -> c_int {
+        match (self.connection.quit, self.pending.is_empty()) {
+            (true, _) => libc::EPOLLOUT,
+            (false, true) => libc::EPOLLIN,
+            (false, false) => libc::EPOLLIN | libc::EPOLLOUT,
+        }
+    }
+}
+
+struct Shared {
+    listeners: Vec<Socket>,
+    /// Readable once the server is shutting down
+    exit_fd: OwnedFd,
+    clients: Arc<RwLock<Vec<Arc<UserState>>>>,
+    settings: Settings,
+    bans: Arc<Bans>,
+}
+
+pub fn serve(
+    args: Args,
+    listeners: Vec<Socket>,
+    clients: Arc<RwLock<Vec<Arc<UserState>>>>,
+    cores: Vec<CoreId>,
+    canvas_open_lock: Arc<Mutex<()>>,
+    stats: Arc<Stats>,
+    bans: Arc<Bans>,
+) -> eyre::Result<()> {
+    info!("using the epoll backend");
+
+    for listener in listeners.iter() {
+        listener.set_nonblocking(true)?;
+        info!("Listening on {}", listen_address(listener));
+    }
+    let exit_fd = match unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) } {
+        fd if fd < 0 => return Err(io::Error::last_os_error().into()),
+        fd => unsafe { OwnedFd::from_raw_fd(fd) },
+    };
+    // reads are not throttled by this backend
+    let settings = Settings::new(&args);
+    settings.limits.set_ingress_rate(None);
+    let shared = Arc::new(Shared {
+        listeners,
+        exit_fd,
+        clients: clients.clone(),
+        settings,
+        bans,
+    });
+
+    let workers = cores
+        .into_iter()
+        .enumerate()
+        .map(|(i, core)| {
+            let args = args.clone();
+            let shared = shared.clone();
+            let canvas_open_lock = canvas_open_lock.clone();
+            let stats = stats.clone();
+            thread::Builder::new()
+                .name(format!("Epoll-{i}"))
+                .spawn(move || worker(core, i, args, shared, canvas_open_lock, stats))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // reset to the initial color or pattern on SIGUSR1
+    let mut canvas = open_canvas(&args, &canvas_open_lock)?;
+    notify::notify("READY=1");
+    let signals = handle_signals(&args, &shared, &clients, &stats, &mut canvas);
+
+    for (i, join_handle) in workers.into_iter().enumerate() {
+        match join_handle.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("worker {i} failed: {e}"),
+            Err(_) => error!("unable to join worker thread {i}"),
+        }
+    }
+
+    signals
+}
+
+/// Blocks until the server has to shut down, the signals are blocked by main
+fn handle_signals(
+    args: &Args,
+    shared: &Shared,
+    clients: &RwLock<Vec<Arc<UserState>>>,
+    stats: &Stats,
+    canvas: &mut Canvas,
+) -> eyre::Result<()> {
+    let signal_fd = unsafe {
+        let mut sig_set = std::mem::zeroed::<libc::sigset_t>();
+        libc::sigemptyset(std::ptr::addr_of_mut!(sig_set));
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGINT);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGQUIT);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGTERM);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGUSR1);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGUSR2);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGHUP);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGRTMIN());
+
+        match libc::signalfd(-1, std::ptr::addr_of!(sig_set), libc::SFD_CLOEXEC) {
+            fd if fd < 0 => {
+                return Err(eyre::eyre!(
+                    "unable to setup signal handler: {}",
+                    io::Error::last_os_error()
+                ))
+            }
+            fd => OwnedFd::from_raw_fd(fd),
+        }
+    };
+
+    // the signal loop pings the watchdog and checks on draining in between signals
+    let watchdog = notify::watchdog_interval();
+    let mut drain_deadline: Option<Instant> = None;
+    loop {
+        let timeout = [watchdog, drain_deadline.map(|_| DRAIN_CHECK_INTERVAL)]
+            .into_iter()
+            .flatten()
+            .min();
+        if let Some(timeout) = timeout {
+            let mut poll_fd = libc::pollfd {
+                fd: signal_fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            match unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as c_int) } {
+                n if n < 0 => match io::Error::last_os_error() {
+                    e if e.kind() == io::ErrorKind::Interrupted => continue,
+                    e => return Err(e.into()),
+                },
+                0 => {
+                    if watchdog.is_some() {
+                        notify::notify("WATCHDOG=1");
+                    }
+                    if let Some(deadline) = drain_deadline {
+                        let connections = open_connections(clients);
+                        if connections == 0 {
+                            info!("all connections are gone. Shutting down...");
+                            exit_workers(shared);
+                            return Ok(());
+                        }
+                        if Instant::now() >= deadline {
+                            info!("grace period is over, closing {connections} connections. Shutting down...");
+                            exit_workers(shared);
+                            return Ok(());
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        let mut siginfo = unsafe { std::mem::zeroed::<libc::signalfd_siginfo>() };
+        let n = unsafe {
+            libc::read(
+                signal_fd.as_raw_fd(),
+                std::ptr::addr_of_mut!(siginfo) as *mut _,
+                std::mem::size_of::<libc::signalfd_siginfo>(),
+            )
+        };
+        if n < 0 {
+            match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => continue,
+                e => return Err(e.into()),
+            }
+        }
+
+        let sig_name = unsafe {
+            CStr::from_ptr(libc::strsignal(siginfo.ssi_signo as c_int)).to_string_lossy()
+        };
+        match siginfo.ssi_signo as c_int {
+            libc::SIGINT | libc::SIGQUIT | libc::SIGTERM => {
+                info!("received {sig_name} signal. Shutting down...");
+                notify::notify("STOPPING=1");
+                exit_workers(shared);
+                return Ok(());
+            }
+            signal if signal == libc::SIGRTMIN() && drain_deadline.is_some() => {
+                info!("received {sig_name} signal. Already draining...");
+            }
+            signal if signal == libc::SIGRTMIN() => {
+                let grace_period = args.drain_grace_period();
+                info!("received {sig_name} signal. Draining connections for up to {grace_period:?}...");
+                notify::notify("STOPPING=1");
+                stop_listening(&shared.listeners);
+                drain_deadline = Some(Instant::now() + grace_period);
+            }
+            libc::SIGUSR2 => {
+                let clients = clients
+                    .read()
+                    .expect("unable to acquire lock on clients")
+                    .clone();
+                match write_stats_dump(&args.stats_dump, &clients, stats) {
+                    Ok(()) => info!(
+                        "received {sig_name} signal. Wrote stats to {}",
+                        args.stats_dump.display()
+                    ),
+                    Err(e) => error!(
+                        "received {sig_name} signal. Unable to write stats to {}: {e}",
+                        args.stats_dump.display()
+                    ),
+                }
+            }
+            libc::SIGHUP => match shared.settings.reload() {
+                Ok(()) => {
+                    shared.settings.limits.set_ingress_rate(None);
+                    info!("received {sig_name} signal. Reloaded the settings")
+                }
+                Err(e) => error!("received {sig_name} signal. Unable to reload the settings: {e}"),
+            },
+            libc::SIGUSR1 => {
+                canvas.reset(&initial_pixels(args, canvas.width(), canvas.height()));
+                info!("received {sig_name} signal. Cleared the canvas");
+            }
+            _ => info!("received {sig_name} signal. Ignoring..."),
+        }
+    }
+}
+
+/// Tells every worker to close its connections and exit
+fn exit_workers(shared: &Shared) {
+    let exit = 1u64;
+    if unsafe {
+        libc::write(
+            shared.exit_fd.as_raw_fd(),
+            std::ptr::addr_of!(exit) as *const _,
+            std::mem::size_of::<u64>(),
+        )
+    } < 0
+    {
+        error!("unable to shutdown gracefully\nAborting...");
+        std::process::exit(-1);
+    }
+}
+
+fn worker(
+    core: CoreId,
+    index: usize,
+    args: Args,
+    shared: Arc<Shared>,
+    canvas_open_lock: Arc<Mutex<()>>,
+    stats: Arc<Stats>,
+) -> eyre::Result<()> {
+    let _worker = info_span!("worker", index).entered();
+    if core_affinity::set_for_current(core) {
+        debug!("[worker: {index}] bound to core {core:?}");
+    } else {
+        warn!("[worker: {index}] unable to bind core {core:?}");
+    }
+
+    let canvas = open_canvas(&args, &canvas_open_lock)?;
+    // no io_uring operations are submitted by this backend
+    let mut handler = PixelflutConnectionHandler::new(
+        canvas,
+        args.parse_errors,
+        stats.clone(),
+        index,
+        args.palette.clone(),
+        RingFeatures::default(),
+        shared.settings.limits.clone(),
+        shared.settings.help_text.clone(),
+        args.max_rect_pixels,
+        None,
+    );
+
+    let epoll = match unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) } {
+        fd if fd < 0 => return Err(io::Error::last_os_error().into()),
+        fd => unsafe { OwnedFd::from_raw_fd(fd) },
+    };
+    let epoll = epoll.as_raw_fd();
+    for listener in shared.listeners.iter() {
+        epoll_ctl(
+            epoll,
+            libc::EPOLL_CTL_ADD,
+            listener.as_raw_fd(),
+            libc::EPOLLIN | libc::EPOLLEXCLUSIVE,
+        )?;
+    }
+    epoll_ctl(
+        epoll,
+        libc::EPOLL_CTL_ADD,
+        shared.exit_fd.as_raw_fd(),
+        libc::EPOLLIN,
+    )?;
+
+    let mut clients: HashMap<RawFd, Client> = HashMap::new();
+    let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; EPOLL_EVENTS];
+    // listeners taken out of the epoll set until the instant they are added back
+    let mut paused_listeners: Vec<RawFd> = Vec::new();
+    let mut resume_listeners = Instant::now();
+    loop {
+        // wake up once more to flush the stats after the last traffic
+        let timeout = [
+            handler
+                .has_unflushed_stats()
+                .then_some(STATS_FLUSH_INTERVAL),
+            (!paused_listeners.is_empty())
+                .then(|| resume_listeners.saturating_duration_since(Instant::now())),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .map_or(-1, |timeout| timeout.as_millis() as c_int);
+        let n =
+            unsafe { libc::epoll_wait(epoll, events.as_mut_ptr(), EPOLL_EVENTS as c_int, timeout) };
+        if n == 0 {
+            handler.flush_stats();
+        }
+        if !paused_listeners.is_empty() && Instant::now() >= resume_listeners {
+            for fd in paused_listeners.drain(..) {
+                epoll_ctl(
+                    epoll,
+                    libc::EPOLL_CTL_ADD,
+                    fd,
+                    libc::EPOLLIN | libc::EPOLLEXCLUSIVE,
+                )?;
+            }
+        }
+        if n < 0 {
+            match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => continue,
+                e => return Err(e.into()),
+            }
+        }
+
+        for event in events[..n as usize].iter() {
+            let (fd, flags) = (event.u64 as RawFd, event.events as c_int);
+
+            if fd == shared.exit_fd.as_raw_fd() {
+                // closes all client connections
+                drop(clients);
+                handler.flush_stats();
+                return Ok(());
+            }
+
+            if let Some(listener) = shared.listeners.iter().find(|l| l.as_raw_fd() == fd) {
+                if !accept(epoll, listener, &args, &shared, &stats, &mut clients)? {
+                    // the listener stays readable, so it would wake this worker up right away
+                    epoll_ctl(epoll, libc::EPOLL_CTL_DEL, fd, 0)?;
+                    if paused_listeners.is_empty() {
+                        resume_listeners = Instant::now() + ACCEPT_BACKOFF;
+                    }
+                    paused_listeners.push(fd);
+                }
+                continue;
+            }
+
+            let Some(client) = clients.get_mut(&fd) else {
+                continue;
+            };
+            let span = client.connection.span.clone();
+            let _entered = span.enter();
+            let keep = match flags & libc::EPOLLIN != 0 && !client.connection.quit {
+                true => read(&mut handler, client),
+                false => flags & (libc::EPOLLERR | libc::EPOLLHUP) == 0,
+            } && flush(epoll, client)?;
+
+            if !keep {
+                epoll_ctl(epoll, libc::EPOLL_CTL_DEL, fd, 0)?;
+                clients.remove(&fd);
+            }
+        }
+    }
+}
+
+/// Accepts all pending connections of a listener.
+/// Returns false if the listener has to back off, as the process or system ran out of file descriptors or buffers
+fn accept(
+    epoll: RawFd,
+    listener: &Socket,
+    args: &Args,
+    shared: &Shared,
+    stats: &Arc<Stats>,
+    clients: &mut HashMap<RawFd, Client>,
+) -> eyre::Result<bool> {
+    loop {
+        let (socket, address) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+            // shut down by draining
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                epoll_ctl(epoll, libc::EPOLL_CTL_DEL, listener.as_raw_fd(), 0)?;
+                return Ok(true);
+            }
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+                ) =>
+            {
+                warn!("failed to accept new client: {e}, pausing for {ACCEPT_BACKOFF:?}");
+                return Ok(false);
+            }
+            Err(e) => {
+                debug!("failed to accept new client: {e}");
+                return Ok(true);
+            }
+        };
+        let address = address.as_socket().unwrap_or(UNIX_PEER);
+        if shared.bans.is_banned(address.ip()) {
+            debug!("refused connection from banned {address}");
+            continue;
+        }
+        if is_full(&shared.settings.limits) {
+            debug!("server full, refused connection from {address}");
+            refuse(&socket, SERVER_FULL);
+            continue;
+        }
+        socket.set_nonblocking(true)?;
+        if address != UNIX_PEER {
+            if let Err(e) = args.socket_options().apply(&socket) {
+                warn!("unable to set socket options for {address}: {e}");
+            }
+        }
+
+        let fd = socket.as_raw_fd();
+        // local generators share one user
+        let max_connections = shared
+            .settings
+            .limits
+            .user_connections()
+            .filter(|_| address != UNIX_PEER);
+        let connection = match NewClient::new(
+            socket,
+            address,
+            &shared.clients,
+            &shared.settings.masks,
+            args.teams.as_ref(),
+            stats,
+            args.connection_buffer_size.get(),
+            max_connections,
+        ) {
+            Ok(new_client) => Connection::new(new_client),
+            Err(socket) => {
+                debug!("too many connections, refused connection from {address}");
+                refuse(&socket, TOO_MANY_CONNECTIONS);
+                continue;
+            }
+        };
+        epoll_ctl(epoll, libc::EPOLL_CTL_ADD, fd, libc::EPOLLIN)?;
+        clients.insert(
+            fd,
+            Client {
+                connection,
+                pending: Vec::new(),
+                interest: libc::EPOLLIN,
+            },
+        );
+    }
+}
+
+/// Returns false if the connection has to be closed
+fn read(handler: &mut PixelflutConnectionHandler, client: &mut Client) -> bool {
+    let connection = &mut client.connection;
+    let (ptr, len) = connection.read_buffer();
+    let n = unsafe { libc::read(connection.socket.as_raw_fd(), ptr as *mut _, len as usize) };
+
+    match n {
+        n if n > 0 => {
+            let Some(static_replies) =
+                handler.process_read(connection, &mut client.pending, n as usize)
+            else {
+                return false;
+            };
+            for reply in handler.static_replies(static_replies, connection) {
+                client.pending.extend_from_slice(reply);
+            }
+            true
+        }
+        0 => false,
+        _ => match io::Error::last_os_error() {
+            e if e.kind() == io::ErrorKind::WouldBlock => true,
+            e => {
+                warn!(
+                    "unable to read from connection {}: {e}; closing connection…",
+                    connection.address
+                );
+                false
+            }
+        },
+    }
+}
+
+/// Writes pending replies and waits for the socket to become writable if they don't fit.
+/// Returns false if the connection has to be closed
+fn flush(epoll: RawFd, client: &mut Client) -> io::Result<bool> {
+    let fd = client.connection.socket.as_raw_fd();
+
+    while !client.pending.is_empty() {
+        let n = unsafe {
+            libc::send(
+                fd,
+                client.pending.as_ptr() as *const _,
+                client.pending.len(),
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        if n < 0 {
+            match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::WouldBlock => break,
+                e => {
+                    debug!("unable to write to {}: {e}", client.connection.address);
+                    return Ok(false);
+                }
+            }
+        }
+        client.pending.drain(..n as usize);
+    }
+
+    if client.pending.len() > MAX_PENDING_REPLIES {
+        warn!(
+            "[user: {}] does not read its replies; closing connection…",
+            client.connection.user_id
+        );
+        return Ok(false);
+    }
+
+    if client.connection.quit && client.pending.is_empty() {
+        let _ = client.connection.socket.shutdown(std::net::Shutdown::Both);
+        return Ok(false);
+    }
+
+    if client.interest() != client.interest {
+        client.interest = client.interest();
+        epoll_ctl(epoll, libc::EPOLL_CTL_MOD, fd, client.interest)?;
+    }
+    Ok(true)
+}
+
+fn epoll_ctl(epoll: RawFd, op: c_int, fd: RawFd, events: c_int) -> io::Result<()> {
+    let mut event = libc::epoll_event {
+        events: events as u32,
+        u64: fd as u64,
+    };
+    match unsafe { libc::epoll_ctl(epoll, op, fd, std::ptr::addr_of_mut!(event)) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
@@ -1,38 +1,57 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 use nftables::batch::Batch;
-use nftables::expr::{BinaryOperation, Expression, NamedExpression, Payload, CT};
-use nftables::helper::apply_ruleset;
-use nftables::schema::{Chain, FlushObject, NfCmd, NfListObject, Rule, Table};
+use nftables::expr::{BinaryOperation, Elem, Expression, NamedExpression, Payload, Prefix, CT};
+use nftables::helper::{apply_ruleset, NftablesError};
+use nftables::schema::{
+    Chain, Element, FlushObject, NfCmd, NfListObject, Rule, Set, SetFlag, SetType, SetTypeValue,
+    Table,
+};
 use nftables::stmt::{CTCount, Match, Meter, Operator, Statement};
 use nftables::types::{NfChainPolicy, NfChainType, NfFamily, NfHook};
-use tracing::{debug, enabled, error, trace, Level};
+use tracing::{debug, enabled, error, info, trace, Level};
 
 const NFT_TABLE_NAME: &str = "WELLENBRECHER_FILTER";
 const NFT_IN_CHAIN_NAME: &str = "WELLENBRECHER_IN_CHAIN";
+const NFT_BAN_SET_NAME: &str = "WELLENBRECHER_BANNED";
 
-pub struct ConnectionLimit {
+/// Connection limit and bans for the pixelflut port.
+///
+/// Banned addresses live in an nftables set, so they are added and removed at runtime
+/// without touching the rules.
+#[derive(Debug)]
+pub struct Firewall {
     applied_once: AtomicBool,
     port: u16,
-    connections_per_ip: u32,
+    connections_per_ip: Option<u32>,
     table_ipv4: Table,
     table_ipv6: Table,
     in_chain_ipv4: Chain,
     in_chain_ipv6: Chain,
+    ban_set_ipv4: Set,
+    ban_set_ipv6: Set,
     ipv4_mask: Ipv4Addr,
     ipv6_mask: Ipv6Addr,
 }
 
-impl ConnectionLimit {
+impl Firewall {
     pub fn new(
+        instance: Option<&str>,
         port: u16,
-        connections_per_ip: u32,
+        connections_per_ip: Option<u32>,
         ipv4_mask: Ipv4Addr,
         ipv6_mask: Ipv6Addr,
     ) -> Self {
-        let table_ipv4 = Table::new(NfFamily::IP, String::from(NFT_TABLE_NAME));
-        let table_ipv6 = Table::new(NfFamily::IP6, String::from(NFT_TABLE_NAME));
+        // every instance flushes and removes only its own table
+        let table_name = match instance {
+            Some(name) => format!("{NFT_TABLE_NAME}_{name}"),
+            None => String::from(NFT_TABLE_NAME),
+        };
+        let table_ipv4 = Table::new(NfFamily::IP, table_name.clone());
+        let table_ipv6 = Table::new(NfFamily::IP6, table_name);
 
         let in_chain_ipv4 = Chain::new(
             table_ipv4.family.clone(),
@@ -49,6 +68,26 @@ impl ConnectionLimit {
             ..in_chain_ipv4.clone()
         };
 
+        // prefixes cover the masked addresses of a user, elements without a timeout stay forever
+        let ban_set_ipv4 = Set {
+            family: table_ipv4.family.clone(),
+            table: table_ipv4.name.clone(),
+            name: String::from(NFT_BAN_SET_NAME),
+            handle: None,
+            set_type: SetTypeValue::Single(SetType::Ipv4Addr),
+            policy: None,
+            flags: Some(HashSet::from([SetFlag::Interval, SetFlag::Timeout])),
+            elem: None,
+            timeout: None,
+            gc_interval: None,
+            size: None,
+        };
+        let ban_set_ipv6 = Set {
+            family: table_ipv6.family.clone(),
+            set_type: SetTypeValue::Single(SetType::Ipv6Addr),
+            ..ban_set_ipv4.clone()
+        };
+
         Self {
             applied_once: AtomicBool::new(false),
             port,
@@ -59,10 +98,12 @@ impl ConnectionLimit {
             table_ipv6,
             in_chain_ipv4,
             in_chain_ipv6,
+            ban_set_ipv4,
+            ban_set_ipv6,
         }
     }
 
-    pub fn apply(&self) -> Result<(), nftables::helper::NftablesError> {
+    pub fn apply(&self) -> Result<(), NftablesError> {
         let mut batch = Batch::new();
         batch.add_cmd(NfCmd::Add(NfListObject::Table(self.table_ipv4.clone())));
         batch.add_cmd(NfCmd::Add(NfListObject::Table(self.table_ipv6.clone())));
@@ -71,75 +112,112 @@ impl ConnectionLimit {
 
         batch.add_cmd(NfCmd::Add(NfListObject::Chain(self.in_chain_ipv4.clone())));
         batch.add_cmd(NfCmd::Add(NfListObject::Chain(self.in_chain_ipv6.clone())));
+        batch.add_cmd(NfCmd::Add(NfListObject::Set(self.ban_set_ipv4.clone())));
+        batch.add_cmd(NfCmd::Add(NfListObject::Set(self.ban_set_ipv6.clone())));
 
-        let add_limit_rule =
-            |batch: &mut Batch, table: &Table, chain: &Chain, proto: &str, mask: String| {
-                batch.add_cmd(NfCmd::Add(NfListObject::Rule(Rule {
-                    family: table.family.clone(),
-                    table: table.name.clone(),
-                    chain: chain.name.clone(),
-                    expr: vec![
-                        Statement::Match(Match {
-                            op: Operator::EQ,
-                            left: Expression::Named(NamedExpression::Payload(Payload {
-                                protocol: "tcp".to_string(),
-                                field: "dport".to_string(),
-                            })),
-                            right: Expression::Number(self.port as u32),
-                        }),
-                        Statement::Match(Match {
-                            op: Operator::EQ,
-                            left: Expression::Named(NamedExpression::CT(CT {
-                                family: None,
-                                dir: None,
-                                key: "state".to_string(),
-                            })),
-                            right: Expression::String("new".to_string()),
-                        }),
-                        Statement::Meter(Meter {
-                            name: "CONNECTION_METER".to_string(),
-                            key: Expression::BinaryOperation(BinaryOperation::AND(
-                                Box::new(Expression::Named(NamedExpression::Payload(Payload {
-                                    protocol: proto.to_string(),
-                                    field: "saddr".to_string(),
-                                }))),
-                                Box::new(Expression::String(mask.to_string())),
-                            )),
-                            stmt: Box::new(Statement::CTCount(CTCount {
-                                val: Expression::Number(self.connections_per_ip),
-                                inv: Some(true),
-                            })),
-                        }),
-                        Statement::Reject(None),
-                    ],
-                    handle: None,
-                    index: None,
-                    comment: None,
-                })));
-            };
-
-        add_limit_rule(
-            &mut batch,
-            &self.table_ipv4,
-            &self.in_chain_ipv4,
-            "ip",
-            self.ipv4_mask.to_string(),
-        );
-        add_limit_rule(
-            &mut batch,
-            &self.table_ipv6,
-            &self.in_chain_ipv6,
-            "ip6",
-            self.ipv6_mask.to_string(),
-        );
+        let add_ban_rule = |batch: &mut Batch, table: &Table, chain: &Chain, proto: &str| {
+            batch.add_cmd(NfCmd::Add(NfListObject::Rule(Rule {
+                family: table.family.clone(),
+                table: table.name.clone(),
+                chain: chain.name.clone(),
+                expr: vec![
+                    Statement::Match(Match {
+                        op: Operator::EQ,
+                        left: Expression::Named(NamedExpression::Payload(Payload {
+                            protocol: "tcp".to_string(),
+                            field: "dport".to_string(),
+                        })),
+                        right: Expression::Number(self.port as u32),
+                    }),
+                    Statement::Match(Match {
+                        op: Operator::EQ,
+                        left: Expression::Named(NamedExpression::Payload(Payload {
+                            protocol: proto.to_string(),
+                            field: "saddr".to_string(),
+                        })),
+                        right: Expression::String(format!("@{NFT_BAN_SET_NAME}")),
+                    }),
+                    Statement::Drop(None),
+                ],
+                handle: None,
+                index: None,
+                comment: None,
+            })));
+        };
 
-        let args = if enabled!(Level::TRACE) {
-            Some(vec!["-d", "all"])
-        } else {
-            None
+        add_ban_rule(&mut batch, &self.table_ipv4, &self.in_chain_ipv4, "ip");
+        add_ban_rule(&mut batch, &self.table_ipv6, &self.in_chain_ipv6, "ip6");
+
+        let add_limit_rule = |batch: &mut Batch,
+                              table: &Table,
+                              chain: &Chain,
+                              proto: &str,
+                              mask: String,
+                              connections_per_ip: u32| {
+            batch.add_cmd(NfCmd::Add(NfListObject::Rule(Rule {
+                family: table.family.clone(),
+                table: table.name.clone(),
+                chain: chain.name.clone(),
+                expr: vec![
+                    Statement::Match(Match {
+                        op: Operator::EQ,
+                        left: Expression::Named(NamedExpression::Payload(Payload {
+                            protocol: "tcp".to_string(),
+                            field: "dport".to_string(),
+                        })),
+                        right: Expression::Number(self.port as u32),
+                    }),
+                    Statement::Match(Match {
+                        op: Operator::EQ,
+                        left: Expression::Named(NamedExpression::CT(CT {
+                            family: None,
+                            dir: None,
+                            key: "state".to_string(),
+                        })),
+                        right: Expression::String("new".to_string()),
+                    }),
+                    Statement::Meter(Meter {
+                        name: "CONNECTION_METER".to_string(),
+                        key: Expression::BinaryOperation(BinaryOperation::AND(
+                            Box::new(Expression::Named(NamedExpression::Payload(Payload {
+                                protocol: proto.to_string(),
+                                field: "saddr".to_string(),
+                            }))),
+                            Box::new(Expression::String(mask.to_string())),
+                        )),
+                        stmt: Box::new(Statement::CTCount(CTCount {
+                            val: Expression::Number(connections_per_ip),
+                            inv: Some(true),
+                        })),
+                    }),
+                    Statement::Reject(None),
+                ],
+                handle: None,
+                index: None,
+                comment: None,
+            })));
         };
 
-        let result = apply_ruleset(&batch.to_nftables(), None, args);
+        if let Some(connections_per_ip) = self.connections_per_ip {
+            add_limit_rule(
+                &mut batch,
+                &self.table_ipv4,
+                &self.in_chain_ipv4,
+                "ip",
+                self.ipv4_mask.to_string(),
+                connections_per_ip,
+            );
+            add_limit_rule(
+                &mut batch,
+                &self.table_ipv6,
+                &self.in_chain_ipv6,
+                "ip6",
+                self.ipv6_mask.to_string(),
+                connections_per_ip,
+            );
+        }
+
+        let result = apply_ruleset(&batch.to_nftables(), None, Self::nft_args());
         _ = self
             .applied_once
             .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |applied_once| {
@@ -148,9 +226,91 @@ impl ConnectionLimit {
             .unwrap();
         result
     }
+
+    /// Drops all new packets of the user behind `address` to the pixelflut port,
+    /// for `duration` or until it is unbanned
+    pub fn ban(&self, address: IpAddr, duration: Option<Duration>) -> Result<(), NftablesError> {
+        let (set, prefix) = self.ban_set_element(address);
+        let elem = match duration {
+            Some(duration) => Expression::Named(NamedExpression::Elem(Elem {
+                val: Box::new(prefix),
+                timeout: Some(duration.as_secs().max(1) as u32),
+                expires: None,
+                comment: None,
+            })),
+            None => prefix,
+        };
+
+        let mut batch = Batch::new();
+        batch.add_cmd(NfCmd::Add(NfListObject::Element(Element {
+            family: set.family.clone(),
+            table: set.table.clone(),
+            name: set.name.clone(),
+            elem: vec![elem],
+        })));
+        apply_ruleset(&batch.to_nftables(), None, Self::nft_args())?;
+
+        match duration {
+            Some(duration) => info!("banned {address} for {duration:?}"),
+            None => info!("banned {address}"),
+        }
+        Ok(())
+    }
+
+    pub fn unban(&self, address: IpAddr) -> Result<(), NftablesError> {
+        let (set, prefix) = self.ban_set_element(address);
+
+        let mut batch = Batch::new();
+        batch.add_cmd(NfCmd::Delete(NfListObject::Element(Element {
+            family: set.family.clone(),
+            table: set.table.clone(),
+            name: set.name.clone(),
+            elem: vec![prefix],
+        })));
+        apply_ruleset(&batch.to_nftables(), None, Self::nft_args())?;
+
+        info!("unbanned {address}");
+        Ok(())
+    }
+
+    /// The set and the prefix covering all addresses that belong to the same user
+    fn ban_set_element(&self, address: IpAddr) -> (&Set, Expression) {
+        let (set, address, len) = match address {
+            IpAddr::V4(address) => (
+                &self.ban_set_ipv4,
+                IpAddr::V4(Ipv4Addr::from(
+                    u32::from(address) & u32::from(self.ipv4_mask),
+                )),
+                u32::from(self.ipv4_mask).count_ones(),
+            ),
+            IpAddr::V6(address) => (
+                &self.ban_set_ipv6,
+                IpAddr::V6(Ipv6Addr::from(
+                    u128::from(address) & u128::from(self.ipv6_mask),
+                )),
+                u128::from(self.ipv6_mask).count_ones(),
+            ),
+        };
+
+        (
+            set,
+            Expression::Prefix(Prefix {
+                addr: Box::new(Expression::String(address.to_string())),
+                len,
+            }),
+        )
+    }
+
+    fn nft_args() -> Option<Vec<&'static str>> {
+        if enabled!(Level::TRACE) {
+            Some(vec!["-d", "all"])
+        } else {
+            None
+        }
+    }
 }
 
-impl Drop for ConnectionLimit {
+impl Drop for Firewall {
     fn drop(&mut self) {
         if !*self.applied_once.get_mut() {
             return;
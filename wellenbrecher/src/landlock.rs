@@ -0,0 +1,157 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use libc::c_int;
+use tracing::{info, warn};
+
+/*
+Once everything is set up, Landlock keeps the process from creating, writing or removing files
+outside of the directories it works in, e.g. the ones of the canvas and stats file links. Reading
+stays allowed for reloading the config, executing only if nft is needed for the firewall.
+Like capabilities Landlock applies per thread, threads spawned before are not restricted.
+ */
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+// ABI 2
+const ACCESS_FS_REFER: u64 = 1 << 13;
+// ABI 3
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: c_int,
+}
+
+/// Restricts creating, writing and removing files to the directories `writable`, executing to `execute`.
+/// Kernels without Landlock are left unrestricted
+pub fn restrict(writable: &[PathBuf], execute: bool) -> eyre::Result<()> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 0 {
+        warn!(
+            "Landlock is not available, the filesystem stays unrestricted: {}",
+            io::Error::last_os_error()
+        );
+        return Ok(());
+    }
+
+    let mut write = ACCESS_FS_WRITE_FILE
+        | ACCESS_FS_REMOVE_FILE
+        | ACCESS_FS_MAKE_REG
+        | ACCESS_FS_MAKE_SOCK
+        | ACCESS_FS_MAKE_SYM;
+    let mut handled = write
+        | ACCESS_FS_EXECUTE
+        | ACCESS_FS_REMOVE_DIR
+        | ACCESS_FS_MAKE_CHAR
+        | ACCESS_FS_MAKE_DIR
+        | ACCESS_FS_MAKE_FIFO
+        | ACCESS_FS_MAKE_BLOCK;
+    if abi >= 2 {
+        handled |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= ACCESS_FS_TRUNCATE;
+        write |= ACCESS_FS_TRUNCATE;
+    }
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(error("create the Landlock ruleset"));
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as c_int) };
+
+    for path in writable {
+        allow(&ruleset, path, write)?;
+    }
+    if execute {
+        allow(&ruleset, Path::new("/"), ACCESS_FS_EXECUTE)?;
+    }
+
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(error("forbid gaining privileges"));
+        }
+        if libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) != 0 {
+            return Err(error("restrict the filesystem"));
+        }
+    }
+
+    info!("restricted the filesystem with Landlock ABI {abi}");
+    Ok(())
+}
+
+fn allow(ruleset: &OwnedFd, path: &Path, access: u64) -> eyre::Result<()> {
+    let dir = match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+        .open(path)
+    {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("{} does not exist, no access is granted", path.display());
+            return Ok(());
+        }
+        Err(e) => return Err(eyre::eyre!("unable to open {}: {e}", path.display())),
+    };
+
+    let rule = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: dir.as_raw_fd(),
+    };
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &rule,
+            0,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(error(&format!("allow access to {}", path.display()))),
+    }
+}
+
+fn error(action: &str) -> eyre::Report {
+    eyre::eyre!("unable to {action}: {}", io::Error::last_os_error())
+}
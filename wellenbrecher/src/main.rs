@@ -6,33 +6,62 @@
 #![feature(effects)]
 
 use std::fmt::Debug;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
 use std::num::NonZeroU32;
-use std::os::fd::{AsRawFd, RawFd};
+use std::os::fd::AsRawFd;
 use std::os::raw::c_int;
+use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 use core_affinity::CoreId;
 use nftables::helper::NftablesError;
+use rustls::ServerConfig;
 use shared_memory::ShmemError;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
-use tracing::{debug, error, info, warn, Level};
-use tracing_subscriber::EnvFilter;
-
-use wellenbrecher_canvas::{Bgra, Canvas, CanvasCreateInfo};
-
-use crate::cli::Args;
-use crate::firewall::ConnectionLimit;
+use tracing::{debug, error, info, info_span, warn, Level};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::Registry;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use wellenbrecher_canvas::instance::{self, Instance, INSTANCES_FILE};
+use wellenbrecher_canvas::{Bgra, Canvas, CanvasCreateInfo, Stats};
+
+use crate::bans::Bans;
+use crate::cli::{Args, Backend, LogFormat};
+use crate::config::Settings;
+use crate::firewall::Firewall;
+use crate::ring::admin::Admin;
+use crate::ring::datagram_receiver::DatagramReceiver;
+use crate::ring::features::RingFeatures;
 use crate::ring::pixelflut_connection_handler::PixelflutConnectionHandler;
-use crate::ring::ring_coordination::{RingCoordination, UserState};
+use crate::ring::ring_coordination::{Mailbox, RingCoordination, UserState};
 use crate::ring::write_buffer_drop::WriteBufferDrop;
 
+mod activation;
+mod bans;
 mod cli;
+mod config;
+mod epoll;
 mod firewall;
+mod landlock;
+mod mdns;
+mod notify;
+mod palette;
+mod privileges;
+mod replication;
 mod ring;
+mod schedule;
+mod snapshot;
+mod spectate;
+mod teams;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod tls;
 
 const HELP_TEXT: &[u8] = br#"Welcome to Pixelflut!
 
@@ -41,7 +70,20 @@ Commands:
     SIZE                -> get the size of the canvas
     PX <x> <y>          -> get the color of pixel (x, y)
     PX <x> <y> <COLOR>  -> set the color of pixel (x, y)
+    GETRECT <x> <y> <w> <h> [bin]
+                        -> get the colors of a region as rows of rrggbbaa or raw rgba bytes
     OFFSET <x> <y>      -> sets an pixel offset for all following commands
+    OFFSET +<x> -<y>    -> moves the pixel offset relative to the current one
+    FLIP H|V            -> mirrors all following commands horizontally or vertically, again to undo
+    ROTATE <degrees>    -> rotates all following commands clockwise by 0, 90, 180 or 270 degrees
+    PING [token]        -> get PONG [token] back, e.g. to keep the connection alive
+    MODE ack <n>        -> get ACK <n> after every n following commands, 0 turns it off
+    COMPRESS gzip|zstd  -> everything sent afterwards is compressed, replies are not
+    EXT / PROTOCOL      -> get the supported protocol extensions
+    QUIT / BYE          -> close the connection after all replies are sent
+
+    With a pixel budget, PX beyond it gets BUDGET EXHAUSTED <ms until the next pixel> back
+    With a pixel rate, PX above it is dropped and gets THROTTLED pixel-rate back
 
     COLOR:
         Grayscale: ww          ("00"       black .. "ff"       white)
@@ -79,81 +121,119 @@ macro_rules! print_and_return_error {
     }
 }
 
-fn setup_logging() -> eyre::Result<()> {
+/// Logs to stdout in `log_format`, `layer` additionally receives everything that is logged
+fn setup_logging(
+    log_format: LogFormat,
+    layer: Option<Box<dyn Layer<Registry> + Send + Sync>>,
+) -> eyre::Result<()> {
+    let level = match cfg!(debug_assertions) {
+        true => Level::DEBUG,
+        false => Level::INFO,
+    };
+    let filter = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy();
+    let registry = tracing_subscriber::registry().with(layer).with(filter);
+
+    if let LogFormat::Json = log_format {
+        registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true)
+                    .with_thread_names(true),
+            )
+            .try_init()?;
+        return Ok(());
+    }
+
     if cfg!(debug_assertions) {
-        let filter = EnvFilter::builder()
-            .with_default_directive(Level::DEBUG.into())
-            .from_env_lossy();
-
-        let subscriber = tracing_subscriber::fmt()
-            .with_env_filter(filter)
-            .compact()
-            //.with_file(true)
-            //.with_line_number(true)
-            .with_thread_names(true)
-            .without_time()
-            .finish();
-        tracing::subscriber::set_global_default(subscriber)?;
+        registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .compact()
+                    //.with_file(true)
+                    //.with_line_number(true)
+                    .with_thread_names(true)
+                    .without_time(),
+            )
+            .try_init()?;
     } else {
-        let filter = EnvFilter::builder()
-            .with_default_directive(Level::INFO.into())
-            .from_env_lossy();
-
-        let subscriber = tracing_subscriber::fmt()
-            .with_env_filter(filter)
-            .compact()
-            .with_thread_names(true)
-            .with_target(false)
-            .with_thread_names(true)
-            .finish();
-        tracing::subscriber::set_global_default(subscriber)?;
+        registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .compact()
+                    .with_thread_names(true)
+                    .with_target(false)
+                    .with_thread_names(true),
+            )
+            .try_init()?;
     }
 
     Ok(())
 }
 
 fn configure_firewall(
+    instance: Option<&str>,
     connections_per_ip: Option<NonZeroU32>,
+    nft_bans: bool,
     port: u16,
     ipv4_mask: Ipv4Addr,
     ipv6_mask: Ipv6Addr,
-) -> eyre::Result<Option<Arc<ConnectionLimit>>> {
-    match connections_per_ip.map(|connections_per_ip| {
-        debug!("enforcing connection limit…");
-        Arc::new(ConnectionLimit::new(
+) -> eyre::Result<Option<Arc<Firewall>>> {
+    match (connections_per_ip.is_some() || nft_bans).then(|| {
+        debug!("applying nftables rules…");
+        Arc::new(Firewall::new(
+            instance,
             port,
-            connections_per_ip.get(),
+            connections_per_ip.map(NonZeroU32::get),
             ipv4_mask,
             ipv6_mask,
         ))
     }) {
         None => Ok(None),
-        Some(firewall) => {
-            match firewall.apply() {
-                Ok(()) => Ok(Some(firewall)),
-                Err(NftablesError::NftFailed {
-                        program,
-                        mut stdout,
-                        mut stderr,
-                        hint,
-                    }) => Err(eyre::eyre!("unable to enforce connection limits: {program} returned with an error while {hint}{}{}",
-                if !stdout.is_empty() { stdout.insert(0, '\n'); stdout.as_str()} else { "" },
-                if !stderr.is_empty() { stderr.insert(0, '\n'); stderr.as_str()} else { "" })),
-                Err(e) => Err(eyre::eyre!("unable to enforce connection limits: {e} (Is nftables installed?)")),
-            }
-        }
+        Some(firewall) => match firewall.apply() {
+            Ok(()) => Ok(Some(firewall)),
+            Err(NftablesError::NftFailed {
+                program,
+                mut stdout,
+                mut stderr,
+                hint,
+            }) => Err(eyre::eyre!(
+                "unable to apply nftables rules: {program} returned with an error while {hint}{}{}",
+                if !stdout.is_empty() {
+                    stdout.insert(0, '\n');
+                    stdout.as_str()
+                } else {
+                    ""
+                },
+                if !stderr.is_empty() {
+                    stderr.insert(0, '\n');
+                    stderr.as_str()
+                } else {
+                    ""
+                }
+            )),
+            Err(e) => Err(eyre::eyre!(
+                "unable to apply nftables rules: {e} (Is nftables installed?)"
+            )),
+        },
     }
 }
 
 fn main() -> eyre::Result<()> {
-    setup_logging()?;
-
     unsafe {
         let mut sig_set = std::mem::zeroed::<libc::sigset_t>();
         libc::sigemptyset(std::ptr::addr_of_mut!(sig_set));
         libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGINT);
         libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGQUIT);
         libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGTERM);
+        // handled by the empress, see RingCoordination
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGUSR1);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGUSR2);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGHUP);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGRTMIN());
 
         if libc::sigprocmask(
             libc::SIG_BLOCK,
@@ -165,7 +245,14 @@ fn main() -> eyre::Result<()> {
         }
     };
 
-    let args = cli::Args::parse();
+    let args = cli::Args::parse().with_config()?;
+    #[cfg(feature = "otel")]
+    let telemetry = args.otel.then(telemetry::Telemetry::new).transpose()?;
+    #[cfg(feature = "otel")]
+    let layer = telemetry.as_ref().map(telemetry::Telemetry::layer);
+    #[cfg(not(feature = "otel"))]
+    let layer = None;
+    setup_logging(args.log_format, layer)?;
     if args.remove_canvas {
         return remove_canvas(args.canvas_file_link);
     }
@@ -174,14 +261,40 @@ fn main() -> eyre::Result<()> {
         println!("{BANNER}");
     }
 
-    let firewall = configure_firewall(
+    if let Some(primary) = args.mirror_of.clone() {
+        return replication::mirror(primary, args);
+    }
+
+    let clients: Arc<RwLock<Vec<Arc<UserState>>>> = match args.standby_of.clone() {
+        Some(primary) => match replication::standby(primary, &args)? {
+            Some(clients) => Arc::new(RwLock::new(clients)),
+            None => return Ok(()),
+        },
+        None => Default::default(),
+    };
+
+    let firewall = match configure_firewall(
+        args.instance.as_deref(),
         args.connections_per_ip,
+        args.nft_bans,
         args.port,
-        args.ipv4_mask,
-        args.ipv6_mask,
-    )?;
-
-    let clients: Arc<RwLock<Vec<Arc<UserState>>>> = Default::default();
+        args.ipv4_mask(),
+        args.ipv6_mask(),
+    ) {
+        Ok(firewall) => firewall,
+        // the server refuses further connections on its own as well
+        Err(e) if !args.nft_bans => {
+            warn!("{e}; --connections-per-ip and bans are only enforced by the server itself");
+            None
+        }
+        Err(e) => return Err(e),
+    };
+    let bans = Arc::new(Bans::new(
+        firewall.clone(),
+        firewall.is_none() && args.connections_per_ip.is_some(),
+        args.ipv4_mask(),
+        args.ipv6_mask(),
+    ));
 
     // protect the process of creating or opening the shared memory
     let canvas_open_lock = Arc::new(Mutex::new(()));
@@ -190,27 +303,310 @@ fn main() -> eyre::Result<()> {
         Some(cores) => cores,
         None => print_and_return_error!("unable to get core ids"),
     };
+    let worker_count = match args.threads {
+        Some(threads) => cores.len().min(threads.get()),
+        None => cores.len(),
+    };
+    let features = match args.backend {
+        Backend::Auto => match RingFeatures::probe() {
+            Ok(features) => Some(features),
+            Err(e) => {
+                warn!("{e}; falling back to the epoll backend");
+                None
+            }
+        },
+        Backend::IoUring => Some(RingFeatures::probe()?),
+        Backend::Epoll => None,
+    };
+
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(cert, key)?),
+        _ => None,
+    };
+
+    let mut listeners = bind_listeners(&args)?;
+    // every io_uring worker receives datagrams on sockets of its own
+    let datagram_sockets = match (args.udp, features.is_some()) {
+        (true, true) => (0..worker_count)
+            .map(|_| bind_datagram_sockets(&args))
+            .collect::<eyre::Result<Vec<_>>>()?,
+        _ => Vec::new(),
+    };
+    if let Some(user) = &args.user {
+        privileges::drop_privileges(user, args.group.as_deref(), firewall.is_some())?;
+    }
+    if let Some(path) = &args.unix_socket {
+        listeners.push(bind_unix_listener(
+            path,
+            args.tcp_accept_backlog.get() as c_int,
+        )?);
+    }
+    // created before Landlock forbids creating directories
+    if let Some(dir) = &args.snapshot_dir {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| eyre::eyre!("unable to create {}: {e}", dir.display()))?;
+    }
+    if args.landlock {
+        landlock::restrict(&args.writable_directories(), firewall.is_some())?;
+    }
+
+    let stats = Arc::new(Stats::create(
+        args.stats_file_link.as_ref(),
+        worker_count as u32,
+        args.stats_users.get(),
+    )?);
+    // clients taken over from the primary
+    for (i, state) in clients
+        .read()
+        .expect("unable to acquire lock on clients")
+        .iter()
+        .enumerate()
+    {
+        stats.set_team((i + 1) as u32, state.team);
+    }
+    #[cfg(feature = "otel")]
+    if let Some(telemetry) = &telemetry {
+        telemetry.export_metrics(stats.clone(), clients.clone());
+    }
+
+    // created before the workers start, kept open to write the snapshot once they are gone
+    let mut canvas = open_canvas(&args, &canvas_open_lock)?;
+    // once for all workers, the pages are shared by their mappings
+    if args.prefault {
+        let start = Instant::now();
+        canvas.prefault();
+        debug!("prefaulted the canvas in {:?}", start.elapsed());
+    }
+    let snapshot = args.snapshot.clone();
+    if let Some(path) = &snapshot {
+        // a canvas taken over from the primary is newer
+        if path.exists() && args.standby_of.is_none() {
+            match snapshot::restore(&mut canvas, path) {
+                Ok((width, height)) => info!(
+                    "restored the {width}x{height} canvas from {}",
+                    path.display()
+                ),
+                Err(e) => error!("unable to restore the canvas from {}: {e}", path.display()),
+            }
+        }
+    }
+
+    if let Some(name) = &args.instance {
+        let instance = Instance {
+            name: name.clone(),
+            pid: std::process::id(),
+            canvas_file_link: args.canvas_file_link.clone(),
+            stats_file_link: args.stats_file_link.clone(),
+        };
+        match instance::register(&instance) {
+            Ok(()) => info!("running as instance {name}"),
+            Err(e) => warn!("unable to list instance {name} in {INSTANCES_FILE}: {e}"),
+        }
+    }
+
+    if let Some(address) = args.replication_listen {
+        let listener = TcpListener::bind(address)?;
+        let args = args.clone();
+        let clients = clients.clone();
+        let canvas_open_lock = canvas_open_lock.clone();
+        thread::Builder::new()
+            .name("Replication".to_string())
+            .spawn(move || {
+                if let Err(e) = replication::serve(listener, args, clients, canvas_open_lock) {
+                    error!("canvas replication failed: {e}");
+                }
+            })?;
+    }
+
+    if let Some(schedule) = args.clear_schedule.clone() {
+        let args = args.clone();
+        let canvas_open_lock = canvas_open_lock.clone();
+        thread::Builder::new()
+            .name("Clear schedule".to_string())
+            .spawn(move || match open_canvas(&args, &canvas_open_lock) {
+                Ok(canvas) => schedule.run(
+                    canvas,
+                    Duration::from_secs(args.clear_warning),
+                    |width, height| initial_pixels(&args, width, height),
+                ),
+                Err(e) => error!("unable to open the canvas for scheduled clears: {e}"),
+            })?;
+    }
+
+    if let Some(dir) = args.snapshot_dir.clone() {
+        let args = args.clone();
+        let canvas_open_lock = canvas_open_lock.clone();
+        thread::Builder::new()
+            .name("Snapshots".to_string())
+            .spawn(move || match open_canvas(&args, &canvas_open_lock) {
+                Ok(canvas) => snapshot::run(
+                    &canvas,
+                    Duration::from_secs(args.snapshot_interval.get()),
+                    &dir,
+                ),
+                Err(e) => error!("unable to open the canvas for snapshots: {e}"),
+            })?;
+    }
+
+    if let Some(address) = args.spectator_listen {
+        let listener = TcpListener::bind(address)?;
+        let args = args.clone();
+        let canvas_open_lock = canvas_open_lock.clone();
+        thread::Builder::new()
+            .name("Spectators".to_string())
+            .spawn(move || {
+                if let Err(e) = spectate::serve(listener, args, canvas_open_lock) {
+                    error!("spectator streaming failed: {e}");
+                }
+            })?;
+    }
+
+    let announcement = match args.mdns {
+        true => Some(mdns::Announcement::new(&args)?),
+        false => None,
+    };
+
+    let cores = cores.into_iter().take(worker_count).collect::<Vec<_>>();
+    let unix_socket = args.unix_socket.clone();
+    let instance_name = args.instance.clone();
+    match features {
+        Some(features) => serve_io_uring(
+            args,
+            features,
+            listeners,
+            datagram_sockets,
+            clients,
+            cores,
+            canvas_open_lock,
+            stats,
+            bans,
+            tls,
+        )?,
+        None => {
+            if tls.is_some() {
+                return Err(eyre::eyre!("the epoll backend does not support TLS"));
+            }
+            if args.max_ingress_rate.is_some() {
+                warn!("the epoll backend does not throttle reads, --max-ingress-rate is ignored");
+            }
+            if args.udp {
+                warn!("the epoll backend does not receive datagrams, --udp is ignored");
+            }
+            if args.idle_timeout.is_some() {
+                warn!(
+                    "the epoll backend does not close idle connections, --idle-timeout is ignored"
+                );
+            }
+            if args.admin_socket.is_some() {
+                warn!(
+                    "the epoll backend does not serve the admin socket, --admin-socket is ignored"
+                );
+            }
+            epoll::serve(
+                args,
+                listeners,
+                clients,
+                cores,
+                canvas_open_lock,
+                stats,
+                bans,
+            )?
+        }
+    }
+
+    drop(announcement);
+    drop(firewall);
+    if let Some(name) = instance_name {
+        if let Err(e) = instance::unregister(&name) {
+            warn!("unable to remove instance {name} from {INSTANCES_FILE}: {e}");
+        }
+    }
+    if let Some(path) = snapshot {
+        match snapshot::write(&canvas, &path) {
+            Ok(()) => info!("wrote the canvas to {}", path.display()),
+            Err(e) => error!("unable to write the canvas to {}: {e}", path.display()),
+        }
+    }
+    // nothing is lost across a restart, e.g. after draining, shared memory has nothing to flush
+    if let Err(e) = canvas.flush() {
+        warn!("unable to flush the canvas: {e}");
+    }
+    if let Some(path) = unix_socket {
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("unable to remove unix socket {}: {e}", path.display());
+        }
+    }
+
+    info!("Exiting...");
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn serve_io_uring(
+    args: Args,
+    mut features: RingFeatures,
+    listeners: Vec<Socket>,
+    datagram_sockets: Vec<Vec<Socket>>,
+    clients: Arc<RwLock<Vec<Arc<UserState>>>>,
+    cores: Vec<CoreId>,
+    canvas_open_lock: Arc<Mutex<()>>,
+    stats: Arc<Stats>,
+    bans: Arc<Bans>,
+    tls: Option<Arc<ServerConfig>>,
+) -> eyre::Result<()> {
+    if tls.is_some() {
+        // handshake threads hand the connections over to the workers through their eventfd
+        features.msg_ring = false;
+        // kTLS sockets do not support zero-copy sends
+        features.send_zc = false;
+    }
+
+    let settings = Settings::new(&args);
     let mut workers = Vec::new();
+    let mut datagram_sockets = datagram_sockets.into_iter();
 
-    let (fd_rx, primary_core, primary_index) =
+    let (mailbox_rx, primary_core, primary_index, primary_datagram_sockets) =
         {
-            let (fd_tx, fd_rx) = std::sync::mpsc::channel();
-            let mut worker_iter = cores
-                .into_iter()
-                .enumerate()
-                .take_while(|(i, _)| args.threads.is_none() || *i < args.threads.unwrap().get());
+            let (mailbox_tx, mailbox_rx) = std::sync::mpsc::channel();
+            let mut worker_iter = cores.into_iter().enumerate();
 
             let (primary_index, primary_core) = worker_iter.next().unwrap();
+            let primary_datagram_sockets = datagram_sockets.next().unwrap_or_default();
             for (i, core) in worker_iter {
+                let datagram_sockets = datagram_sockets.next().unwrap_or_default();
                 let args = args.clone();
-                let fd_tx = fd_tx.clone();
+                let clients = clients.clone();
+                let mailbox_tx = mailbox_tx.clone();
                 let canvas_open_lock = canvas_open_lock.clone();
+                let stats = stats.clone();
+                let settings = settings.clone();
+                let bans = bans.clone();
                 workers.push(thread::Builder::new().name(format!("Lackey-{i}")).spawn(
-                    move || lackey(args.io_uring_size, core, i, args, fd_tx, canvas_open_lock),
+                    move || {
+                        lackey(
+                            args.io_uring_size,
+                            core,
+                            i,
+                            args,
+                            clients,
+                            features,
+                            mailbox_tx,
+                            datagram_sockets,
+                            canvas_open_lock,
+                            stats,
+                            settings,
+                            bans,
+                        )
+                    },
                 )?);
             }
 
-            (fd_rx, primary_core, primary_index)
+            (
+                mailbox_rx,
+                primary_core,
+                primary_index,
+                primary_datagram_sockets,
+            )
         };
 
     {
@@ -224,8 +620,15 @@ fn main() -> eyre::Result<()> {
                     primary_core,
                     primary_index,
                     args,
-                    fd_rx,
+                    features,
+                    mailbox_rx,
+                    listeners,
+                    primary_datagram_sockets,
                     canvas_open_lock,
+                    stats,
+                    settings,
+                    bans,
+                    tls,
                 )
             })?
             .join()
@@ -240,41 +643,30 @@ fn main() -> eyre::Result<()> {
         }
     }
 
-    drop(firewall);
-
-    info!("Exiting...");
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn empress(
     ring_size: NonZeroU32,
     clients: Arc<RwLock<Vec<Arc<UserState>>>>,
     core: CoreId,
     index: usize,
     args: Args,
-    fd_rx: std::sync::mpsc::Receiver<RawFd>,
+    features: RingFeatures,
+    mailbox_rx: std::sync::mpsc::Receiver<Mailbox>,
+    listeners: Vec<Socket>,
+    datagram_sockets: Vec<Socket>,
     canvas_open_lock: Arc<Mutex<()>>,
+    stats: Arc<Stats>,
+    settings: Settings,
+    bans: Arc<Bans>,
+    tls: Option<Arc<ServerConfig>>,
 ) -> eyre::Result<()> {
     let ring = ring::pixel_flut_ring::Ring::new_raw_ring(ring_size)?;
+    let (mailbox, inbox) = Mailbox::new(&ring, features)?;
 
-    let socket6 = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
-    socket6.set_only_v6(true)?;
-    socket6.set_reuse_address(true)?;
-    socket6.bind(&SockAddr::from(SocketAddr::from((
-        Ipv6Addr::UNSPECIFIED,
-        args.port,
-    ))))?;
-    socket6.listen(args.tcp_accept_backlog.get() as c_int)?;
-
-    let socket4 = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
-    socket4.set_reuse_address(true)?;
-    socket4.bind(&SockAddr::from(SocketAddr::from((
-        Ipv4Addr::UNSPECIFIED,
-        args.port,
-    ))))?;
-    socket4.listen(args.tcp_accept_backlog.get() as c_int)?;
-
-    let ring_fds = fd_rx.iter().chain([ring.as_raw_fd()]).collect::<Vec<_>>();
+    let mailboxes = mailbox_rx.iter().chain([mailbox]).collect::<Vec<_>>();
 
     let signal_fd = unsafe {
         let mut sig_set = std::mem::zeroed::<libc::sigset_t>();
@@ -282,6 +674,10 @@ fn empress(
         libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGINT);
         libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGQUIT);
         libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGTERM);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGUSR1);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGUSR2);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGHUP);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGRTMIN());
 
         if libc::sigprocmask(
             libc::SIG_BLOCK,
@@ -303,91 +699,222 @@ fn empress(
         }
     };
 
+    let admin = match &args.admin_socket {
+        Some(path) => Some(Admin::bind(
+            path,
+            open_canvas(&args, &canvas_open_lock)?,
+            initial_pixels(&args, args.width.get(), args.height.get()),
+            clients.clone(),
+            stats.clone(),
+            settings.limits.clone(),
+            bans.clone(),
+            settings.masks.clone(),
+        )?),
+        None => None,
+    };
+
     worker(
         core,
         index,
         ring,
         RingCoordination::empress(
-            vec![socket6, socket4],
-            ring_fds,
+            listeners,
+            mailboxes,
+            inbox,
+            features,
             signal_fd,
             args.connection_buffer_size,
-            clients,
-            args.ipv4_mask,
-            args.ipv6_mask,
+            args.socket_options(),
+            clients.clone(),
+            args.teams.clone(),
+            stats.clone(),
+            args.stats_dump.clone(),
+            bans.clone(),
+            settings.clone(),
+            tls,
+            admin,
+            open_canvas(&args, &canvas_open_lock)?,
+            initial_pixels(&args, args.width.get(), args.height.get()),
+            args.idle_timeout(),
+            notify::watchdog_interval(),
+            args.drain_grace_period(),
         ),
         args,
+        clients,
+        features,
+        datagram_sockets,
         canvas_open_lock,
+        stats,
+        settings,
+        bans,
     )
 }
 
+/// Listeners on the --listen addresses, every interface on the pixelflut port by default,
+/// or the ones passed by systemd socket activation
+fn bind_listeners(args: &Args) -> eyre::Result<Vec<Socket>> {
+    if let Some(listeners) = activation::listen_fds(args.port)? {
+        if !args.listen.is_empty() {
+            warn!("ignoring --listen, the listeners passed by socket activation are used");
+        }
+        return Ok(listeners);
+    }
+
+    args.listen_addresses()?
+        .into_iter()
+        .map(|address| {
+            let socket = Socket::new(
+                Domain::for_address(address),
+                Type::STREAM,
+                Some(Protocol::TCP),
+            )?;
+            if address.is_ipv6() {
+                socket.set_only_v6(true)?;
+            }
+            socket.set_reuse_address(true)?;
+            socket
+                .bind(&SockAddr::from(address))
+                .map_err(|e| eyre::eyre!("unable to listen on {address}: {e}"))?;
+            socket.listen(args.tcp_accept_backlog.get() as c_int)?;
+
+            if address.port() != args.port {
+                warn!(
+                    "listening on {address}, firewall rules and announcements use --port {}",
+                    args.port
+                );
+            }
+            Ok(socket)
+        })
+        .collect()
+}
+
+/// A leftover socket of a previous run is replaced, access is up to the permissions of its directory
+fn bind_unix_listener(path: &Path, backlog: c_int) -> eyre::Result<Socket> {
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket
+        .bind(&SockAddr::unix(path)?)
+        .map_err(|e| eyre::eyre!("unable to listen on {}: {e}", path.display()))?;
+    socket.listen(backlog)?;
+    Ok(socket)
+}
+
+/// IPv6 and IPv4 UDP sockets on the pixelflut port, bound by every worker
+fn bind_datagram_sockets(args: &Args) -> eyre::Result<Vec<Socket>> {
+    let socket6 = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket6.set_only_v6(true)?;
+    socket6.set_reuse_port(true)?;
+    socket6.bind(&SockAddr::from(SocketAddr::from((
+        Ipv6Addr::UNSPECIFIED,
+        args.port,
+    ))))?;
+
+    let socket4 = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket4.set_reuse_port(true)?;
+    socket4.bind(&SockAddr::from(SocketAddr::from((
+        Ipv4Addr::UNSPECIFIED,
+        args.port,
+    ))))?;
+
+    Ok(vec![socket6, socket4])
+}
+
+#[allow(clippy::too_many_arguments)]
 fn lackey(
     ring_size: NonZeroU32,
     core: CoreId,
     index: usize,
     args: Args,
-    fd_tx: std::sync::mpsc::Sender<RawFd>,
+    clients: Arc<RwLock<Vec<Arc<UserState>>>>,
+    features: RingFeatures,
+    mailbox_tx: std::sync::mpsc::Sender<Mailbox>,
+    datagram_sockets: Vec<Socket>,
     canvas_open_lock: Arc<Mutex<()>>,
+    stats: Arc<Stats>,
+    settings: Settings,
+    bans: Arc<Bans>,
 ) -> eyre::Result<()> {
     let ring = ring::pixel_flut_ring::Ring::new_raw_ring(ring_size)?;
-    fd_tx.send(ring.as_raw_fd())?;
-    drop(fd_tx);
+    let (mailbox, inbox) = Mailbox::new(&ring, features)?;
+    mailbox_tx.send(mailbox)?;
+    drop(mailbox_tx);
 
     worker(
         core,
         index,
         ring,
-        RingCoordination::lackey(),
+        RingCoordination::lackey(inbox, args.idle_timeout()),
         args,
+        clients,
+        features,
+        datagram_sockets,
         canvas_open_lock,
+        stats,
+        settings,
+        bans,
     )
 }
 
+#[allow(clippy::too_many_arguments)]
 fn worker(
     core: CoreId,
     index: usize,
     ring: rummelplatz::io_uring::IoUring,
     coordination: RingCoordination,
     args: Args,
+    clients: Arc<RwLock<Vec<Arc<UserState>>>>,
+    features: RingFeatures,
+    datagram_sockets: Vec<Socket>,
     canvas_open_lock: Arc<Mutex<()>>,
+    stats: Arc<Stats>,
+    settings: Settings,
+    bans: Arc<Bans>,
 ) -> eyre::Result<()> {
+    let _worker = info_span!("worker", index).entered();
     if core_affinity::set_for_current(core) {
         debug!("[worker: {index}] bound to core {core:?}");
     } else {
         warn!("[worker: {index}] unable to bind core {core:?}");
     }
 
-    let canvas = {
-        let lock = canvas_open_lock
-            .lock()
-            .expect("unable to lock canvas_open_lock");
-
-        let canvas = match Canvas::open(
-            args.canvas_file_link.as_ref(),
-            true,
-            Some(CanvasCreateInfo {
-                width: args.width.get(),
-                height: args.height.get(),
-                initial_canvas: vec![
-                    Bgra::default();
-                    (args.width.get() * args.height.get()) as usize
-                ]
-                .into_boxed_slice(),
-            }),
-        ) {
-            Ok(canvas) => canvas,
-            Err(e) => return Err(e.into()),
-        };
-
-        drop(lock);
-        canvas
+    let canvas = open_canvas(&args, &canvas_open_lock)?;
+    let datagram_canvas = match datagram_sockets.is_empty() {
+        true => None,
+        false => Some(canvas.reopen()?),
     };
+    let datagram_receiver = DatagramReceiver::new(
+        datagram_sockets,
+        datagram_canvas,
+        args.palette.clone(),
+        settings.limits.clone(),
+        bans,
+        stats.clone(),
+        index,
+        clients,
+        settings.masks,
+        args.teams.clone(),
+    );
 
     let mut ring = ring::pixel_flut_ring::Ring::new(
         ring,
         None,
-        PixelflutConnectionHandler::new(canvas),
+        PixelflutConnectionHandler::new(
+            canvas,
+            args.parse_errors,
+            stats,
+            index,
+            args.palette.clone(),
+            features,
+            settings.limits,
+            settings.help_text,
+            args.max_rect_pixels,
+            args.idle_timeout(),
+        ),
         WriteBufferDrop,
+        datagram_receiver,
         coordination,
     );
 
@@ -395,7 +922,54 @@ fn worker(
     Ok(())
 }
 
+fn open_canvas(args: &Args, canvas_open_lock: &Mutex<()>) -> eyre::Result<Canvas> {
+    let lock = canvas_open_lock
+        .lock()
+        .expect("unable to lock canvas_open_lock");
+
+    let create_info = CanvasCreateInfo {
+        width: args.width.get(),
+        height: args.height.get(),
+        initial_canvas: initial_pixels(args, args.width.get(), args.height.get()),
+        timestamps: args.pixel_timestamps,
+        user_counters: args.user_counters(),
+    };
+
+    let canvas = Canvas::create(
+        args.canvas_file_link.as_ref(),
+        args.shm_backend,
+        true,
+        create_info,
+    )?;
+
+    drop(lock);
+    Ok(canvas)
+}
+
+/// The initial color or pattern of a canvas of the given size
+fn initial_pixels(args: &Args, width: u32, height: u32) -> Box<[Bgra]> {
+    match args.initial_pattern {
+        Some(pattern) => pattern.generate(width, height),
+        None => vec![args.initial_color.unwrap_or_default(); (width * height) as usize]
+            .into_boxed_slice(),
+    }
+}
+
 fn remove_canvas<P: AsRef<Path> + Debug + Clone>(path: P) -> eyre::Result<()> {
+    // a memfd canvas is only linked to, it goes away along with its server
+    if path
+        .as_ref()
+        .symlink_metadata()
+        .is_ok_and(|metadata| metadata.is_symlink())
+    {
+        return Ok(std::fs::remove_file(path.as_ref())?);
+    }
+
+    // dropping a non-persistent canvas bumps its generation for remaining mappings and removes it
+    if Canvas::open(path.as_ref(), false, None).is_ok() {
+        return Ok(());
+    }
+
     match shared_memory::ShmemConf::new().flink(path.clone()).open() {
         Ok(mut shmem) => {
             shmem.set_owner(true);
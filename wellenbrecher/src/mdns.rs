@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tracing::{info, warn};
+
+use crate::cli::Args;
+
+const SERVICE_TYPE: &str = "_pixelflut._tcp.local.";
+
+/// Commands understood on the pixelflut port, announced in the `commands` TXT record
+const COMMANDS: &str = "HELP,SIZE,PX,GETRECT,OFFSET,FLIP,ROTATE,PING,MODE,COMPRESS,EXT,QUIT";
+
+/// Announces the pixelflut port via mDNS until dropped
+pub struct Announcement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Announcement {
+    pub fn new(args: &Args) -> eyre::Result<Self> {
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")?;
+        let hostname = hostname.trim();
+        let name = args.mdns_name.as_deref().unwrap_or(hostname);
+
+        let width = args.width.to_string();
+        let height = args.height.to_string();
+        let spectator_port = args
+            .spectator_listen
+            .map(|address| address.port().to_string());
+        let mut properties = vec![
+            ("width", width.as_str()),
+            ("height", height.as_str()),
+            ("commands", COMMANDS),
+            (
+                "server",
+                concat!("wellenbrecher/", env!("CARGO_PKG_VERSION")),
+            ),
+        ];
+        if let Some(port) = spectator_port.as_deref() {
+            properties.push(("spectator", port));
+        }
+
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            name,
+            &format!("{hostname}.local."),
+            (),
+            args.port,
+            properties.as_slice(),
+        )?
+        .enable_addr_auto();
+        let fullname = service.get_fullname().to_string();
+
+        let daemon = ServiceDaemon::new()?;
+        daemon.register(service)?;
+        info!("announcing {fullname} via mDNS");
+
+        Ok(Self { daemon, fullname })
+    }
+}
+
+impl Drop for Announcement {
+    fn drop(&mut self) {
+        match self.daemon.unregister(&self.fullname) {
+            // let the goodbye go out so clients forget the server right away
+            Ok(status) => {
+                let _ = status.recv_timeout(Duration::from_secs(1));
+            }
+            Err(e) => warn!("unable to withdraw the mDNS announcement: {e}"),
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
@@ -0,0 +1,40 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+use tracing::warn;
+
+/// Sends `state` to the service manager, e.g. READY=1, see sd_notify(3).
+/// Does nothing unless the server was started with NOTIFY_SOCKET
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&path, state) {
+        warn!("unable to notify the service manager about {state}: {e}");
+    }
+}
+
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    let address = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+/// How often WATCHDOG=1 has to be sent, half of the WatchdogSec of the service
+pub fn watchdog_interval() -> Option<Duration> {
+    let timeout = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    // meant for another process
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    (timeout > 0).then(|| Duration::from_micros(timeout / 2))
+}
@@ -0,0 +1,56 @@
+use std::path::Path;
+
+use wellenbrecher_canvas::Bgra;
+
+/// Colors submitted by players are mapped to the nearest entry before they are written
+#[derive(Debug, Clone)]
+pub struct Palette {
+    colors: Box<[Bgra]>,
+}
+
+impl Palette {
+    /// One color per line as rrggbb or #rrggbb, anything after the color is ignored
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("unable to read palette {}: {e}", path.display()))?;
+
+        let colors = content
+            .lines()
+            .enumerate()
+            .filter_map(|(i, line)| {
+                line.split_whitespace()
+                    .next()
+                    .map(|color| (i, color.trim_start_matches('#')))
+            })
+            .map(|(i, color)| match color.len() {
+                6 => u32::from_str_radix(color, 16)
+                    .map(Bgra::from_rgb)
+                    .map_err(|e| format!("invalid color in line {}: {e}", i + 1)),
+                _ => Err(format!("invalid color in line {}: expected rrggbb", i + 1)),
+            })
+            .collect::<Result<Box<[_]>, _>>()?;
+
+        if colors.is_empty() {
+            return Err(format!("palette {} is empty", path.display()));
+        }
+
+        Ok(Self { colors })
+    }
+
+    /// Transparent colors are kept, all others become the opaque palette entry
+    /// closest to their rgb value
+    #[inline]
+    pub fn nearest(&self, color: Bgra) -> Bgra {
+        if color.a == 0 {
+            return color;
+        }
+
+        let distance = |entry: &&Bgra| {
+            let dr = entry.r as i32 - color.r as i32;
+            let dg = entry.g as i32 - color.g as i32;
+            let db = entry.b as i32 - color.b as i32;
+            dr * dr + dg * dg + db * db
+        };
+        *self.colors.iter().min_by_key(distance).unwrap()
+    }
+}
@@ -0,0 +1,154 @@
+use std::ffi::{CStr, CString};
+use std::io;
+
+use libc::{c_int, gid_t, uid_t};
+use tracing::info;
+
+/*
+The firewall, the pixelflut listeners and the TLS keys are set up as root, afterwards the server
+continues as an unprivileged account and everything else, e.g. the canvas, is created by it.
+With a firewall CAP_NET_ADMIN is kept as ambient capability, so that nft is still able to add bans
+and to remove the rules on exit. Capabilities are per thread, threads spawned before keep none.
+ */
+
+const CAP_NET_ADMIN: u32 = 12;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: c_int,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Continues as `user` in `group`, the primary group of the user by default.
+/// Both are names or numeric ids
+pub fn drop_privileges(user: &str, group: Option<&str>, keep_net_admin: bool) -> eyre::Result<()> {
+    let (uid, primary_gid, name) = lookup_user(user)?;
+    let gid = match group {
+        Some(group) => lookup_group(group)?,
+        None => primary_gid
+            .ok_or_else(|| eyre::eyre!("user {user} has no primary group, pass --group"))?,
+    };
+
+    unsafe {
+        if keep_net_admin && libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) != 0 {
+            return Err(error("keep the capabilities"));
+        }
+        let groups = match &name {
+            Some(name) => libc::initgroups(name.as_ptr(), gid),
+            None => libc::setgroups(1, &gid),
+        };
+        if groups != 0 {
+            return Err(error("set the supplementary groups"));
+        }
+        if libc::setgid(gid) != 0 {
+            return Err(error(&format!("switch to group {gid}")));
+        }
+        if libc::setuid(uid) != 0 {
+            return Err(error(&format!("switch to user {uid}")));
+        }
+    }
+
+    if keep_net_admin {
+        keep_capability(CAP_NET_ADMIN)?;
+    }
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(eyre::eyre!(
+            "dropped privileges, but still able to regain root"
+        ));
+    }
+
+    info!(
+        "running as user {uid} in group {gid}{}",
+        match keep_net_admin {
+            true => " with CAP_NET_ADMIN",
+            false => "",
+        }
+    );
+    Ok(())
+}
+
+/// (uid, primary gid, name), the latter two are unknown for ids without a passwd entry
+fn lookup_user(user: &str) -> eyre::Result<(uid_t, Option<gid_t>, Option<CString>)> {
+    let passwd = match user.parse::<uid_t>() {
+        Ok(uid) => unsafe { libc::getpwuid(uid) },
+        Err(_) => {
+            let name = CString::new(user)?;
+            unsafe { libc::getpwnam(name.as_ptr()) }
+        }
+    };
+
+    match (passwd.is_null(), user.parse::<uid_t>()) {
+        (false, _) => unsafe {
+            Ok((
+                (*passwd).pw_uid,
+                Some((*passwd).pw_gid),
+                Some(CStr::from_ptr((*passwd).pw_name).to_owned()),
+            ))
+        },
+        (true, Ok(uid)) => Ok((uid, None, None)),
+        (true, Err(_)) => Err(eyre::eyre!("unknown user {user}")),
+    }
+}
+
+fn lookup_group(group: &str) -> eyre::Result<gid_t> {
+    if let Ok(gid) = group.parse::<gid_t>() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    match entry.is_null() {
+        true => Err(eyre::eyre!("unknown group {group}")),
+        false => Ok(unsafe { (*entry).gr_gid }),
+    }
+}
+
+/// Limits the capabilities of this thread to `capability` and passes it on to executed programs
+fn keep_capability(capability: u32) -> eyre::Result<()> {
+    let header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let bit = 1 << capability;
+    let data = [
+        CapUserData {
+            effective: bit,
+            permitted: bit,
+            inheritable: bit,
+        },
+        CapUserData::default(),
+    ];
+
+    unsafe {
+        if libc::syscall(libc::SYS_capset, &header, data.as_ptr()) != 0 {
+            return Err(error("keep CAP_NET_ADMIN"));
+        }
+        if libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_RAISE,
+            capability as libc::c_ulong,
+            0,
+            0,
+        ) != 0
+        {
+            return Err(error("pass CAP_NET_ADMIN on to nft"));
+        }
+    }
+    Ok(())
+}
+
+fn error(action: &str) -> eyre::Report {
+    eyre::eyre!(
+        "unable to {action} while dropping privileges: {}",
+        io::Error::last_os_error()
+    )
+}
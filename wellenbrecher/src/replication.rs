@@ -0,0 +1,503 @@
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, error, info, warn};
+
+use wellenbrecher_canvas::{Bgra, Canvas, CanvasCreateInfo, UserID};
+
+use crate::cli::Args;
+use crate::open_canvas;
+use crate::ring::ring_coordination::UserState;
+
+const REPLICATION_MAGIC: &[u8; 4] = b"WBRP";
+const REPLICATION_VERSION: u32 = 3;
+
+/// unchanged pixels between two changed pixels that are still sent as part of a single run
+pub(crate) const RUN_MERGE_GAP: usize = 8;
+
+const CLIENTS_UNCHANGED: u32 = u32::MAX;
+
+const MIRROR_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_SECRET_LEN: u32 = 1024;
+/// largest canvas accepted from a primary, 16384x16384
+const MAX_CANVAS_PIXELS: usize = 1 << 28;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+enum Role {
+    Mirror = 0,
+    Standby = 1,
+}
+
+/*
+Wire format (all integers little endian):
+    hello:      "WBRP" | version: u32 | role: u32 | secret len: u32 | secret
+    handshake:  "WBRP" | version: u32 | width: u32 | height: u32
+    frame:      runs: u32 | runs * (offset: u32 | len: u32 | len * Bgra | len * UserID)
+                clients: u32 | clients * (ipv6 or ipv4-mapped address: [u8; 16])
+
+Mirrors and standbys open with the hello, the primary closes the connection
+if the secret does not match or too many of them are connected already.
+The first frame after the handshake is a keyframe covering the whole canvas.
+The client table is only sent to standbys and only if it changed, otherwise clients is CLIENTS_UNCHANGED.
+Frames without runs are sent anyway and serve as heartbeat.
+ */
+
+pub fn serve(
+    listener: TcpListener,
+    args: Args,
+    clients: Arc<RwLock<Vec<Arc<UserState>>>>,
+    canvas_open_lock: Arc<Mutex<()>>,
+) -> eyre::Result<()> {
+    info!("Serving canvas replication on {}", listener.local_addr()?);
+    if args.replication_secret.is_none() {
+        warn!("no --replication-secret given, anyone reaching the replication listener gets the canvas and the client table");
+    }
+    let interval = Duration::from_millis(args.replication_interval.get());
+    let max_mirrors = args.replication_max_mirrors.get() as usize;
+    let mirrors = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("unable to accept mirror: {e}");
+                continue;
+            }
+        };
+        // one failing mirror must not stop the replication of all others
+        let peer = match stream.peer_addr() {
+            Ok(peer) => peer,
+            Err(e) => {
+                warn!("unable to get the address of a mirror: {e}");
+                continue;
+            }
+        };
+        // every mirror costs a thread, a shadow copy and a scan of the canvas per update
+        if mirrors.fetch_add(1, Ordering::AcqRel) >= max_mirrors {
+            mirrors.fetch_sub(1, Ordering::AcqRel);
+            warn!("refusing mirror {peer}: {max_mirrors} mirrors are connected already");
+            continue;
+        }
+        let mirrors = mirrors.clone();
+        let args = args.clone();
+        let clients = clients.clone();
+        let canvas_open_lock = canvas_open_lock.clone();
+
+        thread::Builder::new()
+            .name(format!("Replication-{peer}"))
+            .spawn(move || {
+                if let Err(e) = replicate(stream, peer, &args, clients, &canvas_open_lock, interval)
+                {
+                    info!("- mirror {peer}: {e}");
+                }
+                mirrors.fetch_sub(1, Ordering::AcqRel);
+            })?;
+    }
+
+    Ok(())
+}
+
+fn replicate(
+    stream: TcpStream,
+    peer: SocketAddr,
+    args: &Args,
+    clients: Arc<RwLock<Vec<Arc<UserState>>>>,
+    canvas_open_lock: &Mutex<()>,
+    interval: Duration,
+) -> eyre::Result<()> {
+    stream.set_read_timeout(Some(HELLO_TIMEOUT))?;
+    let role = match read_hello(&mut &stream, args.replication_secret.as_deref()) {
+        Ok(role) => role,
+        Err(e) => {
+            warn!("refusing mirror {peer}: {e}");
+            return Ok(());
+        }
+    };
+    let canvas = open_canvas(args, canvas_open_lock)?;
+    info!("+ mirror {peer} ({role:?})");
+
+    stream.set_nodelay(true)?;
+    let mut stream = BufWriter::new(stream);
+
+    stream.write_all(REPLICATION_MAGIC)?;
+    stream.write_all(&REPLICATION_VERSION.to_le_bytes())?;
+    stream.write_all(&canvas.width().to_le_bytes())?;
+    stream.write_all(&canvas.height().to_le_bytes())?;
+
+    let len = canvas.pixel_slice().len();
+    let mut shadow_pixels = vec![Bgra::default(); len].into_boxed_slice();
+    let mut shadow_user_ids = vec![0 as UserID; len].into_boxed_slice();
+    let mut shadow_clients = None;
+    let mut runs = vec![(0, len)];
+
+    loop {
+        for &(offset, len) in runs.iter() {
+            // the canvas keeps changing, so send exactly what ends up in the shadow copy
+            shadow_pixels[offset..offset + len]
+                .copy_from_slice(&canvas.pixel_slice()[offset..offset + len]);
+            shadow_user_ids[offset..offset + len]
+                .copy_from_slice(&canvas.user_id_slice()[offset..offset + len]);
+        }
+        write_runs(&mut stream, &shadow_pixels, &shadow_user_ids, &runs)?;
+
+        // read-only mirrors never take over, so they do not get to know the players
+        let current_clients = match role {
+            Role::Standby => Some(
+                clients
+                    .read()
+                    .expect("unable to acquire lock on clients")
+                    .iter()
+                    .map(|state| state.ip)
+                    .collect::<Vec<_>>(),
+            ),
+            Role::Mirror => None,
+        };
+        match current_clients {
+            Some(current_clients) if shadow_clients.as_ref() != Some(&current_clients) => {
+                write_clients(&mut stream, &current_clients)?;
+                shadow_clients = Some(current_clients);
+            }
+            _ => stream.write_all(&CLIENTS_UNCHANGED.to_le_bytes())?,
+        }
+        stream.flush()?;
+
+        thread::sleep(interval);
+        runs = changed_runs(&canvas, &shadow_pixels, &shadow_user_ids);
+    }
+}
+
+fn read_hello<R: Read>(stream: &mut R, secret: Option<&str>) -> eyre::Result<Role> {
+    let mut magic = [0u8; 4];
+    stream.read_exact(&mut magic)?;
+    if &magic != REPLICATION_MAGIC {
+        return Err(eyre::eyre!("not speaking the replication protocol"));
+    }
+
+    let version = read_u32(stream)?;
+    if version != REPLICATION_VERSION {
+        return Err(eyre::eyre!(
+            "unsupported replication protocol version {version} (expected {REPLICATION_VERSION})"
+        ));
+    }
+
+    let role = match read_u32(stream)? {
+        0 => Role::Mirror,
+        1 => Role::Standby,
+        role => return Err(eyre::eyre!("unknown role {role}")),
+    };
+
+    let len = read_u32(stream)?;
+    if len > MAX_SECRET_LEN {
+        return Err(eyre::eyre!("secret of {len} bytes is too long"));
+    }
+    let mut presented = vec![0u8; len as usize];
+    stream.read_exact(&mut presented)?;
+
+    match secret {
+        Some(secret) if !secret_matches(secret.as_bytes(), &presented) => {
+            Err(eyre::eyre!("wrong secret"))
+        }
+        _ => Ok(role),
+    }
+}
+
+fn write_hello<W: Write>(stream: &mut W, role: Role, secret: Option<&str>) -> std::io::Result<()> {
+    let secret = secret.unwrap_or_default().as_bytes();
+
+    let mut hello = Vec::with_capacity(16 + secret.len());
+    hello.extend_from_slice(REPLICATION_MAGIC);
+    hello.extend_from_slice(&REPLICATION_VERSION.to_le_bytes());
+    hello.extend_from_slice(&(role as u32).to_le_bytes());
+    hello.extend_from_slice(&(secret.len() as u32).to_le_bytes());
+    hello.extend_from_slice(secret);
+    stream.write_all(&hello)?;
+    stream.flush()
+}
+
+/// compares in constant time, so the secret can not be guessed byte by byte
+fn secret_matches(secret: &[u8], presented: &[u8]) -> bool {
+    secret.len() == presented.len()
+        && secret
+            .iter()
+            .zip(presented)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn changed_runs(canvas: &Canvas, pixels: &[Bgra], user_ids: &[UserID]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+
+    let current = canvas.pixel_slice().iter().zip(canvas.user_id_slice());
+    for (i, _) in current
+        .zip(pixels.iter().zip(user_ids))
+        .enumerate()
+        .filter(|(_, (current, shadow))| current != shadow)
+    {
+        match runs.last_mut() {
+            Some((start, len)) if i - (*start + *len) <= RUN_MERGE_GAP => *len = i - *start + 1,
+            _ => runs.push((i, 1)),
+        }
+    }
+
+    runs
+}
+
+fn write_runs<W: Write>(
+    stream: &mut W,
+    pixels: &[Bgra],
+    user_ids: &[UserID],
+    runs: &[(usize, usize)],
+) -> std::io::Result<()> {
+    stream.write_all(&(runs.len() as u32).to_le_bytes())?;
+
+    for &(offset, len) in runs {
+        stream.write_all(&(offset as u32).to_le_bytes())?;
+        stream.write_all(&(len as u32).to_le_bytes())?;
+        stream.write_all(bytemuck::cast_slice(&pixels[offset..offset + len]))?;
+        stream.write_all(bytemuck::cast_slice(&user_ids[offset..offset + len]))?;
+    }
+
+    Ok(())
+}
+
+fn write_clients<W: Write>(stream: &mut W, clients: &[IpAddr]) -> std::io::Result<()> {
+    stream.write_all(&(clients.len() as u32).to_le_bytes())?;
+
+    for ip in clients {
+        let ip = match ip {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => *ip,
+        };
+        stream.write_all(&ip.octets())?;
+    }
+
+    Ok(())
+}
+
+/// Local copy of the primary's state
+struct Replica {
+    canvas: Option<Canvas>,
+    clients: Vec<IpAddr>,
+    last_contact: Instant,
+}
+
+impl Replica {
+    fn new() -> Self {
+        Self {
+            canvas: None,
+            clients: Vec::new(),
+            last_contact: Instant::now(),
+        }
+    }
+
+    fn follow(
+        &mut self,
+        primary: &str,
+        role: Role,
+        args: &Args,
+        timeout: Duration,
+    ) -> eyre::Result<()> {
+        let mut stream = TcpStream::connect(primary)?;
+        stream.set_read_timeout(Some(timeout))?;
+        write_hello(&mut stream, role, args.replication_secret.as_deref())?;
+        let mut stream = BufReader::new(stream);
+
+        let mut magic = [0u8; 4];
+        stream.read_exact(&mut magic)?;
+        if &magic != REPLICATION_MAGIC {
+            return Err(eyre::eyre!("{primary} does not speak the replication protocol"));
+        }
+
+        let version = read_u32(&mut stream)?;
+        if version != REPLICATION_VERSION {
+            return Err(eyre::eyre!(
+                "unsupported replication protocol version {version} (expected {REPLICATION_VERSION})"
+            ));
+        }
+
+        let width = read_u32(&mut stream)?;
+        let height = read_u32(&mut stream)?;
+        let len = (width as usize)
+            .checked_mul(height as usize)
+            .filter(|len| (1..=MAX_CANVAS_PIXELS).contains(len))
+            .ok_or_else(|| eyre::eyre!("{primary} sent an unsupported {width}x{height} canvas"))?;
+        info!("Replicating {width}x{height} canvas from {primary}");
+
+        let canvas = self.canvas.insert(Canvas::create(
+            args.canvas_file_link.as_ref(),
+            args.shm_backend,
+            true,
+            CanvasCreateInfo {
+                width,
+                height,
+                initial_canvas: vec![Bgra::default(); len].into_boxed_slice(),
+                timestamps: args.pixel_timestamps,
+                user_counters: args.user_counters(),
+            },
+        )?);
+
+        loop {
+            let runs = read_u32(&mut stream)?;
+            for _ in 0..runs {
+                let offset = read_u32(&mut stream)? as usize;
+                let len = read_u32(&mut stream)? as usize;
+
+                let pixels = canvas
+                    .pixel_slice_mut()
+                    .get_mut(offset..offset + len)
+                    .ok_or_else(|| eyre::eyre!("run {offset}+{len} exceeds the canvas"))?;
+                stream.read_exact(bytemuck::cast_slice_mut(pixels))?;
+
+                let user_ids = &mut canvas.user_id_slice_mut()[offset..offset + len];
+                stream.read_exact(bytemuck::cast_slice_mut(user_ids))?;
+            }
+            if runs > 0 {
+                canvas.recount_user_pixels();
+            }
+
+            match read_u32(&mut stream)? {
+                CLIENTS_UNCHANGED => {}
+                clients => {
+                    self.clients.clear();
+                    for _ in 0..clients {
+                        let mut octets = [0u8; 16];
+                        stream.read_exact(&mut octets)?;
+                        let ip = Ipv6Addr::from(octets);
+                        self.clients.push(match ip.to_ipv4_mapped() {
+                            Some(ip) => IpAddr::V4(ip),
+                            None => IpAddr::V6(ip),
+                        });
+                    }
+                }
+            }
+
+            self.last_contact = Instant::now();
+            debug!("applied {runs} runs");
+        }
+    }
+}
+
+pub fn mirror(primary: String, args: Args) -> eyre::Result<()> {
+    let timeout = Duration::from_secs(args.takeover_timeout.get());
+
+    thread::Builder::new()
+        .name("Mirror".to_string())
+        .spawn(move || {
+            let mut replica = Replica::new();
+            loop {
+                match replica.follow(primary.as_str(), Role::Mirror, &args, timeout) {
+                    Ok(()) => warn!("replication stream from {primary} ended"),
+                    Err(e) => warn!("replication stream from {primary} failed: {e}"),
+                }
+                thread::sleep(MIRROR_RECONNECT_DELAY);
+            }
+        })?;
+
+    wait_for_exit_signal()
+}
+
+/// Follows the primary until it is gone for longer than the takeover timeout.
+///
+/// Returns the replicated client table, or `None` if the standby was asked to exit.
+pub fn standby(primary: String, args: &Args) -> eyre::Result<Option<Vec<Arc<UserState>>>> {
+    let timeout = Duration::from_secs(args.takeover_timeout.get());
+    let mut replica = Replica::new();
+
+    info!("Standing by for {primary}");
+    loop {
+        match replica.follow(primary.as_str(), Role::Standby, args, timeout) {
+            Ok(()) => warn!("replication stream from {primary} ended"),
+            Err(e) => match e.downcast_ref::<std::io::Error>().map(|e| e.kind()) {
+                Some(ErrorKind::ConnectionRefused) => debug!("{primary} refused replication"),
+                _ => warn!("replication stream from {primary} failed: {e}"),
+            },
+        }
+
+        if exit_signal_pending()? {
+            info!("Exiting...");
+            return Ok(None);
+        }
+
+        if replica.last_contact.elapsed() >= timeout {
+            break;
+        }
+        thread::sleep(MIRROR_RECONNECT_DELAY);
+    }
+
+    info!(
+        "{primary} is gone for {:?}, taking over with {} known clients",
+        replica.last_contact.elapsed(),
+        replica.clients.len()
+    );
+
+    Ok(Some(
+        replica
+            .clients
+            .into_iter()
+            .map(|ip| {
+                let team = args.teams.as_ref().map(|teams| teams.team(ip));
+                Arc::new(UserState::new(ip, team.unwrap_or_default()))
+            })
+            .collect(),
+    ))
+}
+
+fn read_u32<R: Read>(stream: &mut R) -> std::io::Result<u32> {
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn exit_signal_set() -> libc::sigset_t {
+    unsafe {
+        let mut sig_set = std::mem::zeroed::<libc::sigset_t>();
+        libc::sigemptyset(std::ptr::addr_of_mut!(sig_set));
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGINT);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGQUIT);
+        libc::sigaddset(std::ptr::addr_of_mut!(sig_set), libc::SIGTERM);
+        sig_set
+    }
+}
+
+fn exit_signal_pending() -> eyre::Result<bool> {
+    let sig_set = exit_signal_set();
+    let timeout = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+
+    match unsafe {
+        libc::sigtimedwait(
+            std::ptr::addr_of!(sig_set),
+            std::ptr::null_mut(),
+            std::ptr::addr_of!(timeout),
+        )
+    } {
+        -1 => match std::io::Error::last_os_error() {
+            e if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            e if e.kind() == ErrorKind::Interrupted => Ok(false),
+            e => Err(eyre::eyre!("unable to check for signals: {e}")),
+        },
+        _ => Ok(true),
+    }
+}
+
+fn wait_for_exit_signal() -> eyre::Result<()> {
+    let sig_set = exit_signal_set();
+
+    let mut signal: libc::c_int = 0;
+    if unsafe { libc::sigwait(std::ptr::addr_of!(sig_set), std::ptr::addr_of_mut!(signal)) } != 0 {
+        error!("unable to wait for signals");
+        return Err(eyre::eyre!("unable to wait for signals"));
+    }
+
+    info!("Exiting...");
+    Ok(())
+}
@@ -0,0 +1,370 @@
+use std::fmt::Write as _;
+use std::io;
+use std::io::Write;
+use std::net::IpAddr;
+use std::num::NonZeroU64;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use wellenbrecher_canvas::{Bgra, Canvas, Stats, UserID};
+
+use crate::bans::Bans;
+use crate::ring::ring_coordination::{Limits, Masks, UserState};
+
+/// Longest command line, longer ones close the admin connection
+const MAX_LINE_LEN: usize = 4096;
+/// Admin clients not reading their replies for this long are dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const HELP: &str = "\
+help                    -> this information page
+users                   -> one line per user: <id> <ip> team=<team> connections=<n> bytes=<n> pixels=<n> dropped=<n>
+kick <ip>               -> close all connections of the user of <ip> on their next read
+erase <user id>|<ip>    -> reset all pixels last set by a user to the background
+ban <ip> [seconds]      -> kick the user of <ip> and refuse its connections and datagrams, forever by default
+unban <ip>              -> lift the ban of <ip>
+bans                    -> one line per banned address: <ip> <remaining seconds>|forever
+clear [rrggbb]          -> fill the canvas with a color, black by default
+limits                  -> the current limits, 0 is unlimited
+limit <name> <value>    -> change a limit (ingress-rate, pixel-rate, pixel-budget, connections, connections-per-ip), 0 is unlimited
+drain                   -> stop accepting connections and exit once the remaining ones are gone, like SIGRTMIN
+";
+
+/*
+The admin socket is served by the empress ring: connections are accepted and read through io_uring,
+commands are executed right away and replied to with a blocking write. Every reply ends with a line
+of either OK or ERR <reason>.
+ */
+
+/// Runtime control of the server through a unix socket
+#[derive(Debug)]
+pub struct Admin {
+    listener: UnixListener,
+    path: PathBuf,
+    canvas: Canvas,
+    /// what erased pixels are reset to
+    background: Box<[Bgra]>,
+    clients: Arc<RwLock<Vec<Arc<UserState>>>>,
+    stats: Arc<Stats>,
+    limits: Arc<Limits>,
+    bans: Arc<Bans>,
+    masks: Arc<Masks>,
+}
+
+impl Admin {
+    /// Binds the socket, only accessible by the user running the server
+    #[allow(clippy::too_many_arguments)]
+    pub fn bind(
+        path: &Path,
+        canvas: Canvas,
+        background: Box<[Bgra]>,
+        clients: Arc<RwLock<Vec<Arc<UserState>>>>,
+        stats: Arc<Stats>,
+        limits: Arc<Limits>,
+        bans: Arc<Bans>,
+        masks: Arc<Masks>,
+    ) -> io::Result<Self> {
+        // a leftover of a previous run
+        if path
+            .symlink_metadata()
+            .is_ok_and(|metadata| std::os::unix::fs::FileTypeExt::is_socket(&metadata.file_type()))
+        {
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        info!("admin socket listening on {}", path.display());
+
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+            canvas,
+            background,
+            clients,
+            stats,
+            limits,
+            bans,
+            masks,
+        })
+    }
+
+    #[inline]
+    pub fn listener(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// Returns the reply to a command line
+    fn execute(&mut self, line: &str) -> String {
+        let mut reply = String::new();
+        let result = match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [] => return reply,
+            ["help"] => {
+                reply.push_str(HELP);
+                Ok(())
+            }
+            ["users"] => {
+                self.users(&mut reply);
+                Ok(())
+            }
+            ["kick", ip] => parse_ip(ip).and_then(|ip| self.kick(ip, &mut reply)),
+            ["erase", user] => self.erase(user, &mut reply),
+            ["ban", ip] => self.ban(ip, None, &mut reply),
+            ["ban", ip, seconds] => match seconds.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => {
+                    self.ban(ip, Some(Duration::from_secs(seconds)), &mut reply)
+                }
+                _ => Err("expected the ban duration in seconds".to_string()),
+            },
+            ["unban", ip] => self.unban(ip),
+            ["bans"] => {
+                self.bans(&mut reply);
+                Ok(())
+            }
+            ["clear"] => self.clear(Bgra::from_rgb(0)),
+            ["clear", color] => match u32::from_str_radix(color.trim_start_matches('#'), 16) {
+                Ok(rgb) if color.trim_start_matches('#').len() == 6 => {
+                    self.clear(Bgra::from_rgb(rgb))
+                }
+                _ => Err("expected a color in the form rrggbb".to_string()),
+            },
+            ["limits"] => {
+                self.limits(&mut reply);
+                Ok(())
+            }
+            ["limit", name, value] => self.limit(name, value),
+            ["drain"] => drain(),
+            _ => Err("unknown command, see help".to_string()),
+        };
+
+        match result {
+            Ok(()) => reply.push_str("OK\n"),
+            Err(e) => {
+                let _ = writeln!(reply, "ERR {e}");
+            }
+        }
+        reply
+    }
+
+    fn users(&self, reply: &mut String) {
+        let clients = self
+            .clients
+            .read()
+            .expect("unable to acquire lock on clients");
+        for (i, state) in clients.iter().enumerate() {
+            let user_id = (i + 1) as u32;
+            let (bytes, pixels) = self
+                .stats
+                .user(user_id)
+                .map(|counters| counters.load())
+                .unwrap_or_default();
+            let _ = writeln!(
+                reply,
+                "{user_id} {} team={} connections={} bytes={bytes} pixels={pixels} dropped={}",
+                state.ip,
+                state.team,
+                state.connections.load(Ordering::Relaxed),
+                state.dropped_pixels.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    fn kick(&self, ip: IpAddr, reply: &mut String) -> Result<(), String> {
+        let ip = self.masks.mask(ip);
+
+        let clients = self
+            .clients
+            .read()
+            .expect("unable to acquire lock on clients");
+        let (i, state) = clients
+            .iter()
+            .enumerate()
+            .find(|(_, state)| state.ip == ip && state.connections.load(Ordering::Relaxed) > 0)
+            .ok_or_else(|| format!("no connected user with address {ip}"))?;
+
+        state.kick();
+        info!("[user: {}] kicked through the admin socket", i + 1);
+        let _ = writeln!(reply, "kicked user {}", i + 1);
+        Ok(())
+    }
+
+    fn ban(&self, ip: &str, duration: Option<Duration>, reply: &mut String) -> Result<(), String> {
+        let ip = parse_ip(ip)?;
+        let banned = self.bans.ban(ip, duration);
+
+        // connected users are kicked as well, even if the firewall failed
+        let _ = self.kick(ip, reply);
+        banned.map_err(|e| format!("banned, but unable to add {ip} to the firewall: {e}"))
+    }
+
+    fn unban(&self, ip: &str) -> Result<(), String> {
+        let ip = parse_ip(ip)?;
+        match self.bans.unban(ip) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(format!("{ip} is not banned")),
+            Err(e) => Err(format!(
+                "unbanned, but unable to remove {ip} from the firewall: {e}"
+            )),
+        }
+    }
+
+    fn bans(&self, reply: &mut String) {
+        for (ip, remaining) in self.bans.list() {
+            let _ = match remaining {
+                Some(remaining) => writeln!(reply, "{ip} {}", remaining.as_secs()),
+                None => writeln!(reply, "{ip} forever"),
+            };
+        }
+    }
+
+    fn erase(&mut self, user: &str, reply: &mut String) -> Result<(), String> {
+        let user_id = match user.parse::<UserID>() {
+            Ok(user_id) => user_id,
+            Err(_) => {
+                let ip = user
+                    .parse::<IpAddr>()
+                    .map_err(|_| "expected a user id or an address".to_string())?;
+                let ip = self.masks.mask(ip);
+                self.clients
+                    .read()
+                    .expect("unable to acquire lock on clients")
+                    .iter()
+                    .position(|state| state.ip == ip)
+                    .map(|i| (i + 1) as UserID)
+                    .ok_or_else(|| format!("no user with address {ip}"))?
+            }
+        };
+        if user_id == 0 {
+            return Err("user id 0 owns the untouched pixels".to_string());
+        }
+
+        let erased = self.canvas.erase_user(user_id, &self.background);
+        info!("[user: {user_id}] erased {erased} pixels through the admin socket");
+        let _ = writeln!(reply, "erased {erased} pixels of user {user_id}");
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Bgra) -> Result<(), String> {
+        self.canvas.clear(color);
+        info!("cleared the canvas through the admin socket");
+        Ok(())
+    }
+
+    fn limits(&self, reply: &mut String) {
+        let raw = |limit: Option<NonZeroU64>| limit.map_or(0, NonZeroU64::get);
+        let _ = writeln!(reply, "ingress-rate {}", raw(self.limits.ingress_rate()));
+        let _ = writeln!(reply, "pixel-rate {}", raw(self.limits.pixel_rate()));
+        let _ = writeln!(
+            reply,
+            "pixel-budget {}",
+            raw(self.limits.pixel_budget().map(|budget| budget.pixels))
+        );
+        let _ = writeln!(reply, "connections {}", raw(self.limits.connections()));
+        let _ = writeln!(
+            reply,
+            "connections-per-ip {}",
+            raw(self.limits.user_connections())
+        );
+    }
+
+    fn limit(&self, name: &str, value: &str) -> Result<(), String> {
+        let value = value
+            .parse::<u64>()
+            .map(NonZeroU64::new)
+            .map_err(|e| format!("invalid value: {e}"))?;
+        match name {
+            "ingress-rate" => self.limits.set_ingress_rate(value),
+            "pixel-rate" => self.limits.set_pixel_rate(value),
+            "pixel-budget" => self.limits.set_pixel_budget(value),
+            "connections" => self.limits.set_connections(value),
+            "connections-per-ip" => self.limits.set_user_connections(value),
+            _ => return Err(format!("unknown limit {name}")),
+        }
+        info!("set {name} to {value:?} through the admin socket");
+        Ok(())
+    }
+}
+
+/// Draining is up to the signal handling of the empress
+fn drain() -> Result<(), String> {
+    match unsafe { libc::kill(libc::getpid(), libc::SIGRTMIN()) } {
+        0 => {
+            info!("draining through the admin socket");
+            Ok(())
+        }
+        _ => Err(format!(
+            "unable to start draining: {}",
+            std::io::Error::last_os_error()
+        )),
+    }
+}
+
+fn parse_ip(ip: &str) -> Result<IpAddr, String> {
+    ip.parse::<IpAddr>()
+        .map_err(|e| format!("invalid address: {e}"))
+}
+
+impl Drop for Admin {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("unable to remove admin socket {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// An operator connected to the admin socket
+#[derive(Debug)]
+pub struct AdminConnection {
+    socket: UnixStream,
+    buffer: Box<[u8]>,
+    len: usize,
+}
+
+impl AdminConnection {
+    pub fn new(socket: UnixStream) -> io::Result<Self> {
+        socket.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        Ok(Self {
+            socket,
+            buffer: vec![0; MAX_LINE_LEN].into_boxed_slice(),
+            len: 0,
+        })
+    }
+
+    #[inline]
+    pub fn socket(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+
+    /// Where the next read goes
+    #[inline]
+    pub fn read_buffer(&mut self) -> (*mut u8, u32) {
+        let free = &mut self.buffer[self.len..];
+        (free.as_mut_ptr(), free.len() as u32)
+    }
+
+    /// Executes all complete lines after `n` bytes were read.
+    /// Returns false if the connection has to be closed
+    pub fn on_read(&mut self, n: usize, admin: &mut Admin) -> bool {
+        self.len += n;
+        while let Some(end) = self.buffer[..self.len].iter().position(|&b| b == b'\n') {
+            let line = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+            let reply = admin.execute(line.trim());
+            if let Err(e) = (&self.socket).write_all(reply.as_bytes()) {
+                warn!("unable to reply on the admin socket: {e}");
+                return false;
+            }
+
+            self.buffer.copy_within(end + 1..self.len, 0);
+            self.len -= end + 1;
+        }
+
+        self.len < self.buffer.len()
+    }
+}
@@ -1,38 +1,302 @@
+use std::fmt::{Display, Formatter};
+use std::ops::AddAssign;
+
 use rummelplatz::io_uring::opcode;
-use rummelplatz::io_uring::squeue::PushError;
+use rummelplatz::io_uring::squeue::{Entry, Flags, PushError};
 use rummelplatz::io_uring::types::Fd;
 use rummelplatz::SubmissionQueueSubmitter;
 use thiserror::Error;
 
 use wellenbrecher_canvas::{Bgra, Canvas, CanvasError};
 
+use crate::ring::decompressor::Compression;
+use crate::ring::ring_coordination::PixelBudget;
 use crate::ring::write_buffer_drop::WriteBufferDropDescriptor;
 
 #[derive(Debug)]
 pub enum Command {
     Help,
     Size,
-    SetPixel { x: u32, y: u32, color: Bgra },
-    GetPixel { x: u32, y: u32 },
-    Offset { x: u32, y: u32 },
+    SetPixel {
+        x: u32,
+        y: u32,
+        color: Bgra,
+    },
+    GetPixel {
+        x: u32,
+        y: u32,
+    },
+    /// Replies the pixels of a region row by row, as hex colors or raw r, g, b, a bytes
+    GetRect {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        binary: bool,
+    },
+    Offset {
+        x: u32,
+        y: u32,
+    },
+    RelativeOffset {
+        dx: i64,
+        dy: i64,
+    },
+    /// Answered with PONG right away, not subject to the egress amplification mitigation
+    Ping {
+        token: Box<[u8]>,
+    },
+    /// Replies `ACK <every>` after every `every` following commands, 0 turns it off
+    AckMode {
+        every: u32,
+    },
+    /// Closes the connection once the replies of the preceding commands are written
+    Quit,
+    /// Everything sent afterwards is compressed
+    Compress(Compression),
+    /// Lists the supported [Extension]s
+    Extensions,
+    /// Toggles mirroring the following commands horizontally or vertically
+    Flip {
+        horizontal: bool,
+    },
+    /// Rotates the following commands clockwise
+    Rotate {
+        quarter_turns: u8,
+    },
+}
+
+/// Maps the coordinates of a connection to the canvas, set by FLIP and ROTATE.
+/// Coordinates are mirrored first and rotated afterwards, the offset applies before both
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Transform {
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    /// clockwise
+    pub quarter_turns: u8,
+}
+
+impl Transform {
+    #[inline]
+    pub fn is_identity(&self) -> bool {
+        *self == Transform::default()
+    }
+
+    /// Size of the canvas as seen by the connection
+    #[inline]
+    pub fn dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        match self.quarter_turns % 2 {
+            0 => (width, height),
+            _ => (height, width),
+        }
+    }
+
+    /// Canvas coordinates of (x, y) as seen by the connection, None if out of bounds
+    #[inline]
+    pub fn apply(&self, x: u32, y: u32, width: u32, height: u32) -> Option<(u32, u32)> {
+        let (width, height) = self.dimensions(width, height);
+        if x >= width || y >= height {
+            return None;
+        }
+
+        let x = if self.flip_horizontal {
+            width - 1 - x
+        } else {
+            x
+        };
+        let y = if self.flip_vertical {
+            height - 1 - y
+        } else {
+            y
+        };
+        Some(match self.quarter_turns {
+            0 => (x, y),
+            1 => (height - 1 - y, x),
+            2 => (width - 1 - x, height - 1 - y),
+            _ => (y, width - 1 - x),
+        })
+    }
+
+    /// [Canvas::region_to_rgba8] of a region as seen by the connection
+    fn region_to_rgba8(
+        &self,
+        canvas: &Canvas,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> Result<Vec<u8>, CanvasError> {
+        let (canvas_width, canvas_height) = (canvas.width(), canvas.height());
+        let mut rgba = Vec::with_capacity(width as usize * height as usize * 4);
+        for y in y..y.saturating_add(height) {
+            for x in x..x.saturating_add(width) {
+                let (x, y) = self
+                    .apply(x, y, canvas_width, canvas_height)
+                    .ok_or(CanvasError::PixelOutOfBounds { x, y })?;
+                let pixel = canvas.pixel(x, y)?;
+                rgba.extend_from_slice(&[pixel.r, pixel.g, pixel.b, pixel.a]);
+            }
+        }
+        Ok(rgba)
+    }
+}
+
+/// Protocol extensions beyond HELP, SIZE and PX, listed by EXT as `EXT <extension> ...`
+#[derive(Debug, Clone, Copy)]
+pub enum Extension {
+    /// `OFFSET <x> <y>`
+    Offset,
+    /// `OFFSET +<x> -<y>`
+    RelativeOffset,
+    /// `PING [token]`
+    Ping,
+    /// `MODE ack <n>`
+    Ack,
+    /// `QUIT` and `BYE`
+    Quit,
+    /// `GETRECT <x> <y> <w> <h> [bin]` for regions up to `max_pixels`
+    GetRect { max_pixels: u32 },
+    /// `COMPRESS gzip|zstd`
+    Compress,
+    /// `FLIP H|V`
+    Flip,
+    /// `ROTATE 0|90|180|270`
+    Rotate,
+    /// `pixels` per `seconds` may be set, more are rejected with `BUDGET EXHAUSTED <ms>`
+    Budget { pixels: u64, seconds: u64 },
+}
+
+impl Extension {
+    /// Everything this server supports, in the order EXT lists it
+    pub fn supported(max_rect_pixels: u32, pixel_budget: Option<PixelBudget>) -> Vec<Self> {
+        let mut extensions = vec![
+            Extension::Offset,
+            Extension::RelativeOffset,
+            Extension::Ping,
+            Extension::Ack,
+            Extension::Quit,
+        ];
+        if max_rect_pixels > 0 {
+            extensions.push(Extension::GetRect {
+                max_pixels: max_rect_pixels,
+            });
+        }
+        extensions.extend([Extension::Compress, Extension::Flip, Extension::Rotate]);
+        if let Some(budget) = pixel_budget {
+            extensions.push(Extension::Budget {
+                pixels: budget.pixels.get(),
+                seconds: budget.interval.as_secs(),
+            });
+        }
+        extensions
+    }
+
+    /// The reply to EXT
+    pub fn reply(extensions: &[Self]) -> Box<[u8]> {
+        let mut reply = String::from("EXT");
+        for extension in extensions {
+            reply.push(' ');
+            reply.push_str(&extension.to_string());
+        }
+        reply.push('\n');
+        reply.into_boxed_str().into_boxed_bytes()
+    }
+}
+
+impl Display for Extension {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Extension::Offset => write!(f, "OFFSET"),
+            Extension::RelativeOffset => write!(f, "OFFSET_RELATIVE"),
+            Extension::Ping => write!(f, "PING"),
+            Extension::Ack => write!(f, "ACK"),
+            Extension::Quit => write!(f, "QUIT"),
+            Extension::GetRect { max_pixels } => write!(f, "GETRECT={max_pixels}"),
+            Extension::Compress => write!(f, "COMPRESS=gzip,zstd"),
+            Extension::Flip => write!(f, "FLIP"),
+            Extension::Rotate => write!(f, "ROTATE"),
+            Extension::Budget { pixels, seconds } => write!(f, "BUDGET={pixels}/{seconds}"),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Default, Debug)]
 pub struct StaticReplies {
     pub help: usize,
     pub size: usize,
+    pub extensions: usize,
+}
+
+impl AddAssign for StaticReplies {
+    #[inline]
+    fn add_assign(&mut self, other: Self) {
+        self.help += other.help;
+        self.size += other.size;
+        self.extensions += other.extensions;
+    }
+}
+
+/// Receives the replies of commands that are answered right away
+pub trait ReplySink {
+    fn reply(&mut self, msg: Box<[u8]>) -> Result<(), CommandExecutionError>;
+}
+
+impl<F: FnMut(Box<[u8]>) -> Result<(), CommandExecutionError>> ReplySink for F {
+    #[inline]
+    fn reply(&mut self, msg: Box<[u8]>) -> Result<(), CommandExecutionError> {
+        self(msg)
+    }
+}
+
+/// Buffers the replies until the socket is writable
+impl ReplySink for Vec<u8> {
+    #[inline]
+    fn reply(&mut self, msg: Box<[u8]>) -> Result<(), CommandExecutionError> {
+        self.extend_from_slice(&msg);
+        Ok(())
+    }
+}
+
+/// Smaller replies are copied, pinning their pages and the extra notification cost more than the copy
+pub const ZERO_COPY_THRESHOLD: usize = 2048;
+
+/// Submits the reply as a write to the socket, large ones are sent without copying if `zero_copy`
+#[inline]
+pub fn submit_reply<D, W: Fn(&mut Entry, D)>(
+    socket_fd: Fd,
+    submitter: &mut SubmissionQueueSubmitter<D, W>,
+    msg: Box<[u8]>,
+    zero_copy: bool,
+    flags: Flags,
+) -> Result<(), CommandExecutionError> {
+    let write = match zero_copy && msg.len() >= ZERO_COPY_THRESHOLD {
+        true => opcode::SendZc::new(socket_fd, msg.as_ptr(), msg.len() as u32).build(),
+        false => opcode::Write::new(socket_fd, msg.as_ptr(), msg.len() as u32).build(),
+    }
+    .flags(flags)
+    .user_data(
+        crate::ring::pixel_flut_ring::UserData::write_buffer_drop(
+            WriteBufferDropDescriptor::Buffer(msg),
+        )
+        .into(),
+    );
+
+    unsafe {
+        submitter.push_raw(write)?;
+    }
+    Ok(())
 }
 
 impl Command {
     #[inline]
-    pub fn handle_command<D, W: Fn(&mut rummelplatz::io_uring::squeue::Entry, D)>(
+    pub fn handle_command(
         self,
         canvas: &mut Canvas,
-        socket_fd: Fd,
-        submitter: &mut SubmissionQueueSubmitter<D, W>,
+        replies: &mut impl ReplySink,
         static_replies: &mut StaticReplies,
         user_id: u32,
         user_offset: &mut (u32, u32),
+        transform: &mut Transform,
     ) -> Result<(), CommandExecutionError> {
         match self {
             Command::Help => {
@@ -43,34 +307,103 @@ impl Command {
                 static_replies.size += 1;
                 Ok(())
             }
+            Command::Extensions => {
+                static_replies.extensions += 1;
+                Ok(())
+            }
             Command::SetPixel { x, y, color } => {
                 let x = user_offset.0 + x;
                 let y = user_offset.1 + y;
-                canvas.set_pixel(x, y, color, user_id).map_err(|e| e.into())
+                if transform.is_identity() {
+                    return canvas.set_pixel(x, y, color, user_id).map_err(|e| e.into());
+                }
+
+                let (canvas_x, canvas_y) = transform
+                    .apply(x, y, canvas.width(), canvas.height())
+                    .ok_or(CanvasError::PixelOutOfBounds { x, y })?;
+                canvas
+                    .set_pixel(canvas_x, canvas_y, color, user_id)
+                    .map_err(|e| e.into())
             }
             Command::GetPixel { x, y } => {
                 let x = user_offset.0 + x;
                 let y = user_offset.1 + y;
-                let color = u32::from(canvas.pixel(x, y).unwrap_or_default());
+                let pixel = match transform.is_identity() {
+                    true => canvas.pixel(x, y).ok(),
+                    false => transform
+                        .apply(x, y, canvas.width(), canvas.height())
+                        .and_then(|(x, y)| canvas.pixel(x, y).ok()),
+                };
+                let color = u32::from(pixel.unwrap_or_default());
                 let msg = format!("PX {x} {y} {color:0>8x}\n")
                     .into_boxed_str()
                     .into_boxed_bytes();
-                let write = opcode::Write::new(socket_fd, msg.as_ptr(), msg.len() as u32)
-                    .build()
-                    .user_data(
-                        crate::ring::pixel_flut_ring::UserData::write_buffer_drop(
-                            WriteBufferDropDescriptor::Buffer(msg),
-                        )
-                        .into(),
-                    );
-
-                unsafe {
-                    submitter.push_raw(write)?;
+                replies.reply(msg)
+            }
+            Command::GetRect {
+                x,
+                y,
+                width,
+                height,
+                binary,
+            } => {
+                let x = user_offset.0 + x;
+                let y = user_offset.1 + y;
+                let rgba = match transform.is_identity() {
+                    true => canvas.region_to_rgba8(x, y, width, height)?,
+                    false => transform.region_to_rgba8(canvas, x, y, width, height)?,
+                };
+                let header = match binary {
+                    true => format!("RECT {x} {y} {width} {height} bin\n"),
+                    false => format!("RECT {x} {y} {width} {height}\n"),
+                };
+
+                let msg = match binary {
+                    true => [header.as_bytes(), &rgba].concat(),
+                    false => {
+                        const HEX: &[u8; 16] = b"0123456789abcdef";
+                        let row_len = width as usize * 4;
+                        let mut msg =
+                            Vec::with_capacity(header.len() + rgba.len() * 2 + height as usize);
+                        msg.extend_from_slice(header.as_bytes());
+                        for row in 0..height as usize {
+                            for byte in &rgba[row * row_len..(row + 1) * row_len] {
+                                msg.push(HEX[(byte >> 4) as usize]);
+                                msg.push(HEX[(byte & 0xf) as usize]);
+                            }
+                            msg.push(b'\n');
+                        }
+                        msg
+                    }
+                };
+                replies.reply(msg.into_boxed_slice())
+            }
+            Command::Ping { token } => {
+                let mut msg = Vec::with_capacity(b"PONG \n".len() + token.len());
+                msg.extend_from_slice(b"PONG");
+                if !token.is_empty() {
+                    msg.push(b' ');
+                    msg.extend_from_slice(&token);
                 }
+                msg.push(b'\n');
+                replies.reply(msg.into_boxed_slice())
+            }
+            Command::Quit | Command::AckMode { .. } | Command::Compress(_) => Ok(()),
+            Command::Flip { horizontal: true } => {
+                transform.flip_horizontal = !transform.flip_horizontal;
+                Ok(())
+            }
+            Command::Flip { horizontal: false } => {
+                transform.flip_vertical = !transform.flip_vertical;
+                Ok(())
+            }
+            Command::Rotate { quarter_turns } => {
+                transform.quarter_turns = quarter_turns;
                 Ok(())
             }
             Command::Offset { x, y } => {
-                if x >= canvas.width() || y >= canvas.height() {
+                let (width, height) = transform.dimensions(canvas.width(), canvas.height());
+                if x >= width || y >= height {
                     return Err(CommandExecutionError::CanvasError(
                         CanvasError::PixelOutOfBounds { x, y },
                     ));
@@ -79,6 +412,17 @@ impl Command {
                 *user_offset = (x, y);
                 Ok(())
             }
+            Command::RelativeOffset { dx, dy } => {
+                let x = user_offset.0 as i64 + dx;
+                let y = user_offset.1 as i64 + dy;
+                let (width, height) = transform.dimensions(canvas.width(), canvas.height());
+                if !(0..width as i64).contains(&x) || !(0..height as i64).contains(&y) {
+                    return Err(CommandExecutionError::OffsetOutOfBounds { x, y });
+                }
+
+                *user_offset = (x as u32, y as u32);
+                Ok(())
+            }
         }
     }
 }
@@ -89,4 +433,6 @@ pub enum CommandExecutionError {
     Submission(#[from] PushError),
     #[error("invalid canvas operation {0}")]
     CanvasError(#[from] CanvasError),
+    #[error("offset ({x}, {y}) out of bounds")]
+    OffsetOutOfBounds { x: i64, y: i64 },
 }
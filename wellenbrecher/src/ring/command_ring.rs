@@ -8,6 +8,7 @@ use tracing::trace;
 use wellenbrecher_canvas::Bgra;
 
 use crate::ring::command::Command;
+use crate::ring::decompressor::Compression;
 
 #[derive(Debug)]
 pub struct CommandRing {
@@ -18,11 +19,13 @@ pub struct CommandRing {
     read: *mut u8,
     write: *mut u8,
     last_op: Operation,
+    /// discard everything up to and including the next new line before parsing again
+    skip_line: bool,
 
     tag: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum Operation {
     Read,
     Write,
@@ -31,11 +34,36 @@ enum Operation {
 const HELP_VERB: &str = "HELP\n";
 const SIZE_VERB: &str = "SIZE\n";
 const PX_VERB: &str = "PX";
+const PING_VERB: &str = "PING";
+const MODE_VERB: &str = "MODE";
+const ACK_MODE: &str = "ack";
+const BYE_VERB: &str = "BYE\n";
+const QUIT_VERB: &str = "QUIT\n";
 const OFFSET_VERB: &str = "OFFSET";
+const EXT_VERB: &str = "EXT\n";
+const FLIP_VERB: &str = "FLIP";
+const FLIP_HORIZONTAL: &str = "H\n";
+const FLIP_VERTICAL: &str = "V\n";
+const ROTATE_VERB: &str = "ROTATE";
+const PROTOCOL_VERB: &str = "PROTOCOL\n";
+const GETRECT_VERB: &str = "GETRECT";
+const BINARY_RECT: &str = "bin\n";
+const COMPRESS_VERB: &str = "COMPRESS";
+const GZIP_COMPRESSION: &str = "gzip\n";
+const ZSTD_COMPRESSION: &str = "zstd\n";
 
 const HEX_LOOKUP: [u16; 256] = hex_lookup();
 const INVALID_HEX_DIGIT: u16 = 0xffff;
 
+const DECIMAL_LOOKUP: [u16; 256] = decimal_lookup();
+const INVALID_DECIMAL_DIGIT: u16 = 0xffff;
+
+/// longest line the fast path handles: "PX 65535 65535 rrggbbaa\n"
+const FAST_PATH_MAX_LINE: usize = 24;
+const FAST_PATH_MAX_DECIMAL_DIGITS: usize = 5;
+
+const PING_TOKEN_MAX_LEN: usize = 64;
+
 const fn hex_lookup() -> [u16; 256] {
     let mut lookup = [INVALID_HEX_DIGIT; 256];
 
@@ -74,6 +102,60 @@ const fn hex_lookup() -> [u16; 256] {
     }
 }
 
+const fn decimal_lookup() -> [u16; 256] {
+    let mut lookup = [INVALID_DECIMAL_DIGIT; 256];
+
+    let mut i = b'0';
+    while i <= b'9' {
+        lookup[i as usize] = (i - b'0') as u16;
+        i += 1;
+    }
+
+    lookup
+}
+
+/// Decodes up to [FAST_PATH_MAX_DECIMAL_DIGITS] decimal digits without branching per digit.
+/// Invalid digits set the upper bits of the accumulated error mask instead of returning early.
+#[inline(always)]
+fn decode_decimal(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() || digits.len() > FAST_PATH_MAX_DECIMAL_DIGITS {
+        return None;
+    }
+
+    let mut value = 0u32;
+    let mut invalid = 0u16;
+    for &digit in digits {
+        let digit = DECIMAL_LOOKUP[digit as usize];
+        invalid |= digit;
+        value = value * 10 + (digit & 0xf) as u32;
+    }
+
+    (invalid & 0xfff0 == 0).then_some(value)
+}
+
+/// Decodes a 2, 6 or 8 digit hexadecimal color without branching per digit.
+#[inline(always)]
+fn decode_color(digits: &[u8]) -> Option<Bgra> {
+    let mut value = 0u32;
+    let mut invalid = 0u16;
+    for &digit in digits {
+        let digit = HEX_LOOKUP[digit as usize];
+        invalid |= digit;
+        value = (value << 4) | (digit & 0xf) as u32;
+    }
+
+    if invalid & 0xfff0 != 0 {
+        return None;
+    }
+
+    match digits.len() {
+        6 => Some(Bgra::from_rgb(value)),
+        2 => Some(Bgra::from_bw(value as u8)),
+        8 => Some(Bgra::from_rgba(value)),
+        _ => None,
+    }
+}
+
 impl Drop for CommandRing {
     fn drop(&mut self) {
         trace!("dropping command ring {}", self.tag);
@@ -112,8 +194,8 @@ macro_rules! impl_consume_decimal_u32_until {
                             }
                             )+
 
-                            match HEX_LOOKUP[digit as usize] {
-                                INVALID_HEX_DIGIT => return Err(CommandRingError::InvalidDecimalDigit(digit as char)),
+                            match DECIMAL_LOOKUP[digit as usize] {
+                                INVALID_DECIMAL_DIGIT => return Err(CommandRingError::InvalidDecimalDigit(digit as char)),
                                 digit => value = (value * 10) + digit as u32,
                             }
                         }
@@ -170,6 +252,7 @@ impl CommandRing {
                 read: ptr,
                 write: ptr,
                 last_op: Operation::Read,
+                skip_line: false,
             }
         }
     }
@@ -222,20 +305,91 @@ impl CommandRing {
         }
     }
 
+    /// On error the read position is reset to the start of the offending command
     #[inline]
     pub fn read_next_command(&mut self) -> RingResult<Command> {
+        if self.skip_line {
+            self.discard_line()?;
+            self.skip_line = false;
+        }
+
         let old_read = self.read;
+        let old_last_op = self.last_op;
 
         match self.read_next_command_inner() {
             Ok(cmd) => Ok(cmd),
-            Err(CommandRingError::MoreDataRequired) => {
+            Err(e) => {
                 self.read = old_read;
-                Err(CommandRingError::MoreDataRequired)
+                self.last_op = old_last_op;
+                Err(e)
             }
-            Err(e) => Err(e),
         }
     }
 
+    /// Discards everything unread, the whole ring is available for writing afterwards
+    #[inline]
+    pub fn clear(&mut self) {
+        self.read = self.ptr;
+        self.write = self.ptr;
+        self.last_op = Operation::Read;
+        self.skip_line = false;
+    }
+
+    /// Takes everything unread out of the ring, e.g. data that has to be decompressed first
+    pub fn take_unread(&mut self) -> Vec<u8> {
+        let mut unread = Vec::with_capacity(self.available_to_read());
+        while self.available_to_read() > 0 {
+            let len = self.contig_read() as usize;
+            unread.extend_from_slice(unsafe { from_raw_parts(self.read, len) });
+            unsafe { self.advance_read_unchecked(len) };
+        }
+
+        self.clear();
+        unread
+    }
+
+    /// Recovers from a parse error by skipping the rest of the offending line,
+    /// even if it has not been received completely yet
+    #[inline]
+    pub fn skip_line(&mut self) {
+        self.skip_line = true;
+    }
+
+    #[inline]
+    fn discard_line(&mut self) -> RingResult<()> {
+        while self.available_to_read() > 0 {
+            let byte = unsafe { self.read.read() };
+            unsafe { self.increment_read_unchecked() };
+            if byte == b'\n' {
+                return Ok(());
+            }
+        }
+
+        Err(CommandRingError::MoreDataRequired)
+    }
+
+    /// Copies up to `max_len` unread bytes, stopping after the first new line
+    pub fn peek_line(&self, max_len: usize) -> Vec<u8> {
+        let len = self.available_to_read().min(max_len);
+        let mut line = Vec::with_capacity(len);
+
+        let mut ptr = self.read;
+        for _ in 0..len {
+            let byte = unsafe { ptr.read() };
+            line.push(byte);
+            if byte == b'\n' {
+                break;
+            }
+
+            ptr = unsafe { ptr.add(1) };
+            if ptr == self.end {
+                ptr = self.ptr;
+            }
+        }
+
+        line
+    }
+
     #[inline]
     fn consume_compare(&mut self, other: &str) -> RingResult<bool> {
         let contig_read = self.contig_read() as usize;
@@ -306,6 +460,55 @@ impl CommandRing {
         Ok(())
     }
 
+    /// Consumes a leading `+` or `-` and returns its sign
+    #[inline]
+    fn consume_sign(&mut self) -> RingResult<Option<i64>> {
+        if self.available_to_read() == 0 {
+            return Err(CommandRingError::MoreDataRequired);
+        }
+
+        let sign = match unsafe { self.read.read() } {
+            b'+' => 1,
+            b'-' => -1,
+            _ => return Ok(None),
+        };
+        unsafe { self.increment_read_unchecked() };
+        Ok(Some(sign))
+    }
+
+    /// Consumes an optional ` <token>` up to and including the new line
+    #[inline]
+    fn consume_ping_token(&mut self) -> RingResult<Box<[u8]>> {
+        if self.available_to_read() == 0 {
+            return Err(CommandRingError::MoreDataRequired);
+        }
+
+        unsafe {
+            let separator = self.read.read();
+            self.increment_read_unchecked();
+            match separator {
+                b'\n' => return Ok(Box::default()),
+                b' ' => {}
+                _ => return Err(CommandRingError::UnknownVerb),
+            }
+
+            let mut token = Vec::new();
+            loop {
+                if self.read == self.write {
+                    return Err(CommandRingError::MoreDataRequired);
+                }
+                let chr = self.read.read();
+                self.increment_read_unchecked();
+
+                match chr {
+                    b'\n' => return Ok(token.into_boxed_slice()),
+                    b'!'..=b'~' if token.len() < PING_TOKEN_MAX_LEN => token.push(chr),
+                    _ => return Err(CommandRingError::InvalidPingToken),
+                }
+            }
+        }
+    }
+
     #[inline]
     fn consume_hexadecimal_color_until_new_line(&mut self) -> RingResult<Bgra> {
         if self.available_to_read() == 0 {
@@ -341,8 +544,41 @@ impl CommandRing {
         return Err(CommandRingError::InvalidColor);
     }
 
+    /// Fast path for the by far most common command shape: `PX <x> <y> <color>\n` with single
+    /// spaces, completely contained in the contiguous part of the ring.
+    ///
+    /// Returns `None` without consuming anything if the line does not fit that shape,
+    /// the general parser takes over in that case.
+    #[inline(always)]
+    fn read_set_pixel_fast(&mut self) -> Option<Command> {
+        let contig_read = self.contig_read() as usize;
+        let line = unsafe { from_raw_parts(self.read, contig_read.min(FAST_PATH_MAX_LINE)) };
+
+        let params = line.strip_prefix(b"PX ")?;
+        let new_line = params.iter().position(|&b| b == b'\n')?;
+        let params = &params[..new_line];
+
+        let x_end = params.iter().position(|&b| b == b' ')?;
+        let (x, params) = (&params[..x_end], &params[x_end + 1..]);
+        let y_end = params.iter().position(|&b| b == b' ')?;
+        let (y, color) = (&params[..y_end], &params[y_end + 1..]);
+
+        let command = Command::SetPixel {
+            x: decode_decimal(x)?,
+            y: decode_decimal(y)?,
+            color: decode_color(color)?,
+        };
+
+        unsafe { self.advance_read_unchecked(PX_VERB.len() + 1 + new_line + 1) };
+        Some(command)
+    }
+
     #[inline]
     fn read_next_command_inner(&mut self) -> RingResult<Command> {
+        if let Some(cmd) = self.read_set_pixel_fast() {
+            return Ok(cmd);
+        }
+
         // These ifs have to be ordered by increasing VERB length!
         if self.consume_compare(PX_VERB)? {
             self.consume_whitespace()?;
@@ -356,17 +592,92 @@ impl CommandRing {
             } else {
                 Ok(Command::GetPixel { x, y })
             }
+        } else if self.consume_compare(PING_VERB)? {
+            Ok(Command::Ping {
+                token: self.consume_ping_token()?,
+            })
+        } else if self.consume_compare(MODE_VERB)? {
+            self.consume_whitespace()?;
+            if !self.consume_compare(ACK_MODE)? {
+                return Err(CommandRingError::UnknownMode);
+            }
+            self.consume_whitespace()?;
+            let (every, _) = self.consume_decimal_u32_until_new_line()?;
+            Ok(Command::AckMode { every })
+        } else if self.consume_compare(EXT_VERB)? {
+            Ok(Command::Extensions)
+        } else if self.consume_compare(FLIP_VERB)? {
+            self.consume_whitespace()?;
+            if self.consume_compare(FLIP_HORIZONTAL)? {
+                Ok(Command::Flip { horizontal: true })
+            } else if self.consume_compare(FLIP_VERTICAL)? {
+                Ok(Command::Flip { horizontal: false })
+            } else {
+                Err(CommandRingError::InvalidTransform)
+            }
+        } else if self.consume_compare(BYE_VERB)? || self.consume_compare(QUIT_VERB)? {
+            Ok(Command::Quit)
         } else if self.consume_compare(SIZE_VERB)? {
             Ok(Command::Size)
         } else if self.consume_compare(HELP_VERB)? {
             Ok(Command::Help)
         } else if self.consume_compare(OFFSET_VERB)? {
             self.consume_whitespace()?;
+            let x_sign = self.consume_sign()?;
             let (x, _) = self.consume_decimal_u32_until_whitespace()?;
             self.consume_whitespace()?;
+            let y_sign = self.consume_sign()?;
             let (y, _) = self.consume_decimal_u32_until_new_line()?;
 
-            Ok(Command::Offset { x, y })
+            match (x_sign, y_sign) {
+                (None, None) => Ok(Command::Offset { x, y }),
+                (Some(x_sign), Some(y_sign)) => Ok(Command::RelativeOffset {
+                    dx: x_sign * x as i64,
+                    dy: y_sign * y as i64,
+                }),
+                _ => Err(CommandRingError::MixedOffset),
+            }
+        } else if self.consume_compare(ROTATE_VERB)? {
+            self.consume_whitespace()?;
+            let (degrees, _) = self.consume_decimal_u32_until_new_line()?;
+            match degrees {
+                0 | 90 | 180 | 270 => Ok(Command::Rotate {
+                    quarter_turns: (degrees / 90) as u8,
+                }),
+                _ => Err(CommandRingError::InvalidTransform),
+            }
+        } else if self.consume_compare(GETRECT_VERB)? {
+            self.consume_whitespace()?;
+            let (x, _) = self.consume_decimal_u32_until_whitespace()?;
+            self.consume_whitespace()?;
+            let (y, _) = self.consume_decimal_u32_until_whitespace()?;
+            self.consume_whitespace()?;
+            let (width, _) = self.consume_decimal_u32_until_whitespace()?;
+            self.consume_whitespace()?;
+            let (height, terminator) = self.consume_decimal_u32_until_whitespace_or_new_line()?;
+
+            let binary = terminator == b' ';
+            if binary && !self.consume_compare(BINARY_RECT)? {
+                return Err(CommandRingError::UnknownRectFormat);
+            }
+            Ok(Command::GetRect {
+                x,
+                y,
+                width,
+                height,
+                binary,
+            })
+        } else if self.consume_compare(COMPRESS_VERB)? {
+            self.consume_whitespace()?;
+            if self.consume_compare(GZIP_COMPRESSION)? {
+                Ok(Command::Compress(Compression::Gzip))
+            } else if self.consume_compare(ZSTD_COMPRESSION)? {
+                Ok(Command::Compress(Compression::Zstd))
+            } else {
+                Err(CommandRingError::UnknownCompression)
+            }
+        } else if self.consume_compare(PROTOCOL_VERB)? {
+            Ok(Command::Extensions)
         } else {
             Err(CommandRingError::UnknownVerb)
         }
@@ -387,4 +698,611 @@ pub enum CommandRingError {
     InvalidColor,
     #[error("got an unknown verb")]
     UnknownVerb,
+    #[error("got a mix of absolute and relative offsets")]
+    MixedOffset,
+    #[error("got an invalid ping token")]
+    InvalidPingToken,
+    #[error("got an unknown mode")]
+    UnknownMode,
+    #[error("got an unknown region format")]
+    UnknownRectFormat,
+    #[error("got an unknown compression")]
+    UnknownCompression,
+    #[error("got an invalid transform")]
+    InvalidTransform,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(ring: &mut CommandRing, mut data: &[u8]) {
+        while !data.is_empty() {
+            let (ptr, len) = ring.contig_write();
+            assert!(len > 0, "ring is full");
+            let n = data.len().min(len as usize);
+            unsafe {
+                std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, n);
+                ring.advance_write_unchecked(n);
+            }
+            data = &data[n..];
+        }
+    }
+
+    fn ring_with(data: &[u8]) -> CommandRing {
+        let mut ring = CommandRing::new(64);
+        write(&mut ring, data);
+        ring
+    }
+
+    #[test]
+    fn partial_commands() {
+        let mut ring = CommandRing::new(64);
+
+        for chunk in [&b"PX 12"[..], b"3 4", b"56 ff", b"00ff"] {
+            write(&mut ring, chunk);
+            assert!(matches!(
+                ring.read_next_command(),
+                Err(CommandRingError::MoreDataRequired)
+            ));
+        }
+
+        write(&mut ring, b"\nSI");
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::SetPixel { x: 123, y: 456, color }) if color == Bgra::from_rgb(0xff00ff)
+        ));
+        assert!(matches!(
+            ring.read_next_command(),
+            Err(CommandRingError::MoreDataRequired)
+        ));
+
+        write(&mut ring, b"ZE\n");
+        assert!(matches!(ring.read_next_command(), Ok(Command::Size)));
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut ring = CommandRing::new(16);
+        let len = ring.len;
+
+        // move the read and write pointers close to the end of the buffer
+        write(&mut ring, &vec![b'\n'; len - 5]);
+        unsafe { ring.advance_read_unchecked(len - 5) };
+
+        for _ in 0..8 {
+            write(&mut ring, b"OFFSET 7 9\n");
+            assert!(matches!(
+                ring.read_next_command(),
+                Ok(Command::Offset { x: 7, y: 9 })
+            ));
+
+            write(&mut ring, b"PX 3 4 ab\n");
+            assert!(matches!(
+                ring.read_next_command(),
+                Ok(Command::SetPixel { x: 3, y: 4, color }) if color == Bgra::from_bw(0xab)
+            ));
+
+            write(&mut ring, b"PX 3 4\n");
+            assert!(matches!(
+                ring.read_next_command(),
+                Ok(Command::GetPixel { x: 3, y: 4 })
+            ));
+        }
+    }
+
+    #[test]
+    fn clear_discards_incomplete_line() {
+        let mut ring = CommandRing::new(16);
+        let len = ring.len;
+
+        write(&mut ring, b"PX 1 2 ff\nPX 3");
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::SetPixel { x: 1, y: 2, .. })
+        ));
+
+        ring.clear();
+        assert_eq!(ring.contig_write().1 as usize, len);
+        write(&mut ring, b"SIZE\n");
+        assert!(matches!(ring.read_next_command(), Ok(Command::Size)));
+    }
+
+    #[test]
+    fn set_pixel_fast_path() {
+        let mut ring = ring_with(b"PX 420 69 1144ee\nPX 1 2 cc1144ee\nPX 3 4 7f\n");
+
+        assert!(matches!(
+            ring.read_set_pixel_fast(),
+            Some(Command::SetPixel { x: 420, y: 69, color }) if color == Bgra::from_rgb(0x1144ee)
+        ));
+        assert!(matches!(
+            ring.read_set_pixel_fast(),
+            Some(Command::SetPixel { x: 1, y: 2, color }) if color == Bgra::from_rgba(0xcc1144ee)
+        ));
+        assert!(matches!(
+            ring.read_set_pixel_fast(),
+            Some(Command::SetPixel { x: 3, y: 4, color }) if color == Bgra::from_bw(0x7f)
+        ));
+        assert!(matches!(
+            ring.read_next_command(),
+            Err(CommandRingError::MoreDataRequired)
+        ));
+    }
+
+    #[test]
+    fn set_pixel_fast_path_falls_back() {
+        for line in [
+            &b"PX  1 2 ffffff\n"[..],
+            b"PX 1 2 fffff\n",
+            b"PX 1 2 fffffg\n",
+            b"PX 1a 2 ffffff\n",
+            b"PX 1 2\n",
+            b"PX 1 2 ffffff",
+        ] {
+            let mut ring = ring_with(line);
+            assert!(ring.read_set_pixel_fast().is_none());
+            assert_eq!(ring.available_to_read(), line.len());
+        }
+
+        let mut ring = ring_with(b"PX  1  2 ffffff\n");
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::SetPixel { x: 1, y: 2, .. })
+        ));
+
+        // both paths reject what the other one rejects
+        for line in [
+            &b"PX 1a 2 ffffff\n"[..],
+            b"PX 1 2b ffffff\n",
+            b"PX  1f 2 ffffff\n",
+        ] {
+            let mut ring = ring_with(line);
+            assert!(matches!(
+                ring.read_next_command(),
+                Err(CommandRingError::InvalidDecimalDigit(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn errors_keep_the_offending_command() {
+        let mut ring = ring_with(b"SIZE\nPX 1 2 zz\nHELP\n");
+
+        assert!(matches!(ring.read_next_command(), Ok(Command::Size)));
+        assert!(matches!(
+            ring.read_next_command(),
+            Err(CommandRingError::InvalidHexadecimalDigit('z'))
+        ));
+        assert_eq!(ring.peek_line(64), b"PX 1 2 zz\n");
+        assert_eq!(ring.peek_line(4), b"PX 1");
+    }
+
+    #[test]
+    fn skip_line() {
+        let mut ring = ring_with(b"PX 1 2 zz\nSIZE\nPX 1 2 ffffffffff");
+
+        assert!(ring.read_next_command().is_err());
+        ring.skip_line();
+        assert!(matches!(ring.read_next_command(), Ok(Command::Size)));
+
+        assert!(ring.read_next_command().is_err());
+        ring.skip_line();
+        assert!(matches!(
+            ring.read_next_command(),
+            Err(CommandRingError::MoreDataRequired)
+        ));
+
+        write(&mut ring, b"ff\nHELP\n");
+        assert!(matches!(ring.read_next_command(), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn relative_offset() {
+        let mut ring = ring_with(b"OFFSET +7 -9\nOFFSET -0 +0\nOFFSET 7 -9\n");
+
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::RelativeOffset { dx: 7, dy: -9 })
+        ));
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::RelativeOffset { dx: 0, dy: 0 })
+        ));
+        assert!(matches!(
+            ring.read_next_command(),
+            Err(CommandRingError::MixedOffset)
+        ));
+    }
+
+    #[test]
+    fn ping() {
+        let mut ring = ring_with(b"PING\nPING 1234abc\nPING a b\n");
+
+        assert!(
+            matches!(ring.read_next_command(), Ok(Command::Ping { token }) if token.is_empty())
+        );
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::Ping { token }) if *token == *b"1234abc"
+        ));
+        assert!(matches!(
+            ring.read_next_command(),
+            Err(CommandRingError::InvalidPingToken)
+        ));
+
+        let mut ring = CommandRing::new(2 * PING_TOKEN_MAX_LEN);
+        write(
+            &mut ring,
+            format!("PING {}\n", "x".repeat(PING_TOKEN_MAX_LEN + 1)).as_bytes(),
+        );
+        assert!(matches!(
+            ring.read_next_command(),
+            Err(CommandRingError::InvalidPingToken)
+        ));
+    }
+
+    #[test]
+    fn get_rect() {
+        let mut ring = ring_with(b"GETRECT 1 2 3 4\nGETRECT 5 6 7 8 bin\nGETRECT 1 2 3 4 hex\n");
+
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::GetRect {
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4,
+                binary: false
+            })
+        ));
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::GetRect {
+                x: 5,
+                y: 6,
+                width: 7,
+                height: 8,
+                binary: true
+            })
+        ));
+        assert!(matches!(
+            ring.read_next_command(),
+            Err(CommandRingError::UnknownRectFormat)
+        ));
+    }
+
+    #[test]
+    fn compress_takes_the_rest() {
+        let mut ring = CommandRing::new(32);
+        let len = ring.len;
+
+        write(&mut ring, &vec![b'\n'; len - 5]);
+        unsafe { ring.advance_read_unchecked(len - 5) };
+        write(&mut ring, b"COMPRESS zstd\n\x28\xb5\x2f\xfd");
+
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::Compress(Compression::Zstd))
+        ));
+        assert_eq!(ring.take_unread(), b"\x28\xb5\x2f\xfd");
+        assert_eq!(ring.available_to_read(), 0);
+
+        let mut ring = ring_with(b"COMPRESS brotli\n");
+        assert!(matches!(
+            ring.read_next_command(),
+            Err(CommandRingError::UnknownCompression)
+        ));
+    }
+
+    #[test]
+    fn extensions() {
+        let mut ring = ring_with(b"EXT\nPROTOCOL\nEXT 1\n");
+
+        assert!(matches!(ring.read_next_command(), Ok(Command::Extensions)));
+        assert!(matches!(ring.read_next_command(), Ok(Command::Extensions)));
+        assert!(ring.read_next_command().is_err());
+    }
+
+    #[test]
+    fn transforms() {
+        let mut ring = ring_with(b"FLIP H\nFLIP V\nROTATE 270\nROTATE 45\n");
+
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::Flip { horizontal: true })
+        ));
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::Flip { horizontal: false })
+        ));
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::Rotate { quarter_turns: 3 })
+        ));
+        assert!(matches!(
+            ring.read_next_command(),
+            Err(CommandRingError::InvalidTransform)
+        ));
+    }
+
+    #[test]
+    fn quit() {
+        let mut ring = ring_with(b"QUIT\nBYE\nQUIT now\n");
+
+        assert!(matches!(ring.read_next_command(), Ok(Command::Quit)));
+        assert!(matches!(ring.read_next_command(), Ok(Command::Quit)));
+        assert!(ring.read_next_command().is_err());
+    }
+
+    #[test]
+    fn ack_mode() {
+        let mut ring = ring_with(b"MODE ack 100\nMODE ack 0\nMODE nack 1\n");
+
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::AckMode { every: 100 })
+        ));
+        assert!(matches!(
+            ring.read_next_command(),
+            Ok(Command::AckMode { every: 0 })
+        ));
+        assert!(matches!(
+            ring.read_next_command(),
+            Err(CommandRingError::UnknownMode)
+        ));
+    }
+
+    /// Differential tests against the reference parser in `pfparse`
+    mod differential {
+        use pfparse::{ParserError, PixelflutParser, RawCommand, RawCommandHandler};
+        use proptest::prelude::*;
+        use proptest::sample::Index;
+
+        use super::*;
+
+        #[derive(Debug, PartialEq)]
+        enum Parsed {
+            Help,
+            Size,
+            Offset { x: u32, y: u32 },
+            RelativeOffset { dx: i64, dy: i64 },
+            Ping { token: Box<[u8]> },
+            Quit,
+            AckMode { every: u32 },
+            Compress(Compression),
+            Extensions,
+            Flip { horizontal: bool },
+            Rotate { quarter_turns: u8 },
+            GetPixel { x: u32, y: u32 },
+            GetRect([u32; 4], bool),
+            SetPixel { x: u32, y: u32, color: Bgra },
+            Error(ErrorKind),
+        }
+
+        /// What both parsers agree an invalid line is wrong about
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        enum ErrorKind {
+            Command,
+            Coordinate,
+            Color,
+        }
+
+        impl pfparse::CommandExecutionError for ErrorKind {}
+
+        impl From<CommandRingError> for ErrorKind {
+            fn from(e: CommandRingError) -> Self {
+                match e {
+                    CommandRingError::InvalidDecimalDigit(_) | CommandRingError::MixedOffset => {
+                        ErrorKind::Coordinate
+                    }
+                    CommandRingError::InvalidHexadecimalDigit(_)
+                    | CommandRingError::InvalidColor => ErrorKind::Color,
+                    CommandRingError::UnknownVerb
+                    | CommandRingError::InvalidPingToken
+                    | CommandRingError::UnknownMode
+                    | CommandRingError::UnknownRectFormat
+                    | CommandRingError::UnknownCompression
+                    | CommandRingError::InvalidTransform => ErrorKind::Command,
+                    CommandRingError::MoreDataRequired => unreachable!("not an error of the line"),
+                }
+            }
+        }
+
+        impl From<Command> for Parsed {
+            fn from(cmd: Command) -> Self {
+                match cmd {
+                    Command::Help => Parsed::Help,
+                    Command::Size => Parsed::Size,
+                    Command::Offset { x, y } => Parsed::Offset { x, y },
+                    Command::RelativeOffset { dx, dy } => Parsed::RelativeOffset { dx, dy },
+                    Command::Ping { token } => Parsed::Ping { token },
+                    Command::Quit => Parsed::Quit,
+                    Command::AckMode { every } => Parsed::AckMode { every },
+                    Command::Compress(compression) => Parsed::Compress(compression),
+                    Command::Extensions => Parsed::Extensions,
+                    Command::Flip { horizontal } => Parsed::Flip { horizontal },
+                    Command::Rotate { quarter_turns } => Parsed::Rotate { quarter_turns },
+                    Command::GetPixel { x, y } => Parsed::GetPixel { x, y },
+                    Command::GetRect {
+                        x,
+                        y,
+                        width,
+                        height,
+                        binary,
+                    } => Parsed::GetRect([x, y, width, height], binary),
+                    Command::SetPixel { x, y, color } => Parsed::SetPixel { x, y, color },
+                }
+            }
+        }
+
+        /// Decodes every field on its own to tell which one is invalid
+        struct Collect(Vec<Parsed>);
+        impl RawCommandHandler for Collect {
+            type Error = ErrorKind;
+
+            fn handle_raw(&mut self, cmd: RawCommand<'_>) -> Result<(), ParserError<ErrorKind>> {
+                let coordinate = |coordinate| {
+                    pfparse::parse_coordinate::<ErrorKind>(coordinate)
+                        .map(u32::from)
+                        .map_err(|_| ErrorKind::Coordinate)
+                };
+
+                self.0.push(match cmd {
+                    RawCommand::Help => Parsed::Help,
+                    RawCommand::Size => Parsed::Size,
+                    RawCommand::Offset { x, y } => Parsed::Offset {
+                        x: coordinate(x)?,
+                        y: coordinate(y)?,
+                    },
+                    RawCommand::GetPixel { x, y } => Parsed::GetPixel {
+                        x: coordinate(x)?,
+                        y: coordinate(y)?,
+                    },
+                    RawCommand::SetPixel { x, y, color } => Parsed::SetPixel {
+                        x: coordinate(x)?,
+                        y: coordinate(y)?,
+                        color: pfparse::parse_color::<ErrorKind>(color)
+                            .map_err(|_| ErrorKind::Color)?,
+                    },
+                });
+                Ok(())
+            }
+        }
+
+        fn parse_naive(stream: &[u8]) -> Vec<Parsed> {
+            let mut collect = Collect(Vec::new());
+            let kind = match pfparse::NaiveParser.feed_raw(stream, &mut collect) {
+                Ok(()) => return collect.0,
+                Err(ParserError::CommandExecutionError(kind)) => kind,
+                Err(ParserError::UnknownCommand) => ErrorKind::Command,
+                Err(ParserError::InvalidCoordinates) => ErrorKind::Coordinate,
+                Err(e) => unreachable!("fields are decoded by the handler: {e:?}"),
+            };
+            collect.0.push(Parsed::Error(kind));
+            collect.0
+        }
+
+        /// Feeds the chunks like consecutive socket reads, parsing stops at the first error
+        fn parse_ring(chunks: &[&[u8]]) -> Vec<Parsed> {
+            let mut ring = CommandRing::new(128);
+            let mut parsed = Vec::new();
+
+            for mut chunk in chunks.iter().copied() {
+                while !chunk.is_empty() {
+                    let (ptr, len) = ring.contig_write();
+                    assert!(len > 0, "ring is full");
+                    let n = chunk.len().min(len as usize);
+                    unsafe {
+                        std::ptr::copy_nonoverlapping(chunk.as_ptr(), ptr, n);
+                        ring.advance_write_unchecked(n);
+                    }
+                    chunk = &chunk[n..];
+
+                    loop {
+                        match ring.read_next_command() {
+                            Ok(cmd) => parsed.push(cmd.into()),
+                            Err(CommandRingError::MoreDataRequired) => break,
+                            Err(e) => {
+                                parsed.push(Parsed::Error(e.into()));
+                                return parsed;
+                            }
+                        }
+                    }
+                }
+            }
+
+            parsed
+        }
+
+        fn coordinate() -> impl Strategy<Value = String> {
+            (any::<u16>(), 0..3usize).prop_map(|(n, zeros)| format!("{}{n}", "0".repeat(zeros)))
+        }
+
+        /// Lines both parsers support, including invalid ones
+        fn line() -> impl Strategy<Value = String> {
+            prop_oneof![
+                Just("HELP\n".to_string()),
+                Just("SIZE\n".to_string()),
+                (coordinate(), coordinate()).prop_map(|(x, y)| format!("OFFSET {x} {y}\n")),
+                (coordinate(), coordinate()).prop_map(|(x, y)| format!("PX {x} {y}\n")),
+                (coordinate(), coordinate(), "[0-9a-fA-F]{2}|[0-9a-fA-F]{6}")
+                    .prop_map(|(x, y, color)| format!("PX {x} {y} {color}\n")),
+                // invalid colors
+                (
+                    coordinate(),
+                    coordinate(),
+                    "[0-9a-f]{0,1}|[0-9a-f]{3,5}|[0-9a-f]{7}|[g-z][0-9a-f]{5}|[0-9a-z]{9,12}"
+                )
+                    .prop_map(|(x, y, color)| format!("PX {x} {y} {color}\n")),
+                // hexadecimal digits in coordinates
+                (coordinate(), "[a-fA-F][0-9a-fA-F]{0,2}", coordinate())
+                    .prop_map(|(x, hex, y)| format!("PX {x}{hex} {y} ffffff\n")),
+                (coordinate(), coordinate(), "[a-fA-F]")
+                    .prop_map(|(x, y, hex)| format!("PX {x} {y}{hex}\n")),
+                (coordinate(), "[a-fA-F]", coordinate())
+                    .prop_map(|(x, hex, y)| format!("OFFSET {x}{hex} {y}\n")),
+                // junk, not starting like any verb of either parser
+                "[a-zADI-LNT-Z][ -~]{0,15}".prop_map(|junk| format!("{junk}\n")),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn parsers_agree(
+                lines in prop::collection::vec(line(), 1..32),
+                splits in prop::collection::vec(any::<Index>(), 0..8),
+            ) {
+                // trailing bytes let the ring decide on junk shorter than its longest verb
+                let stream = lines
+                    .iter()
+                    .map(String::as_str)
+                    .chain(["SIZE\n", "SIZE\n"])
+                    .collect::<String>()
+                    .into_bytes();
+
+                let mut splits = splits
+                    .iter()
+                    .map(|split| split.index(stream.len()))
+                    .collect::<Vec<_>>();
+                splits.sort_unstable();
+                let mut chunks = Vec::with_capacity(splits.len() + 1);
+                let mut start = 0;
+                for split in splits {
+                    chunks.push(&stream[start..split]);
+                    start = split;
+                }
+                chunks.push(&stream[start..]);
+
+                // every line yields exactly one command or the final error
+                prop_assert_eq!(parse_ring(&chunks), parse_naive(&stream));
+            }
+        }
+
+        /// Pins the protocol differences [line] leaves out, drop an entry once both parsers agree
+        #[test]
+        fn known_divergences() {
+            for stream in [
+                // 8 digit colors: rrggbbaa vs aarrggbb
+                &b"PX 1 2 cc1144ee\n"[..],
+                // CRLF line endings are not supported by the ring
+                b"SIZE\r\n",
+                // the ring accepts repeated spaces
+                b"PX  1 2\n",
+                // pfparse limits coordinates to u16
+                b"OFFSET 70000 2\n",
+                // pfparse skips empty lines
+                b"\nSIZE\n",
+                // pfparse accepts a leading plus sign
+                b"PX +1 2\n",
+            ] {
+                assert_ne!(
+                    parse_ring(&[stream]),
+                    parse_naive(stream),
+                    "{:?}",
+                    String::from_utf8_lossy(stream)
+                );
+            }
+        }
+    }
 }
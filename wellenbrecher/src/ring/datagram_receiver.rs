@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+
+use rummelplatz::io_uring::opcode;
+use rummelplatz::io_uring::squeue::Entry;
+use rummelplatz::io_uring::types::Fd;
+use rummelplatz::{ControlFlow, RingOperation, SubmissionQueueSubmitter};
+use socket2::{SockAddr, Socket};
+use tracing::{info, warn};
+
+use wellenbrecher_canvas::{Canvas, Stats};
+
+use crate::bans::Bans;
+use crate::palette::Palette;
+use crate::ring::command::{Command, StaticReplies, Transform};
+use crate::ring::command_ring::{CommandRing, CommandRingError};
+use crate::ring::ring_coordination::{
+    get_or_create_user_state, nanos_since_epoch, Limits, Masks, UserState,
+};
+use crate::teams::Teams;
+
+/// Largest UDP payload
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+/// Senders remembered per worker, all of them are forgotten once exceeded
+const MAX_SENDERS: usize = 4096;
+
+/*
+Every worker binds its own UDP sockets on the pixelflut port, SO_REUSEPORT spreads the senders
+across the workers. A datagram is a self-contained batch of commands: the offset starts at (0, 0),
+nothing is replied and a missing new line at the end is added. Commands after an invalid one are
+dropped along with the rest of the datagram.
+A sender counts as a connection of its user while the worker remembers it, so that its user id
+is not handed to somebody else meanwhile.
+ */
+
+/// Receives datagrams full of commands and executes them on the canvas
+#[derive(Debug)]
+pub struct DatagramReceiver {
+    sockets: Vec<Socket>,
+    canvas: Option<Canvas>,
+    palette: Option<Palette>,
+    /// pixels above the pixel rate are dropped, as are pixels beyond the budget
+    limits: Arc<Limits>,
+    bans: Arc<Bans>,
+    stats: Arc<Stats>,
+    worker_index: usize,
+    clients: Arc<RwLock<Vec<Arc<UserState>>>>,
+    masks: Arc<Masks>,
+    teams: Option<Teams>,
+    senders: HashMap<IpAddr, (u32, Arc<UserState>)>,
+}
+
+/// A pending receive, the header points into its own allocation and the command ring
+#[derive(Debug)]
+pub struct Datagram {
+    socket: RawFd,
+    command_ring: CommandRing,
+    header: Box<DatagramHeader>,
+}
+
+#[derive(Debug)]
+struct DatagramHeader {
+    msg: libc::msghdr,
+    iov: libc::iovec,
+    address: libc::sockaddr_storage,
+}
+
+impl Datagram {
+    fn new(socket: RawFd) -> Self {
+        let mut header = Box::new(DatagramHeader {
+            msg: unsafe { std::mem::zeroed() },
+            iov: libc::iovec {
+                iov_base: std::ptr::null_mut(),
+                iov_len: 0,
+            },
+            address: unsafe { std::mem::zeroed() },
+        });
+        header.msg.msg_iov = std::ptr::addr_of_mut!(header.iov);
+        header.msg.msg_iovlen = 1;
+        header.msg.msg_name = std::ptr::addr_of_mut!(header.address) as *mut _;
+
+        Self {
+            socket,
+            command_ring: CommandRing::new(MAX_DATAGRAM_SIZE),
+            header,
+        }
+    }
+
+    fn recv(&mut self) -> Entry {
+        self.command_ring.clear();
+        let (ptr, len) = self.command_ring.contig_write();
+        // keep a byte to terminate the last line
+        self.header.iov.iov_base = ptr as *mut _;
+        self.header.iov.iov_len = len.saturating_sub(1) as usize;
+        self.header.msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as u32;
+        self.header.msg.msg_flags = 0;
+
+        opcode::RecvMsg::new(Fd(self.socket), std::ptr::addr_of_mut!(self.header.msg)).build()
+    }
+
+    fn sender(&self) -> Option<SocketAddr> {
+        let address = unsafe {
+            SockAddr::new(
+                std::ptr::read(std::ptr::addr_of!(self.header.address)),
+                self.header.msg.msg_namelen,
+            )
+        };
+        address.as_socket()
+    }
+}
+
+impl DatagramReceiver {
+    /// Without sockets no datagrams are received and the canvas is not needed
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        sockets: Vec<Socket>,
+        canvas: Option<Canvas>,
+        palette: Option<Palette>,
+        limits: Arc<Limits>,
+        bans: Arc<Bans>,
+        stats: Arc<Stats>,
+        worker_index: usize,
+        clients: Arc<RwLock<Vec<Arc<UserState>>>>,
+        masks: Arc<Masks>,
+        teams: Option<Teams>,
+    ) -> Self {
+        Self {
+            sockets,
+            canvas,
+            palette,
+            limits,
+            bans,
+            stats,
+            worker_index,
+            clients,
+            masks,
+            teams,
+            senders: HashMap::new(),
+        }
+    }
+
+    fn sender(&mut self, ip: IpAddr) -> (u32, Arc<UserState>) {
+        if let Some(sender) = self.senders.get(&ip) {
+            return sender.clone();
+        }
+
+        if self.senders.len() >= MAX_SENDERS {
+            self.forget_senders();
+        }
+
+        let (uid, state) = get_or_create_user_state(
+            self.clients
+                .write()
+                .expect("unable to acquire lock on clients")
+                .as_mut(),
+            ip,
+            &self.masks,
+            self.teams.as_ref(),
+        );
+        state.datagram_senders.fetch_add(1, Ordering::Relaxed);
+        self.stats.set_team(uid, state.team);
+        self.stats.connected(uid);
+        self.senders.insert(ip, (uid, state.clone()));
+        (uid, state)
+    }
+
+    fn forget_senders(&mut self) {
+        for (_, (uid, state)) in self.senders.drain() {
+            state.datagram_senders.fetch_sub(1, Ordering::Relaxed);
+            self.stats.disconnected(uid);
+        }
+    }
+
+    fn process_datagram(&mut self, datagram: &mut Datagram, len: usize) {
+        let Some(sender) = datagram.sender() else {
+            return;
+        };
+        if self.bans.is_banned(sender.ip()) {
+            return;
+        }
+        let (user_id, user_state) = self.sender(sender.ip());
+        let Some(canvas) = self.canvas.as_mut() else {
+            return;
+        };
+
+        let truncated = datagram.header.msg.msg_flags & libc::MSG_TRUNC != 0;
+        let mut len = len;
+        unsafe {
+            let last = (datagram.header.iov.iov_base as *mut u8).add(len);
+            if !truncated && len > 0 && last.sub(1).read() != b'\n' {
+                last.write(b'\n');
+                len += 1;
+            }
+            datagram.command_ring.advance_write_unchecked(len);
+        }
+
+        let mut user_offset = (0, 0);
+        let mut transform = Transform::default();
+        let mut static_replies = StaticReplies::default();
+        let mut pixels = 0u64;
+        let now = nanos_since_epoch();
+        let pixel_rate = self.limits.pixel_rate();
+        let pixel_budget = self.limits.pixel_budget();
+        loop {
+            let result = match datagram.command_ring.read_next_command() {
+                // nothing is replied, no need to copy the region
+                Ok(Command::GetRect { .. }) => Ok(()),
+                Ok(Command::Compress(_)) => Err("datagrams can not be compressed".to_string()),
+                Ok(mut cmd) => {
+                    if let (Some(palette), Command::SetPixel { color, .. }) =
+                        (&self.palette, &mut cmd)
+                    {
+                        *color = palette.nearest(*color);
+                    }
+                    let sets_pixel = matches!(cmd, Command::SetPixel { .. });
+                    if sets_pixel
+                        && (pixel_rate.is_some_and(|rate| !user_state.admit_pixel(now, rate))
+                            || pixel_budget.is_some_and(|budget| {
+                                user_state.spend_budget(now, budget).is_err()
+                            }))
+                    {
+                        continue;
+                    }
+                    cmd.handle_command(
+                        canvas,
+                        &mut |_: Box<[u8]>| Ok(()),
+                        &mut static_replies,
+                        user_id,
+                        &mut user_offset,
+                        &mut transform,
+                    )
+                    .map(|()| pixels += sets_pixel as u64)
+                    .map_err(|e| e.to_string())
+                }
+                // done, possibly leaving the incomplete last line of a truncated datagram
+                Err(CommandRingError::MoreDataRequired) => break,
+                Err(e) => Err(e.to_string()),
+            };
+
+            if let Err(e) = result {
+                if let Some(rejected) = user_state.sample_rejected_command() {
+                    warn!(
+                        "[user: {user_id}] dropping the rest of a datagram from {sender}: {e} (rejected commands: {rejected})"
+                    );
+                }
+                break;
+            }
+        }
+
+        if let Some(user) = self.stats.user(user_id) {
+            user.add(len as u64, pixels);
+        }
+        if let Some(worker) = self.stats.worker(self.worker_index) {
+            worker.add(len as u64, pixels);
+        }
+    }
+}
+
+impl RingOperation for DatagramReceiver {
+    type RingData = Datagram;
+    type SetupError = eyre::Error;
+    type TeardownError = eyre::Error;
+    type ControlFlowWarn = eyre::Error;
+    type ControlFlowError = eyre::Error;
+
+    fn setup<W: Fn(&mut Entry, Self::RingData)>(
+        &mut self,
+        mut submitter: SubmissionQueueSubmitter<Self::RingData, W>,
+    ) -> eyre::Result<()> {
+        for socket in self.sockets.iter() {
+            if self.worker_index == 0 {
+                info!(
+                    "Receiving datagrams on {}",
+                    socket.local_addr()?.as_socket().unwrap()
+                );
+            }
+            let mut datagram = Datagram::new(socket.as_raw_fd());
+            submitter.push(datagram.recv(), datagram)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn on_completion<W: Fn(&mut Entry, Self::RingData)>(
+        &mut self,
+        completion_entry: rummelplatz::io_uring::cqueue::Entry,
+        mut datagram: Self::RingData,
+        mut submitter: SubmissionQueueSubmitter<Self::RingData, W>,
+    ) -> (
+        ControlFlow<Self::ControlFlowWarn, Self::ControlFlowError>,
+        Option<Self::RingData>,
+    ) {
+        match completion_entry.result() {
+            n if n >= 0 => self.process_datagram(&mut datagram, n as usize),
+            e => warn!(
+                "unable to receive datagram: {}",
+                io::Error::from_raw_os_error(-e)
+            ),
+        }
+
+        match submitter.push(datagram.recv(), datagram) {
+            Ok(()) => (ControlFlow::Continue, None),
+            Err(e) => (ControlFlow::Error(e.into()), None),
+        }
+    }
+
+    fn on_teardown_completion<W: Fn(&mut Entry, Self::RingData)>(
+        &mut self,
+        _: rummelplatz::io_uring::cqueue::Entry,
+        datagram: Self::RingData,
+        _: SubmissionQueueSubmitter<Self::RingData, W>,
+    ) -> eyre::Result<()> {
+        drop(datagram);
+        self.forget_senders();
+        Ok(())
+    }
+}
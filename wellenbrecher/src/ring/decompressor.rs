@@ -0,0 +1,114 @@
+use std::fmt::{Debug, Formatter};
+use std::io;
+
+use flate2::{Decompress, FlushDecompress, Status};
+use zstd::stream::raw::{Decoder, Operation};
+
+/// Compressed bytes buffered per connection at least
+const INPUT_BUFFER_SIZE: usize = 64 * 1024;
+const GZIP_WINDOW_BITS: u8 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+enum Stream {
+    Gzip(Box<Decompress>),
+    Zstd(Decoder<'static>),
+}
+
+/// Decompresses everything a connection sends after COMPRESS
+pub struct Decompressor {
+    stream: Stream,
+    /// received bytes, start..end are not decompressed yet
+    input: Box<[u8]>,
+    start: usize,
+    end: usize,
+}
+
+impl Debug for Decompressor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let compression = match self.stream {
+            Stream::Gzip(_) => Compression::Gzip,
+            Stream::Zstd(_) => Compression::Zstd,
+        };
+        f.debug_struct("Decompressor")
+            .field("compression", &compression)
+            .field("buffered", &(self.end - self.start))
+            .finish()
+    }
+}
+
+impl Decompressor {
+    /// `received` is what was read past the COMPRESS command already
+    pub fn new(compression: Compression, received: &[u8]) -> io::Result<Self> {
+        let stream = match compression {
+            Compression::Gzip => Stream::Gzip(Box::new(Decompress::new_gzip(GZIP_WINDOW_BITS))),
+            Compression::Zstd => Stream::Zstd(Decoder::new()?),
+        };
+
+        let mut input = vec![0; INPUT_BUFFER_SIZE.max(received.len())].into_boxed_slice();
+        input[..received.len()].copy_from_slice(received);
+        Ok(Self {
+            stream,
+            input,
+            start: 0,
+            end: received.len(),
+        })
+    }
+
+    /// Where the next read goes, empty if the command ring does not take anything anymore
+    #[inline]
+    pub fn read_buffer(&mut self) -> (*mut u8, u32) {
+        if self.start > 0 {
+            self.input.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+
+        let free = &mut self.input[self.end..];
+        (free.as_mut_ptr(), free.len() as u32)
+    }
+
+    /// # Safety
+    /// `n` bytes have been written to the buffer of [Decompressor::read_buffer]
+    #[inline]
+    pub unsafe fn advance_input(&mut self, n: usize) {
+        self.end += n;
+    }
+
+    /// Decompresses into `output` until it is full or more input is needed.
+    /// Returns the number of bytes written, 0 if nothing can be done until more input arrives
+    pub fn decompress(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let input = &self.input[self.start..self.end];
+            let (read, written) = match &mut self.stream {
+                Stream::Gzip(stream) => {
+                    let (total_in, total_out) = (stream.total_in(), stream.total_out());
+                    let status = stream.decompress(input, output, FlushDecompress::None)?;
+                    let progress = (
+                        (stream.total_in() - total_in) as usize,
+                        (stream.total_out() - total_out) as usize,
+                    );
+                    // concatenated gzip members continue the stream
+                    if status == Status::StreamEnd {
+                        **stream = Decompress::new_gzip(GZIP_WINDOW_BITS);
+                    }
+                    progress
+                }
+                Stream::Zstd(stream) => {
+                    let status = stream.run_on_buffers(input, output)?;
+                    (status.bytes_read, status.bytes_written)
+                }
+            };
+            self.start += read;
+
+            // frame headers and trailers are consumed without any output
+            if written > 0 || read == 0 {
+                return Ok(written);
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::ffi::CStr;
+
+use rummelplatz::io_uring::{opcode, IoUring, Probe};
+use tracing::{info, warn};
+
+/// Optional io_uring features, probed once at startup
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RingFeatures {
+    /// IORING_OP_MSG_RING passing cqe flags (6.3), otherwise workers are woken up through an eventfd
+    pub msg_ring: bool,
+    /// Multishot accept (5.19), otherwise accept is submitted again for every connection
+    pub accept_multi: bool,
+    /// IORING_OP_SHUTDOWN (5.11), otherwise QUIT only closes the socket
+    pub shutdown: bool,
+    /// IORING_OP_SEND_ZC and IORING_OP_SENDMSG_ZC (6.1), otherwise large replies are copied
+    pub send_zc: bool,
+}
+
+impl RingFeatures {
+    pub fn probe() -> eyre::Result<Self> {
+        let ring = IoUring::new(2).map_err(|e| {
+            eyre::eyre!("io_uring is unavailable: {e} (the kernel might be too old or io_uring is disabled, e.g. by seccomp or the kernel.io_uring_disabled sysctl)")
+        })?;
+
+        let mut probe = Probe::new();
+        ring.submitter().register_probe(&mut probe).map_err(|e| {
+            eyre::eyre!("unable to probe io_uring opcodes: {e} (Linux 5.6 or newer is required)")
+        })?;
+
+        for (name, code) in [
+            ("IORING_OP_NOP", opcode::Nop::CODE),
+            ("IORING_OP_READ", opcode::Read::CODE),
+            ("IORING_OP_WRITE", opcode::Write::CODE),
+            ("IORING_OP_WRITEV", opcode::Writev::CODE),
+            ("IORING_OP_ACCEPT", opcode::Accept::CODE),
+        ] {
+            if !probe.is_supported(code) {
+                return Err(eyre::eyre!(
+                    "the kernel does not support {name}, which is required"
+                ));
+            }
+        }
+
+        let kernel = kernel_version();
+        let features = Self {
+            msg_ring: probe.is_supported(opcode::MsgRingData::CODE) && kernel >= Some((6, 3)),
+            accept_multi: kernel >= Some((5, 19)),
+            shutdown: probe.is_supported(opcode::Shutdown::CODE),
+            send_zc: probe.is_supported(opcode::SendZc::CODE)
+                && probe.is_supported(opcode::SendMsgZc::CODE),
+        };
+        features.report();
+
+        Ok(features)
+    }
+
+    fn report(&self) {
+        if !self.msg_ring {
+            warn!("io_uring: message passing between rings is not supported, using eventfd wakeups instead (requires Linux 6.3)");
+        }
+        if !self.accept_multi {
+            warn!("io_uring: multishot accept is not supported, accepting one connection at a time instead (requires Linux 5.19)");
+        }
+        if !self.shutdown {
+            warn!("io_uring: IORING_OP_SHUTDOWN is not supported, QUIT closes connections without shutting them down first (requires Linux 5.11)");
+        }
+        if !self.send_zc {
+            warn!("io_uring: zero-copy sends are not supported, large replies are copied instead (requires Linux 6.1)");
+        }
+        if self.msg_ring && self.accept_multi && self.shutdown && self.send_zc {
+            info!("io_uring: all features are supported");
+        }
+    }
+}
+
+/// (major, minor) of the running kernel
+fn kernel_version() -> Option<(u32, u32)> {
+    let release = unsafe {
+        let mut uts = std::mem::zeroed::<libc::utsname>();
+        if libc::uname(std::ptr::addr_of_mut!(uts)) != 0 {
+            return None;
+        }
+        CStr::from_ptr(uts.release.as_ptr())
+            .to_string_lossy()
+            .into_owned()
+    };
+
+    let mut version = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse().ok());
+    Some((version.next()??, version.next()??))
+}
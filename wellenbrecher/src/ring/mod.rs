@@ -1,5 +1,9 @@
+pub mod admin;
 mod command;
 mod command_ring;
+pub mod datagram_receiver;
+mod decompressor;
+pub mod features;
 pub mod pixelflut_connection_handler;
 pub mod ring_coordination;
 pub mod write_buffer_drop;
@@ -7,5 +11,6 @@ pub mod write_buffer_drop;
 rummelplatz::ring! {pixel_flut_ring,
     pixelflut_connection_handler: crate::ring::pixelflut_connection_handler::PixelflutConnectionHandler,
     write_buffer_drop: crate::ring::write_buffer_drop::WriteBufferDrop,
+    datagram_receiver: crate::ring::datagram_receiver::DatagramReceiver,
     coordination: crate::ring::ring_coordination::RingCoordination
 }
@@ -1,39 +1,477 @@
 use std::io;
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::os::fd::{AsRawFd, RawFd};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use rummelplatz::io_uring::opcode;
-use rummelplatz::io_uring::squeue::Entry;
-use rummelplatz::io_uring::types::Fd;
+use rummelplatz::io_uring::squeue::{Entry, Flags};
+use rummelplatz::io_uring::types::{Fd, Timespec};
 use rummelplatz::{ControlFlow, RingOperation, SubmissionQueueSubmitter};
 use socket2::Socket;
-use tracing::{info, warn};
+use tracing::{debug, info, info_span, warn, Span};
 
-use wellenbrecher_canvas::{Canvas, CanvasError};
+use wellenbrecher_canvas::{Canvas, CanvasError, Stats};
 
-use crate::ring::command::{CommandExecutionError, StaticReplies};
+use crate::palette::Palette;
+use crate::ring;
+use crate::ring::command::{
+    submit_reply, Command, CommandExecutionError, Extension, ReplySink, StaticReplies, Transform,
+    ZERO_COPY_THRESHOLD,
+};
 use crate::ring::command_ring::{CommandRing, CommandRingError};
-use crate::ring::ring_coordination::UserState;
-use crate::ring::write_buffer_drop::WriteBufferDropDescriptor;
-use crate::{ring, HELP_TEXT};
+use crate::ring::decompressor::Decompressor;
+use crate::ring::features::RingFeatures;
+use crate::ring::ring_coordination::{
+    nanos_since_epoch, HelpText, Limits, NewClient, PixelBudget, UserState, OPEN_CONNECTIONS,
+};
+use crate::ring::write_buffer_drop::{message, WriteBufferDropDescriptor};
+
+/// Number of raw bytes of a rejected command that are logged
+const REJECTED_COMMAND_SAMPLE_LEN: usize = 64;
+/// How often the worker counters in the stats segment are updated at most
+pub const STATS_FLUSH_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How a connection reacts to malformed commands
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ParseErrorPolicy {
+    /// Close the connection
+    #[default]
+    Drop,
+    /// Skip the malformed line
+    Skip,
+    /// Skip up to n malformed lines per connection, then close it
+    SkipN(NonZeroUsize),
+    /// Reply `ERR <reason>` and skip the malformed line
+    Reply,
+}
+
+impl ParseErrorPolicy {
+    #[inline]
+    fn skips(&self, skipped_lines: usize) -> bool {
+        match self {
+            ParseErrorPolicy::Drop => false,
+            ParseErrorPolicy::Skip | ParseErrorPolicy::Reply => true,
+            ParseErrorPolicy::SkipN(n) => skipped_lines < n.get(),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct PixelflutConnectionHandler {
     canvas: Canvas,
     size_reply_buffer: Box<[u8]>,
+    /// SIZE of connections rotated by 90 or 270 degrees
+    rotated_size_reply_buffer: Box<[u8]>,
+    extensions_reply_buffer: Box<[u8]>,
+    help_reply_buffer: Box<[u8]>,
+    /// replaced EXT and HELP replies, writes still in flight may point into them
+    retired_reply_buffers: Vec<Box<[u8]>>,
+    parse_error_policy: ParseErrorPolicy,
+    stats: Arc<Stats>,
+    worker_index: usize,
+    palette: Option<Palette>,
+    features: RingFeatures,
+    /// (bytes, pixels) not yet added to the worker counters
+    unflushed_traffic: (u64, u64),
+    stats_flushed_at: Instant,
+    /// reads above the ingress rate are delayed, pixels above the pixel rate are dropped
+    limits: Arc<Limits>,
+    /// the budget EXT currently lists
+    extensions_budget: Option<PixelBudget>,
+    help_text: Arc<HelpText>,
+    /// the version of the help text HELP currently replies
+    help_text_version: u64,
+    /// largest region a GETRECT may request
+    max_rect_pixels: u32,
+    /// connections not sending anything for this long are closed
+    idle_timeout: Option<Duration>,
 }
 
 impl PixelflutConnectionHandler {
-    pub fn new(canvas: Canvas) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        canvas: Canvas,
+        parse_error_policy: ParseErrorPolicy,
+        stats: Arc<Stats>,
+        worker_index: usize,
+        palette: Option<Palette>,
+        features: RingFeatures,
+        limits: Arc<Limits>,
+        help_text: Arc<HelpText>,
+        max_rect_pixels: u32,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        let pixel_budget = limits.pixel_budget();
+        let help_text_version = help_text.version();
         Self {
             size_reply_buffer: format!("SIZE {} {}\n", canvas.width(), canvas.height())
                 .into_boxed_str()
                 .into_boxed_bytes(),
+            rotated_size_reply_buffer: format!("SIZE {} {}\n", canvas.height(), canvas.width())
+                .into_boxed_str()
+                .into_boxed_bytes(),
+            extensions_reply_buffer: Extension::reply(&Extension::supported(
+                max_rect_pixels,
+                pixel_budget,
+            )),
+            help_reply_buffer: help_text.get(),
             canvas,
+            parse_error_policy,
+            stats,
+            worker_index,
+            palette,
+            features,
+            unflushed_traffic: (0, 0),
+            stats_flushed_at: Instant::now(),
+            limits,
+            extensions_budget: pixel_budget,
+            help_text,
+            help_text_version,
+            retired_reply_buffers: Vec::new(),
+            max_rect_pixels,
+            idle_timeout,
+        }
+    }
+
+    /// Executes all complete commands after `read` bytes were received into [Connection::read_buffer].
+    /// Returns the replies to HELP and SIZE still to send or None if the connection has to be closed.
+    #[tracing::instrument(level = "debug", skip_all, fields(bytes = read))]
+    pub(crate) fn process_read(
+        &mut self,
+        connection: &mut Connection,
+        replies: &mut impl ReplySink,
+        read: usize,
+    ) -> Option<StaticReplies> {
+        if connection.kicked() {
+            info!(
+                "[user: {}] kicked, closing connection from {}…",
+                connection.user_id, connection.address
+            );
+            return None;
+        }
+
+        // the budget and the help text may be changed at runtime
+        let pixel_budget = self.limits.pixel_budget();
+        if pixel_budget != self.extensions_budget {
+            let extensions_reply_buffer = std::mem::replace(
+                &mut self.extensions_reply_buffer,
+                Extension::reply(&Extension::supported(self.max_rect_pixels, pixel_budget)),
+            );
+            self.retired_reply_buffers.push(extensions_reply_buffer);
+            self.extensions_budget = pixel_budget;
+        }
+        let help_text_version = self.help_text.version();
+        if help_text_version != self.help_text_version {
+            let help_reply_buffer =
+                std::mem::replace(&mut self.help_reply_buffer, self.help_text.get());
+            self.retired_reply_buffers.push(help_reply_buffer);
+            self.help_text_version = help_text_version;
+        }
+
+        let mut static_replies = match connection.decompressor.as_mut() {
+            Some(decompressor) => {
+                unsafe { decompressor.advance_input(read) };
+                self.record_traffic(connection.user_id, read as u64, 0);
+                StaticReplies::default()
+            }
+            None => {
+                unsafe { connection.command_ring.advance_write_unchecked(read) };
+                self.process_commands(connection, replies, read)?
+            }
+        };
+
+        // also picks up where COMPRESS left off
+        while let Some(decompressor) = connection.decompressor.as_mut() {
+            if connection.quit {
+                break;
+            }
+
+            let (ptr, len) = connection.command_ring.contig_write();
+            let output = unsafe { std::slice::from_raw_parts_mut(ptr, len as usize) };
+            let decompressed = match decompressor.decompress(output) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    warn!(
+                        "[user: {}] unable to decompress commands: {e}; closing connection…",
+                        connection.user_id
+                    );
+                    return None;
+                }
+            };
+            unsafe {
+                connection
+                    .command_ring
+                    .advance_write_unchecked(decompressed)
+            };
+            // the compressed bytes are counted as they are received
+            static_replies += self.process_commands(connection, replies, 0)?;
+        }
+
+        Some(static_replies)
+    }
+
+    /// Executes all complete commands in the command ring after `read` bytes were received.
+    /// Returns the replies to HELP and SIZE still to send or None if the connection has to be closed.
+    fn process_commands(
+        &mut self,
+        connection: &mut Connection,
+        replies: &mut impl ReplySink,
+        read: usize,
+    ) -> Option<StaticReplies> {
+        /*
+        To mitigate DoS attacks using commands that generate significantly more egress traffic
+        than required ingress traffic, we only reply to the first occurrence of the
+            - HELP (>10x egress)
+            - SIZE (~ 2x egress)
+            - EXT  (~15x egress)
+        command that yield from one socket read.
+        It should be ok to do that because:
+            - HELP/SIZE is only issued manually by non-machine players, that are not that fast.
+            - HELP/SIZE/EXT is only issued once for feature/canvas size detection by machines
+         */
+        let mut static_replies = StaticReplies::default();
+        let mut pixels = 0u64;
+        let now = nanos_since_epoch();
+        let pixel_rate = self.limits.pixel_rate();
+        let pixel_budget = self.limits.pixel_budget();
+        // BUDGET EXHAUSTED and THROTTLED are replied once per read
+        let mut budget_exhausted = false;
+        let mut throttled = false;
+        loop {
+            match connection.command_ring.read_next_command() {
+                Ok(Command::Quit) => {
+                    connection.quit = true;
+                    break;
+                }
+                Ok(Command::AckMode { every }) => {
+                    connection.ack_every = every;
+                    connection.unacked = 0;
+                }
+                Ok(Command::Compress(compression)) => {
+                    if connection.decompressor.is_some() {
+                        warn!(
+                            "[user: {}] tried to enable compression twice; closing connection…",
+                            connection.user_id
+                        );
+                        self.record_traffic(connection.user_id, read as u64, pixels);
+                        return None;
+                    }
+
+                    let compressed = connection.command_ring.take_unread();
+                    match Decompressor::new(compression, &compressed) {
+                        Ok(decompressor) => connection.decompressor = Some(decompressor),
+                        Err(e) => {
+                            warn!(
+                                "[user: {}] unable to enable compression: {e}; closing connection…",
+                                connection.user_id
+                            );
+                            self.record_traffic(connection.user_id, read as u64, pixels);
+                            return None;
+                        }
+                    }
+                    break;
+                }
+                Ok(Command::GetRect { width, height, .. })
+                    if width as u64 * height as u64 > self.max_rect_pixels as u64 =>
+                {
+                    warn!(
+                        "[user: {}] requested a region of {width}x{height} pixels, more than the {} allowed; closing connection…",
+                        connection.user_id, self.max_rect_pixels
+                    );
+                    self.record_traffic(connection.user_id, read as u64, pixels);
+                    return None;
+                }
+                Ok(mut cmd) => {
+                    if let (Some(palette), Command::SetPixel { color, .. }) =
+                        (&self.palette, &mut cmd)
+                    {
+                        *color = palette.nearest(*color);
+                    }
+                    let is_pixel = matches!(cmd, Command::SetPixel { .. });
+                    let dropped = is_pixel
+                        && pixel_rate
+                            .is_some_and(|rate| !connection.user_state.admit_pixel(now, rate));
+                    let over_budget = match pixel_budget {
+                        Some(budget) if is_pixel && !dropped => {
+                            connection.user_state.spend_budget(now, budget).err()
+                        }
+                        _ => None,
+                    };
+                    let sets_pixel = is_pixel && !dropped && over_budget.is_none();
+                    // dropped and rejected pixels are still acknowledged
+                    let result = match (over_budget, dropped) {
+                        (Some(wait), _) if !budget_exhausted => {
+                            budget_exhausted = true;
+                            replies.reply(
+                                format!("BUDGET EXHAUSTED {}\n", wait.as_millis())
+                                    .into_boxed_str()
+                                    .into_boxed_bytes(),
+                            )
+                        }
+                        (None, true) if !throttled => {
+                            throttled = true;
+                            replies.reply(Box::from(&b"THROTTLED pixel-rate\n"[..]))
+                        }
+                        (Some(_), _) | (None, true) => Ok(()),
+                        (None, false) => cmd.handle_command(
+                            &mut self.canvas,
+                            replies,
+                            &mut static_replies,
+                            connection.user_id,
+                            &mut connection.user_offset,
+                            &mut connection.transform,
+                        ),
+                    };
+                    match result.and_then(|()| match connection.count_processed() {
+                        Some(count) => replies
+                            .reply(format!("ACK {count}\n").into_boxed_str().into_boxed_bytes()),
+                        None => Ok(()),
+                    }) {
+                        Ok(()) => pixels += sets_pixel as u64,
+                        Err(CommandExecutionError::CanvasError(
+                            CanvasError::PixelOutOfBounds { x, y },
+                        )) => {
+                            warn!("[user: {}] tried to set pixel out of bounds: ({x}, {y}); closing connection…",connection.user_id);
+                            self.record_traffic(connection.user_id, read as u64, pixels);
+                            return None;
+                        }
+                        Err(e) => {
+                            warn!(
+                                "[user: {}] unable to execute command: {e}; closing connection…",
+                                connection.user_id
+                            );
+                            self.record_traffic(connection.user_id, read as u64, pixels);
+                            return None;
+                        }
+                    }
+                }
+                Err(CommandRingError::MoreDataRequired) => {
+                    break;
+                }
+                Err(e) => {
+                    let sample = connection
+                        .user_state
+                        .sample_rejected_command()
+                        .map(|rejected| {
+                            (
+                                rejected,
+                                connection
+                                    .command_ring
+                                    .peek_line(REJECTED_COMMAND_SAMPLE_LEN),
+                            )
+                        });
+
+                    if self.parse_error_policy.skips(connection.skipped_lines) {
+                        if let Some((rejected, raw)) = sample {
+                            warn!(
+                                "[user: {}] skipping invalid command: {e} (rejected commands: {rejected}, raw: \"{}\")",
+                                connection.user_id,
+                                raw.escape_ascii()
+                            );
+                        }
+                        connection.skipped_lines += 1;
+                        connection.command_ring.skip_line();
+                        if matches!(self.parse_error_policy, ParseErrorPolicy::Reply) {
+                            let reply = format!("ERR {e}\n").into_boxed_str().into_boxed_bytes();
+                            if let Err(e) = replies.reply(reply) {
+                                warn!(
+                                    "[user: {}] unable to reply to invalid command: {e}; closing connection…",
+                                    connection.user_id
+                                );
+                                self.record_traffic(connection.user_id, read as u64, pixels);
+                                return None;
+                            }
+                        }
+                        continue;
+                    }
+
+                    match sample {
+                        Some((rejected, raw)) => warn!(
+                            "[user: {}] error while parsing command: {e} (rejected commands: {rejected}, raw: \"{}\"); closing connection…",
+                            connection.user_id,
+                            raw.escape_ascii()
+                        ),
+                        None => warn!(
+                            "[user: {}] error while parsing command: {e}; closing connection…",
+                            connection.user_id
+                        ),
+                    }
+                    self.record_traffic(connection.user_id, read as u64, pixels);
+                    return None;
+                }
+            }
+        }
+        self.record_traffic(connection.user_id, read as u64, pixels);
+        Some(static_replies)
+    }
+
+    pub(crate) fn static_replies(
+        &self,
+        static_replies: StaticReplies,
+        connection: &Connection,
+    ) -> impl Iterator<Item = &[u8]> {
+        if static_replies.size > 8 {
+            warn!(
+                "connection {} from {} might be trying to DoS using SIZE egress amplification",
+                connection.user_id, connection.address,
+            )
+        }
+        if static_replies.help > 8 {
+            warn!(
+                "connection {} from {} might be trying to DoS using HELP egress amplification",
+                connection.user_id, connection.address,
+            )
+        }
+        if static_replies.extensions > 8 {
+            warn!(
+                "connection {} from {} might be trying to DoS using EXT egress amplification",
+                connection.user_id, connection.address,
+            )
+        }
+
+        [
+            (static_replies.size > 0).then_some(match connection.transform.quarter_turns % 2 {
+                0 => &*self.size_reply_buffer,
+                _ => &*self.rotated_size_reply_buffer,
+            }),
+            (static_replies.help > 0).then_some(&*self.help_reply_buffer),
+            (static_replies.extensions > 0).then_some(&*self.extensions_reply_buffer),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    #[inline]
+    fn record_traffic(&mut self, user_id: u32, bytes: u64, pixels: u64) {
+        if let Some(user) = self.stats.user(user_id) {
+            user.add(bytes, pixels);
+        }
+
+        self.unflushed_traffic.0 += bytes;
+        self.unflushed_traffic.1 += pixels;
+        if self.stats_flushed_at.elapsed() >= STATS_FLUSH_INTERVAL {
+            self.flush_stats();
         }
     }
+
+    /// Adds the traffic counted since the last flush to the worker counters.
+    /// Happens on its own while there is traffic, a worker going idle has to flush the rest
+    pub(crate) fn flush_stats(&mut self) {
+        let (bytes, pixels) = std::mem::take(&mut self.unflushed_traffic);
+        if let Some(worker) = self.stats.worker(self.worker_index) {
+            worker.add(bytes, pixels);
+        }
+        self.stats_flushed_at = Instant::now();
+    }
+
+    #[inline]
+    pub(crate) fn has_unflushed_stats(&self) -> bool {
+        self.unflushed_traffic != (0, 0)
+    }
 }
 
 impl RingOperation for PixelflutConnectionHandler {
@@ -61,111 +499,131 @@ impl RingOperation for PixelflutConnectionHandler {
         ControlFlow<Self::ControlFlowWarn, Self::ControlFlowError>,
         Option<Self::RingData>,
     ) {
+        let span = connection.span.clone();
+        let _entered = span.enter();
+
+        if connection.quit {
+            // the shutdown completed, closing the socket
+            drop(connection);
+            return (ControlFlow::Continue, None);
+        }
+
         match completion_entry.result() {
             n if n > 0 => {
-                unsafe {
-                    connection.command_ring.advance_write_unchecked(n as usize);
-                }
+                let socket_fd = Fd(connection.socket.as_raw_fd());
+                let zero_copy = self.features.send_zc;
+                // held back until it is known whether the connection quits
+                let mut pending_replies = Vec::new();
+                let replies = &mut |msg| {
+                    pending_replies.push(msg);
+                    Ok(())
+                };
+                let Some(static_replies) = self.process_read(&mut connection, replies, n as usize)
+                else {
+                    drop(connection);
+                    return (ControlFlow::Continue, None);
+                };
 
-                /*
-                To mitigate DoS attacks using commands that generate significantly more egress traffic
-                than required ingress traffic, we only reply to the first occurrence of the
-                    - HELP (>10x egress)
-                    - SIZE (~ 2x egress)
-                command that yield from one socket read.
-                It should be ok to do that because:
-                    - HELP/SIZE is only issued manually by non-machine players, that are not that fast.
-                    - HELP/SIZE is only issued once for feature/canvas size detection by machines
-                 */
-                let mut static_replies = StaticReplies::default();
-                loop {
-                    match connection.command_ring.read_next_command() {
-                        Ok(cmd) => match cmd.handle_command(
-                            &mut self.canvas,
-                            Fd(connection.socket.as_raw_fd()),
-                            &mut submitter,
-                            &mut static_replies,
-                            connection.user_id,
-                            &mut connection.user_offset,
-                        ) {
-                            Ok(()) => {}
-                            Err(CommandExecutionError::CanvasError(
-                                CanvasError::PixelOutOfBounds { x, y },
-                            )) => {
-                                warn!("[user: {}] tried to set pixel out of bounds: ({x}, {y}); closing connection…",connection.user_id);
-                                drop(connection);
-                                return (ControlFlow::Continue, None);
-                            }
-                            Err(e) => {
-                                warn!("[user: {}] unable to execute command: {e}; closing connection…",connection.user_id);
-                                drop(connection);
-                                return (ControlFlow::Continue, None);
-                            }
-                        },
-                        Err(CommandRingError::MoreDataRequired) => {
-                            break;
-                        }
-                        Err(e) => {
-                            warn!(
-                                "[user: {}] error while parsing command: {e}; closing connection…",
-                                connection.user_id
-                            );
-                            drop(connection);
-                            return (ControlFlow::Continue, None);
-                        }
+                // QUIT links the replies of this read to the shutdown, so they are written first
+                // without holding back the other connections of the worker
+                let link = match connection.quit {
+                    true => Flags::IO_LINK,
+                    false => Flags::empty(),
+                };
+                for msg in pending_replies {
+                    if let Err(e) = submit_reply(socket_fd, &mut submitter, msg, zero_copy, link) {
+                        return (ControlFlow::Error(e.into()), None);
                     }
                 }
-                unsafe {
-                    let mut iovecs = Vec::with_capacity(0);
-                    if static_replies.size > 0 {
-                        if static_replies.size > 8 {
-                            warn!("connection {} from {} might be trying to DoS using SIZE egress amplification",
-                                connection.user_id, connection.address,
-                            )
-                        }
 
-                        iovecs.push(libc::iovec {
-                            iov_base: self.size_reply_buffer.as_ptr() as _,
-                            iov_len: self.size_reply_buffer.len(),
-                        })
-                    }
-                    if static_replies.help > 0 {
-                        if static_replies.help > 8 {
-                            warn!("connection {} from {} might be trying to DoS using HELP egress amplification",
-                                connection.user_id, connection.address,
+                let iovecs = self
+                    .static_replies(static_replies, &connection)
+                    .map(|reply| libc::iovec {
+                        iov_base: reply.as_ptr() as _,
+                        iov_len: reply.len(),
+                    })
+                    .collect::<Vec<_>>();
+                if !iovecs.is_empty() {
+                    let len = iovecs.iter().map(|iovec| iovec.iov_len).sum::<usize>();
+                    // the static replies live as long as the handler, HELP does not need to be copied
+                    let (write, descriptor) = match self.features.send_zc
+                        && len >= ZERO_COPY_THRESHOLD
+                    {
+                        true => {
+                            let message = message(iovecs);
+                            (
+                                opcode::SendMsgZc::new(socket_fd, &message.0).build(),
+                                WriteBufferDropDescriptor::Message(message),
                             )
                         }
-                        iovecs.push(libc::iovec {
-                            iov_base: HELP_TEXT.as_ptr() as _,
-                            iov_len: HELP_TEXT.len(),
-                        })
+                        false => (
+                            opcode::Writev::new(socket_fd, iovecs.as_ptr(), iovecs.len() as u32)
+                                .build(),
+                            WriteBufferDropDescriptor::IoVec(iovecs),
+                        ),
+                    };
+                    let write = write.flags(link).user_data(
+                        ring::pixel_flut_ring::UserData::write_buffer_drop(descriptor).into(),
+                    );
+                    if let Err(e) = unsafe { submitter.push_raw(write) } {
+                        return (ControlFlow::Error(e.into()), None);
                     }
+                }
 
-                    if !iovecs.is_empty() {
-                        let writev = opcode::Writev::new(
+                if connection.quit {
+                    // ends the chain of replies, a failed write cancels it and closes the socket anyway
+                    let shutdown = match self.features.shutdown {
+                        true => opcode::Shutdown::new(
                             Fd(connection.socket.as_raw_fd()),
-                            iovecs.as_ptr(),
-                            iovecs.len() as u32,
+                            libc::SHUT_RDWR,
                         )
+                        .build(),
+                        false => opcode::Nop::new().build(),
+                    };
+
+                    return match submitter.push(shutdown, connection) {
+                        Ok(()) => (ControlFlow::Continue, None),
+                        Err(e) => (ControlFlow::Error(e.into()), None),
+                    };
+                }
+
+                let throttle = self
+                    .limits
+                    .ingress_rate()
+                    .and_then(|rate| connection.user_state.throttle_ingress(n as u64, rate));
+                if let Some(delay) = throttle {
+                    // the hard link lets the read follow the expired timeout, the unread data
+                    // makes TCP slow down the sender meanwhile
+                    let timespec = Box::new(Timespec::from(delay));
+                    let timeout = opcode::Timeout::new(&*timespec)
                         .build()
+                        .flags(Flags::IO_HARDLINK)
                         .user_data(
                             ring::pixel_flut_ring::UserData::write_buffer_drop(
-                                WriteBufferDropDescriptor::IoVec(iovecs),
+                                WriteBufferDropDescriptor::Timespec(timespec),
                             )
                             .into(),
                         );
-                        if let Err(e) = submitter.push_raw(writev) {
-                            return (ControlFlow::Error(e.into()), None);
-                        }
+                    if let Err(e) = unsafe { submitter.push_raw(timeout) } {
+                        return (ControlFlow::Error(e.into()), None);
                     }
                 }
 
-                let (ptr, len) = connection.command_ring.contig_write();
+                let (ptr, len) = connection.read_buffer();
                 let read =
                     opcode::Read::new(Fd(RawFd::from(connection.socket.as_raw_fd())), ptr, len)
                         .build();
 
-                match submitter.push(read, connection) {
+                let Some(idle_timeout) = self.idle_timeout else {
+                    return match submitter.push(read, connection) {
+                        Ok(()) => (ControlFlow::Continue, None),
+                        Err(e) => (ControlFlow::Error(e.into()), None),
+                    };
+                };
+                if let Err(e) = submitter.push(read.flags(Flags::IO_LINK), connection) {
+                    return (ControlFlow::Error(e.into()), None);
+                }
+                match unsafe { submitter.push_raw(link_idle_timeout(idle_timeout)) } {
                     Ok(()) => (ControlFlow::Continue, None),
                     Err(e) => (ControlFlow::Error(e.into()), None),
                 }
@@ -174,6 +632,15 @@ impl RingOperation for PixelflutConnectionHandler {
                 drop(connection);
                 (ControlFlow::Continue, None)
             }
+            e if e == -libc::ECANCELED => {
+                debug!(
+                    "connection {} from {} idle for {:?}, closing connection…",
+                    connection.user_id,
+                    connection.address,
+                    self.idle_timeout.unwrap_or_default()
+                );
+                (ControlFlow::Continue, None)
+            }
             e => {
                 let e = io::Error::from_raw_os_error(-e);
                 warn!(
@@ -192,23 +659,120 @@ impl RingOperation for PixelflutConnectionHandler {
         _submitter: SubmissionQueueSubmitter<Self::RingData, W>,
     ) -> eyre::Result<()> {
         drop(connection);
+        self.flush_stats();
         Ok(())
     }
 }
 
+/// Cancels the read pushed right before, which has to be flagged with IO_LINK,
+/// if it does not complete within `idle_timeout`. The read then fails with ECANCELED
+pub(crate) fn link_idle_timeout(idle_timeout: Duration) -> Entry {
+    let timespec = Box::new(Timespec::from(idle_timeout));
+    opcode::LinkTimeout::new(&*timespec).build().user_data(
+        ring::pixel_flut_ring::UserData::write_buffer_drop(WriteBufferDropDescriptor::Timespec(
+            timespec,
+        ))
+        .into(),
+    )
+}
+
 #[derive(Debug)]
 pub struct Connection {
     pub user_id: u32,
     pub user_offset: (u32, u32),
+    pub transform: Transform,
     pub user_state: Arc<UserState>,
+    /// the open connections of the user are published there as well
+    pub stats: Arc<Stats>,
     pub socket: Socket,
     pub address: SocketAddr,
     pub command_ring: CommandRing,
+    pub skipped_lines: usize,
+    /// QUIT was received, the pending operation is the final shutdown
+    pub quit: bool,
+    /// commands per ACK reply, 0 if not requested
+    pub ack_every: u32,
+    pub unacked: u32,
+    /// set by COMPRESS, everything read afterwards is decompressed into the command ring
+    pub decompressor: Option<Decompressor>,
+    /// ns since the epoch, kicking the user closes all connections made before
+    pub connected_at: u64,
+    /// entered while the connection is handled, a child of the worker span
+    pub span: Span,
+}
+
+impl Connection {
+    pub fn new(new_client: NewClient) -> Self {
+        let span = info_span!(
+            "connection",
+            uid = new_client.uid,
+            peer = %new_client.address
+        );
+        let _entered = span.enter();
+        info!(
+            "+ {} [user: {}, connections: {}]",
+            new_client.address,
+            new_client.uid,
+            new_client.state.connections.load(Ordering::Relaxed)
+        );
+
+        new_client.state.opened(new_client.socket.as_raw_fd());
+        Self {
+            user_id: new_client.uid,
+            user_offset: (0, 0),
+            transform: Transform::default(),
+            user_state: new_client.state,
+            stats: new_client.stats,
+            socket: new_client.socket,
+            address: new_client.address,
+            command_ring: CommandRing::new(new_client.buffer_size),
+            skipped_lines: 0,
+            quit: false,
+            ack_every: 0,
+            unacked: 0,
+            decompressor: None,
+            connected_at: nanos_since_epoch(),
+            span: span.clone(),
+        }
+    }
+
+    #[inline]
+    fn kicked(&self) -> bool {
+        self.user_state.kicked_at.load(Ordering::Relaxed) >= self.connected_at
+    }
+
+    /// Where the next read goes, empty if nothing more fits until commands are processed
+    #[inline]
+    pub fn read_buffer(&mut self) -> (*mut u8, u32) {
+        match self.decompressor.as_mut() {
+            Some(decompressor) => decompressor.read_buffer(),
+            None => self.command_ring.contig_write(),
+        }
+    }
+
+    /// Counts a processed command, returns the count to acknowledge once an ACK is due
+    #[inline]
+    fn count_processed(&mut self) -> Option<u32> {
+        if self.ack_every == 0 {
+            return None;
+        }
+
+        self.unacked += 1;
+        (self.unacked == self.ack_every).then(|| {
+            self.unacked = 0;
+            self.ack_every
+        })
+    }
 }
 
 impl Drop for Connection {
     fn drop(&mut self) {
+        let _entered = self.span.enter();
+        // the socket is closed along with the fields
+        self.user_state.closed(self.socket.as_raw_fd());
         let connections = self.user_state.connections.fetch_sub(1, Ordering::Relaxed) - 1;
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+        self.stats.disconnected(self.user_id);
         info!(
             "- {} [user: {}, connections: {}]",
             self.address, self.user_id, connections,
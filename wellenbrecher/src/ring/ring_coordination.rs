@@ -1,84 +1,336 @@
 use std::ffi::CStr;
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::num::NonZeroUsize;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::ops::Sub;
-use std::os::fd::{AsRawFd, FromRawFd, RawFd};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use libc::c_int;
-use rummelplatz::io_uring::opcode;
-use rummelplatz::io_uring::squeue::{Entry, PushError};
-use rummelplatz::io_uring::types::Fd;
+use rummelplatz::io_uring::squeue::{Entry, Flags, PushError};
+use rummelplatz::io_uring::types::{Fd, Timespec};
+use rummelplatz::io_uring::{cqueue, opcode, IoUring};
 use rummelplatz::{ControlFlow, RingOperation, SubmissionQueueSubmitter};
+use rustls::ServerConfig;
 use socket2::Socket;
-use tracing::{debug, error, info};
+use tracing::{debug, debug_span, error, info, warn};
 
-use crate::ring::command_ring::CommandRing;
+use wellenbrecher_canvas::{Bgra, Canvas, Stats, TeamID};
+
+use crate::bans::Bans;
+use crate::config::Settings;
+use crate::notify;
+use crate::ring::admin::{Admin, AdminConnection};
+use crate::ring::features::RingFeatures;
 use crate::ring::pixel_flut_ring::UserData;
-use crate::ring::pixelflut_connection_handler::Connection;
+use crate::ring::pixelflut_connection_handler::{link_idle_timeout, Connection};
+use crate::teams::Teams;
+use crate::tls;
 
 const IORING_CQE_F_MORE: u32 = 1u32 << 1;
+const REJECTED_COMMAND_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+/// Traffic above the ingress rate that is let through before reads are delayed
+const INGRESS_BURST: Duration = Duration::from_secs(1);
+const PIXEL_BURST: Duration = Duration::from_secs(1);
+/// Further connections are dropped while this many TLS handshakes are in progress
+const MAX_PENDING_HANDSHAKES: usize = 1024;
+/// Sent to peers refused due to --max-connections
+pub(crate) const SERVER_FULL: &[u8] = b"ERR server full\n";
+/// Sent to peers refused due to --connections-per-ip
+pub(crate) const TOO_MANY_CONNECTIONS: &[u8] = b"ERR too many connections\n";
+
+/// Open connections of all users, counted from [NewClient::new] until the [Connection] is dropped
+pub(crate) static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Debug)]
 pub enum RingMessage {
-    NewConnection,
+    NewConnection {
+        listener: RawFd,
+    },
     NewClient(NewClient),
     Signal(Box<libc::signalfd_siginfo>),
+    /// The eventfd of the [Inbox] was written to
+    Wakeup(Box<u64>),
+    NewAdminConnection {
+        listener: RawFd,
+    },
+    AdminRead(Box<AdminConnection>),
+    /// Time to ping the watchdog of the service manager again
+    Watchdog(Box<Timespec>),
+    /// Time to check whether draining is done
+    Drain(Box<Timespec>),
     Exit,
 }
 
+/// Stands in for the address of peers connected via --unix-socket. No TCP peer has it, so they all
+/// share one user, their pids are reused too quickly to tell them apart
+pub(crate) const UNIX_PEER: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
 #[derive(Debug)]
 pub struct NewClient {
     pub(crate) socket: Socket,
     pub(crate) address: SocketAddr,
     pub(crate) uid: u32,
     pub(crate) state: Arc<UserState>,
+    pub(crate) stats: Arc<Stats>,
     pub(crate) buffer_size: usize,
 }
 
+impl NewClient {
+    /// Looks up or creates the user the peer belongs to and counts the new connection.
+    /// The socket is handed back if the user has `max_connections` open already
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        socket: Socket,
+        address: SocketAddr,
+        clients: &RwLock<Vec<Arc<UserState>>>,
+        masks: &Masks,
+        teams: Option<&Teams>,
+        stats: &Arc<Stats>,
+        buffer_size: usize,
+        max_connections: Option<NonZeroU64>,
+    ) -> Result<Self, Socket> {
+        let (uid, state) = get_or_create_user_state(
+            clients
+                .write()
+                .expect("unable to acquire lock on clients")
+                .as_mut(),
+            address.ip(),
+            masks,
+            teams,
+        );
+        let admitted =
+            state
+                .connections
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |connections| {
+                    match max_connections {
+                        Some(max) if connections as u64 >= max.get() => None,
+                        _ => Some(connections + 1),
+                    }
+                });
+        if admitted.is_err() {
+            return Err(socket);
+        }
+        OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        stats.set_team(uid, state.team);
+        stats.connected(uid);
+
+        Ok(Self {
+            socket,
+            address,
+            uid,
+            state,
+            stats: stats.clone(),
+            buffer_size,
+        })
+    }
+}
+
+/// How messages are handed to the coordination of a worker ring
 #[derive(Debug)]
+pub enum Mailbox {
+    /// Passed with IORING_OP_MSG_RING to the ring fd
+    Ring(RawFd),
+    /// Queued in the [Inbox] of the worker which is woken up through its eventfd
+    EventFd {
+        event_fd: RawFd,
+        messages: Sender<RingMessage>,
+    },
+}
+
+/// Receiving end of [Mailbox::EventFd]
+#[derive(Debug)]
+pub struct Inbox {
+    event_fd: OwnedFd,
+    messages: Receiver<RingMessage>,
+}
+
+impl Mailbox {
+    pub fn new(ring: &IoUring, features: RingFeatures) -> io::Result<(Self, Option<Inbox>)> {
+        if features.msg_ring {
+            return Ok((Mailbox::Ring(ring.as_raw_fd()), None));
+        }
+
+        let event_fd = match unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) } {
+            fd if fd < 0 => return Err(io::Error::last_os_error()),
+            fd => unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        Ok((
+            Mailbox::EventFd {
+                event_fd: event_fd.as_raw_fd(),
+                messages: tx,
+            },
+            Some(Inbox {
+                event_fd,
+                messages: rx,
+            }),
+        ))
+    }
+
+    fn send<W: Fn(&mut Entry, <RingCoordination as RingOperation>::RingData)>(
+        &self,
+        submitter: &mut SubmissionQueueSubmitter<<RingCoordination as RingOperation>::RingData, W>,
+        message: RingMessage,
+    ) -> eyre::Result<()> {
+        match self {
+            Mailbox::Ring(ring_fd) => {
+                let msg = opcode::MsgRingData::new(
+                    Fd(*ring_fd),
+                    0,
+                    UserData::coordination(message).into(),
+                    Some(IORING_CQE_F_MORE),
+                )
+                .build()
+                .user_data(0);
+                unsafe { submitter.push_raw(msg)? };
+            }
+            Mailbox::EventFd { .. } => self.post(message)?,
+        }
+
+        Ok(())
+    }
+
+    /// Sends from outside of the rings, only possible for [Mailbox::EventFd]
+    pub fn post(&self, message: RingMessage) -> eyre::Result<()> {
+        let Mailbox::EventFd { event_fd, messages } = self else {
+            return Err(eyre::eyre!(
+                "messages to the ring fd need a ring to be sent"
+            ));
+        };
+
+        messages
+            .send(message)
+            .map_err(|_| eyre::eyre!("worker is gone"))?;
+        let wakeup = 1u64;
+        let n = unsafe {
+            libc::write(
+                *event_fd,
+                std::ptr::addr_of!(wakeup) as *const _,
+                std::mem::size_of::<u64>(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(())
+    }
+
+    fn try_clone(&self) -> Option<Self> {
+        match self {
+            Mailbox::Ring(_) => None,
+            Mailbox::EventFd { event_fd, messages } => Some(Mailbox::EventFd {
+                event_fd: *event_fd,
+                messages: messages.clone(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum RingCoordination {
     Empress {
         sockets: Vec<Socket>,
-        ring_fds: Vec<RawFd>,
-        ring_fds_cycle_idx: usize,
+        mailboxes: Vec<Mailbox>,
+        mailboxes_cycle_idx: usize,
+        inbox: Option<Inbox>,
+        features: RingFeatures,
         signal_fd: RawFd,
 
         connection_buffer_size: NonZeroUsize,
+        socket_options: SocketOptions,
         clients: Arc<RwLock<Vec<Arc<UserState>>>>,
-        ipv4_mask: Ipv4Addr,
-        ipv6_mask: Ipv6Addr,
+        teams: Option<Teams>,
+        bans: Arc<Bans>,
+        /// reloaded on SIGHUP
+        settings: Settings,
+
+        stats: Arc<Stats>,
+        stats_dump: PathBuf,
+
+        /// Connections are handed to the workers once their TLS handshake is done
+        tls: Option<Arc<ServerConfig>>,
+        admin: Option<Box<Admin>>,
+        /// reset to the initial color or pattern in `background` on SIGUSR1
+        canvas: Canvas,
+        background: Box<[Bgra]>,
+        idle_timeout: Option<Duration>,
+        /// how often the watchdog of the service manager is pinged
+        watchdog: Option<Duration>,
+        drain_grace_period: Duration,
+        /// set once draining started, the server exits by then at the latest
+        drain_deadline: Option<Instant>,
 
         last_exit_signal: Instant,
     },
-    Lackey,
+    Lackey {
+        inbox: Option<Inbox>,
+        idle_timeout: Option<Duration>,
+    },
 }
 
 impl RingCoordination {
-    pub fn lackey() -> Self {
-        Self::Lackey
+    pub fn lackey(inbox: Option<Inbox>, idle_timeout: Option<Duration>) -> Self {
+        Self::Lackey {
+            inbox,
+            idle_timeout,
+        }
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn empress(
         sockets: Vec<Socket>,
-        ring_fds: Vec<RawFd>,
+        mailboxes: Vec<Mailbox>,
+        inbox: Option<Inbox>,
+        features: RingFeatures,
         signal_fd: RawFd,
         connection_buffer_size: NonZeroUsize,
+        socket_options: SocketOptions,
         clients: Arc<RwLock<Vec<Arc<UserState>>>>,
-        ipv4_mask: Ipv4Addr,
-        ipv6_mask: Ipv6Addr,
+        teams: Option<Teams>,
+        stats: Arc<Stats>,
+        stats_dump: PathBuf,
+        bans: Arc<Bans>,
+        settings: Settings,
+        tls: Option<Arc<ServerConfig>>,
+        admin: Option<Admin>,
+        canvas: Canvas,
+        background: Box<[Bgra]>,
+        idle_timeout: Option<Duration>,
+        watchdog: Option<Duration>,
+        drain_grace_period: Duration,
     ) -> Self {
         Self::Empress {
             sockets,
-            ring_fds,
-            ring_fds_cycle_idx: 0,
+            mailboxes,
+            mailboxes_cycle_idx: 0,
+            inbox,
+            features,
             signal_fd,
             connection_buffer_size,
+            socket_options,
             clients,
-            ipv4_mask,
-            ipv6_mask,
+            teams,
+            bans,
+            settings,
+            stats,
+            stats_dump,
+            tls,
+            admin: admin.map(Box::new),
+            canvas,
+            background,
+            idle_timeout,
+            watchdog,
+            drain_grace_period,
+            drain_deadline: None,
             last_exit_signal: Instant::now().sub(Duration::from_secs(20)),
         }
     }
@@ -97,16 +349,43 @@ impl RingOperation for RingCoordination {
     ) -> eyre::Result<()> {
         match self {
             RingCoordination::Empress {
-                sockets, signal_fd, ..
+                sockets,
+                inbox,
+                features,
+                signal_fd,
+                admin,
+                watchdog,
+                ..
             } => {
                 for socket in sockets {
-                    setup_socket(&mut submitter, &socket)?;
+                    setup_socket(&mut submitter, &socket, features.accept_multi)?;
                 }
                 setup_signal(&mut submitter, *signal_fd)?;
+                if let Some(admin) = admin {
+                    let listener = admin.listener();
+                    submitter.push(
+                        accept(listener, features.accept_multi),
+                        RingMessage::NewAdminConnection { listener },
+                    )?;
+                }
+                if let Some(inbox) = inbox {
+                    setup_inbox(&mut submitter, inbox)?;
+                }
+                if let Some(interval) = watchdog {
+                    setup_watchdog(&mut submitter, Box::new(Timespec::from(*interval)))?;
+                }
+
+                // the lackeys have their rings by now
+                notify::notify("READY=1");
+                Ok(())
+            }
+            RingCoordination::Lackey { inbox, .. } => {
+                if let Some(inbox) = inbox {
+                    setup_inbox(&mut submitter, inbox)?;
+                }
 
                 Ok(())
             }
-            RingCoordination::Lackey => Ok(()),
         }
     }
 
@@ -121,17 +400,44 @@ impl RingOperation for RingCoordination {
     ) {
         match (ring_data, self) {
             (
-                RingMessage::NewConnection,
+                RingMessage::NewConnection { listener },
                 Self::Empress {
-                    ring_fds,
-                    ring_fds_cycle_idx,
+                    mailboxes,
+                    mailboxes_cycle_idx,
+                    features,
                     clients,
-                    ipv4_mask,
-                    ipv6_mask,
+                    teams,
+                    bans,
+                    settings,
+                    stats,
                     connection_buffer_size,
+                    socket_options,
+                    tls,
+                    drain_deadline,
                     ..
                 },
             ) => {
+                // the listener was shut down by draining
+                if drain_deadline.is_some() && completion_entry.result() < 0 {
+                    debug!("stopped accepting new clients on a listener");
+                    return (ControlFlow::Continue, None);
+                }
+
+                // a single accept or the multishot accept was terminated, e.g. due to a full cq
+                let pending_accept = match cqueue::more(completion_entry.flags()) {
+                    true => Some(RingMessage::NewConnection { listener }),
+                    false => {
+                        let accept = accept(listener, features.accept_multi);
+                        if let Err(e) =
+                            submitter.push(accept, RingMessage::NewConnection { listener })
+                        {
+                            error!("unable to accept new clients");
+                            return (ControlFlow::Error(e.into()), None);
+                        }
+                        None
+                    }
+                };
+
                 if completion_entry.result() < 0 {
                     let e = io::Error::from_raw_os_error(-completion_entry.result());
                     error!("failed to accept new client: {e}");
@@ -141,53 +447,101 @@ impl RingOperation for RingCoordination {
                 let socket = unsafe { Socket::from_raw_fd(completion_entry.result()) };
 
                 let peer_addr = match socket.peer_addr() {
-                    Ok(peer_addr) => peer_addr.as_socket().unwrap(),
+                    Ok(peer_addr) => peer_addr.as_socket().unwrap_or(UNIX_PEER),
                     Err(e) => {
                         debug!("connection lost early: {e}");
-                        return (ControlFlow::Continue, Some(RingMessage::NewConnection));
+                        return (ControlFlow::Continue, pending_accept);
                     }
                 };
+                let _entered = debug_span!("accept", peer = %peer_addr).entered();
+                if bans.is_banned(peer_addr.ip()) {
+                    debug!("refused connection from banned {peer_addr}");
+                    return (ControlFlow::Continue, pending_accept);
+                }
+                let unix = peer_addr == UNIX_PEER;
+                // local generators share one user
+                let max_connections = settings.limits.user_connections().filter(|_| !unix);
+                if is_full(&settings.limits) {
+                    debug!("server full, refused connection from {peer_addr}");
+                    // a TLS client would not understand the reply
+                    if tls.is_none() || unix {
+                        refuse(&socket, SERVER_FULL);
+                    }
+                    return (ControlFlow::Continue, pending_accept);
+                }
+                if !unix {
+                    if let Err(e) = socket_options.apply(&socket) {
+                        warn!("unable to set socket options for {peer_addr}: {e}");
+                    }
+                }
+
+                let mailbox = mailboxes
+                    .get(*mailboxes_cycle_idx % mailboxes.len())
+                    .unwrap();
+                *mailboxes_cycle_idx = mailboxes_cycle_idx.wrapping_add(1);
 
-                let (user_id, user_state) = get_or_create_user_state(
-                    clients
-                        .write()
-                        .expect("unable to acquire lock on clients")
-                        .as_mut(),
-                    peer_addr.ip(),
-                    *ipv4_mask,
-                    *ipv6_mask,
-                );
-                user_state.connections.fetch_add(1, Ordering::Relaxed);
-
-                let new_client = NewClient {
+                // local generators skip the handshake
+                if let Some(tls) = tls.as_ref().filter(|_| !unix) {
+                    let Some(mailbox) = mailbox.try_clone() else {
+                        error!("TLS connections need eventfd mailboxes");
+                        return (
+                            ControlFlow::Error(eyre::eyre!("unable to hand over TLS connections")),
+                            None,
+                        );
+                    };
+                    start_tls_handshake(
+                        tls.clone(),
+                        socket,
+                        peer_addr,
+                        mailbox,
+                        clients.clone(),
+                        settings.masks.clone(),
+                        teams.clone(),
+                        stats.clone(),
+                        connection_buffer_size.get(),
+                        max_connections,
+                    );
+                    return (ControlFlow::Continue, pending_accept);
+                }
+
+                let new_client = match NewClient::new(
                     socket,
-                    address: peer_addr,
-                    uid: user_id,
-                    state: user_state,
-                    buffer_size: connection_buffer_size.get(),
+                    peer_addr,
+                    clients,
+                    &settings.masks,
+                    teams.as_ref(),
+                    stats,
+                    connection_buffer_size.get(),
+                    max_connections,
+                ) {
+                    Ok(new_client) => new_client,
+                    Err(socket) => {
+                        debug!("too many connections, refused connection from {peer_addr}");
+                        refuse(&socket, TOO_MANY_CONNECTIONS);
+                        return (ControlFlow::Continue, pending_accept);
+                    }
                 };
-
-                let fd = ring_fds.get(*ring_fds_cycle_idx % ring_fds.len()).unwrap();
-                *ring_fds_cycle_idx = ring_fds_cycle_idx.wrapping_add(1);
-                let msg = opcode::MsgRingData::new(
-                    Fd(*fd),
-                    0,
-                    UserData::coordination(RingMessage::NewClient(new_client)).into(),
-                    Some(IORING_CQE_F_MORE),
-                )
-                .build()
-                .user_data(0);
-                if let Err(e) = unsafe { submitter.push_raw(msg) } {
+                if let Err(e) = mailbox.send(&mut submitter, RingMessage::NewClient(new_client)) {
                     error!("unable to send new client to worker");
-                    return (ControlFlow::Error(e.into()), None);
+                    return (ControlFlow::Error(e), None);
                 }
 
-                (ControlFlow::Continue, Some(RingMessage::NewConnection))
+                (ControlFlow::Continue, pending_accept)
             }
             (
                 RingMessage::Signal(signal),
                 Self::Empress {
-                    ring_fds,
+                    sockets,
+                    mailboxes,
+                    signal_fd,
+                    clients,
+                    stats,
+                    stats_dump,
+                    settings,
+                    canvas,
+                    background,
+                    drain_grace_period,
+                    drain_deadline,
                     last_exit_signal,
                     ..
                 },
@@ -205,22 +559,74 @@ impl RingOperation for RingCoordination {
                         *last_exit_signal = Instant::now();
 
                         info!("received {sig_name} signal. Shutting down...");
+                        notify::notify("STOPPING=1");
+                        exit_workers(&mut submitter, mailboxes);
+                    }
+                    signal if signal == libc::SIGRTMIN() => {
+                        if drain_deadline.is_some() {
+                            info!("received {sig_name} signal. Already draining...");
+                        } else {
+                            info!(
+                                "received {sig_name} signal. Draining connections for up to {drain_grace_period:?}..."
+                            );
+                            notify::notify("STOPPING=1");
+                            stop_listening(sockets);
+                            *drain_deadline = Some(Instant::now() + *drain_grace_period);
 
-                        for fd in ring_fds {
-                            let msg = opcode::MsgRingData::new(
-                                Fd(fd.as_raw_fd()),
-                                0,
-                                UserData::coordination(RingMessage::Exit).into(),
-                                Some(IORING_CQE_F_MORE),
-                            )
-                            .build()
-                            .user_data(0);
-
-                            if let Err(e) = unsafe { submitter.push_raw(msg) } {
-                                error!("unable to shutdown gracefully: {e}\nAborting...");
-                                std::process::exit(-1);
+                            let interval = Box::new(Timespec::from(DRAIN_CHECK_INTERVAL));
+                            if let Err(e) = setup_drain_check(&mut submitter, interval) {
+                                error!("unable to drain connections");
+                                return (ControlFlow::Error(e.into()), None);
                             }
                         }
+
+                        if let Err(e) = setup_signal(&mut submitter, *signal_fd) {
+                            error!("unable to keep handling signals: {e}");
+                            return (ControlFlow::Error(e.into()), None);
+                        }
+                    }
+                    libc::SIGUSR2 => {
+                        let clients = clients
+                            .read()
+                            .expect("unable to acquire lock on clients")
+                            .clone();
+                        match write_stats_dump(stats_dump, &clients, stats) {
+                            Ok(()) => info!(
+                                "received {sig_name} signal. Wrote stats to {}",
+                                stats_dump.display()
+                            ),
+                            Err(e) => error!(
+                                "received {sig_name} signal. Unable to write stats to {}: {e}",
+                                stats_dump.display()
+                            ),
+                        }
+
+                        if let Err(e) = setup_signal(&mut submitter, *signal_fd) {
+                            error!("unable to keep handling signals: {e}");
+                            return (ControlFlow::Error(e.into()), None);
+                        }
+                    }
+                    libc::SIGHUP => {
+                        match settings.reload() {
+                            Ok(()) => info!("received {sig_name} signal. Reloaded the settings"),
+                            Err(e) => error!(
+                                "received {sig_name} signal. Unable to reload the settings: {e}"
+                            ),
+                        }
+
+                        if let Err(e) = setup_signal(&mut submitter, *signal_fd) {
+                            error!("unable to keep handling signals: {e}");
+                            return (ControlFlow::Error(e.into()), None);
+                        }
+                    }
+                    libc::SIGUSR1 => {
+                        canvas.reset(background);
+                        info!("received {sig_name} signal. Cleared the canvas");
+
+                        if let Err(e) = setup_signal(&mut submitter, *signal_fd) {
+                            error!("unable to keep handling signals: {e}");
+                            return (ControlFlow::Error(e.into()), None);
+                        }
                     }
                     _ => {
                         info!("received {sig_name} signal. Ignoring...");
@@ -229,34 +635,144 @@ impl RingOperation for RingCoordination {
 
                 (ControlFlow::Continue, None)
             }
-            (RingMessage::NewClient(new_client), _) => {
-                info!(
-                    "+ {} [user: {}, connections: {}]",
-                    new_client.address,
-                    new_client.uid,
-                    new_client.state.connections.load(Ordering::Relaxed)
-                );
-
-                let connection = Connection {
-                    user_id: new_client.uid,
-                    user_offset: (0, 0),
-                    user_state: new_client.state,
-                    socket: new_client.socket,
-                    address: new_client.address,
-                    command_ring: CommandRing::new(new_client.buffer_size),
+            (
+                RingMessage::NewAdminConnection { listener },
+                Self::Empress {
+                    features,
+                    admin: Some(_),
+                    ..
+                },
+            ) => {
+                let pending_accept = match cqueue::more(completion_entry.flags()) {
+                    true => Some(RingMessage::NewAdminConnection { listener }),
+                    false => {
+                        let accept = accept(listener, features.accept_multi);
+                        if let Err(e) =
+                            submitter.push(accept, RingMessage::NewAdminConnection { listener })
+                        {
+                            error!("unable to accept new admin connections");
+                            return (ControlFlow::Error(e.into()), None);
+                        }
+                        None
+                    }
                 };
 
-                let (ptr, len) = connection.command_ring.contig_write();
-                let read =
-                    opcode::Read::new(Fd(RawFd::from(connection.socket.as_raw_fd())), ptr, len)
-                        .build()
-                        .user_data(UserData::pixelflut_connection_handler(connection).into());
+                if completion_entry.result() < 0 {
+                    let e = io::Error::from_raw_os_error(-completion_entry.result());
+                    error!("failed to accept admin connection: {e}");
+                    return (ControlFlow::Continue, pending_accept);
+                }
 
-                unsafe {
-                    match submitter.push_raw(read) {
+                let socket = unsafe { UnixStream::from_raw_fd(completion_entry.result()) };
+                let connection = match AdminConnection::new(socket) {
+                    Ok(connection) => Box::new(connection),
+                    Err(e) => {
+                        error!("unable to set up admin connection: {e}");
+                        return (ControlFlow::Continue, pending_accept);
+                    }
+                };
+                debug!("new admin connection");
+                if let Err(e) = admin_read(&mut submitter, connection) {
+                    return (ControlFlow::Error(e.into()), pending_accept);
+                }
+
+                (ControlFlow::Continue, pending_accept)
+            }
+            (
+                RingMessage::AdminRead(mut connection),
+                Self::Empress {
+                    admin: Some(admin), ..
+                },
+            ) => {
+                match completion_entry.result() {
+                    n if n < 0 => {
+                        let e = io::Error::from_raw_os_error(-n);
+                        debug!("admin connection lost: {e}");
+                    }
+                    0 => debug!("admin connection closed"),
+                    n => {
+                        if connection.on_read(n as usize, admin) {
+                            if let Err(e) = admin_read(&mut submitter, connection) {
+                                return (ControlFlow::Error(e.into()), None);
+                            }
+                        }
+                    }
+                }
+
+                (ControlFlow::Continue, None)
+            }
+            (
+                RingMessage::NewClient(new_client),
+                Self::Empress { idle_timeout, .. } | Self::Lackey { idle_timeout, .. },
+            ) => match start_connection(&mut submitter, new_client, *idle_timeout) {
+                Ok(()) => (ControlFlow::Continue, None),
+                Err(e) => (ControlFlow::Error(e.into()), None),
+            },
+            (
+                RingMessage::Wakeup(_),
+                Self::Empress {
+                    inbox: Some(inbox),
+                    idle_timeout,
+                    ..
+                }
+                | Self::Lackey {
+                    inbox: Some(inbox),
+                    idle_timeout,
+                },
+            ) => {
+                let mut control_flow = ControlFlow::Continue;
+                for message in inbox.messages.try_iter() {
+                    match message {
+                        RingMessage::NewClient(new_client) => {
+                            if let Err(e) =
+                                start_connection(&mut submitter, new_client, *idle_timeout)
+                            {
+                                return (ControlFlow::Error(e.into()), None);
+                            }
+                        }
+                        RingMessage::Exit => control_flow = ControlFlow::Exit,
+                        _ => unreachable!(),
+                    }
+                }
+
+                if let ControlFlow::Continue = control_flow {
+                    if let Err(e) = setup_inbox(&mut submitter, inbox) {
+                        return (ControlFlow::Error(e.into()), None);
+                    }
+                }
+                (control_flow, None)
+            }
+            (
+                RingMessage::Drain(timespec),
+                Self::Empress {
+                    mailboxes,
+                    clients,
+                    drain_deadline: Some(deadline),
+                    ..
+                },
+            ) => {
+                let connections = open_connections(clients);
+                if connections == 0 {
+                    info!("all connections are gone. Shutting down...");
+                } else if Instant::now() >= *deadline {
+                    info!(
+                        "grace period is over, closing {connections} connections. Shutting down..."
+                    );
+                } else {
+                    return match setup_drain_check(&mut submitter, timespec) {
                         Ok(()) => (ControlFlow::Continue, None),
                         Err(e) => (ControlFlow::Error(e.into()), None),
-                    }
+                    };
+                }
+
+                exit_workers(&mut submitter, mailboxes);
+                (ControlFlow::Continue, None)
+            }
+            (RingMessage::Watchdog(timespec), Self::Empress { .. }) => {
+                notify::notify("WATCHDOG=1");
+                match setup_watchdog(&mut submitter, timespec) {
+                    Ok(()) => (ControlFlow::Continue, None),
+                    Err(e) => (ControlFlow::Error(e.into()), None),
                 }
             }
             (RingMessage::Exit, _) => (ControlFlow::Exit, None),
@@ -275,16 +791,205 @@ impl RingOperation for RingCoordination {
     }
 }
 
+/// Whether --max-connections connections are open already
+pub(crate) fn is_full(limits: &Limits) -> bool {
+    limits
+        .connections()
+        .is_some_and(|max| OPEN_CONNECTIONS.load(Ordering::Relaxed) as u64 >= max.get())
+}
+
+/// Tells a peer why it is about to be disconnected. The reply fits into the send buffer of a new
+/// socket, peers not reading it are not waited for
+pub(crate) fn refuse(socket: &Socket, reply: &[u8]) {
+    let _ = socket.send_with_flags(reply, libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL);
+}
+
+/// Does the handshake on its own thread, the ring only sees the connection once it is done
+#[allow(clippy::too_many_arguments)]
+fn start_tls_handshake(
+    config: Arc<ServerConfig>,
+    socket: Socket,
+    address: SocketAddr,
+    mailbox: Mailbox,
+    clients: Arc<RwLock<Vec<Arc<UserState>>>>,
+    masks: Arc<Masks>,
+    teams: Option<Teams>,
+    stats: Arc<Stats>,
+    buffer_size: usize,
+    max_connections: Option<NonZeroU64>,
+) {
+    static PENDING_HANDSHAKES: AtomicUsize = AtomicUsize::new(0);
+    if PENDING_HANDSHAKES.fetch_add(1, Ordering::Relaxed) >= MAX_PENDING_HANDSHAKES {
+        PENDING_HANDSHAKES.fetch_sub(1, Ordering::Relaxed);
+        debug!("too many pending TLS handshakes, dropping connection from {address}");
+        return;
+    }
+
+    let handshake = move || {
+        let result = tls::accept(config, &socket);
+        PENDING_HANDSHAKES.fetch_sub(1, Ordering::Relaxed);
+        if let Err(e) = result {
+            debug!("TLS handshake with {address} failed: {e}");
+            return;
+        }
+
+        let Ok(new_client) = NewClient::new(
+            socket,
+            address,
+            &clients,
+            &masks,
+            teams.as_ref(),
+            &stats,
+            buffer_size,
+            max_connections,
+        ) else {
+            debug!("too many connections, dropping connection from {address}");
+            return;
+        };
+        if let Err(e) = mailbox.post(RingMessage::NewClient(new_client)) {
+            error!("unable to send new client to worker: {e}");
+        }
+    };
+    if let Err(e) = std::thread::Builder::new()
+        .name("TLS handshake".to_string())
+        .spawn(handshake)
+    {
+        PENDING_HANDSHAKES.fetch_sub(1, Ordering::Relaxed);
+        error!("unable to start TLS handshake with {address}: {e}");
+    }
+}
+
+fn start_connection<W: Fn(&mut Entry, <RingCoordination as RingOperation>::RingData)>(
+    submitter: &mut SubmissionQueueSubmitter<<RingCoordination as RingOperation>::RingData, W>,
+    new_client: NewClient,
+    idle_timeout: Option<Duration>,
+) -> Result<(), PushError> {
+    let connection = Connection::new(new_client);
+
+    let (ptr, len) = connection.command_ring.contig_write();
+    let read = opcode::Read::new(Fd(RawFd::from(connection.socket.as_raw_fd())), ptr, len)
+        .build()
+        .user_data(UserData::pixelflut_connection_handler(connection).into());
+
+    match idle_timeout {
+        Some(idle_timeout) => unsafe {
+            submitter.push_raw(read.flags(Flags::IO_LINK))?;
+            submitter.push_raw(link_idle_timeout(idle_timeout))
+        },
+        None => unsafe { submitter.push_raw(read) },
+    }
+}
+
+fn admin_read<W: Fn(&mut Entry, <RingCoordination as RingOperation>::RingData)>(
+    submitter: &mut SubmissionQueueSubmitter<<RingCoordination as RingOperation>::RingData, W>,
+    mut connection: Box<AdminConnection>,
+) -> Result<(), PushError> {
+    let (ptr, len) = connection.read_buffer();
+    let read = opcode::Read::new(Fd(connection.socket()), ptr, len).build();
+    submitter.push(read, RingMessage::AdminRead(connection))
+}
+
+fn accept(listener: RawFd, multi: bool) -> Entry {
+    match multi {
+        true => opcode::AcceptMulti::new(Fd(listener)).build(),
+        false => {
+            opcode::Accept::new(Fd(listener), std::ptr::null_mut(), std::ptr::null_mut()).build()
+        }
+    }
+}
+
+/// How often draining checks whether all connections are gone
+pub(crate) const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Stops accepting new connections, pending accepts fail and the addresses may be bound again,
+/// e.g. by the server taking over
+pub(crate) fn stop_listening(listeners: &[Socket]) {
+    for listener in listeners {
+        if let Err(e) = listener.shutdown(std::net::Shutdown::Read) {
+            warn!(
+                "unable to stop listening on {}: {e}",
+                listen_address(listener)
+            );
+        }
+    }
+}
+
+/// Connections of all users, datagram senders count as long as they are remembered
+pub(crate) fn open_connections(clients: &RwLock<Vec<Arc<UserState>>>) -> usize {
+    clients
+        .read()
+        .expect("unable to acquire lock on clients")
+        .iter()
+        .map(|state| state.connections.load(Ordering::Relaxed))
+        .sum()
+}
+
+/// Where a listener accepts connections, the path of unix sockets
+pub(crate) fn listen_address(socket: &Socket) -> String {
+    match socket.local_addr() {
+        Ok(address) => match (address.as_socket(), address.as_pathname()) {
+            (Some(address), _) => address.to_string(),
+            (None, Some(path)) => path.display().to_string(),
+            (None, None) => "an unnamed socket".to_string(),
+        },
+        Err(e) => format!("an unknown address ({e})"),
+    }
+}
+
 fn setup_socket<W: Fn(&mut Entry, <RingCoordination as RingOperation>::RingData)>(
     submitter: &mut SubmissionQueueSubmitter<<RingCoordination as RingOperation>::RingData, W>,
     socket: &Socket,
+    accept_multi: bool,
 ) -> Result<(), PushError> {
-    info!(
-        "Listening on {}",
-        socket.local_addr().unwrap().as_socket().unwrap()
-    );
-    let accept_multi = opcode::AcceptMulti::new(Fd(socket.as_raw_fd())).build();
-    submitter.push(accept_multi, RingMessage::NewConnection)
+    info!("Listening on {}", listen_address(socket));
+    let listener = socket.as_raw_fd();
+    submitter.push(
+        accept(listener, accept_multi),
+        RingMessage::NewConnection { listener },
+    )
+}
+
+fn setup_inbox<W: Fn(&mut Entry, <RingCoordination as RingOperation>::RingData)>(
+    submitter: &mut SubmissionQueueSubmitter<<RingCoordination as RingOperation>::RingData, W>,
+    inbox: &Inbox,
+) -> Result<(), PushError> {
+    let mut counter = Box::new(0u64);
+    let read = opcode::Read::new(
+        Fd(inbox.event_fd.as_raw_fd()),
+        std::ptr::addr_of_mut!(*counter) as *mut _,
+        std::mem::size_of::<u64>() as u32,
+    )
+    .build();
+    submitter.push(read, RingMessage::Wakeup(counter))
+}
+
+fn setup_watchdog<W: Fn(&mut Entry, <RingCoordination as RingOperation>::RingData)>(
+    submitter: &mut SubmissionQueueSubmitter<<RingCoordination as RingOperation>::RingData, W>,
+    interval: Box<Timespec>,
+) -> Result<(), PushError> {
+    let timeout = opcode::Timeout::new(&*interval).build();
+    submitter.push(timeout, RingMessage::Watchdog(interval))
+}
+
+fn setup_drain_check<W: Fn(&mut Entry, <RingCoordination as RingOperation>::RingData)>(
+    submitter: &mut SubmissionQueueSubmitter<<RingCoordination as RingOperation>::RingData, W>,
+    interval: Box<Timespec>,
+) -> Result<(), PushError> {
+    let timeout = opcode::Timeout::new(&*interval).build();
+    submitter.push(timeout, RingMessage::Drain(interval))
+}
+
+/// Tells every worker to close its connections and exit
+fn exit_workers<W: Fn(&mut Entry, <RingCoordination as RingOperation>::RingData)>(
+    submitter: &mut SubmissionQueueSubmitter<<RingCoordination as RingOperation>::RingData, W>,
+    mailboxes: &[Mailbox],
+) {
+    for mailbox in mailboxes.iter() {
+        if let Err(e) = mailbox.send(submitter, RingMessage::Exit) {
+            error!("unable to shutdown gracefully: {e}\nAborting...");
+            std::process::exit(-1);
+        }
+    }
 }
 
 fn setup_signal<W: Fn(&mut Entry, <RingCoordination as RingOperation>::RingData)>(
@@ -310,16 +1015,444 @@ fn setup_signal<W: Fn(&mut Entry, <RingCoordination as RingOperation>::RingData)
 #[derive(Debug)]
 pub struct UserState {
     pub(crate) ip: IpAddr,
+    pub(crate) team: TeamID,
     pub(crate) connections: AtomicUsize,
+    /// workers remembering the user as a datagram sender, only keeps its user id from being
+    /// reused and counts neither against --connections-per-ip nor for draining
+    pub(crate) datagram_senders: AtomicUsize,
+    rejected_commands: AtomicU64,
+    last_rejected_command_sample: AtomicU64,
+    /// when the user has used up its ingress rate (ns since the epoch), shared by all its connections
+    ingress_exhausted_at: AtomicU64,
+    /// when the user has used up its pixel rate (ns since the epoch), shared by all its connections
+    pixels_exhausted_at: AtomicU64,
+    /// when the user has used up its pixel budget (ns since the epoch), shared by all its connections
+    budget_exhausted_at: AtomicU64,
+    pub(crate) dropped_pixels: AtomicU64,
+    /// connections made until then (ns since the epoch) are closed on their next read
+    pub(crate) kicked_at: AtomicU64,
+    /// sockets of the open connections, removed before they are closed
+    sockets: Mutex<Vec<RawFd>>,
 }
 
-pub(crate) fn get_or_create_user_state(
-    clients: &mut Vec<Arc<UserState>>,
-    ip: IpAddr,
-    ipv4_mask: Ipv4Addr,
-    ipv6_mask: Ipv6Addr,
-) -> (u32, Arc<UserState>) {
-    let ip = match ip {
+/// Pixels a user may set per interval, the budget refills continuously
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PixelBudget {
+    pub pixels: NonZeroU64,
+    pub interval: Duration,
+}
+
+/// Options set on every accepted socket
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    pub quickack: bool,
+    /// SO_RCVBUF, None keeps the kernel default
+    pub recv_buffer_size: Option<NonZeroUsize>,
+    /// SO_SNDBUF, None keeps the kernel default
+    pub send_buffer_size: Option<NonZeroUsize>,
+}
+
+impl SocketOptions {
+    pub fn apply(&self, socket: &Socket) -> io::Result<()> {
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if self.quickack {
+            socket.set_quickack(true)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size.get())?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size.get())?;
+        }
+        Ok(())
+    }
+}
+
+/// Masks grouping addresses into users, adjustable at runtime through the config file.
+/// Users that are already known keep the address they were grouped by
+#[derive(Debug)]
+pub struct Masks {
+    ipv4: AtomicU32,
+    /// high and low half
+    ipv6: [AtomicU64; 2],
+}
+
+impl Masks {
+    pub fn new(ipv4: Ipv4Addr, ipv6: Ipv6Addr) -> Self {
+        let masks = Self {
+            ipv4: AtomicU32::new(0),
+            ipv6: Default::default(),
+        };
+        masks.set(ipv4, ipv6);
+        masks
+    }
+
+    pub fn get(&self) -> (Ipv4Addr, Ipv6Addr) {
+        let ipv6 = (self.ipv6[0].load(Ordering::Relaxed) as u128) << 64
+            | self.ipv6[1].load(Ordering::Relaxed) as u128;
+        (
+            Ipv4Addr::from(self.ipv4.load(Ordering::Relaxed)),
+            Ipv6Addr::from(ipv6),
+        )
+    }
+
+    pub fn set(&self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) {
+        let ipv6 = u128::from(ipv6);
+        self.ipv4.store(u32::from(ipv4), Ordering::Relaxed);
+        self.ipv6[0].store((ipv6 >> 64) as u64, Ordering::Relaxed);
+        self.ipv6[1].store(ipv6 as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn mask(&self, ip: IpAddr) -> IpAddr {
+        let (ipv4_mask, ipv6_mask) = self.get();
+        mask_ip(ip, ipv4_mask, ipv6_mask)
+    }
+}
+
+/// Reply to HELP, adjustable at runtime through the config file
+#[derive(Debug)]
+pub struct HelpText {
+    text: RwLock<Box<[u8]>>,
+    /// bumped on every change, workers only copy the text once it changed
+    version: AtomicU64,
+}
+
+impl HelpText {
+    pub fn new(text: &[u8]) -> Self {
+        Self {
+            text: RwLock::new(text.into()),
+            version: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    pub fn get(&self) -> Box<[u8]> {
+        self.text
+            .read()
+            .expect("unable to acquire lock on help text")
+            .clone()
+    }
+
+    pub fn set(&self, text: &[u8]) {
+        let mut current = self
+            .text
+            .write()
+            .expect("unable to acquire lock on help text");
+        if **current != *text {
+            *current = text.into();
+            self.version.fetch_add(1, Ordering::Release);
+        }
+    }
+}
+
+/// Limits applying to every user, adjustable at runtime through the admin socket or the config file
+#[derive(Debug)]
+pub struct Limits {
+    /// bytes per second, 0 is unlimited
+    ingress_rate: AtomicU64,
+    /// pixels per second, 0 is unlimited
+    pixel_rate: AtomicU64,
+    /// pixels per budget interval, 0 is unlimited
+    budget_pixels: AtomicU64,
+    budget_interval: Duration,
+    /// open connections of all users together, 0 is unlimited
+    connections: AtomicU64,
+    /// open connections per user, 0 is unlimited
+    user_connections: AtomicU64,
+}
+
+impl Limits {
+    pub fn new(
+        ingress_rate: Option<NonZeroU64>,
+        pixel_rate: Option<NonZeroU64>,
+        budget_pixels: Option<NonZeroU64>,
+        budget_interval: Duration,
+        connections: Option<NonZeroU64>,
+        user_connections: Option<NonZeroU64>,
+    ) -> Self {
+        let raw = |limit: Option<NonZeroU64>| AtomicU64::new(limit.map_or(0, NonZeroU64::get));
+        Self {
+            ingress_rate: raw(ingress_rate),
+            pixel_rate: raw(pixel_rate),
+            budget_pixels: raw(budget_pixels),
+            budget_interval,
+            connections: raw(connections),
+            user_connections: raw(user_connections),
+        }
+    }
+
+    #[inline]
+    pub fn ingress_rate(&self) -> Option<NonZeroU64> {
+        NonZeroU64::new(self.ingress_rate.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    pub fn pixel_rate(&self) -> Option<NonZeroU64> {
+        NonZeroU64::new(self.pixel_rate.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    pub fn pixel_budget(&self) -> Option<PixelBudget> {
+        NonZeroU64::new(self.budget_pixels.load(Ordering::Relaxed)).map(|pixels| PixelBudget {
+            pixels,
+            interval: self.budget_interval,
+        })
+    }
+
+    #[inline]
+    pub fn connections(&self) -> Option<NonZeroU64> {
+        NonZeroU64::new(self.connections.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    pub fn user_connections(&self) -> Option<NonZeroU64> {
+        NonZeroU64::new(self.user_connections.load(Ordering::Relaxed))
+    }
+
+    pub fn set_ingress_rate(&self, rate: Option<NonZeroU64>) {
+        self.ingress_rate
+            .store(rate.map_or(0, NonZeroU64::get), Ordering::Relaxed);
+    }
+
+    pub fn set_pixel_rate(&self, rate: Option<NonZeroU64>) {
+        self.pixel_rate
+            .store(rate.map_or(0, NonZeroU64::get), Ordering::Relaxed);
+    }
+
+    pub fn set_pixel_budget(&self, pixels: Option<NonZeroU64>) {
+        self.budget_pixels
+            .store(pixels.map_or(0, NonZeroU64::get), Ordering::Relaxed);
+    }
+
+    pub fn set_connections(&self, connections: Option<NonZeroU64>) {
+        self.connections
+            .store(connections.map_or(0, NonZeroU64::get), Ordering::Relaxed);
+    }
+
+    pub fn set_user_connections(&self, connections: Option<NonZeroU64>) {
+        self.user_connections
+            .store(connections.map_or(0, NonZeroU64::get), Ordering::Relaxed);
+    }
+}
+
+impl UserState {
+    pub(crate) fn new(ip: IpAddr, team: TeamID) -> Self {
+        Self {
+            ip,
+            team,
+            connections: Default::default(),
+            datagram_senders: Default::default(),
+            rejected_commands: Default::default(),
+            last_rejected_command_sample: Default::default(),
+            ingress_exhausted_at: Default::default(),
+            pixels_exhausted_at: Default::default(),
+            budget_exhausted_at: Default::default(),
+            dropped_pixels: Default::default(),
+            kicked_at: Default::default(),
+            sockets: Default::default(),
+        }
+    }
+
+    pub(crate) fn opened(&self, socket: RawFd) {
+        self.sockets
+            .lock()
+            .expect("unable to lock sockets")
+            .push(socket);
+    }
+
+    pub(crate) fn closed(&self, socket: RawFd) {
+        self.sockets
+            .lock()
+            .expect("unable to lock sockets")
+            .retain(|&fd| fd != socket);
+    }
+
+    /// Closes all connections of the user. Shutting their sockets down completes pending reads,
+    /// so silent connections are closed right away instead of on their next read
+    pub(crate) fn kick(&self) {
+        let sockets = self.sockets.lock().expect("unable to lock sockets");
+        self.kicked_at.store(nanos_since_epoch(), Ordering::Relaxed);
+        for &socket in sockets.iter() {
+            // still open, the connection removes it before closing it
+            unsafe { libc::shutdown(socket, libc::SHUT_RDWR) };
+        }
+    }
+
+    /// Accounts received bytes against the ingress rate of the user and returns
+    /// how long to wait before reading again to stay within it
+    pub(crate) fn throttle_ingress(&self, bytes: u64, rate: NonZeroU64) -> Option<Duration> {
+        let now = nanos_since_epoch();
+        let cost = (bytes as u128 * 1_000_000_000 / rate.get() as u128) as u64;
+
+        // the update always succeeds
+        let previous = self
+            .ingress_exhausted_at
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |exhausted_at| {
+                Some(exhausted_at.max(now) + cost)
+            })
+            .unwrap_or_default();
+        let exhausted_at = previous.max(now) + cost;
+
+        // bursts are not delayed
+        let delay = exhausted_at.saturating_sub(now + INGRESS_BURST.as_nanos() as u64);
+        (delay > 0).then(|| Duration::from_nanos(delay))
+    }
+
+    /// Accounts a pixel against the pixel rate of the user at `now` (ns since the epoch).
+    /// Returns false if the pixel is above the rate and has to be dropped
+    pub(crate) fn admit_pixel(&self, now: u64, rate: NonZeroU64) -> bool {
+        let cost = 1_000_000_000 / rate.get();
+        let admitted = take_tokens(
+            &self.pixels_exhausted_at,
+            now,
+            cost,
+            PIXEL_BURST.as_nanos() as u64,
+        )
+        .is_ok();
+        if !admitted {
+            self.dropped_pixels.fetch_add(1, Ordering::Relaxed);
+        }
+        admitted
+    }
+
+    /// Spends a pixel of the budget of the user at `now` (ns since the epoch).
+    /// Returns how long until the next pixel may be set if the budget is exhausted
+    pub(crate) fn spend_budget(&self, now: u64, budget: PixelBudget) -> Result<(), Duration> {
+        let interval = budget.interval.as_nanos() as u64;
+        let cost = interval / budget.pixels.get();
+        take_tokens(&self.budget_exhausted_at, now, cost, interval).map_err(|wait| {
+            self.dropped_pixels.fetch_add(1, Ordering::Relaxed);
+            Duration::from_nanos(wait)
+        })
+    }
+
+    /// Counts a rejected command and returns the number of rejected commands so far
+    /// if this one should be logged in detail, at most once per [REJECTED_COMMAND_SAMPLE_INTERVAL]
+    pub(crate) fn sample_rejected_command(&self) -> Option<u64> {
+        let rejected = self.rejected_commands.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let last = self.last_rejected_command_sample.load(Ordering::Relaxed);
+        if now.saturating_sub(last) < REJECTED_COMMAND_SAMPLE_INTERVAL.as_millis() as u64 {
+            return None;
+        }
+
+        self.last_rejected_command_sample
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .ok()
+            .map(|_| rejected)
+    }
+}
+
+/// Takes `cost` from a token bucket holding up to `capacity`, both in ns. `exhausted_at` is when the
+/// bucket was or will be full again. Returns how long to wait for enough tokens if there are not
+fn take_tokens(exhausted_at: &AtomicU64, now: u64, cost: u64, capacity: u64) -> Result<(), u64> {
+    let mut wait = 0;
+    exhausted_at
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |exhausted_at| {
+            let exhausted_at = exhausted_at.max(now) + cost;
+            wait = exhausted_at.saturating_sub(now + capacity);
+            (wait == 0).then_some(exhausted_at)
+        })
+        .map(|_| ())
+        .map_err(|_| wait)
+}
+
+pub(crate) fn nanos_since_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Writes the client table and traffic stats as JSON, replacing the file atomically
+pub(crate) fn write_stats_dump(
+    path: &Path,
+    clients: &[Arc<UserState>],
+    stats: &Stats,
+) -> io::Result<()> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut out = BufWriter::new(std::fs::File::create(&tmp_path)?);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (bytes, pixels) = stats.total();
+    let dropped_pixels = clients
+        .iter()
+        .map(|state| state.dropped_pixels.load(Ordering::Relaxed))
+        .sum::<u64>();
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"timestamp\": {timestamp},")?;
+    writeln!(
+        out,
+        "  \"traffic\": {{ \"bytes\": {bytes}, \"pixels\": {pixels}, \"dropped_pixels\": {dropped_pixels} }},"
+    )?;
+
+    writeln!(out, "  \"workers\": [")?;
+    for (i, worker) in stats.workers().iter().enumerate() {
+        let (bytes, pixels) = worker.load();
+        let separator = if i + 1 < stats.workers().len() {
+            ","
+        } else {
+            ""
+        };
+        writeln!(
+            out,
+            "    {{ \"bytes\": {bytes}, \"pixels\": {pixels} }}{separator}"
+        )?;
+    }
+    writeln!(out, "  ],")?;
+
+    let teams = stats.team_totals();
+    writeln!(out, "  \"teams\": [")?;
+    for (i, (team, (bytes, pixels))) in teams.iter().enumerate() {
+        let separator = if i + 1 < teams.len() { "," } else { "" };
+        writeln!(
+            out,
+            "    {{ \"id\": {team}, \"bytes\": {bytes}, \"pixels\": {pixels} }}{separator}"
+        )?;
+    }
+    writeln!(out, "  ],")?;
+
+    writeln!(out, "  \"users\": [")?;
+    for (i, state) in clients.iter().enumerate() {
+        let user_id = (i + 1) as u32;
+        let (bytes, pixels) = stats.user(user_id).map(|c| c.load()).unwrap_or_default();
+        let separator = if i + 1 < clients.len() { "," } else { "" };
+        writeln!(
+            out,
+            "    {{ \"id\": {user_id}, \"ip\": \"{}\", \"team\": {}, \"connections\": {}, \"bytes\": {bytes}, \"pixels\": {pixels}, \"dropped_pixels\": {} }}{separator}",
+            state.ip,
+            state.team,
+            state.connections.load(Ordering::Relaxed),
+            state.dropped_pixels.load(Ordering::Relaxed)
+        )?;
+    }
+    writeln!(out, "  ]")?;
+    writeln!(out, "}}")?;
+
+    out.into_inner()?.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Addresses of the same user are equal once masked
+pub(crate) fn mask_ip(ip: IpAddr, ipv4_mask: Ipv4Addr, ipv6_mask: Ipv6Addr) -> IpAddr {
+    match ip {
         IpAddr::V4(ip) => {
             let ip = ip.octets();
             let mask = ipv4_mask.octets();
@@ -352,23 +1485,29 @@ pub(crate) fn get_or_create_user_state(
                 ip[15] & mask[15],
             ])
         }
-    };
+    }
+}
+
+pub(crate) fn get_or_create_user_state(
+    clients: &mut Vec<Arc<UserState>>,
+    ip: IpAddr,
+    masks: &Masks,
+    teams: Option<&Teams>,
+) -> (u32, Arc<UserState>) {
+    let ip = masks.mask(ip);
 
     if let Some((idx, state)) = clients.iter().enumerate().find(|(_, state)| state.ip == ip) {
         return ((idx + 1) as u32, state.clone());
     }
 
-    let new_state = Arc::new(UserState {
-        ip,
-        connections: Default::default(),
-    });
+    let team = teams.map(|teams| teams.team(ip)).unwrap_or_default();
+    let new_state = Arc::new(UserState::new(ip, team));
 
     // re-use old entry
-    if let Some((idx, state)) = clients
-        .iter_mut()
-        .enumerate()
-        .find(|(_, state)| state.connections.load(Ordering::Relaxed) == 0)
-    {
+    if let Some((idx, state)) = clients.iter_mut().enumerate().find(|(_, state)| {
+        state.connections.load(Ordering::Relaxed) == 0
+            && state.datagram_senders.load(Ordering::Relaxed) == 0
+    }) {
         *state = new_state.clone();
         return ((idx + 1) as u32, new_state);
     }
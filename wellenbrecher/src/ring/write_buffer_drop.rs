@@ -1,4 +1,6 @@
+use rummelplatz::io_uring::cqueue;
 use rummelplatz::io_uring::squeue::Entry;
+use rummelplatz::io_uring::types::Timespec;
 use rummelplatz::{ControlFlow, RingOperation, SubmissionQueueSubmitter};
 
 #[derive(Debug)]
@@ -9,6 +11,18 @@ pub enum WriteBufferDropDescriptor {
     None,
     Buffer(Box<[u8]>),
     IoVec(Vec<libc::iovec>),
+    /// Duration of a timeout, read by the kernel on submission
+    Timespec(Box<Timespec>),
+    /// Header and iovecs of a zero-copy sendmsg
+    Message(Box<(libc::msghdr, Vec<libc::iovec>)>),
+}
+
+/// Header of a message of all `iovecs`, e.g. for IORING_OP_SENDMSG_ZC
+pub fn message(mut iovecs: Vec<libc::iovec>) -> Box<(libc::msghdr, Vec<libc::iovec>)> {
+    let mut header = unsafe { std::mem::zeroed::<libc::msghdr>() };
+    header.msg_iov = iovecs.as_mut_ptr();
+    header.msg_iovlen = iovecs.len() as _;
+    Box::new((header, iovecs))
 }
 
 impl RingOperation for WriteBufferDrop {
@@ -28,13 +42,18 @@ impl RingOperation for WriteBufferDrop {
     #[inline]
     fn on_completion<W: Fn(&mut Entry, Self::RingData)>(
         &mut self,
-        _: rummelplatz::io_uring::cqueue::Entry,
+        completion_entry: rummelplatz::io_uring::cqueue::Entry,
         buf: Self::RingData,
         _: SubmissionQueueSubmitter<Self::RingData, W>,
     ) -> (
         ControlFlow<Self::ControlFlowWarn, Self::ControlFlowError>,
         Option<Self::RingData>,
     ) {
+        // zero-copy sends use the buffer until the notification that follows the result
+        if cqueue::more(completion_entry.flags()) {
+            return (ControlFlow::Continue, Some(buf));
+        }
+
         drop(buf);
         (ControlFlow::Continue, None)
     }
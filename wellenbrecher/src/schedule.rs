@@ -0,0 +1,126 @@
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use croner::Cron;
+use tracing::{info, warn};
+
+use wellenbrecher_canvas::{Bgra, Canvas};
+
+/// What happens to the canvas when an entry of the [ClearSchedule] is due
+#[derive(Debug, Clone, Copy)]
+pub enum ClearAction {
+    /// Fill the canvas with a color
+    Clear(Bgra),
+    /// Back to the initial color or pattern
+    Reset,
+}
+
+/// Clears the canvas at fixed times, e.g. every night for long-running installations
+#[derive(Debug, Clone)]
+pub struct ClearSchedule {
+    entries: Box<[(Cron, ClearAction)]>,
+}
+
+impl ClearSchedule {
+    /// One entry per line as `<minute> <hour> <day of month> <month> <day of week> <action>`
+    /// in local time, where the action is `clear [rrggbb]` or `reset`. Anything after `#` is ignored
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("unable to read clear schedule {}: {e}", path.display()))?;
+
+        let mut entries = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let fields = line
+                .split('#')
+                .next()
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect::<Vec<_>>();
+            if fields.is_empty() {
+                continue;
+            }
+            if fields.len() < 6 {
+                return Err(format!(
+                    "invalid entry in line {}: expected five cron fields and an action",
+                    i + 1
+                ));
+            }
+
+            let cron = Cron::new(&fields[..5].join(" "))
+                .parse()
+                .map_err(|e| format!("invalid schedule in line {}: {e}", i + 1))?;
+            let action = match fields[5..] {
+                ["clear"] => ClearAction::Clear(Bgra::from_rgb(0)),
+                ["clear", color] => u32::from_str_radix(color.trim_start_matches('#'), 16)
+                    .ok()
+                    .filter(|_| color.trim_start_matches('#').len() == 6)
+                    .map(|rgb| ClearAction::Clear(Bgra::from_rgb(rgb)))
+                    .ok_or_else(|| format!("invalid color in line {}: expected rrggbb", i + 1))?,
+                ["reset"] => ClearAction::Reset,
+                _ => {
+                    return Err(format!(
+                        "invalid action in line {}: expected clear [rrggbb] or reset",
+                        i + 1
+                    ))
+                }
+            };
+            entries.push((cron, action));
+        }
+
+        if entries.is_empty() {
+            return Err(format!("clear schedule {} is empty", path.display()));
+        }
+
+        Ok(Self {
+            entries: entries.into_boxed_slice(),
+        })
+    }
+
+    /// The earliest entry due after `after`
+    fn next(&self, after: &DateTime<Local>) -> Option<(DateTime<Local>, ClearAction)> {
+        self.entries
+            .iter()
+            .filter_map(|(cron, action)| {
+                cron.find_next_occurrence(after, false)
+                    .ok()
+                    .map(|at| (at, *action))
+            })
+            .min_by_key(|(at, _)| *at)
+    }
+
+    /// Clears the canvas whenever an entry is due, viewers are told about it `warning` ahead.
+    /// `initial` yields the pixels of a reset canvas of the given size. Only returns once nothing is due anymore
+    pub fn run(
+        &self,
+        mut canvas: Canvas,
+        warning: Duration,
+        initial: impl Fn(u32, u32) -> Box<[Bgra]>,
+    ) {
+        let warning = chrono::Duration::from_std(warning).unwrap_or(chrono::Duration::zero());
+        let wait_until = |at: DateTime<Local>| {
+            if let Ok(duration) = (at - Local::now()).to_std() {
+                thread::sleep(duration);
+            }
+        };
+
+        while let Some((at, action)) = self.next(&Local::now()) {
+            info!("next scheduled canvas clear at {at}");
+            if !warning.is_zero() {
+                wait_until(at - warning);
+                canvas.announce_clear(at.timestamp() as u64);
+            }
+            wait_until(at);
+
+            match action {
+                ClearAction::Clear(color) => canvas.clear(color),
+                ClearAction::Reset => canvas.reset(&initial(canvas.width(), canvas.height())),
+            }
+            canvas.announce_clear(0);
+            info!("cleared the canvas as scheduled");
+        }
+
+        warn!("no scheduled canvas clears left");
+    }
+}
@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tracing::{debug, warn};
+
+use wellenbrecher_canvas::{Bgra, Canvas, UserID};
+
+/*
+Snapshots use the zstd format of `kanal snapshots`: width and height (little endian) followed by
+the pixel and user id planes as laid out in the canvas, so seebruecke is able to play them back.
+Periodic snapshots are named like the ones of kanal as well. They are read from the shared canvas
+on a thread of their own, the workers keep setting pixels meanwhile.
+ */
+
+const SNAPSHOT_PREFIX: &str = "canvas-";
+
+/// Writes a timestamped snapshot into `dir` every `interval`, forever
+pub fn run(canvas: &Canvas, interval: Duration, dir: &Path) {
+    let mut next = Instant::now();
+    loop {
+        let path = snapshot_path(dir);
+        match write(canvas, &path) {
+            Ok(()) => debug!("wrote snapshot {}", path.display()),
+            Err(e) => warn!("unable to write snapshot {}: {e}", path.display()),
+        }
+
+        next += interval;
+        let now = Instant::now();
+        match next.checked_duration_since(now) {
+            Some(timeout) => thread::sleep(timeout),
+            None => {
+                warn!("taking the snapshot took longer than the interval");
+                next = now;
+            }
+        }
+    }
+}
+
+fn snapshot_path(dir: &Path) -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    dir.join(format!("{SNAPSHOT_PREFIX}{timestamp:013}.zst"))
+}
+
+/// Writes to a temporary file first, an interrupted write keeps the previous snapshot
+pub fn write(canvas: &Canvas, path: &Path) -> eyre::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let result = (|| {
+        let writer = BufWriter::new(File::create(&tmp_path)?);
+        let mut encoder = zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        encoder.write_all(&canvas.width().to_le_bytes())?;
+        encoder.write_all(&canvas.height().to_le_bytes())?;
+        encoder.write_all(canvas.pixel_byte_slice())?;
+        encoder.write_all(canvas.user_id_byte_slice())?;
+        encoder.finish()?.flush()
+    })();
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Copies a snapshot into the canvas and returns its dimensions.
+/// Snapshots of other dimensions end up in the top-left corner
+pub fn restore(canvas: &mut Canvas, path: &Path) -> eyre::Result<(u32, u32)> {
+    let data = zstd::decode_all(BufReader::new(File::open(path)?))?;
+    if data.len() < 8 {
+        return Err(eyre::eyre!("truncated snapshot"));
+    }
+
+    let width = u32::from_le_bytes(data[0..4].try_into()?);
+    let height = u32::from_le_bytes(data[4..8].try_into()?);
+    let len = width as usize * height as usize;
+    let pixel_plane = len * std::mem::size_of::<Bgra>();
+    let user_id_plane = len * std::mem::size_of::<UserID>();
+    if data.len() != 8 + pixel_plane + user_id_plane {
+        return Err(eyre::eyre!(
+            "snapshot size does not match its dimensions ({width}x{height})"
+        ));
+    }
+
+    let (pixels, user_ids) = data[8..].split_at(pixel_plane);
+    let canvas_width = canvas.width() as usize;
+    let overlap = width.min(canvas.width()) as usize;
+    let rows = height.min(canvas.height()) as usize;
+    copy_rows(
+        bytemuck::cast_slice_mut(canvas.pixel_slice_mut()),
+        pixels,
+        width as usize * std::mem::size_of::<Bgra>(),
+        canvas_width * std::mem::size_of::<Bgra>(),
+        overlap * std::mem::size_of::<Bgra>(),
+        rows,
+    );
+    copy_rows(
+        bytemuck::cast_slice_mut(canvas.user_id_slice_mut()),
+        user_ids,
+        width as usize * std::mem::size_of::<UserID>(),
+        canvas_width * std::mem::size_of::<UserID>(),
+        overlap * std::mem::size_of::<UserID>(),
+        rows,
+    );
+    canvas.recount_user_pixels();
+
+    Ok((width, height))
+}
+
+/// Copies the first `len` bytes of `rows` rows between planes of different strides
+fn copy_rows(
+    dst: &mut [u8],
+    src: &[u8],
+    src_stride: usize,
+    dst_stride: usize,
+    len: usize,
+    rows: usize,
+) {
+    for y in 0..rows {
+        dst[y * dst_stride..y * dst_stride + len]
+            .copy_from_slice(&src[y * src_stride..y * src_stride + len]);
+    }
+}
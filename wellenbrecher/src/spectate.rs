@@ -0,0 +1,224 @@
+use std::io::{BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+use wellenbrecher_canvas::{Bgra, Canvas};
+
+use crate::cli::Args;
+use crate::open_canvas;
+use crate::replication::RUN_MERGE_GAP;
+
+const SPECTATOR_MAGIC: &[u8; 4] = b"WBSP";
+const SPECTATOR_VERSION: u32 = 1;
+
+const FRAME_KEY: u8 = 0;
+const FRAME_DELTA: u8 = 1;
+
+/// frames a spectator may lag behind before it has to wait for the next keyframe
+const SPECTATOR_QUEUE: usize = 64;
+
+/*
+Wire format (all integers little endian):
+    handshake:  "WBSP" | version: u32 | width: u32 | height: u32
+    frame:      kind: u8 | runs: u32 | runs * (offset: u32 | len: u32 | len * Bgra)
+
+A keyframe (kind 0) is a single run covering the whole canvas, a delta (kind 1) contains
+the runs changed since the previous frame. Every frame is encoded once and shared by all
+spectators. New spectators receive the last keyframe and all deltas since.
+Frames without runs are sent anyway and serve as heartbeat.
+ */
+
+type Frame = Arc<[u8]>;
+
+struct Spectator {
+    peer: SocketAddr,
+    frames: SyncSender<Frame>,
+    /// dropped a frame, skips deltas until the next keyframe
+    desynced: bool,
+}
+
+impl Spectator {
+    /// Returns false once the spectator is gone
+    fn send(&mut self, frame: &Frame) -> bool {
+        if self.desynced {
+            if frame[0] != FRAME_KEY {
+                return true;
+            }
+            self.desynced = false;
+        }
+
+        match self.frames.try_send(frame.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                debug!(
+                    "spectator {} is lagging behind, waiting for the next keyframe",
+                    self.peer
+                );
+                self.desynced = true;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+pub fn serve(
+    listener: TcpListener,
+    args: Args,
+    canvas_open_lock: Arc<Mutex<()>>,
+) -> eyre::Result<()> {
+    info!("Serving spectators on {}", listener.local_addr()?);
+    let canvas = open_canvas(&args, &canvas_open_lock)?;
+    let (width, height) = (canvas.width(), canvas.height());
+    let interval = Duration::from_millis(args.spectator_interval.get());
+    let keyframe_interval = args.spectator_keyframe_interval.get();
+
+    let (spectator_tx, spectator_rx) = std::sync::mpsc::channel();
+    thread::Builder::new()
+        .name("Spectate".to_string())
+        .spawn(move || broadcast(canvas, spectator_rx, interval, keyframe_interval))?;
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("unable to accept spectator: {e}");
+                continue;
+            }
+        };
+        let peer = stream.peer_addr()?;
+
+        let (frames_tx, frames_rx) = std::sync::mpsc::sync_channel(SPECTATOR_QUEUE);
+        thread::Builder::new()
+            .name(format!("Spectator-{peer}"))
+            .spawn(move || {
+                info!("+ spectator {peer}");
+                match stream_frames(stream, width, height, frames_rx) {
+                    Ok(()) => info!("- spectator {peer}"),
+                    Err(e) => info!("- spectator {peer}: {e}"),
+                }
+            })?;
+
+        spectator_tx.send(Spectator {
+            peer,
+            frames: frames_tx,
+            desynced: false,
+        })?;
+    }
+
+    Ok(())
+}
+
+fn broadcast(
+    canvas: Canvas,
+    new_spectators: Receiver<Spectator>,
+    interval: Duration,
+    keyframe_interval: u32,
+) {
+    let len = canvas.pixel_slice().len();
+    let mut shadow = vec![Bgra::default(); len].into_boxed_slice();
+    let mut spectators: Vec<Spectator> = Vec::new();
+    let mut joining: Vec<Spectator> = Vec::new();
+    // the last keyframe followed by all deltas since
+    let mut backlog: Vec<Frame> = Vec::new();
+    let mut frames_since_keyframe = keyframe_interval;
+
+    loop {
+        if spectators.is_empty() {
+            // nothing to encode frames for, the next spectator starts with a fresh keyframe
+            match new_spectators.recv() {
+                Ok(spectator) => joining.push(spectator),
+                Err(_) => return,
+            }
+            frames_since_keyframe = keyframe_interval;
+        }
+        joining.extend(new_spectators.try_iter());
+
+        let frame = if frames_since_keyframe >= keyframe_interval {
+            shadow.copy_from_slice(canvas.pixel_slice());
+            frames_since_keyframe = 0;
+            backlog.clear();
+            encode_frame(FRAME_KEY, &shadow, &[(0, len)])
+        } else {
+            let runs = changed_runs(canvas.pixel_slice(), &shadow);
+            for &(offset, len) in runs.iter() {
+                // the canvas keeps changing, so send exactly what ends up in the shadow copy
+                shadow[offset..offset + len]
+                    .copy_from_slice(&canvas.pixel_slice()[offset..offset + len]);
+            }
+            frames_since_keyframe += 1;
+            encode_frame(FRAME_DELTA, &shadow, &runs)
+        };
+        backlog.push(frame.clone());
+
+        spectators.retain_mut(|spectator| spectator.send(&frame));
+        for mut spectator in joining.drain(..) {
+            if backlog.iter().all(|frame| spectator.send(frame)) {
+                spectators.push(spectator);
+            }
+        }
+
+        thread::sleep(interval);
+    }
+}
+
+fn stream_frames(
+    stream: TcpStream,
+    width: u32,
+    height: u32,
+    frames: Receiver<Frame>,
+) -> std::io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut stream = BufWriter::new(stream);
+
+    stream.write_all(SPECTATOR_MAGIC)?;
+    stream.write_all(&SPECTATOR_VERSION.to_le_bytes())?;
+    stream.write_all(&width.to_le_bytes())?;
+    stream.write_all(&height.to_le_bytes())?;
+
+    for frame in frames {
+        stream.write_all(&frame)?;
+        stream.flush()?;
+    }
+
+    Ok(())
+}
+
+fn changed_runs(current: &[Bgra], shadow: &[Bgra]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+
+    for (i, _) in current
+        .iter()
+        .zip(shadow)
+        .enumerate()
+        .filter(|(_, (current, shadow))| current != shadow)
+    {
+        match runs.last_mut() {
+            Some((start, len)) if i - (*start + *len) <= RUN_MERGE_GAP => *len = i - *start + 1,
+            _ => runs.push((i, 1)),
+        }
+    }
+
+    runs
+}
+
+fn encode_frame(kind: u8, pixels: &[Bgra], runs: &[(usize, usize)]) -> Frame {
+    let pixel_count = runs.iter().map(|&(_, len)| len).sum::<usize>();
+    let mut frame =
+        Vec::with_capacity(1 + 4 + runs.len() * 8 + pixel_count * std::mem::size_of::<Bgra>());
+
+    frame.push(kind);
+    frame.extend_from_slice(&(runs.len() as u32).to_le_bytes());
+    for &(offset, len) in runs {
+        frame.extend_from_slice(&(offset as u32).to_le_bytes());
+        frame.extend_from_slice(&(len as u32).to_le_bytes());
+        frame.extend_from_slice(bytemuck::cast_slice(&pixels[offset..offset + len]));
+    }
+
+    frame.into()
+}
@@ -0,0 +1,91 @@
+use std::net::IpAddr;
+use std::path::Path;
+
+use wellenbrecher_canvas::TeamID;
+
+/// Groups players into teams by the address range they connect from
+#[derive(Debug, Clone)]
+pub struct Teams {
+    /// (network, prefix length, team)
+    ranges: Box<[(IpAddr, u8, TeamID)]>,
+}
+
+impl Teams {
+    /// One team per line as `<team id> <address>[/<prefix>] ...`, anything after `#` is ignored
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("unable to read teams {}: {e}", path.display()))?;
+
+        let mut ranges = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let mut fields = line
+                .split('#')
+                .next()
+                .unwrap_or_default()
+                .split_whitespace();
+            let Some(team) = fields.next() else {
+                continue;
+            };
+            let team = match team.parse::<TeamID>() {
+                Ok(0) | Err(_) => {
+                    return Err(format!(
+                        "invalid team id in line {}: expected a number above 0",
+                        i + 1
+                    ))
+                }
+                Ok(team) => team,
+            };
+
+            let mut empty = true;
+            for range in fields {
+                let (address, prefix) = range.split_once('/').unwrap_or((range, ""));
+                let address = address
+                    .parse::<IpAddr>()
+                    .map_err(|e| format!("invalid address in line {}: {e}", i + 1))?;
+                let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    "" => max_prefix,
+                    prefix => prefix
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|&prefix| prefix <= max_prefix)
+                        .ok_or_else(|| format!("invalid prefix length in line {}", i + 1))?,
+                };
+                ranges.push((address, prefix, team));
+                empty = false;
+            }
+            if empty {
+                return Err(format!("team {team} in line {} has no addresses", i + 1));
+            }
+        }
+
+        // the first match is the most specific one
+        ranges.sort_by_key(|&(_, prefix, _)| std::cmp::Reverse(prefix));
+        Ok(Self {
+            ranges: ranges.into_boxed_slice(),
+        })
+    }
+
+    /// The team of the most specific range containing `ip`, 0 if there is none
+    pub fn team(&self, ip: IpAddr) -> TeamID {
+        self.ranges
+            .iter()
+            .find(|&&(network, prefix, _)| contains(network, prefix, ip))
+            .map(|&(_, _, team)| team)
+            .unwrap_or_default()
+    }
+}
+
+fn contains(network: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
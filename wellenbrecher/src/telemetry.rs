@@ -0,0 +1,116 @@
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+
+use opentelemetry::metrics::MeterProvider;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{MetricExporter, SpanExporter};
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::warn;
+use tracing_subscriber::registry::Registry;
+use tracing_subscriber::Layer;
+
+use wellenbrecher_canvas::Stats;
+
+use crate::ring::ring_coordination::UserState;
+
+/*
+Spans and metrics are exported via OTLP over HTTP, the endpoint and everything else is configured
+by the OTEL_EXPORTER_OTLP_* environment variables. Both are batched and sent by the threads of the
+exporters, the workers only pay for the spans they enter. Spans below the log level are disabled,
+e.g. RUST_LOG=debug exports a span per read of every connection.
+ */
+
+/// Exports spans and metrics until it is dropped
+pub struct Telemetry {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Telemetry {
+    pub fn new() -> eyre::Result<Self> {
+        let resource = Resource::builder()
+            .with_service_name(env!("CARGO_PKG_NAME"))
+            .build();
+
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_batch_exporter(SpanExporter::builder().with_http().build()?)
+            .with_resource(resource.clone())
+            .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_periodic_exporter(MetricExporter::builder().with_http().build()?)
+            .with_resource(resource)
+            .build();
+
+        Ok(Self {
+            tracer_provider,
+            meter_provider,
+        })
+    }
+
+    /// Exports the spans logged through it
+    pub fn layer(&self) -> Box<dyn Layer<Registry> + Send + Sync> {
+        tracing_opentelemetry::layer()
+            .with_tracer(self.tracer_provider.tracer(env!("CARGO_PKG_NAME")))
+            .boxed()
+    }
+
+    /// Exports the traffic of the workers and the connections of the users
+    pub fn export_metrics(&self, stats: Arc<Stats>, clients: Arc<RwLock<Vec<Arc<UserState>>>>) {
+        let meter = self.meter_provider.meter(env!("CARGO_PKG_NAME"));
+
+        let traffic_stats = stats.clone();
+        meter
+            .u64_observable_counter("wellenbrecher.traffic")
+            .with_description("Bytes received per worker")
+            .with_unit("By")
+            .with_callback(move |observer| {
+                for (worker, counters) in traffic_stats.workers().iter().enumerate() {
+                    let (bytes, _) = counters.load();
+                    observer.observe(bytes, &[KeyValue::new("worker", worker as i64)]);
+                }
+            })
+            .build();
+
+        meter
+            .u64_observable_counter("wellenbrecher.pixels")
+            .with_description("Pixels set per worker")
+            .with_unit("{pixel}")
+            .with_callback(move |observer| {
+                for (worker, counters) in stats.workers().iter().enumerate() {
+                    let (_, pixels) = counters.load();
+                    observer.observe(pixels, &[KeyValue::new("worker", worker as i64)]);
+                }
+            })
+            .build();
+
+        meter
+            .u64_observable_gauge("wellenbrecher.connections")
+            .with_description("Open connections of all users")
+            .with_unit("{connection}")
+            .with_callback(move |observer| {
+                let connections = clients
+                    .read()
+                    .expect("unable to acquire lock on clients")
+                    .iter()
+                    .map(|state| state.connections.load(Ordering::Relaxed) as u64)
+                    .sum();
+                observer.observe(connections, &[]);
+            })
+            .build();
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        // flushes what was not exported yet
+        if let Err(e) = self.tracer_provider.shutdown() {
+            warn!("unable to export the remaining spans: {e}");
+        }
+        if let Err(e) = self.meter_provider.shutdown() {
+            warn!("unable to export the remaining metrics: {e}");
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::io;
+use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ConnectionTrafficSecrets, ProtocolVersion, ServerConfig, ServerConnection};
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Clients taking longer than this for a step of the handshake are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TLS_RECORD_HEADER_LEN: usize = 5;
+
+// linux/tls.h
+const TLS_TX: libc::c_int = 1;
+const TLS_RX: libc::c_int = 2;
+const TLS_1_2_VERSION: u16 = 0x0303;
+const TLS_1_3_VERSION: u16 = 0x0304;
+const TLS_CIPHER_AES_GCM_128: u16 = 51;
+const TLS_CIPHER_AES_GCM_256: u16 = 52;
+const TLS_CIPHER_CHACHA20_POLY1305: u16 = 54;
+
+/*
+The handshake is done in userspace by rustls, afterwards the traffic secrets are handed to the
+kernel (kTLS). From then on the socket is read and written like any other connection, the
+kernel decrypts and encrypts the records and the io_uring read path stays the same.
+ */
+
+/// Server config for the pixelflut port, the secrets are extractable to be handed to the kernel
+pub fn server_config(cert: &Path, key: &Path) -> eyre::Result<Arc<ServerConfig>> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| eyre::eyre!("unable to load certificates from {}: {e}", cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| eyre::eyre!("unable to load private key from {}: {e}", key.display()))?;
+
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)?;
+    config.enable_secret_extraction = true;
+    // tickets would be sent after the handshake, when the kernel already owns the connection
+    config.send_tls13_tickets = 0;
+
+    // fail right away instead of on every connection
+    let probe = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+    if let Err(e) = setsockopt(&probe, libc::SOL_TCP, libc::TCP_ULP, b"tls") {
+        if e.raw_os_error() == Some(libc::ENOENT) {
+            return Err(eyre::eyre!(
+                "kernel TLS is unavailable, the tls kernel module is required"
+            ));
+        }
+    }
+
+    Ok(Arc::new(config))
+}
+
+/// Blocking handshake, afterwards the socket transparently en- and decrypts via kTLS
+pub fn accept(config: Arc<ServerConfig>, socket: &Socket) -> eyre::Result<()> {
+    socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    socket.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    let mut connection = ServerConnection::new(config)?;
+    let mut reader = RecordReader::new(socket);
+    let mut writer = socket;
+    let mut plaintext = 0;
+    while connection.is_handshaking() {
+        if connection.wants_write() {
+            connection.write_tls(&mut writer)?;
+            continue;
+        }
+
+        if connection.read_tls(&mut reader)? == 0 {
+            return Err(eyre::eyre!("connection closed during the handshake"));
+        }
+        match connection.process_new_packets() {
+            Ok(state) => plaintext = state.plaintext_bytes_to_read(),
+            Err(e) => {
+                // try to tell the client what went wrong
+                let _ = connection.write_tls(&mut writer);
+                return Err(e.into());
+            }
+        }
+    }
+    while connection.wants_write() {
+        connection.write_tls(&mut writer)?;
+    }
+    if plaintext > 0 {
+        return Err(eyre::eyre!(
+            "client sent data before the handshake was done"
+        ));
+    }
+
+    let version = match connection.protocol_version() {
+        Some(ProtocolVersion::TLSv1_2) => TLS_1_2_VERSION,
+        Some(ProtocolVersion::TLSv1_3) => TLS_1_3_VERSION,
+        version => return Err(eyre::eyre!("unsupported protocol version {version:?}")),
+    };
+    let secrets = connection.dangerous_extract_secrets()?;
+
+    setsockopt(socket, libc::SOL_TCP, libc::TCP_ULP, b"tls")
+        .map_err(|e| eyre::eyre!("unable to enable kernel TLS: {e}"))?;
+    set_crypto_info(socket, TLS_TX, version, secrets.tx)?;
+    set_crypto_info(socket, TLS_RX, version, secrets.rx)?;
+
+    socket.set_read_timeout(None)?;
+    socket.set_write_timeout(None)?;
+    Ok(())
+}
+
+/// tls12_crypto_info_* of linux/tls.h
+#[repr(C)]
+struct CryptoInfo<const IV: usize, const KEY: usize, const SALT: usize> {
+    version: u16,
+    cipher_type: u16,
+    iv: [u8; IV],
+    key: [u8; KEY],
+    salt: [u8; SALT],
+    rec_seq: [u8; 8],
+}
+
+impl<const IV: usize, const KEY: usize, const SALT: usize> CryptoInfo<IV, KEY, SALT> {
+    /// `iv` is the salt followed by the explicit part of the nonce
+    fn new(version: u16, cipher_type: u16, key: &[u8], iv: &[u8], seq: u64) -> eyre::Result<Self> {
+        let (salt, iv) = iv.split_at(SALT);
+        Ok(Self {
+            version,
+            cipher_type,
+            iv: iv.try_into()?,
+            key: key.try_into()?,
+            salt: salt.try_into()?,
+            rec_seq: seq.to_be_bytes(),
+        })
+    }
+}
+
+fn set_crypto_info(
+    socket: &Socket,
+    direction: libc::c_int,
+    version: u16,
+    (seq, secrets): (u64, ConnectionTrafficSecrets),
+) -> eyre::Result<()> {
+    let result = match secrets {
+        ConnectionTrafficSecrets::Aes128Gcm { key, iv } => setsockopt(
+            socket,
+            libc::SOL_TLS,
+            direction,
+            &CryptoInfo::<8, 16, 4>::new(
+                version,
+                TLS_CIPHER_AES_GCM_128,
+                key.as_ref(),
+                iv.as_ref(),
+                seq,
+            )?,
+        ),
+        ConnectionTrafficSecrets::Aes256Gcm { key, iv } => setsockopt(
+            socket,
+            libc::SOL_TLS,
+            direction,
+            &CryptoInfo::<8, 32, 4>::new(
+                version,
+                TLS_CIPHER_AES_GCM_256,
+                key.as_ref(),
+                iv.as_ref(),
+                seq,
+            )?,
+        ),
+        ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => setsockopt(
+            socket,
+            libc::SOL_TLS,
+            direction,
+            &CryptoInfo::<12, 32, 0>::new(
+                version,
+                TLS_CIPHER_CHACHA20_POLY1305,
+                key.as_ref(),
+                iv.as_ref(),
+                seq,
+            )?,
+        ),
+        _ => return Err(eyre::eyre!("cipher suite is not supported by kernel TLS")),
+    };
+
+    result.map_err(|e| eyre::eyre!("unable to hand the traffic secrets to the kernel: {e}"))
+}
+
+fn setsockopt<T>(
+    socket: &Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const _,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Never reads past the end of the current TLS record, so that nothing
+/// sent after the handshake ends up in userspace instead of the kernel
+struct RecordReader<'a> {
+    socket: &'a Socket,
+    header: [u8; TLS_RECORD_HEADER_LEN],
+    /// header bytes not yet passed on
+    header_pending: usize,
+    /// body bytes of the current record left to read
+    body_remaining: usize,
+}
+
+impl<'a> RecordReader<'a> {
+    fn new(socket: &'a Socket) -> Self {
+        Self {
+            socket,
+            header: [0; TLS_RECORD_HEADER_LEN],
+            header_pending: 0,
+            body_remaining: 0,
+        }
+    }
+}
+
+impl Read for RecordReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.header_pending == 0 && self.body_remaining == 0 {
+            match self.socket.read_exact(&mut self.header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) => return Err(e),
+            }
+            self.header_pending = TLS_RECORD_HEADER_LEN;
+            self.body_remaining = u16::from_be_bytes([self.header[3], self.header[4]]) as usize;
+        }
+
+        if self.header_pending > 0 {
+            let header = &self.header[TLS_RECORD_HEADER_LEN - self.header_pending..];
+            let n = header.len().min(buf.len());
+            buf[..n].copy_from_slice(&header[..n]);
+            self.header_pending -= n;
+            return Ok(n);
+        }
+
+        let len = buf.len().min(self.body_remaining);
+        let n = self.socket.read(&mut buf[..len])?;
+        self.body_remaining -= n;
+        Ok(n)
+    }
+}